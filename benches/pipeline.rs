@@ -0,0 +1,99 @@
+use std::time::Instant;
+
+// The pipeline doesn't depend on the rest of the crate, which is a binary. Its tests are only
+// run with the crate's, they are unused here.
+#[path = "../src/pipeline.rs"]
+#[allow(unused)]
+mod pipeline;
+
+/*
+ * Throughput of the rendering pipeline with various numbers of workers, with a stand-in for
+ * rendering, and on Windows of conversions of a backup to JSON with the evtq binary. The backup
+ * is the one in EVTQ_BENCH_EVTX, or a copy of the local Application log.
+ *
+ * Run with: cargo bench
+ */
+
+const EVENTS: u64 = 200_000;
+const BATCH_SIZE: u64 = 64;
+const THREADS: &[usize] = &[1, 2, 4, 8];
+
+// A few microseconds of formatting, like rendering an event to JSON
+fn render(i: u64) -> Option<String> {
+    let mut res = String::with_capacity(512);
+    for j in 0..32u64 {
+        res.push_str(&format!("\"field{}\":\"{:x}\",", j, i.wrapping_mul(j + 1).rotate_left(j as u32)));
+    }
+    Some(res)
+}
+
+fn bench_pipeline(threads: usize, ordered: bool) {
+    let mut next = 0;
+    let next_batch = || {
+        if next >= EVENTS {
+            return Ok(None);
+        }
+        let batch = (next..std::cmp::min(next + BATCH_SIZE, EVENTS)).collect();
+        next += BATCH_SIZE;
+        Ok(Some(batch))
+    };
+    let mut written = 0;
+    let start = Instant::now();
+    pipeline::run_pipeline(threads, ordered, next_batch, || render, |output: String| {
+        written += output.len();
+        Ok(())
+    }).unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("pipeline, {} worker(s), {}: {:.0} events/s ({} bytes)", threads,
+             if ordered { "ordered" } else { "unordered" }, EVENTS as f64 / elapsed, written);
+}
+
+#[cfg(windows)]
+fn bench_backup(path: &str, threads: usize) {
+    let out_path = std::env::temp_dir().join("evtq-bench.json");
+    let start = Instant::now();
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_evtq"))
+        .args(["--from-backup", path, "--threads", &threads.to_string(), "--to-json"])
+        .arg(&out_path)
+        .status()
+        .expect("unable to run evtq");
+    let elapsed = start.elapsed().as_secs_f64();
+    assert!(status.success(), "evtq failed on {}", path);
+    let events = std::fs::read_to_string(&out_path).map(|s| s.lines().count()).unwrap_or(0);
+    println!("{}, {} worker(s): {:.0} events/s ({} events)", path, threads, events as f64 / elapsed, events);
+    let _ = std::fs::remove_file(&out_path);
+}
+
+#[cfg(windows)]
+fn bench_backups() {
+    let path = match std::env::var("EVTQ_BENCH_EVTX") {
+        Ok(path) => path,
+        Err(_) => {
+            // The live log cannot be opened as a backup, export it first
+            let path = std::env::temp_dir().join("evtq-bench.evtx").display().to_string();
+            let _ = std::fs::remove_file(&path);
+            let status = std::process::Command::new("wevtutil")
+                .args(["export-log", "Application", &path])
+                .status()
+                .expect("unable to run wevtutil");
+            assert!(status.success(), "unable to export the Application log");
+            path
+        },
+    };
+    for &threads in THREADS {
+        bench_backup(&path, threads);
+    }
+}
+
+#[cfg(not(windows))]
+fn bench_backups() {
+    println!("Conversions of backups are only benchmarked on Windows");
+}
+
+fn main() {
+    for &threads in THREADS {
+        bench_pipeline(threads, true);
+        bench_pipeline(threads, false);
+    }
+    bench_backups();
+}
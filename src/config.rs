@@ -0,0 +1,419 @@
+/*
+ * Configuration files are TOML files whose keys are the long names of command line options,
+ * read as if they had been given before the ones on the command line:
+ *
+ *   columns = "timestamp,provider,eventid,formatted_message"
+ *   import-metadata = ["${EVTQ_HOME}/event_definitions.json"]
+ *
+ *   [profiles.logons]
+ *   include = ["Security/Microsoft-Windows-Security-Auditing/4624"]
+ *   to-csv = "logons.csv"
+ *   csv-header = "columns"
+ *
+ * Options of the selected profile override the ones at the top of the file, and options given
+ * on the command line override both (including lists, e.g. -i replaces include). Strings can
+ * reference environment variables as ${NAME} ($$ for a literal $). Profiles can have a
+ * description, and built-in presets (see presets.toml) are profiles which can be selected by
+ * a preset key, with lower priority than all other options.
+ */
+
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use clap::ArgMatches;
+
+#[derive(PartialEq)]
+enum ConfigValue {
+    // Option without value, set with true
+    Flag,
+    // Option without value which can be repeated, set with a number (e.g. verbose = 2)
+    Count,
+    Value,
+    // Option which can be repeated, set with a string or a list of strings
+    Values,
+}
+
+// Long option name, argument name, and type of value. One-off commands (e.g. --metadata-diff)
+// and options of the configuration itself are not accepted.
+const CONFIG_KEYS: &[(&str, &str, ConfigValue)] = &[
+    ("verbose", "verbosity", ConfigValue::Count),
+    ("quiet", "quiet", ConfigValue::Count),
+    ("log-level", "log-level", ConfigValue::Value),
+    ("log-format", "log-format", ConfigValue::Value),
+    ("log-file", "log-file", ConfigValue::Value),
+    ("export-metadata", "export-metadata", ConfigValue::Value),
+    ("import-metadata", "import-metadata", ConfigValue::Values),
+    ("merge-policy", "merge-policy", ConfigValue::Value),
+    ("extract-metadata", "extract-metadata", ConfigValue::Values),
+    ("image-root", "image-root", ConfigValue::Value),
+    ("software-hive", "software-hive", ConfigValue::Value),
+    ("system-hive", "system-hive", ConfigValue::Value),
+    ("from-host", "from-host", ConfigValue::Value),
+    ("from-backup", "from-backup", ConfigValue::Value),
+    ("threads", "threads", ConfigValue::Value),
+    ("unordered", "unordered", ConfigValue::Flag),
+    ("to-json", "to-json", ConfigValue::Value),
+    ("to-xml", "to-xml", ConfigValue::Value),
+    ("to-csv", "to-csv", ConfigValue::Value),
+    ("to-tsv", "to-tsv", ConfigValue::Value),
+    ("csv-header", "csv-header", ConfigValue::Value),
+    ("csv-delimiter", "csv-delimiter", ConfigValue::Value),
+    ("csv-quote", "csv-quote", ConfigValue::Value),
+    ("csv-line-terminator", "csv-line-terminator", ConfigValue::Value),
+    ("csv-escaping", "csv-escaping", ConfigValue::Value),
+    ("to-evtx", "to-evtx", ConfigValue::Value),
+    ("evtx-renumber", "evtx-renumber", ConfigValue::Flag),
+    ("coverage", "coverage", ConfigValue::Value),
+    ("export-skeletons", "export-skeletons", ConfigValue::Value),
+    ("process-tree", "process-tree", ConfigValue::Value),
+    ("tree-format", "tree-format", ConfigValue::Value),
+    ("tree-root", "tree-root", ConfigValue::Values),
+    ("script-blocks", "script-blocks", ConfigValue::Value),
+    ("scripts-dir", "scripts-dir", ConfigValue::Value),
+    ("decode-commands", "decode-commands", ConfigValue::Flag),
+    ("summary", "summary", ConfigValue::Value),
+    ("summary-json", "summary-json", ConfigValue::Value),
+    ("errors-to", "errors-to", ConfigValue::Value),
+    ("fail-on-error", "fail-on-error", ConfigValue::Value),
+    ("sessions", "sessions", ConfigValue::Flag),
+    ("session-timeout", "session-timeout", ConfigValue::Value),
+    ("append", "append", ConfigValue::Flag),
+    ("dump-existing", "dump-existing", ConfigValue::Flag),
+    ("no-wait", "no-wait", ConfigValue::Flag),
+    ("datefmt", "datefmt", ConfigValue::Value),
+    ("timezone", "timezone", ConfigValue::Value),
+    ("json-pretty", "json-pretty", ConfigValue::Flag),
+    ("xml-document", "xml-document", ConfigValue::Flag),
+    ("xml-canonical", "xml-canonical", ConfigValue::Flag),
+    ("no-system-metadata", "no-system-metadata", ConfigValue::Flag),
+    ("infer-fields", "infer-fields", ConfigValue::Flag),
+    ("learn-fields", "learn-fields", ConfigValue::Value),
+    ("expand-parameters", "expand-parameters", ConfigValue::Flag),
+    ("include", "include", ConfigValue::Values),
+    ("exclude", "exclude", ConfigValue::Values),
+    ("columns", "columns", ConfigValue::Value),
+    ("preset", "preset", ConfigValue::Value),
+];
+
+// Options of which only one is used: one given on the command line replaces all others of the file
+const EXCLUSIVE_KEYS: &[&[&str]] = &[
+    &["from-host", "from-backup"],
+    &["to-json", "to-xml", "to-csv", "to-tsv", "to-evtx", "coverage", "process-tree", "script-blocks"],
+];
+
+// Replaces ${NAME} references to environment variables in a string value
+fn interpolate(value: &str, key: &str) -> Result<String, String> {
+    let mut res = String::new();
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        res.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if rest.starts_with('$') {
+            res.push('$');
+            rest = &rest[1..];
+        } else if rest.starts_with('{') {
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => return Err(format!("Unterminated ${{...}} in value of key '{}'", key)),
+            };
+            let name = &rest[1..end];
+            match std::env::var(name) {
+                Ok(var) => res.push_str(&var),
+                Err(_) => return Err(format!("Environment variable {} referenced by key '{}' is not set", name, key)),
+            }
+            rest = &rest[end + 1..];
+        } else {
+            res.push('$');
+        }
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+fn string_value(value: &toml::Value, key: &str) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => interpolate(s, key),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        _ => Err(format!("Invalid value for key '{}': expected a string", key)),
+    }
+}
+
+// Translates the value of a key to command line arguments
+fn key_arguments(long: &str, kind: &ConfigValue, value: &toml::Value, key: &str) -> Result<Vec<String>, String> {
+    Ok(match (kind, value) {
+        (ConfigValue::Flag, toml::Value::Boolean(true)) => vec![format!("--{}", long)],
+        (ConfigValue::Flag, toml::Value::Boolean(false)) => vec![],
+        (ConfigValue::Flag, _) => return Err(format!("Invalid value for key '{}': expected true or false", key)),
+        (ConfigValue::Count, toml::Value::Integer(i)) if *i >= 0 => vec![format!("--{}", long); *i as usize],
+        (ConfigValue::Count, _) => return Err(format!("Invalid value for key '{}': expected a number of times", key)),
+        (ConfigValue::Value, value) => vec![format!("--{}={}", long, string_value(value, key)?)],
+        (ConfigValue::Values, toml::Value::Array(items)) => {
+            let mut args = vec![];
+            for (i, item) in items.iter().enumerate() {
+                args.push(format!("--{}={}", long, string_value(item, &format!("{}[{}]", key, i))?));
+            }
+            args
+        },
+        (ConfigValue::Values, value) => vec![format!("--{}={}", long, string_value(value, key)?)],
+    })
+}
+
+fn parse_config(config: &str, name: &str) -> Result<toml::value::Table, String> {
+    match toml::from_str(config) {
+        Ok(c) => Ok(c),
+        Err(e) => Err(format!("Unable to parse configuration file {}: {}", name, e)),
+    }
+}
+
+fn config_profiles<'a>(config: &'a toml::value::Table, name: &str) -> Result<Option<&'a toml::value::Table>, String> {
+    match config.get("profiles") {
+        Some(toml::Value::Table(profiles)) => Ok(Some(profiles)),
+        Some(_) => Err(format!("Invalid value for key 'profiles' in {}: expected a table of profiles", name)),
+        None => Ok(None),
+    }
+}
+
+// Where options which were not given on the command line come from, to name them in errors
+#[derive(Default)]
+pub struct OptionOrigins {
+    origins: BTreeMap<String, String>,
+}
+
+impl OptionOrigins {
+    // Options of other, e.g. of the configuration file, take precedence over the ones of a preset
+    pub fn extend(&mut self, other: OptionOrigins) {
+        self.origins.extend(other.origins);
+    }
+
+    // The configuration key an option comes from, or the option itself if given on the command line
+    pub fn name(&self, long: &str) -> String {
+        match self.origins.get(long) {
+            Some(origin) => origin.to_owned(),
+            None => format!("--{}", long),
+        }
+    }
+
+    // Points errors about the value of an option at the configuration key it comes from
+    pub fn check<T>(&self, long: &str, res: Result<T, String>) -> Result<T, String> {
+        res.map_err(|e| match self.origins.get(long) {
+            Some(origin) => format!("Invalid value for {}: {}", origin, e),
+            None => e,
+        })
+    }
+}
+
+// Returns the options at the top level of a configuration and in one of its profiles as command
+// line arguments, except the ones given on the command line
+fn profile_arguments(config: &toml::value::Table, name: &str, profile: Option<&str>, cli_args: &ArgMatches) -> Result<(Vec<String>, OptionOrigins), String> {
+    // Keys of the profile override the ones at the top level, with the full key name and where
+    // it comes from for errors
+    let mut settings: BTreeMap<String, (String, String, &toml::Value)> = BTreeMap::new();
+    for (key, value) in config.iter().filter(|(key, _)| *key != "profiles") {
+        settings.insert(key.to_owned(), (key.to_owned(), format!("key '{}' in {}", key, name), value));
+    }
+    let profiles = config_profiles(config, name)?;
+    if let Some(profile) = profile {
+        let values = match profiles.and_then(|profiles| profiles.get(profile)) {
+            Some(toml::Value::Table(values)) => values,
+            Some(_) => return Err(format!("Invalid value for key 'profiles.{}' in {}: expected a table of options", profile, name)),
+            None => return Err(format!("Profile '{}' not found in {} (available: {})", profile, name,
+                                       profiles.map(|p| p.keys().cloned().collect::<Vec<_>>().join(", ")).unwrap_or_default())),
+        };
+        for (key, value) in values {
+            settings.insert(key.to_owned(), (format!("profiles.{}.{}", profile, key),
+                                             format!("key '{}' of profile '{}' in {}", key, profile, name), value));
+        }
+    }
+
+    let given_on_cli = |long: &str| CONFIG_KEYS.iter()
+        .any(|(key, arg_name, _)| *key == long && cli_args.occurrences_of(arg_name) > 0);
+    let mut args = vec![];
+    let mut origins = OptionOrigins::default();
+    for (long, (key, origin, value)) in &settings {
+        if long == "description" {
+            continue; // only listed
+        }
+        let kind = match CONFIG_KEYS.iter().find(|(name, _, _)| name == long) {
+            Some((_, _, kind)) => kind,
+            None => return Err(format!("Unknown key '{}' in {}", key, name)),
+        };
+        let overridden = match EXCLUSIVE_KEYS.iter().find(|group| group.contains(&&long[..])) {
+            Some(group) => group.iter().any(|other| given_on_cli(other)),
+            None => given_on_cli(long),
+        };
+        let key_args = match key_arguments(long, kind, value, key) {
+            Ok(key_args) => key_args,
+            Err(e) => return Err(format!("{} (in {})", e, name)),
+        };
+        if !overridden {
+            args.extend(key_args);
+            origins.origins.insert(long.to_owned(), origin.to_owned());
+        }
+    }
+    Ok((args, origins))
+}
+
+pub fn config_arguments(path: &str, profile: Option<&str>, cli_args: &ArgMatches) -> Result<(Vec<String>, OptionOrigins), String> {
+    let config = match read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Could not open file {} : {}", path, e)),
+    };
+    profile_arguments(&parse_config(&config, path)?, path, profile, cli_args)
+}
+
+// Presets are profiles of a configuration file shipped with evtq, without top level options
+const PRESETS: &str = include_str!("presets.toml");
+
+pub fn preset_arguments(preset: &str, cli_args: &ArgMatches) -> Result<(Vec<String>, OptionOrigins), String> {
+    let presets = parse_config(PRESETS, "built-in presets")?;
+    if !config_profiles(&presets, "built-in presets")?.map(|p| p.contains_key(preset)).unwrap_or(false) {
+        return Err(format!("Unknown preset '{}' (see --list-presets)", preset));
+    }
+    profile_arguments(&presets, "built-in presets", Some(preset), cli_args)
+}
+
+// Names and descriptions of presets, with their options
+pub fn preset_descriptions() -> Result<Vec<(String, String, String)>, String> {
+    let presets = parse_config(PRESETS, "built-in presets")?;
+    let mut descriptions = vec![];
+    for (name, preset) in config_profiles(&presets, "built-in presets")?.into_iter().flatten() {
+        let options = match preset.as_table() {
+            Some(options) => options,
+            None => continue,
+        };
+        let description = options.get("description").and_then(|d| d.as_str()).unwrap_or_default();
+        let options = options.iter().filter(|(key, _)| *key != "description")
+            .map(|(key, value)| format!("{} = {}", key, value))
+            .collect::<Vec<String>>().join("\n");
+        descriptions.push((name.to_owned(), description.to_owned(), options));
+    }
+    Ok(descriptions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+    use crate::output_cols::{parse_column_names, columns_reference_field_names};
+
+    #[test]
+    fn names_configuration_keys_in_errors() {
+        let config = parse_config(r#"
+            threads = "2"
+            [profiles.logons]
+            columns = "timestamp"
+        "#, "evtq.toml").unwrap();
+        let cli_args = App::new("evtq")
+            .arg(Arg::with_name("threads").long("threads").takes_value(true))
+            .arg(Arg::with_name("columns").long("columns").takes_value(true))
+            .get_matches_from(vec!["evtq", "--threads=4"]);
+        let (args, origins) = profile_arguments(&config, "evtq.toml", Some("logons"), &cli_args).unwrap();
+        assert_eq!(args, vec!["--columns=timestamp"]);
+        assert_eq!(origins.name("columns"), "key 'columns' of profile 'logons' in evtq.toml");
+        assert_eq!(origins.name("threads"), "--threads");
+        assert_eq!(origins.check::<()>("columns", Err("Unknown column 'x'".to_owned())),
+                   Err("Invalid value for key 'columns' of profile 'logons' in evtq.toml: Unknown column 'x'".to_owned()));
+        assert_eq!(origins.check::<()>("threads", Err("Invalid number of threads 'x'".to_owned())),
+                   Err("Invalid number of threads 'x'".to_owned()));
+    }
+
+    fn no_cli_args() -> ArgMatches<'static> {
+        App::new("evtq").get_matches_from(vec!["evtq"])
+    }
+
+    fn arguments(config: &str, profile: Option<&str>) -> Result<Vec<String>, String> {
+        let config = parse_config(config, "evtq.toml")?;
+        profile_arguments(&config, "evtq.toml", profile, &no_cli_args()).map(|(args, _)| args)
+    }
+
+    #[test]
+    fn interpolates_environment_variables() {
+        std::env::set_var("EVTQ_TEST_HOME", "/opt/evtq");
+        std::env::remove_var("EVTQ_TEST_UNSET");
+        assert_eq!(interpolate("${EVTQ_TEST_HOME}/defs.json", "k"), Ok("/opt/evtq/defs.json".to_owned()));
+        assert_eq!(interpolate("a${EVTQ_TEST_HOME}b${EVTQ_TEST_HOME}", "k"), Ok("a/opt/evtqb/opt/evtq".to_owned()));
+        assert_eq!(interpolate("costs $$5, $$${EVTQ_TEST_HOME}", "k"), Ok("costs $5, $/opt/evtq".to_owned()));
+        assert_eq!(interpolate("$HOME and $", "k"), Ok("$HOME and $".to_owned()));
+        assert_eq!(interpolate("${EVTQ_TEST_UNSET}/x", "import-metadata[0]"),
+                   Err("Environment variable EVTQ_TEST_UNSET referenced by key 'import-metadata[0]' is not set".to_owned()));
+        assert_eq!(interpolate("${EVTQ_TEST_HOME", "log-file"),
+                   Err("Unterminated ${...} in value of key 'log-file'".to_owned()));
+        assert_eq!(arguments("import-metadata = [\"${EVTQ_TEST_HOME}/a.json\", \"b.json\"]", None),
+                   Ok(vec!["--import-metadata=/opt/evtq/a.json".to_owned(), "--import-metadata=b.json".to_owned()]));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert_eq!(arguments("colums = \"timestamp\"", None), Err("Unknown key 'colums' in evtq.toml".to_owned()));
+        assert_eq!(arguments("[profiles.logons]\nmetadata-diff = \"a.json\"", Some("logons")),
+                   Err("Unknown key 'profiles.logons.metadata-diff' in evtq.toml".to_owned()));
+        // Unknown keys of profiles which are not selected are not checked
+        assert_eq!(arguments("[profiles.logons]\nbogus = 1", None), Ok(vec![]));
+        assert!(arguments("profiles = 3", None).is_err());
+    }
+
+    #[test]
+    fn types_values_of_keys() {
+        assert_eq!(arguments("verbose = 2\nquiet = 0", None), Ok(vec!["--verbose".to_owned(), "--verbose".to_owned()]));
+        assert_eq!(arguments("json-pretty = true\nappend = false", None), Ok(vec!["--json-pretty".to_owned()]));
+        assert_eq!(arguments("threads = 4\ninclude = \"Security\"", None),
+                   Ok(vec!["--include=Security".to_owned(), "--threads=4".to_owned()]));
+        assert_eq!(arguments("verbose = \"2\"", None),
+                   Err("Invalid value for key 'verbose': expected a number of times (in evtq.toml)".to_owned()));
+        assert_eq!(arguments("verbose = -1", None),
+                   Err("Invalid value for key 'verbose': expected a number of times (in evtq.toml)".to_owned()));
+        assert_eq!(arguments("[profiles.p]\njson-pretty = \"yes\"", Some("p")),
+                   Err("Invalid value for key 'profiles.p.json-pretty': expected true or false (in evtq.toml)".to_owned()));
+        assert_eq!(arguments("include = [\"Security\", 4624, true]", None),
+                   Err("Invalid value for key 'include[2]': expected a string (in evtq.toml)".to_owned()));
+    }
+
+    #[test]
+    fn overrides_exclusive_keys_given_on_the_command_line() {
+        let config = parse_config(r#"
+            to-csv = "events.csv"
+            from-host = "dc01"
+            threads = 2
+        "#, "evtq.toml").unwrap();
+        let cli_args = App::new("evtq")
+            .arg(Arg::with_name("to-json").long("to-json").takes_value(true))
+            .get_matches_from(vec!["evtq", "--to-json=events.json"]);
+        let (args, origins) = profile_arguments(&config, "evtq.toml", None, &cli_args).unwrap();
+        assert_eq!(args, vec!["--from-host=dc01", "--threads=2"]);
+        assert_eq!(origins.name("to-csv"), "--to-csv");
+
+        let cli_args = App::new("evtq")
+            .arg(Arg::with_name("from-backup").long("from-backup").takes_value(true))
+            .get_matches_from(vec!["evtq", "--from-backup=Security.evtx"]);
+        let (args, _) = profile_arguments(&config, "evtq.toml", None, &cli_args).unwrap();
+        assert_eq!(args, vec!["--threads=2", "--to-csv=events.csv"]);
+    }
+
+    #[test]
+    fn names_available_profiles_when_missing() {
+        let config = "[profiles.logons]\nsessions = true\n[profiles.processes]\nprocess-tree = \"tree.txt\"";
+        assert_eq!(arguments(config, Some("logon")),
+                   Err("Profile 'logon' not found in evtq.toml (available: logons, processes)".to_owned()));
+        assert_eq!(arguments("threads = 2", Some("logons")),
+                   Err("Profile 'logons' not found in evtq.toml (available: )".to_owned()));
+        assert_eq!(arguments(config, Some("logons")), Ok(vec!["--sessions".to_owned()]));
+    }
+
+    #[test]
+    fn parses_presets() {
+        let presets = parse_config(PRESETS, "built-in presets").unwrap();
+        let profiles = config_profiles(&presets, "built-in presets").unwrap().unwrap();
+        assert!(profiles.len() >= 8);
+        for (name, preset) in profiles {
+            let (args, origins) = preset_arguments(name, &no_cli_args()).unwrap();
+            assert!(args.iter().any(|arg| arg.starts_with("--include=")), "{}", name);
+            assert_eq!(origins.name("columns"), format!("key 'columns' of profile '{}' in built-in presets", name));
+            let columns = preset["columns"].as_str().unwrap();
+            let specs = parse_column_names(columns).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert!(columns_reference_field_names(&specs), "{}", name);
+            assert!(!preset["description"].as_str().unwrap().is_empty(), "{}", name);
+        }
+        let names: Vec<String> = preset_descriptions().unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, profiles.keys().cloned().collect::<Vec<_>>());
+        assert!(preset_arguments("logon", &no_cli_args()).is_err());
+    }
+}
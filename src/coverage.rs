@@ -0,0 +1,172 @@
+#[cfg(windows)]
+use winapi::um::winevt::*;
+use std::result::Result;
+use std::collections::BTreeMap;
+#[cfg(windows)]
+use crate::windows::{EvtHandle, format_event_message};
+#[cfg(windows)]
+use crate::{RenderingConfig, EventOutput};
+#[cfg(windows)]
+use crate::formatting::{CommonEventProperties, variant_type_name, render_event_values};
+use crate::metadata::{Metadata, ProviderMetadata, EventDefinition, EventFieldDefinition, MetadataSource};
+
+// How well metadata covered the events of a given Provider/EventID/Version
+#[derive(Default)]
+pub struct CoverageStats {
+    pub events: u64,
+    // Events which had a matching EventDefinition
+    pub defined: u64,
+    // Events with more values than named fields, rendered with generic fieldN names
+    pub generic_fields: u64,
+    pub format_failures: u64,
+    // Types of the values of the first event seen, to build skeleton definitions
+    field_types: Vec<String>,
+    hostname: String,
+}
+
+pub type Coverage = BTreeMap<(String, u64, u64), CoverageStats>;
+
+#[cfg(windows)]
+pub fn render_event_coverage(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let (buffer, props_count) = render_event_values(h_event)?;
+
+    let prov_meta = render_cfg.provider_metadata(&common_props.provider, common_props.provider_guid.as_deref());
+    let mut event_def : Option<&EventDefinition> = None;
+    if let Some(prov_meta) = &prov_meta {
+        if let Some(versions) = prov_meta.events.get(&common_props.eventid) {
+            event_def = versions.get(&common_props.version);
+        }
+    }
+    let format_failed = match (&prov_meta, event_def) {
+        (Some(prov_meta), Some(event_def)) if event_def.message.is_some() =>
+            format_event_message(event_def, &prov_meta.parameters, buffer.as_ptr() as *const EVT_VARIANT, props_count).is_err(),
+        _ => false,
+    };
+
+    let mut coverage = match render_cfg.coverage.lock() {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Failed to acquire lock to coverage statistics: {}", e)),
+    };
+    let stats = coverage.entry((common_props.provider.to_owned(), common_props.eventid, common_props.version))
+        .or_insert_with(CoverageStats::default);
+    if stats.events == 0 {
+        stats.hostname = common_props.hostname.to_owned();
+        for i in 0..props_count as usize {
+            let prop : EVT_VARIANT = unsafe {
+                std::ptr::read(buffer.as_ptr().add(i * std::mem::size_of::<EVT_VARIANT>()) as *const _)
+            };
+            stats.field_types.push(variant_type_name(prop.Type).to_owned());
+        }
+    }
+    stats.events += 1;
+    if let Some(event_def) = event_def {
+        stats.defined += 1;
+        if (props_count as usize) > event_def.fields.len() {
+            stats.generic_fields += 1;
+        }
+    } else if props_count > 0 {
+        stats.generic_fields += 1;
+    }
+    if format_failed {
+        stats.format_failures += 1;
+    }
+    // The report is only written once all events are rendered
+    Ok(EventOutput::default())
+}
+
+pub fn write_coverage_report(coverage: &Coverage, out_file: &mut dyn std::io::Write) -> Result<(), String> {
+    let mut lines = vec![format!("{:<60} {:>8} {:>8} {:>8} {:>8}", "Provider/EventID/Version", "Events", "Defined", "Generic", "Failed")];
+    let (mut events, mut defined, mut generic_fields, mut format_failures) = (0, 0, 0, 0);
+    for ((provider, eventid, version), stats) in coverage {
+        lines.push(format!("{:<60} {:>8} {:>8} {:>8} {:>8}", format!("{}/{}/{}", provider, eventid, version),
+                           stats.events, stats.defined, stats.generic_fields, stats.format_failures));
+        events += stats.events;
+        defined += stats.defined;
+        generic_fields += stats.generic_fields;
+        format_failures += stats.format_failures;
+    }
+    lines.push(format!("{:<60} {:>8} {:>8} {:>8} {:>8}", "Total", events, defined, generic_fields, format_failures));
+    match out_file.write_all((lines.join("\n") + "\n").as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Unable to write coverage report: {}", e)),
+    }
+}
+
+// Builds definitions of events which had none, with generic field names and the types of
+// the values seen, so that they can be completed by hand and imported with --import-metadata
+pub fn coverage_skeletons(coverage: &Coverage) -> Metadata {
+    let mut metadata = BTreeMap::new();
+    for ((provider, eventid, version), stats) in coverage {
+        if stats.defined > 0 {
+            continue;
+        }
+        let prov_meta = metadata.entry(provider.to_owned()).or_insert_with(ProviderMetadata::empty);
+        let fields = stats.field_types.iter().enumerate().map(|(i, out_type)| EventFieldDefinition {
+            name: format!("field{}", i + 1),
+            out_type: out_type.to_owned(),
+        }).collect();
+        prov_meta.events.entry(*eventid).or_insert(BTreeMap::new()).insert(*version, EventDefinition {
+            fields,
+            sources: vec![MetadataSource {
+                os_build: None,
+                host: Some(stats.hostname.to_owned()),
+                file: None,
+            }],
+            ..EventDefinition::empty()
+        });
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_coverage() -> Coverage {
+        let mut coverage = Coverage::new();
+        coverage.insert(("Microsoft-Windows-Security-Auditing".to_owned(), 4624, 2), CoverageStats {
+            events: 10,
+            defined: 10,
+            generic_fields: 0,
+            format_failures: 1,
+            field_types: vec!["SID".to_owned(), "String".to_owned()],
+            hostname: "host1".to_owned(),
+        });
+        coverage.insert(("Custom-Provider".to_owned(), 7, 0), CoverageStats {
+            events: 3,
+            defined: 0,
+            generic_fields: 3,
+            format_failures: 0,
+            field_types: vec!["String".to_owned(), "UInt32".to_owned()],
+            hostname: "host2".to_owned(),
+        });
+        coverage
+    }
+
+    #[test]
+    fn writes_coverage_report_with_totals() {
+        let mut out = Vec::new();
+        write_coverage_report(&sample_coverage(), &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<Vec<&str>> = report.lines().map(|l| l.split_whitespace().collect()).collect();
+        assert_eq!(lines, vec![
+            vec!["Provider/EventID/Version", "Events", "Defined", "Generic", "Failed"],
+            vec!["Custom-Provider/7/0", "3", "0", "3", "0"],
+            vec!["Microsoft-Windows-Security-Auditing/4624/2", "10", "10", "0", "1"],
+            vec!["Total", "13", "10", "3", "1"],
+        ]);
+    }
+
+    #[test]
+    fn builds_skeletons_of_undefined_events_only() {
+        let skeletons = coverage_skeletons(&sample_coverage());
+        assert_eq!(skeletons.keys().collect::<Vec<_>>(), vec!["Custom-Provider"]);
+        let event_def = &skeletons["Custom-Provider"].events[&7][&0];
+        let fields: Vec<(&str, &str)> = event_def.fields.iter()
+            .map(|f| (f.name.as_str(), f.out_type.as_str()))
+            .collect();
+        assert_eq!(fields, vec![("field1", "String"), ("field2", "UInt32")]);
+        assert_eq!(event_def.sources.len(), 1);
+        assert_eq!(event_def.sources[0].host.as_deref(), Some("host2"));
+    }
+}
@@ -1,207 +1,272 @@
+#[cfg(windows)]
 use winapi::um::winevt::*;
-use winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER;
-use std::ptr::null_mut;
 use std::result::Result;
-use winapi::ctypes::c_void;
-use crate::windows::{EvtHandle, get_win32_errcode};
-use crate::{RenderingConfig, OutputColumn};
-use crate::formatting::{unwrap_variant_contents, bytes_as_hexstring, format_utc_systemtime, CommonEventProperties, EvtVariant};
-use crate::metadata::EventDefinition;
-
-fn push_filtered_str(dest: &mut String, append: &str, forbidden: &char) {
-    dest.push_str(&append.replace(&forbidden.to_string(), " "))
+#[cfg(windows)]
+use std::collections::BTreeMap;
+use std::str::FromStr;
+#[cfg(windows)]
+use crate::windows::EvtHandle;
+#[cfg(windows)]
+use crate::{RenderingConfig, EventOutput};
+#[cfg(windows)]
+use crate::formatting::{variant_as_string, render_event_values, CommonEventProperties};
+#[cfg(windows)]
+use crate::metadata::{EventDefinition, classify_event};
+#[cfg(windows)]
+use crate::output_cols::{EventColumns, columns_reference_field_names};
+#[cfg(windows)]
+use crate::inference::learned_event_definition;
+use crate::sessions::session_column_names;
+use crate::output_cols::ColumnSpec;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsvEscaping {
+    // RFC 4180: values containing delimiters, quotes or line breaks are quoted, and quotes doubled
+    Quote,
+    // \t, \n, \r and \\, as usual in TSV files which cannot contain tabs or line breaks in values
+    Backslash,
+    // Delimiters and line breaks are replaced with spaces
+    Replace,
 }
 
-pub fn render_event_csv(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<(), String> {
-    let h_ctxuser = unsafe { EvtCreateRenderContext(0, null_mut(), EvtRenderContextUser) };
-    if h_ctxuser.is_null() {
-        return Err(format!("EvtCreateRenderContext(EvtRenderContextUser) failed with code {}", get_win32_errcode()));
-    }
-    let h_ctxuser = EvtHandle::from_raw(h_ctxuser)?;
-
-    let mut buffer_len_req : u32 = 0;
-    let mut props_count : u32 = 0;
-    let res = unsafe {
-        EvtRender(h_ctxuser.as_ptr(),
-                  h_event.as_ptr(),
-                  EvtRenderEventValues,
-                  0,
-                  null_mut(),
-                  &mut buffer_len_req as *mut u32,
-                  &mut props_count as *mut u32)
-    };
-    // res can be != 0 here if, even with a NULL buffer, if there are no event values to render
-    let mut buffer: Vec<u8> = Vec::with_capacity(buffer_len_req as usize);
-    if res == 0 && get_win32_errcode() != ERROR_INSUFFICIENT_BUFFER {
-        return Err(format!("EvtRender(EvtRenderEventValues) failed with code {}", get_win32_errcode()));
-    }
-    else if res == 0 {
-        let res = unsafe {
-            EvtRender(h_ctxuser.as_ptr(),
-                      h_event.as_ptr(),
-                      EvtRenderEventValues,
-                      buffer_len_req,
-                      buffer.as_mut_ptr() as *mut c_void,
-                      &mut buffer_len_req as *mut u32,
-                      &mut props_count as *mut u32)
-        };
-        if res == 0 {
-            return Err(format!("EvtRender(EvtRenderEventValues) failed with code {}", get_win32_errcode()));
+impl FromStr for CsvEscaping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "quote" => Ok(CsvEscaping::Quote),
+            "backslash" => Ok(CsvEscaping::Backslash),
+            "replace" => Ok(CsvEscaping::Replace),
+            _ => Err(format!("Unknown escaping policy '{}' (expected quote, backslash, or replace)", s)),
         }
     }
+}
 
-    let mut event_def = &EventDefinition {
-        channel: None,
-        message: None,
-        level: 0,
-        level_name: None,
-        opcode: 0,
-        opcode_name: None,
-        keywords: 0,
-        keyword_names: vec![],
-        task: 0,
-        task_name: None,
-        fields: vec![],
-    };
-    if let Some(prov_meta) = render_cfg.metadata.get(&common_props.provider) {
-        if let Some(versions) = prov_meta.events.get(&common_props.eventid) {
-            if let Some(known_event_def) = versions.get(&common_props.version) {
-                event_def = known_event_def;
-            }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsvHeader {
+    // A single header row with column names (or aliases) as given in --columns
+    Columns,
+    // A header row with the field names of events, written again whenever they change
+    Fields,
+}
+
+impl FromStr for CsvHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "columns" => Ok(CsvHeader::Columns),
+            "fields" => Ok(CsvHeader::Fields),
+            _ => Err(format!("Unknown header type '{}' (expected columns or fields)", s)),
         }
     }
+}
 
-    let mut line = String::new();
-    let mut first = true;
-    for column in &render_cfg.columns {
-        if ! first {
-            line.push(render_cfg.field_separator);
-        }
-        first = false;
-        match column {
-            OutputColumn::Hostname => push_filtered_str(&mut line,
-                                                        &common_props.hostname,
-                                                        &render_cfg.field_separator),
-            OutputColumn::RecordID => push_filtered_str(&mut line,
-                                                        &common_props.recordid.to_string(),
-                                                        &render_cfg.field_separator),
-            OutputColumn::Timestamp => push_filtered_str(&mut line,
-                                                         &format_utc_systemtime(&common_props.timestamp, &render_cfg.datefmt),
-                                                         &render_cfg.field_separator),
-            OutputColumn::Provider => push_filtered_str(&mut line,
-                                                        &common_props.provider,
-                                                        &render_cfg.field_separator),
-            OutputColumn::EventID => push_filtered_str(&mut line,
-                                                       &common_props.eventid.to_string(),
-                                                       &render_cfg.field_separator),
-            OutputColumn::Version => push_filtered_str(&mut line,
-                                                       &common_props.version.to_string(),
-                                                       &render_cfg.field_separator),
-            OutputColumn::Level => push_filtered_str(&mut line,
-                                                       &event_def.level.to_string(),
-                                                       &render_cfg.field_separator),
-            OutputColumn::LevelName => match &event_def.level_name {
-                Some(s) => push_filtered_str(&mut line,
-                                             s,
-                                             &render_cfg.field_separator),
-                None => (),
-            },
-            OutputColumn::Task => push_filtered_str(&mut line,
-                                                       &event_def.task.to_string(),
-                                                       &render_cfg.field_separator),
-            OutputColumn::TaskName => match &event_def.task_name {
-                Some(s) => push_filtered_str(&mut line,
-                                             s,
-                                             &render_cfg.field_separator),
-                None => (),
-            },
-            OutputColumn::Opcode => push_filtered_str(&mut line,
-                                                       &event_def.opcode.to_string(),
-                                                       &render_cfg.field_separator),
-            OutputColumn::OpcodeName => match &event_def.opcode_name {
-                Some(s) => push_filtered_str(&mut line,
-                                             s,
-                                             &render_cfg.field_separator),
-                None => (),
-            },
-            OutputColumn::Keywords => push_filtered_str(&mut line,
-                                                       &event_def.keywords.to_string(),
-                                                       &render_cfg.field_separator),
-            OutputColumn::KeywordNames => push_filtered_str(&mut line,
-                                                       &event_def.keyword_names.join(","),
-                                                       &render_cfg.field_separator),
-            OutputColumn::UnformattedMessage => {
-                if let Some(template) = &event_def.message {
-                    push_filtered_str(&mut line, template, &render_cfg.field_separator);
+pub struct CsvConfig {
+    pub delimiter: char,
+    pub quote: char,
+    pub line_terminator: &'static str,
+    pub escaping: CsvEscaping,
+    pub header: Option<CsvHeader>,
+}
+
+impl CsvConfig {
+    pub fn new(delimiter: char, quote: char, line_terminator: &'static str, escaping: CsvEscaping, header: Option<CsvHeader>) -> CsvConfig {
+        CsvConfig { delimiter, quote, line_terminator, escaping, header }
+    }
+
+    fn escape(&self, value: &str) -> String {
+        match self.escaping {
+            CsvEscaping::Quote => {
+                if value.contains([self.delimiter, self.quote, '\r', '\n']) {
+                    let quote = self.quote.to_string();
+                    format!("{}{}{}", quote, value.replace(&quote, &quote.repeat(2)), quote)
+                } else {
+                    value.to_owned()
                 }
             },
-            OutputColumn::FormattedMessage => {
-                if let Some(template) = &event_def.message {
-                    match crate::windows::format_event_message(&event_def, buffer.as_ptr() as *const EVT_VARIANT, props_count) {
-                        Ok(message) => {
-                            push_filtered_str(&mut line, &message, &render_cfg.field_separator);
-                        },
-                        Err(e) => {
-                            warn!("Unable to format template \"{}\" of event {}/{}/{}: {}",
-                                  template, common_props.provider, common_props.eventid,
-                                  common_props.version, e);
-                            push_filtered_str(&mut line, &template, &render_cfg.field_separator);
+            CsvEscaping::Backslash => {
+                let mut res = String::with_capacity(value.len());
+                for c in value.chars() {
+                    match c {
+                        '\\' => res.push_str("\\\\"),
+                        '\t' => res.push_str("\\t"),
+                        '\n' => res.push_str("\\n"),
+                        '\r' => res.push_str("\\r"),
+                        c if c == self.delimiter => {
+                            res.push('\\');
+                            res.push(c);
                         },
+                        c => res.push(c),
                     }
                 }
+                res
             },
+            CsvEscaping::Replace => value.replace([self.delimiter, '\r', '\n'], " "),
+        }
+    }
 
-            OutputColumn::EventSpecific(prop_num) => {
-                if prop_num >= &props_count {
-                    break; // silently truncate lines which reference non-existent fields
-                }
-                let buffer_offset = (*prop_num as usize) * std::mem::size_of::<EVT_VARIANT>();
-                let prop : EVT_VARIANT = unsafe {
-                    std::ptr::read(buffer.as_ptr().add(buffer_offset) as *const _)
-                };
-                let prop = unwrap_variant_contents(&prop, None)?;
-                match prop {
-                    EvtVariant::Null => (),
-                    EvtVariant::Handle(_) => push_filtered_str(&mut line,
-                                                               "<handle>",
-                                                               &render_cfg.field_separator),
-                    EvtVariant::String(s) => push_filtered_str(&mut line,
-                                                                      &s,
-                                                                      &render_cfg.field_separator),
-                    EvtVariant::UInt(i) => push_filtered_str(&mut line,
-                                                                   &i.to_string(),
-                                                                   &render_cfg.field_separator),
-                    EvtVariant::Int(i) => push_filtered_str(&mut line,
-                                                                 &i.to_string(),
-                                                                 &render_cfg.field_separator),
-                    EvtVariant::Single(f) => push_filtered_str(&mut line,
-                                                                    &f.to_string(),
-                                                                    &render_cfg.field_separator),
-                    EvtVariant::Double(f) => push_filtered_str(&mut line,
-                                                                     &f.to_string(),
-                                                           &render_cfg.field_separator),
-                    EvtVariant::Boolean(b) => push_filtered_str(&mut line,
-                                                                      if b { "true" } else { "false" },
-                                                                       &render_cfg.field_separator),
-                    EvtVariant::Binary(s) => push_filtered_str(&mut line,
-                                                                        &bytes_as_hexstring(&s),
-                                                                        &render_cfg.field_separator),
-                    EvtVariant::DateTime(d) => push_filtered_str(&mut line,
-                                                                                &format_utc_systemtime(&d, &render_cfg.datefmt),
-                                                                                &render_cfg.field_separator),
-                }
-            },
-        };
+    pub fn row(&self, values: &[String]) -> String {
+        let mut line = values.iter().map(|value| self.escape(value)).collect::<Vec<String>>()
+            .join(&self.delimiter.to_string());
+        line.push_str(self.line_terminator);
+        line
+    }
+}
+
+// Parses a single character option value, e.g. a delimiter ("tab" is accepted for convenience)
+// The option is named in errors as given, e.g. as a configuration key
+pub fn parse_csv_char(option: &str, value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    match (value, chars.next(), chars.next()) {
+        ("tab", _, _) | ("\\t", _, _) => Ok('\t'),
+        (_, Some(c), None) => Ok(c),
+        _ => Err(format!("Expecting a single character for {}, got '{}'", option, value)),
+    }
+}
+
+// Header row with the names of columns as given by the user, written once before any event
+// Names in the header row: the columns of sessions with --sessions, the ones given with --columns otherwise
+pub fn csv_header_names(columns: &[ColumnSpec], sessions: bool) -> Vec<String> {
+    if sessions {
+        session_column_names()
+    } else {
+        columns.iter().map(|spec| spec.name().to_owned()).collect()
     }
-    line.push('\n');
+}
+
+#[cfg(windows)]
+pub fn write_csv_header(render_cfg: &RenderingConfig) -> Result<(), String> {
+    let names = csv_header_names(&render_cfg.columns, render_cfg.sessions.is_some());
+    render_cfg.output.write_all(render_cfg.csv.row(&names).as_bytes())
+}
 
-    match render_cfg.output_file.lock() {
-        Ok(mut f) => {
-            match f.write(line.as_bytes()) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(format!("Unable to write line to output file: {:?}", e)),
+#[cfg(windows)]
+pub fn render_event_csv(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let (buffer, props_count) = render_event_values(h_event)?;
+
+    let mut event_def = &EventDefinition::empty();
+    let no_parameters = BTreeMap::new();
+    let mut parameters = &no_parameters;
+    let prov_meta = render_cfg.provider_metadata(&common_props.provider, common_props.provider_guid.as_deref());
+    if let Some(prov_meta) = &prov_meta {
+        parameters = &prov_meta.parameters;
+        if let Some(versions) = prov_meta.events.get(&common_props.eventid) {
+            if let Some(known_event_def) = versions.get(&common_props.version) {
+                event_def = known_event_def;
             }
-        },
-        Err(e) => return Err(format!("Failed to acquire lock to output file: {}", e)),
+        }
+    }
+
+    // Named columns need field names, even from events without a (complete) definition
+    let learned_def;
+    if render_cfg.infer_fields && event_def.fields.len() < props_count as usize &&
+        columns_reference_field_names(&render_cfg.columns) {
+        learned_def = learned_event_definition(h_event, common_props, render_cfg, event_def,
+                                               buffer.as_ptr() as *const EVT_VARIANT, props_count)?;
+        event_def = &learned_def;
+    }
+
+    let classification = classify_event(common_props, event_def, prov_meta.as_deref());
+    let columns = EventColumns {
+        common_props,
+        render_cfg,
+        event_def,
+        parameters,
+        classification: &classification,
+        variants: buffer.as_ptr() as *const EVT_VARIANT,
+        variant_count: props_count,
+    };
+
+    // Fields this event doesn't have are left empty
+    let mut values = Vec::with_capacity(render_cfg.columns.len());
+    for spec in &render_cfg.columns {
+        values.push(variant_as_string(columns.value(spec)?, &render_cfg.datefmt));
+    }
+    // Written again whenever field names change from one row to the next
+    let header = if render_cfg.csv.header == Some(CsvHeader::Fields) {
+        let names : Vec<String> = render_cfg.columns.iter().map(|spec| columns.key(spec)).collect();
+        Some(render_cfg.csv.row(&names))
+    } else {
+        None
+    };
+    Ok(EventOutput { header, body: render_cfg.csv.row(&values) })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_cols::parse_column_names;
+
+    fn row(config: &CsvConfig, values: &[&str]) -> String {
+        config.row(&values.iter().map(|v| v.to_string()).collect::<Vec<String>>())
+    }
+
+    #[test]
+    fn quotes_values_like_rfc_4180() {
+        let csv = CsvConfig::new(',', '"', "\r\n", CsvEscaping::Quote, None);
+        let cases: &[(&[&str], &str)] = &[
+            (&["a", "b", "c"], "a,b,c\r\n"),
+            (&["", "", ""], ",,\r\n"),
+            (&["a,b", "c"], "\"a,b\",c\r\n"),
+            (&["say \"hi\"", "x"], "\"say \"\"hi\"\"\",x\r\n"),
+            (&["\"", "x"], "\"\"\"\",x\r\n"),
+            (&["line\r\nbreak", "cr\r", "lf\n"], "\"line\r\nbreak\",\"cr\r\",\"lf\n\"\r\n"),
+            (&["tab\there", "semi;colon", "C:\\path"], "tab\there,semi;colon,C:\\path\r\n"),
+            (&[" spaces "], " spaces \r\n"),
+        ];
+        for (values, expected) in cases {
+            assert_eq!(row(&csv, values), *expected, "{:?}", values);
+        }
+    }
+
+    #[test]
+    fn uses_configured_delimiters_and_quotes() {
+        let semicolon = CsvConfig::new(';', '\'', "\n", CsvEscaping::Quote, None);
+        assert_eq!(row(&semicolon, &["a,b", "c;d", "it's", "\"x\""]), "a,b;'c;d';'it''s';\"x\"\n");
+        let tab = CsvConfig::new('\t', '"', "\n", CsvEscaping::Quote, None);
+        assert_eq!(row(&tab, &["a,b", "c\td"]), "a,b\t\"c\td\"\n");
+        let pipe = CsvConfig::new('|', '"', "\r\n", CsvEscaping::Replace, None);
+        assert_eq!(row(&pipe, &["a|b", "c\r\nd", "\"e\""]), "a b|c  d|\"e\"\r\n");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn escapes_tsv_values_with_backslashes() {
+        let tsv = CsvConfig::new('\t', '"', "\n", CsvEscaping::Backslash, None);
+        assert_eq!(row(&tsv, &["a\tb", "c\nd\re", "C:\\dir", "\"q\""]), "a\\tb\tc\\nd\\re\tC:\\\\dir\t\"q\"\n");
+        let semicolon = CsvConfig::new(';', '"', "\n", CsvEscaping::Backslash, None);
+        assert_eq!(row(&semicolon, &["a;b", "c"]), "a\\;b;c\n");
+    }
+
+    #[test]
+    fn writes_header_rows_of_column_names() {
+        // Renamed columns are headed by their alias, computed ones by their expression
+        let columns = parse_column_names("timestamp,user=lower(TargetUserName),coalesce(IpAddress,'-'),variant2").unwrap();
+        let csv = CsvConfig::new(',', '"', "\r\n", CsvEscaping::Quote, Some(CsvHeader::Columns));
+        assert_eq!(csv.row(&csv_header_names(&columns, false)),
+                   "timestamp,user,\"coalesce(IpAddress,'-')\",variant2\r\n");
+
+        // --sessions writes sessions instead of events, whatever the columns
+        let tsv = CsvConfig::new('\t', '"', "\n", CsvEscaping::Backslash, Some(CsvHeader::Columns));
+        let header = tsv.row(&csv_header_names(&columns, true));
+        assert!(header.starts_with("hostname\tlogon_id\tuser\tdomain\t"), "{}", header);
+        assert!(header.ends_with("\tevent_count\tevent_ids\trecord_ids\n"), "{}", header);
+        assert_eq!(header.split('\t').count(), session_column_names().len());
+    }
+
+    #[test]
+    fn parses_csv_options() {
+        assert_eq!(parse_csv_char("--csv-delimiter", ";"), Ok(';'));
+        assert_eq!(parse_csv_char("--csv-delimiter", "tab"), Ok('\t'));
+        assert_eq!(parse_csv_char("--csv-delimiter", "\\t"), Ok('\t'));
+        assert_eq!(parse_csv_char("--csv-delimiter", "é"), Ok('é'));
+        assert!(parse_csv_char("--csv-delimiter", "").is_err());
+        assert!(parse_csv_char("--csv-delimiter", ";;").is_err());
+        assert_eq!(CsvEscaping::from_str("QUOTE"), Ok(CsvEscaping::Quote));
+        assert_eq!(CsvEscaping::from_str("backslash"), Ok(CsvEscaping::Backslash));
+        assert_eq!(CsvEscaping::from_str("replace"), Ok(CsvEscaping::Replace));
+        assert!(CsvEscaping::from_str("escape").is_err());
+        assert_eq!(CsvHeader::from_str("columns"), Ok(CsvHeader::Columns));
+        assert_eq!(CsvHeader::from_str("Fields"), Ok(CsvHeader::Fields));
+        assert!(CsvHeader::from_str("none").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::cell::Cell;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+#[cfg(windows)]
+use serde::Serialize;
+#[cfg(windows)]
+use crate::windows::EvtHandle;
+#[cfg(windows)]
+use crate::formatting::CommonEventProperties;
+#[cfg(windows)]
+use crate::shutdown::request_stop;
+#[cfg(windows)]
+use crate::RenderingConfig;
+
+// Where processing of an event failed
+#[derive(Clone, Copy, PartialEq)]
+pub enum ErrorStage {
+    // Error delivered by a subscription instead of an event
+    Subscription,
+    CommonProperties,
+    // Anything failing in the output format, other than the stages below
+    Rendering,
+    // Not fatal: the event is still written, with this value as null
+    VariantUnwrap,
+    // Not fatal: the event is still written, with its message template unformatted
+    MessageFormat,
+    Write,
+}
+
+impl ErrorStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorStage::Subscription => "subscription",
+            ErrorStage::CommonProperties => "common_properties",
+            ErrorStage::Rendering => "rendering",
+            ErrorStage::VariantUnwrap => "variant_unwrap",
+            ErrorStage::MessageFormat => "message_format",
+            ErrorStage::Write => "write",
+        }
+    }
+}
+
+thread_local! {
+    // Whether the event processed by this thread already failed, so that it is counted once
+    static EVENT_FAILED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Starts processing an event on this thread, e.g. writing one which failed while being rendered
+pub fn start_event(failed: bool) {
+    EVENT_FAILED.with(|f| f.set(failed));
+}
+
+pub fn event_failed() -> bool {
+    EVENT_FAILED.with(|f| f.get())
+}
+
+// Counts the event processed by this thread as failed, unless it already was. Returns the
+// number of failed events, including this one, if it was counted
+fn count_failed_event(failed_events: &AtomicU64) -> Option<u64> {
+    if EVENT_FAILED.with(|f| f.replace(true)) {
+        return None;
+    }
+    Some(failed_events.fetch_add(1, Relaxed) + 1)
+}
+
+// One line of the --errors-to file
+#[cfg(windows)]
+#[derive(Serialize)]
+struct ErrorRecord<'a> {
+    source: &'a str,
+    channel: Option<&'a str>,
+    provider: Option<&'a str>,
+    eventid: Option<u64>,
+    version: Option<u64>,
+    record_id: Option<u64>,
+    stage: &'static str,
+    error: &'a str,
+    xml: Option<String>,
+}
+
+// Events which failed before their common properties could be read may still render as XML
+fn xml_record_id(xml: &str) -> Option<u64> {
+    let doc = roxmltree::Document::parse(xml.trim_end_matches('\0')).ok()?;
+    let system = doc.root_element().children().find(|n| n.has_tag_name("System"))?;
+    let record_id = system.children().find(|n| n.has_tag_name("EventRecordID"))?;
+    record_id.text()?.trim().parse().ok()
+}
+
+// Records an event which could not be (completely) rendered to the --errors-to file, and stops
+// reading events once more than --fail-on-error of them have failed
+// Events are counted once, whatever the number of errors (e.g. of values which can't be unwrapped)
+#[cfg(windows)]
+pub fn record_error(render_cfg: &RenderingConfig, h_event: Option<&EvtHandle>, common_props: Option<&CommonEventProperties>, stage: ErrorStage, error: &str) {
+    if let (Some(failed_events), Some(max_failed_events)) = (count_failed_event(&render_cfg.failed_events), render_cfg.max_failed_events) {
+        if failed_events == max_failed_events + 1 {
+            warn!("More than {} events failed, stopping", max_failed_events);
+            request_stop();
+        }
+    }
+    let errors = match &render_cfg.errors {
+        Some(errors) => errors,
+        None => return,
+    };
+    let xml = h_event.and_then(|h_event| crate::xml::render_xml_string(h_event).ok())
+        .map(|xml| xml.trim_end_matches('\0').to_owned());
+    let record = ErrorRecord {
+        source: &render_cfg.source,
+        channel: common_props.and_then(|props| props.channel.as_deref()),
+        provider: common_props.map(|props| &props.provider[..]),
+        eventid: common_props.map(|props| props.eventid),
+        version: common_props.map(|props| props.version),
+        record_id: common_props.map(|props| props.recordid)
+            .or_else(|| xml.as_ref().and_then(|xml| xml_record_id(xml))),
+        stage: stage.name(),
+        error,
+        xml,
+    };
+    let res = match serde_json::to_string(&record) {
+        Ok(json) => errors.write_all((json + "\n").as_bytes()),
+        Err(e) => Err(format!("Unable to serialize error record to JSON: {}", e)),
+    };
+    if let Err(e) = res {
+        warn!("Unable to record failed event: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_failed_events_once() {
+        let failed_events = AtomicU64::new(0);
+        start_event(false);
+        assert!(!event_failed());
+        // e.g. two values which can't be unwrapped, then a message which can't be formatted
+        assert_eq!(count_failed_event(&failed_events), Some(1));
+        assert_eq!(count_failed_event(&failed_events), None);
+        assert_eq!(count_failed_event(&failed_events), None);
+        assert!(event_failed());
+
+        start_event(false);
+        assert_eq!(count_failed_event(&failed_events), Some(2));
+        // Written after it failed while being rendered
+        start_event(true);
+        assert_eq!(count_failed_event(&failed_events), None);
+        assert_eq!(failed_events.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn counts_events_of_each_thread_separately() {
+        let failed_events = AtomicU64::new(0);
+        start_event(false);
+        assert_eq!(count_failed_event(&failed_events), Some(1));
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                assert!(!event_failed());
+                assert_eq!(count_failed_event(&failed_events), Some(2));
+            });
+        });
+        assert_eq!(count_failed_event(&failed_events), None);
+    }
+
+    #[test]
+    fn reads_record_ids_from_xml() {
+        let xml = "<Event xmlns=\"http://schemas.microsoft.com/win/2004/08/events/event\"><System>\
+            <EventID>4624</EventID><EventRecordID> 42 </EventRecordID></System></Event>\0";
+        assert_eq!(xml_record_id(xml), Some(42));
+        assert_eq!(xml_record_id("<Event><System/></Event>"), None);
+        assert_eq!(xml_record_id("not XML"), None);
+    }
+}
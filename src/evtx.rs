@@ -0,0 +1,1124 @@
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use chrono::{DateTime, Datelike, Timelike};
+#[cfg(windows)]
+use winapi::um::winevt::EVT_VARIANT;
+#[cfg(windows)]
+use crate::windows::EvtHandle;
+#[cfg(windows)]
+use crate::formatting::{CommonEventProperties, render_event_values};
+#[cfg(windows)]
+use crate::xml::render_xml_string;
+#[cfg(windows)]
+use crate::{RenderingConfig, EventOutput};
+
+/*
+ * Writer for the EVTX format used by the EventLog service, so that a filtered subset of events
+ * can be opened in Event Viewer. Events are re-encoded from their XML rendering: each distinct
+ * XML structure becomes a template, with all text and attribute values as substitutions (typed
+ * like the EventLog service does for the System section, with the types of the rendered values
+ * for EventData and UserData, as strings for everything else).
+ *
+ * File header (4096 bytes): "ElfFile\0" u64 first_chunk, u64 last_chunk, u64 next_record_id,
+ *     u32 header_size (128), u16 minor (1), u16 major (3), u16 header_block_size (4096),
+ *     u16 chunk_count, 76 unknown bytes, u32 flags @120, u32 CRC32 of bytes 0-120 @124
+ * Chunks (65536 bytes): "ElfChnk\0" u64 first_record_number, u64 last_record_number,
+ *     u64 first_record_id, u64 last_record_id, u32 header_size (128), u32 last_record_offset,
+ *     u32 free_space_offset, u32 CRC32 of records, 64 unknown bytes, u32 flags @120,
+ *     u32 CRC32 of bytes 0-120 and 128-512 @124, u32 common_strings[64] @128,
+ *     u32 templates[32] @384, records @512
+ * Records: "**\0\0" u32 size, u64 record_id, u64 written_filetime, BinXML, u32 size
+ * BinXML: fragment header 0F 01 01 00, template instance 0C 01 u32 template_id u32 def_offset,
+ *     followed by the template definition if def_offset points right after it:
+ *     { u32 next_def_offset, GUID, u32 data_size, fragment header, element, 00 }
+ *     then u32 value_count { u16 size, u8 type, u8 0 } [value_count], values, and 00
+ * Elements: 01 (41 if it has attributes) u16 dependency (FFFF), u32 data_size, u32 name_offset,
+ *     [u32 attribute_list_size { 06 (46 if not the last) u32 name_offset, value }], then either
+ *     03 (empty) or 02 content 04
+ * Names are stored in chunks once: { u32 next_string_offset, u16 hash, u16 length, UTF-16, 0000 }
+ * and referenced by their offset in the chunk afterwards.
+ *
+ * See https://github.com/libyal/libevtx/blob/main/documentation/Windows%20XML%20Event%20Log%20(EVTX).asciidoc
+ */
+
+const FILE_HEADER_SIZE: usize = 4096;
+const CHUNK_SIZE: usize = 65536;
+const CHUNK_HEADER_SIZE: usize = 512;
+const FILE_FLAG_DIRTY: u32 = 0x0001;
+
+const TOKEN_EOF: u8 = 0x00;
+const TOKEN_OPEN_START_ELEMENT: u8 = 0x01;
+const TOKEN_CLOSE_START_ELEMENT: u8 = 0x02;
+const TOKEN_CLOSE_EMPTY_ELEMENT: u8 = 0x03;
+const TOKEN_END_ELEMENT: u8 = 0x04;
+const TOKEN_VALUE: u8 = 0x05;
+const TOKEN_ATTRIBUTE: u8 = 0x06;
+const TOKEN_TEMPLATE_INSTANCE: u8 = 0x0C;
+const TOKEN_NORMAL_SUBSTITUTION: u8 = 0x0D;
+const TOKEN_FRAGMENT_HEADER: u8 = 0x0F;
+const TOKEN_FLAG_MORE: u8 = 0x40;
+
+// Value types, numbered like EVT_VARIANT_TYPE
+const TYPE_STRING: u8 = 0x01;
+const TYPE_ANSI_STRING: u8 = 0x02;
+const TYPE_INT8: u8 = 0x03;
+const TYPE_UINT8: u8 = 0x04;
+const TYPE_INT16: u8 = 0x05;
+const TYPE_UINT16: u8 = 0x06;
+const TYPE_INT32: u8 = 0x07;
+const TYPE_UINT32: u8 = 0x08;
+const TYPE_INT64: u8 = 0x09;
+const TYPE_UINT64: u8 = 0x0A;
+const TYPE_REAL32: u8 = 0x0B;
+const TYPE_REAL64: u8 = 0x0C;
+const TYPE_BOOL: u8 = 0x0D;
+const TYPE_BINARY: u8 = 0x0E;
+const TYPE_GUID: u8 = 0x0F;
+const TYPE_SIZET: u8 = 0x10;
+const TYPE_FILETIME: u8 = 0x11;
+const TYPE_SYSTEMTIME: u8 = 0x12;
+const TYPE_SID: u8 = 0x13;
+const TYPE_HEXINT32: u8 = 0x14;
+const TYPE_HEXINT64: u8 = 0x15;
+
+// Types of values in the System section, (element, attribute or None for its text)
+const SYSTEM_VALUE_TYPES: &[(&str, Option<&str>, u8)] = &[
+    ("Provider", Some("Guid"), TYPE_GUID),
+    ("EventID", None, TYPE_UINT16),
+    ("EventID", Some("Qualifiers"), TYPE_UINT16),
+    ("Version", None, TYPE_UINT8),
+    ("Level", None, TYPE_UINT8),
+    ("Task", None, TYPE_UINT16),
+    ("Opcode", None, TYPE_UINT8),
+    ("Keywords", None, TYPE_HEXINT64),
+    ("TimeCreated", Some("SystemTime"), TYPE_FILETIME),
+    ("EventRecordID", None, TYPE_UINT64),
+    ("Correlation", Some("ActivityID"), TYPE_GUID),
+    ("Correlation", Some("RelatedActivityID"), TYPE_GUID),
+    ("Execution", Some("ProcessID"), TYPE_UINT32),
+    ("Execution", Some("ThreadID"), TYPE_UINT32),
+    ("Security", Some("UserID"), TYPE_SID),
+];
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn utf16_bytes(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect()
+}
+
+// FNV-1a, so that template identifiers are the same across runs and builds
+fn fnv1a_64(data: &[u8]) -> u64 {
+    let mut hash = 0xCBF2_9CE4_8422_2325u64;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01B3);
+    }
+    hash
+}
+
+fn name_hash(name: &str) -> u16 {
+    let mut hash : u32 = 0;
+    for c in name.encode_utf16() {
+        hash = hash.wrapping_mul(65599).wrapping_add(c as u32);
+    }
+    hash as u16
+}
+
+fn guid_bytes(guid: &str) -> Option<Vec<u8>> {
+    let hex : String = guid.trim_matches(|c| c == '{' || c == '}').split('-').collect();
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let bytes : Vec<u8> = (0..16).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect();
+    // The first three parts are little-endian integers
+    let mut res = vec![bytes[3], bytes[2], bytes[1], bytes[0], bytes[5], bytes[4], bytes[7], bytes[6]];
+    res.extend_from_slice(&bytes[8..]);
+    Some(res)
+}
+
+fn sid_bytes(sid: &str) -> Option<Vec<u8>> {
+    let parts : Vec<&str> = sid.split('-').collect();
+    if parts.len() < 3 || !parts[0].eq_ignore_ascii_case("S") || parts.len() - 3 > 15 {
+        return None;
+    }
+    let revision = parts[1].parse::<u8>().ok()?;
+    let authority = parts[2].parse::<u64>().ok()?;
+    let mut res = vec![revision, (parts.len() - 3) as u8];
+    res.extend_from_slice(&authority.to_be_bytes()[2..]);
+    for sub_authority in &parts[3..] {
+        res.extend_from_slice(&sub_authority.parse::<u32>().ok()?.to_le_bytes());
+    }
+    Some(res)
+}
+
+fn systemtime_filetime(systemtime: &str) -> Option<u64> {
+    let date = DateTime::parse_from_rfc3339(systemtime).ok()?;
+    let secs = date.timestamp().checked_add(11_644_473_600)?;
+    if secs < 0 {
+        return None;
+    }
+    (secs as u64).checked_mul(10_000_000)?.checked_add(date.timestamp_subsec_nanos() as u64 / 100)
+}
+
+fn systemtime_bytes(systemtime: &str) -> Option<Vec<u8>> {
+    let date = DateTime::parse_from_rfc3339(systemtime).ok()?;
+    let year = u16::try_from(date.year()).ok()?;
+    let fields = [year, date.month() as u16, date.weekday().num_days_from_sunday() as u16, date.day() as u16,
+                  date.hour() as u16, date.minute() as u16, date.second() as u16,
+                  (date.timestamp_subsec_millis() % 1000) as u16];
+    Some(fields.iter().flat_map(|f| f.to_le_bytes().to_vec()).collect())
+}
+
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect())
+}
+
+fn hex_integer(text: &str) -> Option<u64> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X"))?;
+    u64::from_str_radix(digits, 16).ok()
+}
+
+// Type in which a rendered EVT_VARIANT is written, strings for those which cannot be encoded
+pub fn variant_value_type(variant_type: u32) -> u8 {
+    match variant_type {
+        // Size depends on the system which logged it, it is rendered as hexadecimal
+        t if t == TYPE_SIZET as u32 => TYPE_HEXINT64,
+        t if t == TYPE_ANSI_STRING as u32 => TYPE_STRING,
+        t if t >= TYPE_STRING as u32 && t <= TYPE_HEXINT64 as u32 => t as u8,
+        _ => TYPE_STRING,
+    }
+}
+
+// Binary value of a substitution, or None if it cannot be encoded with this type
+fn encode_value(value_type: u8, text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    Some(match value_type {
+        TYPE_INT8 => text.parse::<i8>().ok()?.to_le_bytes().to_vec(),
+        TYPE_UINT8 => vec![text.parse::<u8>().ok()?],
+        TYPE_INT16 => text.parse::<i16>().ok()?.to_le_bytes().to_vec(),
+        TYPE_UINT16 => text.parse::<u16>().ok()?.to_le_bytes().to_vec(),
+        TYPE_INT32 => text.parse::<i32>().ok()?.to_le_bytes().to_vec(),
+        TYPE_UINT32 => text.parse::<u32>().ok()?.to_le_bytes().to_vec(),
+        TYPE_INT64 => text.parse::<i64>().ok()?.to_le_bytes().to_vec(),
+        TYPE_UINT64 => text.parse::<u64>().ok()?.to_le_bytes().to_vec(),
+        TYPE_REAL32 => text.parse::<f32>().ok()?.to_le_bytes().to_vec(),
+        TYPE_REAL64 => text.parse::<f64>().ok()?.to_le_bytes().to_vec(),
+        TYPE_BOOL => match text {
+            "true" | "1" => 1u32,
+            "false" | "0" => 0u32,
+            _ => return None,
+        }.to_le_bytes().to_vec(),
+        TYPE_BINARY => hex_bytes(text)?,
+        TYPE_HEXINT32 => u32::try_from(hex_integer(text)?).ok()?.to_le_bytes().to_vec(),
+        TYPE_HEXINT64 => hex_integer(text).or_else(|| u64::from_str_radix(text, 16).ok())?.to_le_bytes().to_vec(),
+        TYPE_GUID => guid_bytes(text)?,
+        TYPE_FILETIME => systemtime_filetime(text)?.to_le_bytes().to_vec(),
+        TYPE_SYSTEMTIME => systemtime_bytes(text)?,
+        TYPE_SID => sid_bytes(text)?,
+        _ => return None,
+    })
+}
+
+// Structure of an event, without its values
+#[derive(Debug)]
+enum TemplateNode {
+    Element {
+        name: String,
+        attributes: Vec<(String, TemplateNode)>,
+        children: Vec<TemplateNode>,
+    },
+    // Namespace declarations, which are the same in all events with this structure
+    Literal(String),
+    Substitution(u16, u8),
+}
+
+struct EventValues<'a> {
+    values: Vec<(u8, Vec<u8>)>,
+    // Value of EventRecordID, when record IDs are renumbered
+    record_id: Option<u64>,
+    // Types of the values of EventData or UserData, in the order in which they appear
+    user_types: &'a [u8],
+    user_values: usize,
+}
+
+impl<'a> EventValues<'a> {
+    fn substitution(&mut self, text: &str, system_type: Option<u8>, is_record_id: bool) -> TemplateNode {
+        let text = match (is_record_id, self.record_id) {
+            (true, Some(record_id)) => record_id.to_string(),
+            _ => text.to_owned(),
+        };
+        let (value_type, bytes) = match system_type.and_then(|t| encode_value(t, &text).map(|bytes| (t, bytes))) {
+            Some(typed) => typed,
+            None => (TYPE_STRING, utf16_bytes(&text)),
+        };
+        self.values.push((value_type, bytes));
+        TemplateNode::Substitution((self.values.len() - 1) as u16, value_type)
+    }
+
+    fn element(&mut self, node: roxmltree::Node, parent_namespace: Option<&str>, in_system: bool, in_user_data: bool) -> TemplateNode {
+        let name = node.tag_name().name();
+        let namespace = node.tag_name().namespace();
+        // Leaf elements of EventData and UserData each hold one value, even when empty
+        let user_type = if in_user_data && !node.children().any(|c| c.is_element()) {
+            self.user_values += 1;
+            self.user_types.get(self.user_values - 1).copied()
+        } else {
+            None
+        };
+        let system_type = |attribute: Option<&str>| if in_system {
+            SYSTEM_VALUE_TYPES.iter().find(|(e, a, _)| *e == name && *a == attribute).map(|(_, _, t)| *t)
+        } else {
+            None
+        };
+        let mut attributes = vec![];
+        if namespace != parent_namespace {
+            attributes.push(("xmlns".to_owned(), TemplateNode::Literal(namespace.unwrap_or("").to_owned())));
+        }
+        for attr in node.attributes() {
+            let value = self.substitution(attr.value(), system_type(Some(attr.name())), false);
+            attributes.push((attr.name().to_owned(), value));
+        }
+        let mut children = vec![];
+        for child in node.children() {
+            if child.is_element() {
+                let is_section = node.parent().map(|p| p.is_root()).unwrap_or(false);
+                let in_system = in_system || (is_section && child.has_tag_name("System"));
+                let in_user_data = in_user_data || (is_section && (child.has_tag_name("EventData") || child.has_tag_name("UserData")));
+                children.push(self.element(child, namespace, in_system, in_user_data));
+            }
+            else if let Some(text) = child.text() {
+                // Whitespace between elements is only there for indentation
+                if !text.trim().is_empty() || node.children().all(|c| !c.is_element()) {
+                    children.push(self.substitution(text, system_type(None).or(user_type), in_system && name == "EventRecordID"));
+                }
+            }
+        }
+        TemplateNode::Element { name: name.to_owned(), attributes, children }
+    }
+}
+
+// BinXML being appended to a chunk, where names are referenced by their offset
+struct BinXmlEncoder<'a> {
+    data: &'a mut Vec<u8>,
+    strings: &'a mut HashMap<String, u32>,
+    string_buckets: &'a mut [u32; 64],
+}
+
+impl<'a> BinXmlEncoder<'a> {
+    fn offset(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn patch_u32(&mut self, offset: usize, v: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn name(&mut self, name: &str) {
+        if let Some(offset) = self.strings.get(name) {
+            let offset = *offset;
+            self.u32(offset);
+            return;
+        }
+        let offset = self.offset() + 4;
+        self.u32(offset);
+        let hash = name_hash(name);
+        let bucket = (hash % 64) as usize;
+        let next = self.string_buckets[bucket];
+        self.u32(next);
+        self.u16(hash);
+        self.u16(name.encode_utf16().count() as u16);
+        self.data.extend_from_slice(&utf16_bytes(name));
+        self.u16(0);
+        self.string_buckets[bucket] = offset;
+        self.strings.insert(name.to_owned(), offset);
+    }
+
+    fn content(&mut self, node: &TemplateNode, more: bool) {
+        match node {
+            TemplateNode::Element { .. } => self.element(node),
+            TemplateNode::Literal(text) => {
+                self.data.push(TOKEN_VALUE | if more { TOKEN_FLAG_MORE } else { 0 });
+                self.data.push(TYPE_STRING);
+                self.u16(text.encode_utf16().count() as u16);
+                self.data.extend_from_slice(&utf16_bytes(text));
+            },
+            TemplateNode::Substitution(index, value_type) => {
+                self.data.push(TOKEN_NORMAL_SUBSTITUTION);
+                self.u16(*index);
+                self.data.push(*value_type);
+            },
+        }
+    }
+
+    fn element(&mut self, node: &TemplateNode) {
+        let (name, attributes, children) = match node {
+            TemplateNode::Element { name, attributes, children } => (name, attributes, children),
+            _ => return,
+        };
+        let start = self.data.len();
+        self.data.push(TOKEN_OPEN_START_ELEMENT | if attributes.is_empty() { 0 } else { TOKEN_FLAG_MORE });
+        self.u16(0xFFFF);
+        self.u32(0);
+        self.name(name);
+        if !attributes.is_empty() {
+            let list_size_offset = self.data.len();
+            self.u32(0);
+            for (i, (attr_name, value)) in attributes.iter().enumerate() {
+                self.data.push(TOKEN_ATTRIBUTE | if i + 1 < attributes.len() { TOKEN_FLAG_MORE } else { 0 });
+                self.name(attr_name);
+                self.content(value, false);
+            }
+            let list_size = self.data.len() - list_size_offset - 4;
+            self.patch_u32(list_size_offset, list_size as u32);
+        }
+        if children.is_empty() {
+            self.data.push(TOKEN_CLOSE_EMPTY_ELEMENT);
+        } else {
+            self.data.push(TOKEN_CLOSE_START_ELEMENT);
+            for (i, child) in children.iter().enumerate() {
+                self.content(child, i + 1 < children.len());
+            }
+            self.data.push(TOKEN_END_ELEMENT);
+        }
+        // Size of everything after the token, dependency and size fields
+        let size = self.data.len() - start - 7;
+        self.patch_u32(start + 3, size as u32);
+    }
+}
+
+struct Chunk {
+    data: Vec<u8>,
+    first_record_number: u64,
+    record_count: u64,
+    first_record_id: u64,
+    last_record_id: u64,
+    last_record_offset: u32,
+    strings: HashMap<String, u32>,
+    string_buckets: [u32; 64],
+    // Offset and identifier of template definitions, by structure
+    templates: HashMap<String, (u32, u32)>,
+    template_buckets: [u32; 32],
+}
+
+impl Chunk {
+    fn new(first_record_number: u64) -> Chunk {
+        Chunk {
+            data: vec![0; CHUNK_HEADER_SIZE],
+            first_record_number,
+            record_count: 0,
+            first_record_id: 0,
+            last_record_id: 0,
+            last_record_offset: 0,
+            strings: HashMap::new(),
+            string_buckets: [0; 64],
+            templates: HashMap::new(),
+            template_buckets: [0; 32],
+        }
+    }
+
+    // Appends a record, or returns false (leaving the chunk untouched) if it doesn't fit
+    fn add_record(&mut self, record_id: u64, filetime: u64, template: &TemplateNode, values: &EventValues) -> bool {
+        let start = self.data.len();
+        let string_buckets = self.string_buckets;
+        let template_buckets = self.template_buckets;
+        let known_strings = self.strings.len();
+
+        self.data.extend_from_slice(b"**\0\0");
+        self.data.extend_from_slice(&[0; 4]);
+        self.data.extend_from_slice(&record_id.to_le_bytes());
+        self.data.extend_from_slice(&filetime.to_le_bytes());
+        self.data.extend_from_slice(&[TOKEN_FRAGMENT_HEADER, 1, 1, 0]);
+
+        let key = format!("{:?}", template);
+        let mut new_template = None;
+        self.data.push(TOKEN_TEMPLATE_INSTANCE);
+        self.data.push(1);
+        match self.templates.get(&key) {
+            Some((def_offset, template_id)) => {
+                let (def_offset, template_id) = (*def_offset, *template_id);
+                self.data.extend_from_slice(&template_id.to_le_bytes());
+                self.data.extend_from_slice(&def_offset.to_le_bytes());
+            },
+            None => {
+                let mut guid = vec![];
+                for salt in 0..2u8 {
+                    let mut salted = vec![salt];
+                    salted.extend_from_slice(key.as_bytes());
+                    guid.extend_from_slice(&fnv1a_64(&salted).to_le_bytes());
+                }
+                let template_id = u32::from_le_bytes(guid[0..4].try_into().unwrap());
+                let def_offset = (self.data.len() + 8) as u32;
+                self.data.extend_from_slice(&template_id.to_le_bytes());
+                self.data.extend_from_slice(&def_offset.to_le_bytes());
+                let bucket = (template_id % 32) as usize;
+                self.data.extend_from_slice(&self.template_buckets[bucket].to_le_bytes());
+                self.data.extend_from_slice(&guid);
+                let size_offset = self.data.len();
+                self.data.extend_from_slice(&[0; 4]);
+                self.data.extend_from_slice(&[TOKEN_FRAGMENT_HEADER, 1, 1, 0]);
+                let mut encoder = BinXmlEncoder {
+                    data: &mut self.data,
+                    strings: &mut self.strings,
+                    string_buckets: &mut self.string_buckets,
+                };
+                encoder.element(template);
+                self.data.push(TOKEN_EOF);
+                let size = (self.data.len() - size_offset - 4) as u32;
+                self.data[size_offset..size_offset + 4].copy_from_slice(&size.to_le_bytes());
+                self.template_buckets[bucket] = def_offset;
+                new_template = Some((def_offset, template_id));
+            },
+        }
+
+        self.data.extend_from_slice(&(values.values.len() as u32).to_le_bytes());
+        for (value_type, bytes) in &values.values {
+            self.data.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+            self.data.push(*value_type);
+            self.data.push(0);
+        }
+        for (_, bytes) in &values.values {
+            self.data.extend_from_slice(bytes);
+        }
+        self.data.push(TOKEN_EOF);
+        let size = (self.data.len() - start + 4) as u32;
+        self.data.extend_from_slice(&size.to_le_bytes());
+        self.data[start + 4..start + 8].copy_from_slice(&size.to_le_bytes());
+
+        if self.data.len() > CHUNK_SIZE {
+            self.data.truncate(start);
+            self.string_buckets = string_buckets;
+            self.template_buckets = template_buckets;
+            if self.strings.len() != known_strings {
+                self.strings.retain(|_, offset| (*offset as usize) < start);
+            }
+            return false;
+        }
+        if let Some(template) = new_template {
+            self.templates.insert(key, template);
+        }
+        // Records keep their IDs in the order they are written, which may not be increasing
+        if self.record_count == 0 {
+            self.first_record_id = record_id;
+            self.last_record_id = record_id;
+        } else {
+            self.first_record_id = std::cmp::min(self.first_record_id, record_id);
+            self.last_record_id = std::cmp::max(self.last_record_id, record_id);
+        }
+        self.last_record_offset = start as u32;
+        self.record_count += 1;
+        true
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let free_space_offset = self.data.len() as u32;
+        let records_crc = crc32(&self.data[CHUNK_HEADER_SIZE..]);
+        self.data.resize(CHUNK_SIZE, 0);
+        let last_record_number = self.first_record_number + self.record_count - 1;
+        let header = &mut self.data[..CHUNK_HEADER_SIZE];
+        header[0..8].copy_from_slice(b"ElfChnk\0");
+        header[8..16].copy_from_slice(&self.first_record_number.to_le_bytes());
+        header[16..24].copy_from_slice(&last_record_number.to_le_bytes());
+        header[24..32].copy_from_slice(&self.first_record_id.to_le_bytes());
+        header[32..40].copy_from_slice(&self.last_record_id.to_le_bytes());
+        header[40..44].copy_from_slice(&128u32.to_le_bytes());
+        header[44..48].copy_from_slice(&self.last_record_offset.to_le_bytes());
+        header[48..52].copy_from_slice(&free_space_offset.to_le_bytes());
+        header[52..56].copy_from_slice(&records_crc.to_le_bytes());
+        for (i, offset) in self.string_buckets.iter().enumerate() {
+            header[128 + i * 4..132 + i * 4].copy_from_slice(&offset.to_le_bytes());
+        }
+        for (i, offset) in self.template_buckets.iter().enumerate() {
+            header[384 + i * 4..388 + i * 4].copy_from_slice(&offset.to_le_bytes());
+        }
+        let mut checksummed = header[0..120].to_vec();
+        checksummed.extend_from_slice(&header[128..CHUNK_HEADER_SIZE]);
+        header[124..128].copy_from_slice(&crc32(&checksummed).to_le_bytes());
+        self.data
+    }
+}
+
+pub struct EvtxWriter {
+    file: File,
+    path: String,
+    renumber: bool,
+    chunk: Chunk,
+    chunk_count: u64,
+    record_count: u64,
+    next_record_id: u64,
+}
+
+impl EvtxWriter {
+    pub fn create(path: &str, renumber: bool) -> Result<EvtxWriter, String> {
+        let file = match OpenOptions::new().write(true).create(true).truncate(true).open(path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Could not open file {} : {}", path, e)),
+        };
+        let mut writer = EvtxWriter {
+            file,
+            path: path.to_owned(),
+            renumber,
+            chunk: Chunk::new(1),
+            chunk_count: 0,
+            record_count: 0,
+            next_record_id: 1,
+        };
+        // Marked as dirty until all chunks are written
+        writer.write_file_header(FILE_FLAG_DIRTY)?;
+        Ok(writer)
+    }
+
+    fn write_file_header(&mut self, flags: u32) -> Result<(), String> {
+        let chunk_count = self.chunk_count + if self.chunk.record_count > 0 { 1 } else { 0 };
+        let mut header = vec![0u8; FILE_HEADER_SIZE];
+        header[0..8].copy_from_slice(b"ElfFile\0");
+        header[8..16].copy_from_slice(&0u64.to_le_bytes());
+        header[16..24].copy_from_slice(&chunk_count.saturating_sub(1).to_le_bytes());
+        header[24..32].copy_from_slice(&self.next_record_id.to_le_bytes());
+        header[32..36].copy_from_slice(&128u32.to_le_bytes());
+        header[36..38].copy_from_slice(&1u16.to_le_bytes());
+        header[38..40].copy_from_slice(&3u16.to_le_bytes());
+        header[40..42].copy_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+        header[42..44].copy_from_slice(&(chunk_count as u16).to_le_bytes());
+        header[120..124].copy_from_slice(&flags.to_le_bytes());
+        let checksum = crc32(&header[0..120]);
+        header[124..128].copy_from_slice(&checksum.to_le_bytes());
+        let res = self.file.seek(SeekFrom::Start(0)).and_then(|_| self.file.write_all(&header));
+        if let Err(e) = res {
+            return Err(format!("Unable to write EVTX header to {}: {}", self.path, e));
+        }
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> Result<(), String> {
+        if self.chunk_count + 1 > u16::MAX as u64 {
+            return Err(format!("Too many events for a single EVTX file ({} chunks)", self.chunk_count));
+        }
+        let next_chunk = Chunk::new(self.record_count + 1);
+        let data = std::mem::replace(&mut self.chunk, next_chunk).finish();
+        let offset = FILE_HEADER_SIZE as u64 + self.chunk_count * CHUNK_SIZE as u64;
+        let res = self.file.seek(SeekFrom::Start(offset)).and_then(|_| self.file.write_all(&data));
+        if let Err(e) = res {
+            return Err(format!("Unable to write EVTX chunk to {}: {}", self.path, e));
+        }
+        self.chunk_count += 1;
+        Ok(())
+    }
+
+    // Writes an event from its XML rendering, with the types of its EventData or UserData values
+    pub fn write_event(&mut self, xml: &str, filetime: u64, record_id: u64, user_types: &[u8]) -> Result<(), String> {
+        let doc = match roxmltree::Document::parse(xml.trim_end_matches('\0')) {
+            Ok(doc) => doc,
+            Err(e) => return Err(format!("Unable to parse event XML: {}", e)),
+        };
+        let record_id = if self.renumber { self.record_count + 1 } else { record_id };
+        let mut values = EventValues {
+            values: vec![],
+            record_id: if self.renumber { Some(record_id) } else { None },
+            user_types,
+            user_values: 0,
+        };
+        let template = values.element(doc.root_element(), None, false, false);
+        if values.values.len() > u16::MAX as usize {
+            return Err(format!("Event with too many values to be written to EVTX ({})", values.values.len()));
+        }
+        if !self.chunk.add_record(record_id, filetime, &template, &values) {
+            if self.chunk.record_count == 0 {
+                return Err(format!("Event record {} is too large to fit in an EVTX chunk", record_id));
+            }
+            self.flush_chunk()?;
+            if !self.chunk.add_record(record_id, filetime, &template, &values) {
+                return Err(format!("Event record {} is too large to fit in an EVTX chunk", record_id));
+            }
+        }
+        self.record_count += 1;
+        self.next_record_id = std::cmp::max(self.next_record_id, record_id + 1);
+        Ok(())
+    }
+
+    // Writes the last chunk and the final file header
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.chunk.record_count > 0 {
+            self.flush_chunk()?;
+        }
+        self.write_file_header(0)?;
+        info!("Wrote {} events in {} chunks to {}", self.record_count, self.chunk_count, self.path);
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+pub fn render_event_evtx(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let xml = render_xml_string(h_event)?;
+    let (buffer, props_count) = render_event_values(h_event)?;
+    let variants = buffer.as_ptr() as *const EVT_VARIANT;
+    let user_types : Vec<u8> = (0..props_count as usize)
+        .map(|i| variant_value_type(unsafe { (*variants.add(i)).Type }))
+        .collect();
+    let writer = match &render_cfg.evtx_writer {
+        Some(writer) => writer,
+        None => return Err(format!("No EVTX file to write events to")),
+    };
+    match writer.lock() {
+        Ok(mut writer) => writer.write_event(&xml, common_props.timestamp, common_props.recordid, &user_types)?,
+        Err(e) => return Err(format!("Failed to acquire lock to EVTX file: {}", e)),
+    }
+    Ok(EventOutput::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    const EVENT: &str = "<Event xmlns=\"http://schemas.microsoft.com/win/2004/08/events/event\"><System>\
+        <Provider Name=\"Microsoft-Windows-Security-Auditing\" Guid=\"{54849625-5478-4994-A5BA-3E3B0328C30D}\"/>\
+        <EventID>4624</EventID><Version>2</Version><Level>0</Level><Task>12544</Task><Opcode>0</Opcode>\
+        <Keywords>0x8020000000000000</Keywords><TimeCreated SystemTime=\"2021-03-04T05:06:07.1234567Z\"/>\
+        <EventRecordID>42</EventRecordID><Correlation/><Execution ProcessID=\"612\" ThreadID=\"7000\"/>\
+        <Channel>Security</Channel><Computer>DC01</Computer><Security/></System><EventData>\
+        <Data Name=\"SubjectUserSid\">S-1-5-18</Data><Data Name=\"LogonType\">2</Data><Data Name=\"Elevated\">true</Data>\
+        <Data Name=\"Blob\">00FF10</Data><Data Name=\"Empty\"/><Data Name=\"Offset\">-5</Data>\
+        <Data Name=\"Fallback\">n/a</Data></EventData></Event>";
+    const EVENT_TYPES: &[u8] = &[TYPE_SID, TYPE_UINT32, TYPE_BOOL, TYPE_BINARY, TYPE_STRING, TYPE_INT32, TYPE_UINT32];
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn utf16_at(data: &[u8], offset: usize, chars: usize) -> String {
+        let units : Vec<u16> = (0..chars).map(|i| u16_at(data, offset + i * 2)).collect();
+        String::from_utf16(&units).unwrap()
+    }
+
+    // Text of a value, as the EventLog service renders it in XML
+    fn format_value(value_type: u8, bytes: &[u8]) -> String {
+        match value_type {
+            TYPE_STRING => utf16_at(bytes, 0, bytes.len() / 2),
+            TYPE_INT8 => (bytes[0] as i8).to_string(),
+            TYPE_UINT8 => bytes[0].to_string(),
+            TYPE_INT16 => (u16_at(bytes, 0) as i16).to_string(),
+            TYPE_UINT16 => u16_at(bytes, 0).to_string(),
+            TYPE_INT32 => (u32_at(bytes, 0) as i32).to_string(),
+            TYPE_UINT32 => u32_at(bytes, 0).to_string(),
+            TYPE_INT64 => (u64_at(bytes, 0) as i64).to_string(),
+            TYPE_UINT64 => u64_at(bytes, 0).to_string(),
+            TYPE_REAL32 => f32::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+            TYPE_REAL64 => f64::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+            TYPE_BOOL => (u32_at(bytes, 0) != 0).to_string(),
+            TYPE_BINARY => bytes.iter().map(|b| format!("{:02X}", b)).collect(),
+            TYPE_GUID => format!("{{{:08X}-{:04X}-{:04X}-{}-{}}}", u32_at(bytes, 0), u16_at(bytes, 4), u16_at(bytes, 6),
+                                 bytes[8..10].iter().map(|b| format!("{:02X}", b)).collect::<String>(),
+                                 bytes[10..16].iter().map(|b| format!("{:02X}", b)).collect::<String>()),
+            TYPE_FILETIME => {
+                let filetime = u64_at(bytes, 0);
+                let date = Utc.timestamp_opt((filetime / 10_000_000) as i64 - 11_644_473_600, 0).unwrap();
+                format!("{}.{:07}Z", date.format("%Y-%m-%dT%H:%M:%S"), filetime % 10_000_000)
+            },
+            TYPE_SYSTEMTIME => format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", u16_at(bytes, 0), u16_at(bytes, 2),
+                                       u16_at(bytes, 6), u16_at(bytes, 8), u16_at(bytes, 10), u16_at(bytes, 12), u16_at(bytes, 14)),
+            TYPE_SID => {
+                let mut authority = [0u8; 8];
+                authority[2..].copy_from_slice(&bytes[2..8]);
+                let mut sid = format!("S-{}-{}", bytes[0], u64::from_be_bytes(authority));
+                for i in 0..bytes[1] as usize {
+                    sid.push_str(&format!("-{}", u32_at(bytes, 8 + i * 4)));
+                }
+                sid
+            },
+            TYPE_HEXINT32 => format!("0x{:x}", u32_at(bytes, 0)),
+            TYPE_HEXINT64 => format!("0x{:x}", u64_at(bytes, 0)),
+            other => panic!("unexpected value type {}", other),
+        }
+    }
+
+    struct ReadRecord {
+        record_number: u64,
+        record_id: u64,
+        filetime: u64,
+        xml: String,
+        types: Vec<u8>,
+    }
+
+    struct ReadChunk {
+        first_record_id: u64,
+        last_record_id: u64,
+        records: Vec<ReadRecord>,
+    }
+
+    // Reads back BinXML written by Chunk: names and templates defined inline the first time they are used
+    struct BinXmlDecoder<'a> {
+        chunk: &'a [u8],
+        pos: usize,
+        values: &'a [(u8, String)],
+    }
+
+    impl<'a> BinXmlDecoder<'a> {
+        fn byte(&mut self) -> u8 {
+            self.pos += 1;
+            self.chunk[self.pos - 1]
+        }
+
+        fn u16(&mut self) -> u16 {
+            self.pos += 2;
+            u16_at(self.chunk, self.pos - 2)
+        }
+
+        fn u32(&mut self) -> u32 {
+            self.pos += 4;
+            u32_at(self.chunk, self.pos - 4)
+        }
+
+        fn name(&mut self) -> String {
+            let offset = self.u32() as usize;
+            let length = u16_at(self.chunk, offset + 6) as usize;
+            if offset == self.pos {
+                self.pos += 8 + length * 2 + 2;
+            }
+            let name = utf16_at(self.chunk, offset + 8, length);
+            assert_eq!(u16_at(self.chunk, offset + 4), name_hash(&name));
+            name
+        }
+
+        fn content(&mut self, out: &mut String) {
+            let token = self.chunk[self.pos];
+            match token & !TOKEN_FLAG_MORE {
+                TOKEN_OPEN_START_ELEMENT => self.element(out),
+                TOKEN_VALUE => {
+                    self.pos += 1;
+                    assert_eq!(self.byte(), TYPE_STRING);
+                    let length = self.u16() as usize;
+                    out.push_str(&utf16_at(self.chunk, self.pos, length));
+                    self.pos += length * 2;
+                },
+                TOKEN_NORMAL_SUBSTITUTION => {
+                    self.pos += 1;
+                    let index = self.u16() as usize;
+                    let value_type = self.byte();
+                    assert_eq!(self.values[index].0, value_type);
+                    out.push_str(&self.values[index].1);
+                },
+                other => panic!("unexpected token {:02X} at {}", other, self.pos),
+            }
+        }
+
+        fn element(&mut self, out: &mut String) {
+            let token = self.byte();
+            assert_eq!(token & !TOKEN_FLAG_MORE, TOKEN_OPEN_START_ELEMENT);
+            assert_eq!(self.u16(), 0xFFFF);
+            let size = self.u32() as usize;
+            let start = self.pos;
+            let name = self.name();
+            out.push_str(&format!("<{}", name));
+            if token & TOKEN_FLAG_MORE != 0 {
+                let list_size = self.u32() as usize;
+                let list_start = self.pos;
+                loop {
+                    let attr_token = self.byte();
+                    assert_eq!(attr_token & !TOKEN_FLAG_MORE, TOKEN_ATTRIBUTE);
+                    let attr_name = self.name();
+                    out.push_str(&format!(" {}=\"", attr_name));
+                    self.content(out);
+                    out.push('"');
+                    if attr_token & TOKEN_FLAG_MORE == 0 {
+                        break;
+                    }
+                }
+                assert_eq!(self.pos - list_start, list_size);
+            }
+            match self.byte() {
+                TOKEN_CLOSE_EMPTY_ELEMENT => out.push_str("/>"),
+                TOKEN_CLOSE_START_ELEMENT => {
+                    out.push('>');
+                    while self.chunk[self.pos] != TOKEN_END_ELEMENT {
+                        self.content(out);
+                    }
+                    self.pos += 1;
+                    out.push_str(&format!("</{}>", name));
+                },
+                other => panic!("unexpected token {:02X} at {}", other, self.pos - 1),
+            }
+            assert_eq!(self.pos - start, size);
+        }
+    }
+
+    fn read_record(chunk: &[u8], pos: usize) -> (ReadRecord, usize) {
+        assert_eq!(&chunk[pos..pos + 4], b"**\0\0");
+        let size = u32_at(chunk, pos + 4) as usize;
+        assert_eq!(u32_at(chunk, pos + size - 4) as usize, size);
+        let mut decoder = BinXmlDecoder { chunk, pos: pos + 24, values: &[] };
+        assert_eq!(&chunk[decoder.pos..decoder.pos + 4], &[TOKEN_FRAGMENT_HEADER, 1, 1, 0]);
+        decoder.pos += 4;
+        assert_eq!(decoder.byte(), TOKEN_TEMPLATE_INSTANCE);
+        assert_eq!(decoder.byte(), 1);
+        let template_id = decoder.u32();
+        let def_offset = decoder.u32() as usize;
+        assert_eq!(u32_at(chunk, def_offset + 4), template_id);
+        if def_offset == decoder.pos {
+            decoder.pos += 24 + u32_at(chunk, def_offset + 20) as usize;
+        }
+        let value_count = decoder.u32() as usize;
+        let descriptors : Vec<(usize, u8)> = (0..value_count).map(|_| {
+            let size = decoder.u16() as usize;
+            let value_type = decoder.byte();
+            assert_eq!(decoder.byte(), 0);
+            (size, value_type)
+        }).collect();
+        let mut values = vec![];
+        for (size, value_type) in descriptors {
+            values.push((value_type, format_value(value_type, &chunk[decoder.pos..decoder.pos + size])));
+            decoder.pos += size;
+        }
+        assert_eq!(decoder.byte(), TOKEN_EOF);
+        assert_eq!(decoder.pos + 4, pos + size);
+
+        let mut template = BinXmlDecoder { chunk, pos: def_offset + 24, values: &values };
+        assert_eq!(&chunk[template.pos..template.pos + 4], &[TOKEN_FRAGMENT_HEADER, 1, 1, 0]);
+        template.pos += 4;
+        let mut xml = String::new();
+        template.element(&mut xml);
+        assert_eq!(template.byte(), TOKEN_EOF);
+        (ReadRecord {
+            record_number: 0,
+            record_id: u64_at(chunk, pos + 8),
+            filetime: u64_at(chunk, pos + 16),
+            xml,
+            types: values.iter().map(|(t, _)| *t).collect(),
+        }, pos + size)
+    }
+
+    // Reads a file written by EvtxWriter, checking its headers and checksums
+    fn read_evtx(path: &str) -> (u64, Vec<ReadChunk>) {
+        let data = std::fs::read(path).unwrap();
+        assert_eq!(&data[0..8], b"ElfFile\0");
+        assert_eq!(u32_at(&data, 124), crc32(&data[0..120]));
+        assert_eq!(u32_at(&data, 120), 0);
+        let chunk_count = u16_at(&data, 42) as usize;
+        assert_eq!(data.len(), FILE_HEADER_SIZE + chunk_count * CHUNK_SIZE);
+        assert_eq!(u64_at(&data, 16), chunk_count.saturating_sub(1) as u64);
+
+        let mut chunks = vec![];
+        let mut next_record_number = 1;
+        for chunk in data[FILE_HEADER_SIZE..].chunks(CHUNK_SIZE) {
+            assert_eq!(&chunk[0..8], b"ElfChnk\0");
+            let mut checksummed = chunk[0..120].to_vec();
+            checksummed.extend_from_slice(&chunk[128..CHUNK_HEADER_SIZE]);
+            assert_eq!(u32_at(chunk, 124), crc32(&checksummed));
+            let free_space_offset = u32_at(chunk, 48) as usize;
+            assert_eq!(u32_at(chunk, 52), crc32(&chunk[CHUNK_HEADER_SIZE..free_space_offset]));
+            assert_eq!(u64_at(chunk, 8), next_record_number);
+
+            let mut records = vec![];
+            let mut pos = CHUNK_HEADER_SIZE;
+            let mut last_record_offset = 0;
+            while pos < free_space_offset {
+                last_record_offset = pos;
+                let (mut record, next) = read_record(chunk, pos);
+                record.record_number = next_record_number;
+                next_record_number += 1;
+                records.push(record);
+                pos = next;
+            }
+            assert_eq!(u64_at(chunk, 16), next_record_number - 1);
+            assert_eq!(u32_at(chunk, 44) as usize, last_record_offset);
+            chunks.push(ReadChunk {
+                first_record_id: u64_at(chunk, 24),
+                last_record_id: u64_at(chunk, 32),
+                records,
+            });
+        }
+        (u64_at(&data, 24), chunks)
+    }
+
+    fn test_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("evtq-test-{}-{}.evtx", std::process::id(), name)).display().to_string()
+    }
+
+    #[test]
+    fn round_trips_events() {
+        let path = test_path("roundtrip");
+        let mut writer = EvtxWriter::create(&path, false).unwrap();
+        let second = EVENT.replace("<EventRecordID>42<", "<EventRecordID>43<").replace("DC01", "DC02");
+        writer.write_event(EVENT, 132_593_643_671_234_567, 42, EVENT_TYPES).unwrap();
+        writer.write_event(&second, 132_593_643_671_234_568, 43, EVENT_TYPES).unwrap();
+        writer.finish().unwrap();
+        std::mem::drop(writer);
+
+        let (next_record_id, chunks) = read_evtx(&path);
+        assert_eq!(next_record_id, 44);
+        assert_eq!(chunks.len(), 1);
+        let records = &chunks[0].records;
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].record_number, records[0].record_id, records[0].filetime), (1, 42, 132_593_643_671_234_567));
+        assert_eq!(records[0].xml, EVENT);
+        assert_eq!(records[1].xml, second);
+        assert_eq!(records[0].types, vec![
+            TYPE_STRING, TYPE_GUID, TYPE_UINT16, TYPE_UINT8, TYPE_UINT8, TYPE_UINT16, TYPE_UINT8,
+            TYPE_HEXINT64, TYPE_FILETIME, TYPE_UINT64, TYPE_UINT32, TYPE_UINT32, TYPE_STRING, TYPE_STRING,
+            TYPE_STRING, TYPE_SID, TYPE_STRING, TYPE_UINT32, TYPE_STRING, TYPE_BOOL, TYPE_STRING, TYPE_BINARY,
+            TYPE_STRING, TYPE_STRING, TYPE_INT32, TYPE_STRING, TYPE_STRING,
+        ]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn renumbers_records() {
+        let path = test_path("renumber");
+        let mut writer = EvtxWriter::create(&path, true).unwrap();
+        writer.write_event(EVENT, 1, 42, EVENT_TYPES).unwrap();
+        writer.write_event(EVENT, 2, 42, EVENT_TYPES).unwrap();
+        writer.finish().unwrap();
+        std::mem::drop(writer);
+
+        let (next_record_id, chunks) = read_evtx(&path);
+        assert_eq!(next_record_id, 3);
+        let records = &chunks[0].records;
+        assert_eq!((records[0].record_id, records[1].record_id), (1, 2));
+        assert_eq!(records[1].xml, EVENT.replace("<EventRecordID>42<", "<EventRecordID>2<"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn splits_events_in_chunks() {
+        let path = test_path("chunks");
+        let mut writer = EvtxWriter::create(&path, false).unwrap();
+        let mut record_ids = vec![];
+        for i in 0..400u64 {
+            // Not written in increasing order, e.g. from several channels
+            let record_id = 1000 + (i * 7919) % 400;
+            let xml = EVENT.replace("<EventRecordID>42<", &format!("<EventRecordID>{}<", record_id))
+                .replace("n/a", &"x".repeat(300 + i as usize));
+            writer.write_event(&xml, i, record_id, EVENT_TYPES).unwrap();
+            record_ids.push(record_id);
+        }
+        writer.finish().unwrap();
+        std::mem::drop(writer);
+
+        let (next_record_id, chunks) = read_evtx(&path);
+        assert_eq!(next_record_id, 1400);
+        assert!(chunks.len() > 1);
+        let mut read_ids = vec![];
+        for chunk in &chunks {
+            let ids : Vec<u64> = chunk.records.iter().map(|r| r.record_id).collect();
+            assert_eq!(chunk.first_record_id, *ids.iter().min().unwrap());
+            assert_eq!(chunk.last_record_id, *ids.iter().max().unwrap());
+            for record in &chunk.records {
+                assert!(record.xml.contains(&format!("<EventRecordID>{}<", record.record_id)));
+            }
+            read_ids.extend(ids);
+        }
+        assert_eq!(read_ids, record_ids);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_same_templates_across_runs() {
+        assert_eq!(fnv1a_64(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(fnv1a_64(b"a"), 0xAF63_DC4C_8601_EC8C);
+        assert_eq!(fnv1a_64(b"foobar"), 0x8594_4171_F739_67E8);
+        let paths = [test_path("stable1"), test_path("stable2")];
+        for path in &paths {
+            let mut writer = EvtxWriter::create(path, false).unwrap();
+            writer.write_event(EVENT, 1, 42, EVENT_TYPES).unwrap();
+            writer.finish().unwrap();
+        }
+        assert!(std::fs::read(&paths[0]).unwrap() == std::fs::read(&paths[1]).unwrap());
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn encodes_typed_values() {
+        assert_eq!(encode_value(TYPE_INT8, "-2"), Some(vec![0xFE]));
+        assert_eq!(encode_value(TYPE_REAL64, "1.5"), Some(1.5f64.to_le_bytes().to_vec()));
+        assert_eq!(encode_value(TYPE_BOOL, "yes"), None);
+        assert_eq!(encode_value(TYPE_BINARY, "ABC"), None);
+        assert_eq!(encode_value(TYPE_HEXINT32, "0x1F"), Some(vec![0x1F, 0, 0, 0]));
+        assert_eq!(encode_value(TYPE_HEXINT32, "0x1FFFFFFFF"), None);
+        assert_eq!(encode_value(TYPE_SYSTEMTIME, "2021-03-04T05:06:07.123Z").map(|b| format_value(TYPE_SYSTEMTIME, &b)),
+                   Some("2021-03-04T05:06:07.123Z".to_owned()));
+        assert_eq!(encode_value(TYPE_GUID, "{54849625-5478-4994-A5BA-3E3B0328C30D}").map(|b| format_value(TYPE_GUID, &b)),
+                   Some("{54849625-5478-4994-A5BA-3E3B0328C30D}".to_owned()));
+        assert_eq!(systemtime_filetime("1601-01-01T00:00:00.0000001Z"), Some(1));
+        assert_eq!(systemtime_filetime("1600-12-31T23:59:59Z"), None);
+        assert_eq!(variant_value_type(TYPE_SIZET as u32), TYPE_HEXINT64);
+        assert_eq!(variant_value_type(TYPE_ANSI_STRING as u32), TYPE_STRING);
+        assert_eq!(variant_value_type(TYPE_UINT32 as u32 | 0x80), TYPE_STRING);
+    }
+
+    #[test]
+    fn is_read_by_other_parsers() {
+        let path = test_path("parser");
+        let mut writer = EvtxWriter::create(&path, false).unwrap();
+        for i in 0..300u64 {
+            let xml = EVENT.replace("<EventRecordID>42<", &format!("<EventRecordID>{}<", 42 + i))
+                .replace("n/a", &format!("event {} {}", i, "x".repeat(300)));
+            writer.write_event(&xml, 132_593_643_671_234_567 + i, 42 + i, EVENT_TYPES).unwrap();
+        }
+        writer.finish().unwrap();
+        std::mem::drop(writer);
+
+        let data = std::fs::read(&path).unwrap();
+        let header = evtx::EvtxFileHeader::from_bytes(&data).unwrap();
+        assert_eq!(header.checksum, crc32fast::hash(&data[..120]));
+        assert_eq!((header.major_version, header.next_record_id), (3, 342));
+
+        let settings = evtx::ParserSettings::new().validate_checksums(true).num_threads(1);
+        let mut parser = evtx::EvtxParser::from_path(&path).unwrap().with_configuration(settings);
+        let chunks: Vec<evtx::EvtxChunkData> = parser.chunks().map(|chunk| chunk.unwrap()).collect();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.len(), header.chunk_count as usize);
+        assert!(chunks.iter().all(|chunk| chunk.validate_checksum()));
+
+        let records: Vec<_> = parser.records_json_value().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 300);
+        // Records share templates, with their own substitution values
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(record.event_record_id, 42 + i as u64);
+            let system = &record.data["Event"]["System"];
+            assert_eq!(system["EventRecordID"], 42 + i as u64);
+            assert_eq!(system["EventID"], 4624);
+            assert_eq!(system["Computer"], "DC01");
+            assert_eq!(system["Execution"]["#attributes"]["ProcessID"], 612);
+            let event_data = &record.data["Event"]["EventData"];
+            assert_eq!(event_data["SubjectUserSid"], "S-1-5-18");
+            assert_eq!(event_data["LogonType"], 2);
+            assert_eq!(event_data["Elevated"], true);
+            assert_eq!(event_data["Blob"], "00FF10");
+            assert_eq!(event_data["Offset"], -5);
+            assert_eq!(event_data["Fallback"], format!("event {} {}", i, "x".repeat(300)));
+        }
+        assert_eq!(records[0].data["Event"]["System"]["TimeCreated"]["#attributes"]["SystemTime"], "2021-03-04T05:06:07.123456Z");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,29 +1,58 @@
+#[cfg(windows)]
 use winapi::um::winevt::*;
+#[cfg(windows)]
 use winapi::um::minwinbase::SYSTEMTIME;
+#[cfg(windows)]
 use winapi::um::winbase::LocalFree;
-use winapi::um::timezoneapi::FileTimeToSystemTime;
+#[cfg(windows)]
+use winapi::um::timezoneapi::SystemTimeToFileTime;
+use chrono::{DateTime, Utc, Local, TimeZone};
+use chrono::format::{StrftimeItems, Item};
+#[cfg(windows)]
 use winapi::shared::sddl::ConvertSidToStringSidW;
+#[cfg(windows)]
 use winapi::shared::guiddef::GUID;
+#[cfg(windows)]
 use std::ptr::null_mut;
+#[cfg(windows)]
 use winapi::ctypes::c_void;
+#[cfg(windows)]
 use crate::windows::{EvtHandle, get_win32_errcode};
+#[cfg(windows)]
 use winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER;
+#[cfg(windows)]
 use winapi::shared::minwindef::FILETIME;
-use std::fmt::Debug;
-use winapi::_core::fmt::Formatter;
+use std::convert::TryFrom;
+use std::fmt::{Debug, Formatter};
 
+#[derive(Default)]
 pub struct CommonEventProperties {
-    pub timestamp: SYSTEMTIME,
+    // 100ns intervals since 1601-01-01 UTC (FILETIME), to keep the full precision of timestamps
+    pub timestamp: u64,
     pub hostname: String,
     pub recordid: u64,
     pub provider: String,
     pub eventid: u64,
     pub version: u64,
+    // Other values of the System section, which are not present in all events
+    pub provider_guid: Option<String>,
+    pub qualifiers: Option<u64>,
+    pub level: Option<u64>,
+    pub task: Option<u64>,
+    pub opcode: Option<u64>,
+    pub keywords: Option<u64>,
+    pub activity_id: Option<String>,
+    pub related_activity_id: Option<String>,
+    pub process_id: Option<u64>,
+    pub thread_id: Option<u64>,
+    pub channel: Option<String>,
+    pub user_id: Option<String>,
 }
 
 pub enum EvtVariant {
     Null,
     String(String),
+    #[cfg(windows)]
     Handle(EvtHandle),
     UInt(u64),
     Int(i64),
@@ -31,7 +60,8 @@ pub enum EvtVariant {
     Double(f64),
     Boolean(bool),
     Binary(Vec<u8>),
-    DateTime(SYSTEMTIME),
+    DateTime(u64), // as a FILETIME
+    Array(Vec<EvtVariant>),
 }
 
 impl Debug for EvtVariant {
@@ -39,6 +69,7 @@ impl Debug for EvtVariant {
         match self {
             EvtVariant::Null => write!(f, "EvtVariant::Null"),
             EvtVariant::String(x) => write!(f, "EvtVariant::String(\"{}\")", x),
+            #[cfg(windows)]
             EvtVariant::Handle(x) => write!(f, "EvtVariant::Handle({:?})", x),
             EvtVariant::UInt(x) => write!(f, "EvtVariant::Handle({})", x),
             EvtVariant::Int(x) => write!(f, "EvtVariant::Handle({})", x),
@@ -46,10 +77,8 @@ impl Debug for EvtVariant {
             EvtVariant::Double(x) => write!(f, "EvtVariant::Handle({})", x),
             EvtVariant::Boolean(x) => write!(f, "EvtVariant::Handle({})", x),
             EvtVariant::Binary(x) => write!(f, "EvtVariant::Handle({:?})", x),
-            EvtVariant::DateTime(x) => write!(f,
-                "EvtVariant::DateTime({}-{}-{} {}:{}:{}.{})",
-                x.wYear, x.wMonth, x.wDay, x.wHour, x.wMinute, x.wSecond, x.wMilliseconds
-            ),
+            EvtVariant::DateTime(x) => write!(f, "EvtVariant::DateTime({})", format_filetime(*x, &DateConfig::rfc3339_utc())),
+            EvtVariant::Array(x) => write!(f, "EvtVariant::Array({:?})", x),
         }
     }
 }
@@ -64,30 +93,230 @@ pub fn bytes_as_hexstring(bytes: &[u8]) -> String {
 
 pub fn hexstring_to_uint(hex: &str) -> Option<u64> {
     let hex = hex.to_lowercase().replace(" ", "").replace("0x", "");
-    match u64::from_str_radix(&hex, 16) {
-        Ok(u) => Some(u),
-        _ => None
+    u64::from_str_radix(&hex, 16).ok()
+}
+
+// Number of 100ns intervals between 1601-01-01 (FILETIME origin) and 1970-01-01 (Unix epoch)
+const FILETIME_UNIX_EPOCH: i64 = 116_444_736_000_000_000;
+pub const FILETIME_TICKS_PER_SEC: i64 = 10_000_000;
+
+pub enum DateFormat {
+    Strftime(String),
+    // 2006-01-02T15:04:05.1234567Z (or +01:00 when not in UTC)
+    Rfc3339,
+    // 2006-01-02T15:04:05.1234567+00:00
+    Iso8601,
+    // Seconds since 1970-01-01 UTC
+    Epoch,
+    // Milliseconds since 1970-01-01 UTC
+    EpochMs,
+    // 100ns intervals since 1601-01-01 UTC, as stored in event logs
+    FileTime,
+}
+
+#[derive(Clone)]
+pub enum OutputTimeZone {
+    Utc,
+    Local,
+    Named(chrono_tz::Tz),
+}
+
+pub struct DateConfig {
+    pub format: DateFormat,
+    pub timezone: OutputTimeZone,
+}
+
+impl DateFormat {
+    pub fn parse(datefmt: &str) -> Result<DateFormat, String> {
+        Ok(match datefmt.to_lowercase().as_str() {
+            "rfc3339" => DateFormat::Rfc3339,
+            "iso8601" => DateFormat::Iso8601,
+            "epoch" => DateFormat::Epoch,
+            "epoch_ms" => DateFormat::EpochMs,
+            "filetime" => DateFormat::FileTime,
+            _ => {
+                // chrono only reports invalid specifiers when formatting, by panicking
+                if StrftimeItems::new(datefmt).any(|item| item == Item::Error) {
+                    return Err(format!("Invalid date format '{}'", datefmt));
+                }
+                DateFormat::Strftime(datefmt.to_owned())
+            },
+        })
     }
 }
 
-pub fn format_utc_systemtime(stime: &SYSTEMTIME, datefmt: &str) -> String {
-    let res = datefmt.to_owned();
-    let res = res.replace("%Y", &format!("{:04}", stime.wYear));
-    let res = res.replace("%m", &format!("{:02}", stime.wMonth));
-    let res = res.replace("%d", &format!("{:02}", stime.wDay));
-    let res = res.replace("%H", &format!("{:02}", stime.wHour));
-    let res = res.replace("%M", &format!("{:02}", stime.wMinute));
-    let res = res.replace("%S", &format!("{:02}", stime.wSecond));
-    let res = res.replace("%.3f", &format!(".{:03}", stime.wMilliseconds));
-    let res = res.replace("%z", "+0000");
-    res
+impl OutputTimeZone {
+    pub fn parse(timezone: &str) -> Result<OutputTimeZone, String> {
+        Ok(match timezone.to_lowercase().as_str() {
+            "utc" | "z" => OutputTimeZone::Utc,
+            "local" => OutputTimeZone::Local,
+            _ => match timezone.parse::<chrono_tz::Tz>() {
+                Ok(tz) => OutputTimeZone::Named(tz),
+                Err(e) => return Err(format!("Unknown time zone '{}' (expected UTC, local, or an IANA name like Europe/Paris): {}", timezone, e)),
+            },
+        })
+    }
+}
+
+impl DateConfig {
+    pub fn rfc3339_utc() -> DateConfig {
+        DateConfig { format: DateFormat::Rfc3339, timezone: OutputTimeZone::Utc }
+    }
+
+    // Same time zone, e.g. to only render the date part of timestamps
+    pub fn with_format(&self, format: DateFormat) -> DateConfig {
+        DateConfig { format, timezone: self.timezone.clone() }
+    }
+}
+
+// Timestamps come from event records, which can hold any value: they are out of range instead
+// of overflowing when they do not fit in an i64
+fn filetime_unix_ticks(filetime: u64) -> Option<i64> {
+    i64::try_from(filetime).ok()?.checked_sub(FILETIME_UNIX_EPOCH)
+}
+
+fn filetime_to_utc(filetime: u64) -> Option<DateTime<Utc>> {
+    let ticks = filetime_unix_ticks(filetime)?;
+    Utc.timestamp_opt(ticks.div_euclid(FILETIME_TICKS_PER_SEC),
+                      (ticks.rem_euclid(FILETIME_TICKS_PER_SEC) * 100) as u32).single()
+}
+
+fn format_datetime<Tz: TimeZone>(date: DateTime<Tz>, filetime: u64, format: &DateFormat, utc: bool) -> String
+    where Tz::Offset: std::fmt::Display {
+    let fraction = filetime % FILETIME_TICKS_PER_SEC as u64;
+    match format {
+        DateFormat::Strftime(f) => date.format(f).to_string(),
+        DateFormat::Rfc3339 if utc => format!("{}.{:07}Z", date.format("%Y-%m-%dT%H:%M:%S"), fraction),
+        DateFormat::Rfc3339 | DateFormat::Iso8601 =>
+            format!("{}.{:07}{}", date.format("%Y-%m-%dT%H:%M:%S"), fraction, date.format("%:z")),
+        DateFormat::Epoch | DateFormat::EpochMs | DateFormat::FileTime => filetime.to_string(),
+    }
+}
+
+// Returns the timestamp as an integer, if the format is a numeric one
+pub fn filetime_as_integer(filetime: u64, datefmt: &DateConfig) -> Option<i64> {
+    match datefmt.format {
+        DateFormat::Epoch => Some(filetime_unix_ticks(filetime)?.div_euclid(FILETIME_TICKS_PER_SEC)),
+        DateFormat::EpochMs => Some(filetime_unix_ticks(filetime)?.div_euclid(FILETIME_TICKS_PER_SEC / 1000)),
+        DateFormat::FileTime => i64::try_from(filetime).ok(),
+        _ => None,
+    }
+}
+
+pub fn format_filetime(filetime: u64, datefmt: &DateConfig) -> String {
+    if let Some(i) = filetime_as_integer(filetime, datefmt) {
+        return i.to_string();
+    }
+    let date = match filetime_to_utc(filetime) {
+        Some(d) => d,
+        None => return filetime.to_string(),
+    };
+    match &datefmt.timezone {
+        OutputTimeZone::Utc => format_datetime(date, filetime, &datefmt.format, true),
+        OutputTimeZone::Local => format_datetime(date.with_timezone(&Local), filetime, &datefmt.format, false),
+        OutputTimeZone::Named(tz) => format_datetime(date.with_timezone(tz), filetime, &datefmt.format, false),
+    }
+}
+
+pub fn current_filetime() -> u64 {
+    let now = Utc::now();
+    now.timestamp().checked_mul(FILETIME_TICKS_PER_SEC)
+        .and_then(|ticks| ticks.checked_add(FILETIME_UNIX_EPOCH + (now.timestamp_subsec_nanos() / 100) as i64))
+        .and_then(|filetime| u64::try_from(filetime).ok())
+        .unwrap_or(0)
+}
+
+// Time against which sessions and process trees expire, e.g. every second of a live tail: the one of
+// the latest event while existing events are read, and the wall clock once caught up
+pub fn expiry_time(latest_event: u64, wall_clock: Option<u64>) -> u64 {
+    std::cmp::max(latest_event, wall_clock.unwrap_or(0))
+}
+
+// Field type corresponding to the type of values rendered by EvtRender(), as used in manifests
+#[cfg(windows)]
+pub fn variant_type_name(variant_type: u32) -> &'static str {
+    match variant_type & EVT_VARIANT_TYPE_MASK {
+        EvtVarTypeSByte => "xs:byte",
+        EvtVarTypeByte => "xs:unsignedByte",
+        EvtVarTypeInt16 => "xs:short",
+        EvtVarTypeUInt16 => "xs:unsignedShort",
+        EvtVarTypeInt32 => "xs:int",
+        EvtVarTypeUInt32 => "xs:unsignedInt",
+        EvtVarTypeInt64 => "xs:long",
+        EvtVarTypeUInt64 => "xs:unsignedLong",
+        EvtVarTypeSingle => "xs:float",
+        EvtVarTypeDouble => "xs:double",
+        EvtVarTypeBoolean => "xs:boolean",
+        EvtVarTypeBinary => "xs:hexBinary",
+        EvtVarTypeGuid => "xs:GUID",
+        EvtVarTypeSizeT => "win:HexInt64",
+        EvtVarTypeFileTime | EvtVarTypeSysTime => "xs:dateTime",
+        EvtVarTypeHexInt32 => "win:HexInt32",
+        EvtVarTypeHexInt64 => "win:HexInt64",
+        EvtVarTypeEvtXml => "win:Xml",
+        _ => "xs:string",
+    }
+}
+
+// Renders a value as text, e.g. for CSV outputs. Items of arrays are separated with commas.
+pub fn variant_as_string(variant: EvtVariant, datefmt: &DateConfig) -> String {
+    match variant {
+        EvtVariant::Null => String::new(),
+        #[cfg(windows)]
+        EvtVariant::Handle(_) => "<handle>".to_owned(),
+        EvtVariant::String(s) => s,
+        EvtVariant::UInt(i) => i.to_string(),
+        EvtVariant::Int(i) => i.to_string(),
+        EvtVariant::Single(f) => f.to_string(),
+        EvtVariant::Double(f) => f.to_string(),
+        EvtVariant::Boolean(b) => (if b { "true" } else { "false" }).to_owned(),
+        EvtVariant::Binary(v) => bytes_as_hexstring(&v),
+        EvtVariant::DateTime(d) => format_filetime(d, datefmt),
+        EvtVariant::Array(items) => items.into_iter()
+            .map(|item| variant_as_string(item, datefmt))
+            .collect::<Vec<String>>()
+            .join(","),
+    }
+}
+
+// Returns the item of an array variant at the given index, as a variant of its own
+#[cfg(windows)]
+fn array_item(variant: &EVT_VARIANT, index: usize) -> Result<EVT_VARIANT, String> {
+    let item_type = variant.Type & EVT_VARIANT_TYPE_MASK;
+    // Items are stored by value, except GUIDs and SYSTEMTIMEs which are stored in the array
+    // itself but referenced by a pointer in single values
+    let (item_size, by_reference) = match item_type {
+        EvtVarTypeSByte | EvtVarTypeByte => (1, false),
+        EvtVarTypeInt16 | EvtVarTypeUInt16 => (2, false),
+        EvtVarTypeInt32 | EvtVarTypeUInt32 | EvtVarTypeHexInt32 | EvtVarTypeSingle | EvtVarTypeBoolean => (4, false),
+        EvtVarTypeInt64 | EvtVarTypeUInt64 | EvtVarTypeHexInt64 | EvtVarTypeDouble | EvtVarTypeFileTime => (8, false),
+        EvtVarTypeString | EvtVarTypeAnsiString | EvtVarTypeSid | EvtVarTypeSizeT => (std::mem::size_of::<usize>(), false),
+        EvtVarTypeGuid => (std::mem::size_of::<GUID>(), true),
+        EvtVarTypeSysTime => (std::mem::size_of::<SYSTEMTIME>(), true),
+        unknown => return Err(format!("Unsupported EVT_VARIANT array type {}", unknown)),
+    };
+    let items = unsafe { std::ptr::read(&variant.u as *const _ as *const *const u8) };
+    let mut item : EVT_VARIANT = unsafe { std::mem::zeroed() };
+    item.Type = item_type;
+    unsafe {
+        let item_ptr = items.add(index * item_size);
+        if by_reference {
+            std::ptr::write(&mut item.u as *mut _ as *mut *const u8, item_ptr);
+        } else {
+            std::ptr::copy_nonoverlapping(item_ptr, &mut item.u as *mut _ as *mut u8, item_size);
+        }
+    }
+    Ok(item)
 }
 
+#[cfg(windows)]
 pub fn unwrap_variant_contents(variant: &EVT_VARIANT, type_hint: Option<&str>) -> Result<EvtVariant, String> {
-    // Arrays are treated recursively, rendered as "[" + str(value1) + "," + str(value2) + ...
     if (variant.Type & EVT_VARIANT_TYPE_ARRAY) == EVT_VARIANT_TYPE_ARRAY {
-        return Ok(EvtVariant::String(format!("[array]")));
-        // TODO: recursive formatting, but requires unsafe pointer arithmetics inside EVT_VARIANT...
+        let mut items = Vec::with_capacity(variant.Count as usize);
+        for index in 0..(variant.Count as usize) {
+            items.push(unwrap_variant_contents(&array_item(variant, index)?, type_hint)?);
+        }
+        return Ok(EvtVariant::Array(items));
     }
     let res = match variant.Type {
         EvtVarTypeNull => EvtVariant::Null,
@@ -201,19 +430,19 @@ pub fn unwrap_variant_contents(variant: &EVT_VARIANT, type_hint: Option<&str>) -
             EvtVariant::UInt(*val as u64)
         },
         EvtVarTypeFileTime => {
-            let val : FILETIME = unsafe { std::ptr::read(variant.u.FileTimeVal() as *const _ as *const FILETIME) };
-            let mut stime: SYSTEMTIME = unsafe { std::mem::zeroed() };
+            let val : &u64 = unsafe { variant.u.FileTimeVal() };
+            EvtVariant::DateTime(*val)
+        },
+        EvtVarTypeSysTime => {
+            let stime : SYSTEMTIME = unsafe { std::ptr::read(*variant.u.SysTimeVal() as *const SYSTEMTIME) };
+            let mut val : FILETIME = unsafe { std::mem::zeroed() };
             let convert_res = unsafe {
-                FileTimeToSystemTime(&val, &mut stime)
+                SystemTimeToFileTime(&stime, &mut val)
             };
             if convert_res == 0 {
-                return Err(format!("FileTimeToSystemTime() failed with code {}", get_win32_errcode()));
+                return Err(format!("SystemTimeToFileTime() failed with code {}", get_win32_errcode()));
             }
-            EvtVariant::DateTime(stime)
-        },
-        EvtVarTypeSysTime => {
-            let stime : SYSTEMTIME = unsafe { std::ptr::read(variant.u.SysTimeVal() as *const _ as *const SYSTEMTIME) };
-            EvtVariant::DateTime(stime)
+            EvtVariant::DateTime(((val.dwHighDateTime as u64) << 32) | (val.dwLowDateTime as u64))
         },
         EvtVarTypeHexInt64 => {
             let val : u64 = unsafe { std::ptr::read(&variant.u as *const _ as *const u64) };
@@ -262,6 +491,7 @@ pub fn unwrap_variant_contents(variant: &EVT_VARIANT, type_hint: Option<&str>) -
     Ok(res)
 }
 
+#[cfg(windows)]
 fn get_event_common_property(buffer: *const u8, prop_num: u32) -> Result<EvtVariant, String> {
     let buffer_offset = (prop_num as usize) * std::mem::size_of::<EVT_VARIANT>();
     let prop : EVT_VARIANT = unsafe {
@@ -270,6 +500,28 @@ fn get_event_common_property(buffer: *const u8, prop_num: u32) -> Result<EvtVari
     unwrap_variant_contents(&prop, None)
 }
 
+#[cfg(windows)]
+fn get_optional_string_property(buffer: *const u8, prop_num: u32, prop_name: &str) -> Result<Option<String>, String> {
+    match get_event_common_property(buffer, prop_num) {
+        Ok(EvtVariant::String(s)) if !s.is_empty() => Ok(Some(s)),
+        Ok(EvtVariant::String(_)) | Ok(EvtVariant::Null) => Ok(None),
+        Ok(other) => Err(format!("Unexpected EVT_VARIANT type {:?} for {}", other, prop_name)),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(windows)]
+fn get_optional_uint_property(buffer: *const u8, prop_num: u32, prop_name: &str) -> Result<Option<u64>, String> {
+    match get_event_common_property(buffer, prop_num) {
+        Ok(EvtVariant::UInt(u)) => Ok(Some(u)),
+        Ok(EvtVariant::Int(i)) => Ok(Some(i as u64)),
+        Ok(EvtVariant::Null) => Ok(None),
+        Ok(other) => Err(format!("Unexpected EVT_VARIANT type {:?} for {}", other, prop_name)),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(windows)]
 pub fn get_event_common_properties(h_event: &EvtHandle) -> Result<Option<CommonEventProperties>, String> {
     let h_ctxsystem = unsafe { EvtCreateRenderContext(0, null_mut(), EvtRenderContextSystem) };
     if h_ctxsystem.is_null() {
@@ -354,7 +606,107 @@ pub fn get_event_common_properties(h_event: &EvtHandle) -> Result<Option<CommonE
         Err(e) => return Err(e),
     };
 
+    let buffer = buffer.as_ptr();
     Ok(Some(CommonEventProperties {
-        timestamp, hostname, recordid, provider, eventid, version
+        timestamp, hostname, recordid, provider, eventid, version,
+        provider_guid: get_optional_string_property(buffer, EvtSystemProviderGuid, "EvtSystemProviderGuid")?,
+        qualifiers: get_optional_uint_property(buffer, EvtSystemQualifiers, "EvtSystemQualifiers")?,
+        level: get_optional_uint_property(buffer, EvtSystemLevel, "EvtSystemLevel")?,
+        task: get_optional_uint_property(buffer, EvtSystemTask, "EvtSystemTask")?,
+        opcode: get_optional_uint_property(buffer, EvtSystemOpcode, "EvtSystemOpcode")?,
+        keywords: get_optional_uint_property(buffer, EvtSystemKeywords, "EvtSystemKeywords")?,
+        activity_id: get_optional_string_property(buffer, EvtSystemActivityID, "EvtSystemActivityID")?,
+        related_activity_id: get_optional_string_property(buffer, EvtSystemRelatedActivityID, "EvtSystemRelatedActivityID")?,
+        process_id: get_optional_uint_property(buffer, EvtSystemProcessID, "EvtSystemProcessID")?,
+        thread_id: get_optional_uint_property(buffer, EvtSystemThreadID, "EvtSystemThreadID")?,
+        channel: get_optional_string_property(buffer, EvtSystemChannel, "EvtSystemChannel")?,
+        user_id: get_optional_string_property(buffer, EvtSystemUserID, "EvtSystemUserID")?,
     }))
-}
\ No newline at end of file
+}
+
+// Renders the values of an event (from its EventData or UserData), returned as a buffer of
+// EVT_VARIANTs along with their count
+#[cfg(windows)]
+pub fn render_event_values(h_event: &EvtHandle) -> Result<(Vec<u8>, u32), String> {
+    let h_ctxuser = unsafe { EvtCreateRenderContext(0, null_mut(), EvtRenderContextUser) };
+    if h_ctxuser.is_null() {
+        return Err(format!("EvtCreateRenderContext(EvtRenderContextUser) failed with code {}", get_win32_errcode()));
+    }
+    let h_ctxuser = EvtHandle::from_raw(h_ctxuser)?;
+    let mut buffer_len_req : u32 = 0;
+    let mut props_count : u32 = 0;
+    let res = unsafe {
+        EvtRender(h_ctxuser.as_ptr(),
+                  h_event.as_ptr(),
+                  EvtRenderEventValues,
+                  0,
+                  null_mut(),
+                  &mut buffer_len_req as *mut u32,
+                  &mut props_count as *mut u32)
+    };
+    // res can be != 0 here if, even with a NULL buffer, if there are no event values to render
+    let mut buffer : Vec<u8> = Vec::with_capacity(buffer_len_req as usize);
+    if res == 0 && get_win32_errcode() != ERROR_INSUFFICIENT_BUFFER {
+        return Err(format!("EvtRender(EvtRenderEventValues) failed with code {}", get_win32_errcode()));
+    }
+    else if res == 0 {
+        let res = unsafe {
+            EvtRender(h_ctxuser.as_ptr(),
+                      h_event.as_ptr(),
+                      EvtRenderEventValues,
+                      buffer_len_req,
+                      buffer.as_mut_ptr() as *mut c_void,
+                      &mut buffer_len_req as *mut u32,
+                      &mut props_count as *mut u32)
+        };
+        if res == 0 {
+            return Err(format!("Rendering event values failed with code {}", get_win32_errcode()));
+        }
+    }
+    Ok((buffer, props_count))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(format: DateFormat) -> DateConfig {
+        DateConfig { format, timezone: OutputTimeZone::Utc }
+    }
+
+    #[test]
+    fn formats_filetimes() {
+        let filetime = 132_000_000_001_234_567;
+        assert_eq!(format_filetime(filetime, &DateConfig::rfc3339_utc()), "2019-04-17T18:40:00.1234567Z");
+        assert_eq!(format_filetime(filetime, &config(DateFormat::Iso8601)), "2019-04-17T18:40:00.1234567+00:00");
+        assert_eq!(format_filetime(filetime, &config(DateFormat::Strftime("%Y-%m-%d".to_owned()))), "2019-04-17");
+        assert_eq!(filetime_as_integer(filetime, &config(DateFormat::Epoch)), Some(1_555_526_400));
+        assert_eq!(filetime_as_integer(filetime, &config(DateFormat::EpochMs)), Some(1_555_526_400_123));
+        assert_eq!(filetime_as_integer(filetime, &config(DateFormat::FileTime)), Some(filetime as i64));
+        assert_eq!(filetime_as_integer(filetime, &DateConfig::rfc3339_utc()), None);
+    }
+
+    #[test]
+    fn formats_filetimes_before_the_unix_epoch() {
+        assert_eq!(format_filetime(0, &DateConfig::rfc3339_utc()), "1601-01-01T00:00:00.0000000Z");
+        assert_eq!(filetime_as_integer(0, &config(DateFormat::Epoch)), Some(-11_644_473_600));
+        assert_eq!(filetime_as_integer(1, &config(DateFormat::EpochMs)), Some(-11_644_473_600_000));
+    }
+
+    #[test]
+    fn keeps_out_of_range_filetimes() {
+        for filetime in [i64::MAX as u64 + 1, u64::MAX] {
+            assert_eq!(filetime_as_integer(filetime, &config(DateFormat::Epoch)), None);
+            assert_eq!(filetime_as_integer(filetime, &config(DateFormat::EpochMs)), None);
+            assert_eq!(filetime_as_integer(filetime, &config(DateFormat::FileTime)), None);
+            assert_eq!(format_filetime(filetime, &DateConfig::rfc3339_utc()), filetime.to_string());
+            assert_eq!(format_filetime(filetime, &config(DateFormat::Epoch)), filetime.to_string());
+        }
+    }
+
+    #[test]
+    fn reads_current_filetime() {
+        let before = Utc::now().timestamp();
+        let now = filetime_as_integer(current_filetime(), &config(DateFormat::Epoch)).unwrap();
+        assert!(now >= before && now <= Utc::now().timestamp());
+    }
+}
@@ -0,0 +1,221 @@
+#[cfg(windows)]
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+#[cfg(windows)]
+use winapi::um::winevt::EVT_VARIANT;
+#[cfg(windows)]
+use crate::windows::EvtHandle;
+#[cfg(windows)]
+use crate::RenderingConfig;
+#[cfg(windows)]
+use crate::formatting::{CommonEventProperties, variant_type_name};
+#[cfg(windows)]
+use crate::metadata::{ProviderMetadata, MetadataSource};
+use crate::metadata::{EventDefinition, EventFieldDefinition};
+
+// Returns the names of the values of an event, in the order in which EvtRender() returns them,
+// as found in its XML rendering: either <EventData><Data Name="..."> elements, or the child
+// elements of the (single) element in <UserData>. Values without a name are returned as None.
+pub fn infer_field_names(xml: &str) -> Result<Vec<Option<String>>, String> {
+    let xml = match roxmltree::Document::parse(xml.trim_end_matches('\0')) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Unable to parse event XML: {}", e)),
+    };
+    let mut names = Vec::new();
+    for section in xml.root_element().children().filter(|n| n.is_element()) {
+        if section.has_tag_name("EventData") {
+            for data in section.children().filter(|n| n.is_element() && n.has_tag_name("Data")) {
+                names.push(data.attribute("Name").map(|name| name.to_owned()));
+            }
+        } else if section.has_tag_name("UserData") {
+            if let Some(event) = section.children().find(|n| n.is_element()) {
+                for data in event.children().filter(|n| n.is_element()) {
+                    names.push(Some(data.tag_name().name().to_owned()));
+                }
+            }
+        }
+    }
+    Ok(names)
+}
+
+// Returns the named values of an event as they appear in its XML rendering, e.g. to correlate
+// events by fields which have the same name whatever the metadata available
+pub fn event_data_values(xml: &str) -> Result<HashMap<String, String>, String> {
+    let xml = match roxmltree::Document::parse(xml.trim_end_matches('\0')) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Unable to parse event XML: {}", e)),
+    };
+    let mut values = HashMap::new();
+    for section in xml.root_element().children().filter(|n| n.is_element()) {
+        if section.has_tag_name("EventData") {
+            for data in section.children().filter(|n| n.is_element() && n.has_tag_name("Data")) {
+                if let Some(name) = data.attribute("Name") {
+                    values.insert(name.to_owned(), data.text().unwrap_or_default().to_owned());
+                }
+            }
+        } else if section.has_tag_name("UserData") {
+            if let Some(event) = section.children().find(|n| n.is_element()) {
+                for data in event.children().filter(|n| n.is_element()) {
+                    values.insert(data.tag_name().name().to_owned(), data.text().unwrap_or_default().to_owned());
+                }
+            }
+        }
+    }
+    Ok(values)
+}
+
+// Values absent from an event are logged as "-"
+pub fn data_value(data: &HashMap<String, String>, name: &str) -> Option<String> {
+    match data.get(name).map(|value| value.trim()) {
+        None | Some("") | Some("-") => None,
+        Some(value) => Some(value.to_owned()),
+    }
+}
+
+// Whether a learned definition was learned from the given definition, i.e. only adds fields
+// to it: definitions of the same event can differ between hosts and metadata sources
+fn learned_from(learned_def: &EventDefinition, event_def: &EventDefinition) -> bool {
+    if !learned_def.fields.starts_with(&event_def.fields) {
+        return false;
+    }
+    let mut base = learned_def.to_owned();
+    base.fields.truncate(event_def.fields.len());
+    base.sources = event_def.sources.to_owned();
+    base == *event_def
+}
+
+// Adds the given values to a definition, named after the names inferred from the event XML
+// when there is one, or fieldN otherwise
+fn add_inferred_fields(event_def: &EventDefinition, names: &[Option<String>], out_types: &[&str]) -> EventDefinition {
+    let mut learned_def = event_def.to_owned();
+    for (i, out_type) in out_types.iter().enumerate().skip(event_def.fields.len()) {
+        learned_def.fields.push(EventFieldDefinition {
+            name: match names.get(i) {
+                Some(Some(name)) => name.to_owned(),
+                _ => format!("field{}", i + 1),
+            },
+            out_type: (*out_type).to_owned(),
+        });
+    }
+    learned_def
+}
+
+// Returns a definition of the given event with names for all of its values, learning them
+// from its XML rendering the first time an event with that Provider/EventID/Version is seen
+// without a complete definition, and reusing them for later events so that they are consistent.
+#[cfg(windows)]
+pub fn learned_event_definition(h_event: &EvtHandle,
+                                common_props: &CommonEventProperties,
+                                render_cfg: &RenderingConfig,
+                                event_def: &EventDefinition,
+                                variants: *const EVT_VARIANT,
+                                variant_count: u32) -> Result<EventDefinition, String> {
+    let mut learned = match render_cfg.learned_metadata.lock() {
+        Ok(l) => l,
+        Err(e) => return Err(format!("Failed to acquire lock to learned metadata: {}", e)),
+    };
+    if let Some(learned_def) = learned.get(&common_props.provider)
+        .and_then(|prov_meta| prov_meta.events.get(&common_props.eventid))
+        .and_then(|versions| versions.get(&common_props.version)) {
+        if learned_def.fields.len() >= variant_count as usize && learned_from(learned_def, event_def) {
+            return Ok(learned_def.to_owned());
+        }
+    }
+
+    let names = match crate::xml::render_xml_string(h_event).and_then(|xml| infer_field_names(&xml)) {
+        Ok(names) => names,
+        Err(e) => {
+            debug!("Unable to infer field names of event {}/{}/{}: {}",
+                   common_props.provider, common_props.eventid, common_props.version, e);
+            vec![]
+        },
+    };
+    let out_types: Vec<&str> = (0..variant_count as usize)
+        .map(|i| variant_type_name(unsafe { std::ptr::read(variants.add(i)) }.Type))
+        .collect();
+    let mut learned_def = add_inferred_fields(event_def, &names, &out_types);
+    learned_def.sources = vec![MetadataSource {
+        os_build: None,
+        host: Some(common_props.hostname.to_owned()),
+        file: None,
+    }];
+    verbose!("Learned field names of event {}/{}/{}: {}", common_props.provider, common_props.eventid,
+             common_props.version, learned_def.fields.iter().map(|f| f.name.as_str()).collect::<Vec<&str>>().join(", "));
+
+    let prov_meta = learned.entry(common_props.provider.to_owned()).or_insert_with(ProviderMetadata::empty);
+    prov_meta.events.entry(common_props.eventid).or_insert(BTreeMap::new())
+        .insert(common_props.version, learned_def.clone());
+    Ok(learned_def)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::MetadataSource;
+
+    const EVENT_DATA_XML: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event">
+  <System><EventID>4688</EventID></System>
+  <EventData><Data Name="SubjectUserSid">S-1-5-18</Data><Data>unnamed</Data><Data Name="NewProcessName">C:\Windows\cmd.exe</Data><Data Name="CommandLine"/></EventData>
+</Event>"#;
+
+    const USER_DATA_XML: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event">
+  <System><EventID>1102</EventID></System>
+  <UserData><LogFileCleared xmlns="http://manifests.microsoft.com/win/2004/08/windows/eventlog"><SubjectUserName>admin</SubjectUserName><SubjectDomainName>CORP</SubjectDomainName></LogFileCleared></UserData>
+</Event>"#;
+
+    fn field(name: &str, out_type: &str) -> EventFieldDefinition {
+        EventFieldDefinition { name: name.to_owned(), out_type: out_type.to_owned() }
+    }
+
+    #[test]
+    fn infers_names_from_event_data_and_user_data() {
+        assert_eq!(infer_field_names(EVENT_DATA_XML).unwrap(), vec![
+            Some("SubjectUserSid".to_owned()), None, Some("NewProcessName".to_owned()), Some("CommandLine".to_owned()),
+        ]);
+        assert_eq!(infer_field_names(USER_DATA_XML).unwrap(), vec![
+            Some("SubjectUserName".to_owned()), Some("SubjectDomainName".to_owned()),
+        ]);
+        assert!(infer_field_names("<Event></EventData>").is_err());
+    }
+
+    #[test]
+    fn reads_named_values() {
+        let values = event_data_values(EVENT_DATA_XML).unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values["SubjectUserSid"], "S-1-5-18");
+        assert_eq!(values["NewProcessName"], "C:\\Windows\\cmd.exe");
+        assert_eq!(values["CommandLine"], "");
+        let values = event_data_values(USER_DATA_XML).unwrap();
+        assert_eq!(values["SubjectUserName"], "admin");
+        assert_eq!(values["SubjectDomainName"], "CORP");
+    }
+
+    #[test]
+    fn names_missing_fields_after_known_ones() {
+        let event_def = EventDefinition { fields: vec![field("Known", "String")], ..EventDefinition::empty() };
+        let names = infer_field_names(EVENT_DATA_XML).unwrap();
+        let learned_def = add_inferred_fields(&event_def, &names, &["String", "String", "String", "UInt32", "UInt32"]);
+        assert_eq!(learned_def.fields, vec![
+            field("Known", "String"),
+            field("field2", "String"),
+            field("NewProcessName", "String"),
+            field("CommandLine", "UInt32"),
+            field("field5", "UInt32"),
+        ]);
+    }
+
+    #[test]
+    fn reuses_definitions_learned_from_the_same_definition_only() {
+        let event_def = EventDefinition { fields: vec![field("Known", "String")], ..EventDefinition::empty() };
+        let mut learned_def = add_inferred_fields(&event_def, &[], &["String", "UInt32"]);
+        learned_def.sources = vec![MetadataSource { os_build: None, host: Some("host".to_owned()), file: None }];
+        assert!(learned_from(&learned_def, &event_def));
+
+        let renamed = EventDefinition { fields: vec![field("Other", "String")], ..EventDefinition::empty() };
+        assert!(!learned_from(&learned_def, &renamed));
+        let retyped = EventDefinition { fields: vec![field("Known", "UInt32")], ..EventDefinition::empty() };
+        assert!(!learned_from(&learned_def, &retyped));
+        let with_message = EventDefinition { message: Some("Known: %1".to_owned()), ..event_def.clone() };
+        assert!(!learned_from(&learned_def, &with_message));
+    }
+}
@@ -1,168 +1,91 @@
+#[cfg(windows)]
 use winapi::um::winevt::*;
-use winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER;
-use std::ptr::null_mut;
+#[cfg(windows)]
 use std::result::Result;
-use winapi::ctypes::c_void;
-use crate::windows::{EvtHandle, get_win32_errcode};
-use crate::{RenderingConfig, OutputColumn};
-use crate::formatting::{unwrap_variant_contents, bytes_as_hexstring, format_utc_systemtime, CommonEventProperties, EvtVariant};
-use crate::metadata::{EventFieldDefinition, EventDefinition};
+#[cfg(windows)]
+use std::collections::BTreeMap;
+#[cfg(windows)]
+use crate::windows::EvtHandle;
+#[cfg(windows)]
+use crate::{RenderingConfig, EventOutput};
+use crate::formatting::{bytes_as_hexstring, format_filetime, filetime_as_integer, DateConfig, EvtVariant};
+#[cfg(windows)]
+use crate::formatting::{render_event_values, CommonEventProperties};
+#[cfg(windows)]
+use crate::metadata::{EventDefinition, classify_event};
+#[cfg(windows)]
+use crate::output_cols::EventColumns;
+#[cfg(windows)]
+use crate::inference::learned_event_definition;
 
-pub fn render_event_json(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<(), String> {
-    let h_ctxuser = unsafe { EvtCreateRenderContext(0, null_mut(), EvtRenderContextUser) };
-    if h_ctxuser.is_null() {
-        return Err(format!("EvtCreateRenderContext(EvtRenderContextUser) failed with code {}", get_win32_errcode()));
+// Numeric date formats (e.g. epoch) are rendered as JSON numbers, others as strings
+pub fn date_json_value(filetime: u64, datefmt: &DateConfig) -> serde_json::value::Value {
+    match filetime_as_integer(filetime, datefmt) {
+        Some(i) => serde_json::value::Value::from(i),
+        None => serde_json::value::Value::from(format_filetime(filetime, datefmt)),
     }
-    let h_ctxuser = EvtHandle::from_raw(h_ctxuser)?;
-    let mut buffer_len_req : u32 = 0;
-    let mut props_count : u32 = 0;
-    let res = unsafe {
-        EvtRender(h_ctxuser.as_ptr(),
-                  h_event.as_ptr(),
-                  EvtRenderEventValues,
-                  0,
-                  null_mut(),
-                  &mut buffer_len_req as *mut u32,
-                  &mut props_count as *mut u32)
-    };
-    // res can be != 0 here if, even with a NULL buffer, if there are no event values to render
-    let mut buffer : Vec<u8> = Vec::with_capacity(buffer_len_req as usize);
-    if res == 0 && get_win32_errcode() != ERROR_INSUFFICIENT_BUFFER {
-        return Err(format!("EvtRender(EvtRenderEventValues) failed with code {}", get_win32_errcode()));
-    }
-    else if res == 0 {
-        let res = unsafe {
-            EvtRender(h_ctxuser.as_ptr(),
-                      h_event.as_ptr(),
-                      EvtRenderEventValues,
-                      buffer_len_req,
-                      buffer.as_mut_ptr() as *mut c_void,
-                      &mut buffer_len_req as *mut u32,
-                      &mut props_count as *mut u32)
-        };
-        if res == 0 {
-            return Err(format!("Rendering event values failed with code {}", get_win32_errcode()));
-        }
+}
+
+fn variant_json_value(variant: EvtVariant, datefmt: &DateConfig) -> serde_json::value::Value {
+    match variant {
+        EvtVariant::Null => serde_json::value::Value::Null,
+        #[cfg(windows)]
+        EvtVariant::Handle(_) => serde_json::value::Value::from("<handle>"),
+        EvtVariant::String(s) => serde_json::value::Value::from(s),
+        EvtVariant::UInt(i) => serde_json::value::Value::from(i),
+        EvtVariant::Int(i) => serde_json::value::Value::from(i),
+        EvtVariant::Single(f) => serde_json::value::Value::from(f),
+        EvtVariant::Double(f) => serde_json::value::Value::from(f),
+        EvtVariant::Boolean(b) => serde_json::value::Value::from(b),
+        EvtVariant::Binary(s) => serde_json::value::Value::from(bytes_as_hexstring(&s)),
+        EvtVariant::DateTime(d) => date_json_value(d, datefmt),
+        EvtVariant::Array(items) => serde_json::value::Value::Array(items.into_iter()
+            .map(|item| variant_json_value(item, datefmt)).collect()),
     }
+}
 
-    let mut event_def = &EventDefinition {
-        channel: None,
-        message: None,
-        level: 0,
-        level_name: None,
-        opcode: 0,
-        opcode_name: None,
-        keywords: 0,
-        keyword_names: vec![],
-        task: 0,
-        task_name: None,
-        fields: vec![],
-    };
-    if let Some(prov_meta) = render_cfg.metadata.get(&common_props.provider) {
+#[cfg(windows)]
+pub fn render_event_json(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let (buffer, props_count) = render_event_values(h_event)?;
+
+    let mut event_def = &EventDefinition::empty();
+    let no_parameters = BTreeMap::new();
+    let mut parameters = &no_parameters;
+    let prov_meta = render_cfg.provider_metadata(&common_props.provider, common_props.provider_guid.as_deref());
+    if let Some(prov_meta) = &prov_meta {
+        parameters = &prov_meta.parameters;
         if let Some(versions) = prov_meta.events.get(&common_props.eventid) {
             if let Some(known_event_def) = versions.get(&common_props.version) {
                 event_def = known_event_def;
             }
         }
     }
+    // Name values which are not in the definition from the event itself, instead of fieldN
+    let learned_def;
+    if render_cfg.infer_fields && event_def.fields.len() < props_count as usize {
+        learned_def = learned_event_definition(h_event, common_props, render_cfg, event_def,
+                                               buffer.as_ptr() as *const EVT_VARIANT, props_count)?;
+        event_def = &learned_def;
+    }
 
-    let mut event_json = serde_json::Map::new();
-    for column in &render_cfg.columns {
-        match column {
-            OutputColumn::Hostname => { event_json.insert("hostname".to_owned(),
-                      serde_json::value::Value::from(common_props.hostname.to_owned())); }
-            OutputColumn::RecordID => { event_json.insert("recordid".to_owned(),
-                      serde_json::value::Value::from(common_props.recordid)); }
-            OutputColumn::Timestamp => { event_json.insert("timestamp".to_owned(),
-                      serde_json::value::Value::from(format_utc_systemtime(&common_props.timestamp, &render_cfg.datefmt))); }
-            OutputColumn::Provider => { event_json.insert("provider".to_owned(),
-                  serde_json::value::Value::from(common_props.provider.to_owned())); }
-            OutputColumn::EventID => { event_json.insert("eventid".to_owned(),
-                  serde_json::value::Value::from(common_props.eventid)); }
-            OutputColumn::Version => { event_json.insert("version".to_owned(),
-                  serde_json::value::Value::from(common_props.version)); }
-            OutputColumn::Level => { event_json.insert("level".to_owned(),
-                  serde_json::value::Value::from(event_def.level)); }
-            OutputColumn::LevelName => { event_json.insert("level_name".to_owned(),
-                  match &event_def.level_name {
-                      Some(s) => serde_json::value::Value::from(s.to_owned()),
-                      None => serde_json::value::Value::Null,
-                  }); },
-            OutputColumn::Task => { event_json.insert("task".to_owned(),
-                  serde_json::value::Value::from(event_def.task)); }
-            OutputColumn::TaskName => { event_json.insert("task_name".to_owned(),
-                  match &event_def.task_name {
-                      Some(s) => serde_json::value::Value::from(s.to_owned()),
-                      None => serde_json::value::Value::Null,
-                  }); },
-            OutputColumn::Opcode => { event_json.insert("opcode".to_owned(),
-                  serde_json::value::Value::from(event_def.opcode)); }
-            OutputColumn::OpcodeName => { event_json.insert("opcode_name".to_owned(),
-                  match &event_def.opcode_name {
-                      Some(s) => serde_json::value::Value::from(s.to_owned()),
-                      None => serde_json::value::Value::Null,
-                  }); },
-            OutputColumn::Keywords => { event_json.insert("keywords".to_owned(),
-                  serde_json::value::Value::from(event_def.keywords)); }
-            OutputColumn::KeywordNames => { event_json.insert("keyword_names".to_owned(),
-                  serde_json::value::Value::from(&event_def.keyword_names[..])); }
-            OutputColumn::UnformattedMessage => {
-                if let Some(template) = &event_def.message {
-                    event_json.insert("message".to_owned(),serde_json::value::Value::from(template.to_owned()));
-                }
-            },
-            OutputColumn::FormattedMessage => {
-                if let Some(template) = &event_def.message {
-                    match crate::windows::format_event_message(&event_def, buffer.as_ptr() as *const EVT_VARIANT, props_count) {
-                        Ok(message) => {
-                            event_json.insert("message".to_owned(), serde_json::value::Value::from(message));
-                        },
-                        Err(e) => {
-                            warn!("Unable to format template \"{}\" of event {}/{}/{}: {}",
-                                  template, common_props.provider, common_props.eventid,
-                                  common_props.version, e);
-                            event_json.insert("message".to_owned(), serde_json::value::Value::from(template.to_owned()));
-                        },
-                    }
-                }
-            },
-            OutputColumn::EventSpecific(prop_num) => {
-                if *prop_num > props_count {
-                    // The referenced field number does not exist for this event,
-                    // there's no point in inserting an "fieldN": null or "fieldN": "" in JSON
-                    // Remember prop_num is 1-indexed
-                    break;
-                };
-                let mut field_def = & EventFieldDefinition {
-                    name: format!("field{}", prop_num),
-                    out_type: "xs:string".to_owned(),
-                };
-                if (*prop_num - 1) < event_def.fields.len() as u32 {
-                    field_def = &event_def.fields[(*prop_num - 1) as usize];
-                }
-
-                let buffer_offset = ((*prop_num - 1) as usize) * std::mem::size_of::<EVT_VARIANT>();
-                let prop : EVT_VARIANT = unsafe {
-                    std::ptr::read(buffer.as_ptr().add(buffer_offset) as *const _)
-                };
+    let classification = classify_event(common_props, event_def, prov_meta.as_deref());
+    let columns = EventColumns {
+        common_props,
+        render_cfg,
+        event_def,
+        parameters,
+        classification: &classification,
+        variants: buffer.as_ptr() as *const EVT_VARIANT,
+        variant_count: props_count,
+    };
 
-                let prop = unwrap_variant_contents(&prop, Some(&field_def.out_type))?;
-                let json_value = match prop {
-                    EvtVariant::Null => serde_json::value::Value::Null,
-                    EvtVariant::Handle(_) => serde_json::value::Value::from("<handle>"),
-                    EvtVariant::String(s) => serde_json::value::Value::from(s),
-                    EvtVariant::UInt(i) => serde_json::value::Value::from(i),
-                    EvtVariant::Int(i) => serde_json::value::Value::from(i),
-                    EvtVariant::Single(f) => serde_json::value::Value::from(f),
-                    EvtVariant::Double(f) => serde_json::value::Value::from(f),
-                    EvtVariant::Boolean(b) => serde_json::value::Value::from(b),
-                    EvtVariant::Binary(s) => serde_json::value::Value::from(bytes_as_hexstring(&s)),
-                    EvtVariant::DateTime(d) => serde_json::value::Value::from(
-                        format_utc_systemtime(&d, &render_cfg.datefmt)),
-                };
-                event_json.insert(field_def.name.to_owned(), json_value);
-            },
-        };
+    let mut event_json = serde_json::Map::new();
+    for spec in &render_cfg.columns {
+        // There's no point in inserting an "fieldN": null for fields this event doesn't have
+        if columns.is_absent(spec) {
+            continue;
+        }
+        event_json.insert(columns.key(spec), variant_json_value(columns.value(spec)?, &render_cfg.datefmt));
     }
 
     let json = if render_cfg.json_pretty {
@@ -174,13 +97,5 @@ pub fn render_event_json(h_event: &EvtHandle, common_props: &CommonEventProperti
         Ok(s) => s,
         Err(e) => return Err(format!("JSON serialization failed: {}", e)),
     };
-    match render_cfg.output_file.lock() {
-        Ok(mut f) => {
-            match f.write((json + "\n").as_bytes()) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(format!("Unable to write serialized JSON to file: {:?}", e)),
-            }
-        },
-        Err(e) => return Err(format!("Failed to acquire lock to output file: {}", e)),
-    }
+    Ok(EventOutput { header: None, body: json + "\n" })
 }
\ No newline at end of file
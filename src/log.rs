@@ -1,9 +1,71 @@
-use std::sync::atomic::AtomicU8;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8};
 use std::sync::atomic::Ordering::Relaxed;
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
 
-static LOG_LEVEL : AtomicU8 = AtomicU8::new(0);
+type ModuleLevels = Vec<(String, u8)>;
 
-pub const LOG_LEVEL_DEBUG: u8 = 2;
+// Logs are only ever written to stderr or to a log file, never to stdout where events may be written
+pub const LOG_LEVEL_WARN: u8 = 0;
+pub const LOG_LEVEL_INFO: u8 = 1;
+pub const LOG_LEVEL_VERBOSE: u8 = 2;
+pub const LOG_LEVEL_DEBUG: u8 = 3;
+
+static LOG_LEVEL : AtomicU8 = AtomicU8::new(LOG_LEVEL_INFO);
+// Levels of modules (e.g. "metacache") which differ from LOG_LEVEL, only looked up if there are some
+static HAS_MODULE_LEVELS : AtomicBool = AtomicBool::new(false);
+static MODULE_LEVELS : Mutex<ModuleLevels> = Mutex::new(Vec::new());
+static LOG_JSON : AtomicBool = AtomicBool::new(false);
+static LOG_FILE : Mutex<Option<File>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+fn level_name(level: u8) -> &'static str {
+    match level {
+        LOG_LEVEL_WARN => "warn",
+        LOG_LEVEL_INFO => "info",
+        LOG_LEVEL_VERBOSE => "verbose",
+        _ => "debug",
+    }
+}
+
+fn parse_level(s: &str) -> Result<u8, String> {
+    match s {
+        "warn" => Ok(LOG_LEVEL_WARN),
+        "info" => Ok(LOG_LEVEL_INFO),
+        "verbose" => Ok(LOG_LEVEL_VERBOSE),
+        "debug" => Ok(LOG_LEVEL_DEBUG),
+        _ => Err(format!("Invalid log level '{}', expected warn, info, verbose or debug", s)),
+    }
+}
+
+// Module paths are given without the crate name (e.g. "windows" for evtq::windows)
+fn module_name(module_path: &str) -> &str {
+    match module_path.find("::") {
+        Some(pos) => &module_path[pos + 2..],
+        None => "",
+    }
+}
 
 pub fn get_log_level() -> u8 {
     LOG_LEVEL.load(Relaxed)
@@ -13,15 +75,174 @@ pub fn set_log_level(level: u8) {
     LOG_LEVEL.store(level, Relaxed);
 }
 
+// Parses a comma-separated list of levels, either global (e.g. "verbose") or of a module and
+// its submodules (e.g. "metacache=debug"). Module levels are returned most specific first
+fn parse_log_levels(spec: &str) -> Result<(Option<u8>, ModuleLevels), String> {
+    let mut global_level = None;
+    let mut module_levels = vec![];
+    for item in spec.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+        match item.find('=') {
+            Some(pos) => {
+                let module = item[..pos].trim().trim_start_matches("evtq::");
+                module_levels.push((module.to_owned(), parse_level(item[pos + 1..].trim())?));
+            },
+            None => global_level = Some(parse_level(item)?),
+        }
+    }
+    module_levels.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+    Ok((global_level, module_levels))
+}
+
+pub fn set_log_levels(spec: &str) -> Result<(), String> {
+    let (global_level, levels) = parse_log_levels(spec)?;
+    let mut module_levels = match MODULE_LEVELS.lock() {
+        Ok(m) => m,
+        Err(e) => return Err(format!("Failed to acquire lock to log levels: {}", e)),
+    };
+    if let Some(level) = global_level {
+        set_log_level(level);
+    }
+    module_levels.extend(levels);
+    // The most specific module is looked up first
+    module_levels.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+    HAS_MODULE_LEVELS.store(!module_levels.is_empty(), Relaxed);
+    Ok(())
+}
+
+pub fn set_log_format(format: LogFormat) {
+    LOG_JSON.store(format == LogFormat::Json, Relaxed);
+}
+
+pub fn set_log_file(path: &str) -> Result<(), String> {
+    if path.eq("stdout") {
+        return Err(format!("Logs cannot be written to stdout, which is reserved for events"));
+    }
+    let file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Could not open log file {} : {}", path, e)),
+    };
+    match LOG_FILE.lock() {
+        Ok(mut log_file) => *log_file = Some(file),
+        Err(e) => return Err(format!("Failed to acquire lock to log file: {}", e)),
+    }
+    Ok(())
+}
+
+// Level of the given module (e.g. "windows::render"), from the level of the module or of its
+// closest parent: "meta" is a module of its own, not a prefix of "metacache"
+fn module_level(module_levels: &[(String, u8)], module: &str) -> Option<u8> {
+    module_levels.iter()
+        .find(|(prefix, _)| module == prefix ||
+            (module.starts_with(&prefix[..]) && module[prefix.len()..].starts_with("::")))
+        .map(|(_, level)| *level)
+}
+
+pub fn log_enabled(level: u8, module_path: &str) -> bool {
+    if HAS_MODULE_LEVELS.load(Relaxed) {
+        if let Ok(module_levels) = MODULE_LEVELS.lock() {
+            if let Some(module_level) = module_level(&module_levels, module_name(module_path)) {
+                return level <= module_level;
+            }
+        }
+    }
+    level <= get_log_level()
+}
+
+#[derive(Serialize)]
+struct LogRecord<'a> {
+    timestamp: String,
+    level: &'static str,
+    module: &'a str,
+    message: &'a str,
+}
+
+fn json_log_line(timestamp: &str, level: u8, module_path: &str, message: &str) -> Option<String> {
+    let record = LogRecord {
+        timestamp: timestamp.to_owned(),
+        level: level_name(level),
+        module: module_name(module_path),
+        message,
+    };
+    serde_json::to_string(&record).ok()
+}
+
+pub fn write_log(level: u8, module_path: &str, message: &str) {
+    let mut log_file = match LOG_FILE.lock() {
+        Ok(f) => f,
+        Err(_) => return,
+    };
+    let line = if LOG_JSON.load(Relaxed) {
+        match json_log_line(&Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true), level, module_path, message) {
+            Some(json) => json,
+            None => return,
+        }
+    } else {
+        let prefix = if level == LOG_LEVEL_WARN { " [!] " } else { " [.] " };
+        match log_file.as_ref() {
+            // Log files are read after the fact, when the time of each line matters
+            Some(_) => format!("{}{}{}", Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true), prefix, message),
+            None => format!("{}{}", prefix, message),
+        }
+    };
+    let _ = match log_file.as_mut() {
+        Some(f) => writeln!(f, "{}", line),
+        None => writeln!(std::io::stderr().lock(), "{}", line),
+    };
+}
+
 macro_rules! debug {
-    ( $( $args:expr ),* ) => { if crate::log::get_log_level() >= 2 { use std::io::Write; let _ = writeln!(std::io::stderr().lock(), " [.] {}", format!( $($args),* )); } }
+    ( $( $args:expr ),* ) => { if crate::log::log_enabled(crate::log::LOG_LEVEL_DEBUG, module_path!()) { crate::log::write_log(crate::log::LOG_LEVEL_DEBUG, module_path!(), &format!( $($args),* )); } }
 }
 macro_rules! verbose {
-    ( $( $args:expr ),* ) => { if crate::log::get_log_level() >= 1 { eprintln!(" [.] {}", format!( $($args),* )); } }
+    ( $( $args:expr ),* ) => { if crate::log::log_enabled(crate::log::LOG_LEVEL_VERBOSE, module_path!()) { crate::log::write_log(crate::log::LOG_LEVEL_VERBOSE, module_path!(), &format!( $($args),* )); } }
 }
 macro_rules! info {
-    ( $( $args:expr ),* ) => { eprintln!(" [.] {}", format!( $($args),* )); }
+    ( $( $args:expr ),* ) => { if crate::log::log_enabled(crate::log::LOG_LEVEL_INFO, module_path!()) { crate::log::write_log(crate::log::LOG_LEVEL_INFO, module_path!(), &format!( $($args),* )); } }
 }
 macro_rules! warn {
-    ( $( $args:expr ),* ) => { eprintln!(" [!] {}", format!( $($args),* )); }
+    ( $( $args:expr ),* ) => { crate::log::write_log(crate::log::LOG_LEVEL_WARN, module_path!(), &format!( $($args),* )) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_global_and_module_levels() {
+        assert_eq!(parse_log_levels("verbose"), Ok((Some(LOG_LEVEL_VERBOSE), vec![])));
+        assert_eq!(parse_log_levels(""), Ok((None, vec![])));
+        let (global_level, module_levels) = parse_log_levels(
+            " warn, meta=debug ,evtq::windows::render=verbose,windows=info").unwrap();
+        assert_eq!(global_level, Some(LOG_LEVEL_WARN));
+        assert_eq!(module_levels, vec![
+            ("windows::render".to_owned(), LOG_LEVEL_VERBOSE),
+            ("windows".to_owned(), LOG_LEVEL_INFO),
+            ("meta".to_owned(), LOG_LEVEL_DEBUG),
+        ]);
+        assert!(parse_log_levels("loud").is_err());
+        assert!(parse_log_levels("windows=").is_err());
+    }
+
+    #[test]
+    fn matches_modules_and_their_submodules() {
+        let (_, module_levels) = parse_log_levels("meta=debug,windows=info,windows::render=verbose").unwrap();
+        assert_eq!(module_level(&module_levels, "meta"), Some(LOG_LEVEL_DEBUG));
+        assert_eq!(module_level(&module_levels, "meta::system"), Some(LOG_LEVEL_DEBUG));
+        assert_eq!(module_level(&module_levels, "metacache"), None);
+        assert_eq!(module_level(&module_levels, "windows::render"), Some(LOG_LEVEL_VERBOSE));
+        assert_eq!(module_level(&module_levels, "windows::render::xml"), Some(LOG_LEVEL_VERBOSE));
+        assert_eq!(module_level(&module_levels, "windows::query"), Some(LOG_LEVEL_INFO));
+        assert_eq!(module_level(&module_levels, ""), None);
+        assert_eq!(module_name("evtq::metacache"), "metacache");
+        assert_eq!(module_name("evtq"), "");
+    }
+
+    #[test]
+    fn writes_json_log_lines() {
+        let line = json_log_line("2021-03-04T05:06:07.123Z", LOG_LEVEL_WARN, "evtq::metacache", "Cache \"x\" is stale").unwrap();
+        assert_eq!(line, r#"{"timestamp":"2021-03-04T05:06:07.123Z","level":"warn","module":"metacache","message":"Cache \"x\" is stale"}"#);
+        let value: serde_json::Value = serde_json::from_str(&json_log_line("t", LOG_LEVEL_DEBUG, "evtq", "m").unwrap()).unwrap();
+        assert_eq!(value["level"], "debug");
+        assert_eq!(value["module"], "");
+    }
 }
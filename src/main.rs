@@ -1,51 +1,185 @@
 // Bindings auto-generated and imported from WinAPI are not expected
 // to follow Rust's naming convention
 #![allow(non_upper_case_globals)]
+// Only metadata can be extracted on other platforms, so most of the rendering code is unused there
+#![cfg_attr(not(windows), allow(dead_code))]
+// Error messages are all built with format!(), even the ones without arguments
+#![allow(clippy::useless_format)]
 
 extern crate clap;
+#[cfg(windows)]
 extern crate winapi;
 extern crate serde;
-use clap::{Arg, App, ArgGroup};
+use clap::{Arg, App};
 use std::result::Result;
 use std::io;
-use std::time::Instant;
 use std::collections::BTreeMap;
 use std::vec::Vec;
 use std::fs::OpenOptions;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::str::FromStr;
+use std::ffi::OsString;
 
 use crate::log::*;
-use crate::windows::{EvtHandle, RpcCredentials};
-use crate::xml::render_event_xml;
-use crate::json::render_event_json;
-use crate::formatting::CommonEventProperties;
 use crate::metadata::*;
-use crate::csv::render_event_csv;
-use crate::output_cols::{OutputColumn, parse_column_names};
+use crate::metacache::{MetadataCache, is_metadata_cache, export_metadata_to_cache};
+use crate::offline::{import_metadata_from_pe_files, import_metadata_from_hives, find_image_hives};
+use crate::config::{config_arguments, preset_arguments, preset_descriptions, OptionOrigins};
+
+#[cfg(windows)]
+use std::time::Instant;
+#[cfg(windows)]
+use std::collections::HashMap;
+#[cfg(windows)]
+use std::sync::Mutex;
+#[cfg(windows)]
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+#[cfg(windows)]
+use crate::windows::{EvtHandle, RpcCredentials, lookup_account_sid};
+#[cfg(windows)]
+use crate::xml::{render_event_xml, XmlDocument};
+#[cfg(windows)]
+use crate::json::render_event_json;
+#[cfg(windows)]
+use crate::formatting::{CommonEventProperties, DateConfig, DateFormat, OutputTimeZone, current_filetime};
+#[cfg(windows)]
+use crate::evtx::{render_event_evtx, EvtxWriter};
+#[cfg(windows)]
+use crate::output::OutputSink;
+#[cfg(windows)]
+use crate::csv::{render_event_csv, write_csv_header, parse_csv_char, CsvConfig, CsvEscaping, CsvHeader};
+#[cfg(windows)]
+use crate::output_cols::{ColumnSpec, parse_column_names, columns_reference_field_names};
+#[cfg(windows)]
 use crate::filtering::xml_query_from_filters;
+#[cfg(windows)]
+use crate::metacache::ProviderMetadataRef;
+#[cfg(windows)]
+use crate::coverage::{Coverage, render_event_coverage, write_coverage_report, coverage_skeletons};
+#[cfg(windows)]
+use crate::summary::{RunSummary, summary_total, write_summary_report, export_summary_to_file};
+#[cfg(windows)]
+use crate::shutdown::{stop_on_interrupt, stop_requested};
+#[cfg(windows)]
+use crate::sessions::{SessionTracker, SESSION_EVENT_FILTERS, render_event_sessions, expire_sessions, finish_sessions};
+#[cfg(windows)]
+use crate::proctree::{ProcessTrees, ProcessTreeFormat, PROCESS_EVENT_FILTERS, render_event_process_tree, expire_process_trees, finish_process_trees};
+#[cfg(windows)]
+use crate::scripts::{ScriptBlocks, SCRIPT_BLOCK_FILTERS, ENCODED_COMMAND_FILTERS, render_event_script_blocks, finish_script_blocks};
 
 #[macro_use]
 mod log;
-mod windows;
-mod xml;
-mod json;
-mod csv;
 mod metadata;
+mod winmeta;
+mod msgtable;
+mod msgformat;
+mod pe;
+mod wevt;
+mod offline;
+mod regf;
+mod metacache;
+mod config;
 mod output_cols;
+mod csv;
+mod output;
+mod pipeline;
+mod summary;
+mod xml;
+mod evtx;
+mod coverage;
+mod inference;
+mod sessions;
+mod errors;
 mod formatting;
+mod json;
+mod proctree;
+mod scripts;
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
 mod filtering;
+#[cfg(windows)]
+mod shutdown;
+
+// What rendering an event produces, written to the output file in one go
+#[derive(Default)]
+pub struct EventOutput {
+    // Written before the event if it differs from the last one written (e.g. CSV field names)
+    pub header: Option<String>,
+    pub body: String,
+}
 
+#[cfg(windows)]
 pub struct RenderingConfig {
-    render_callback: fn(&EvtHandle, &CommonEventProperties, &RenderingConfig) -> Result<(), String>,
-    output_file: Box<Mutex<dyn std::io::Write>>,
-    datefmt: String,
+    render_callback: fn(&EvtHandle, &CommonEventProperties, &RenderingConfig) -> Result<EventOutput, String>,
+    output: OutputSink,
+    // Workers rendering events from backups, and whether their output must keep the order of events
+    threads: usize,
+    ordered: bool,
+    datefmt: DateConfig,
     metadata: Metadata,
-    field_separator: char,
+    // Binary metadata files, only decoded for providers which are not in metadata
+    metadata_caches: Vec<MetadataCache>,
+    csv: CsvConfig,
     json_pretty: bool,
-    columns: Vec<OutputColumn>,
+    // Wrap XML outputs in an <Events> root, with a <RenderingInfo> in each event
+    xml_document: bool,
+    xml_canonical: bool,
+    expand_parameters: bool,
+    // Name fields of events without a (complete) definition using their XML rendering
+    infer_fields: bool,
+    learned_metadata: Mutex<Metadata>,
+    columns: Vec<ColumnSpec>,
+    // Account names of SIDs, resolved once by lookup() columns
+    account_names: Mutex<HashMap<String, Option<String>>>,
     rendering_start: Instant,
     event_counter: AtomicU64,
+    coverage: Mutex<Coverage>,
+    // Events read, rendered, filtered and failed per Channel/Provider, reported at exit
+    summary: Mutex<RunSummary>,
+    // Backup file or host events are read from, and where events which failed are recorded
+    source: String,
+    errors: Option<OutputSink>,
+    failed_events: AtomicU64,
+    // Events which may fail before reading stops and an error is returned
+    max_failed_events: Option<u64>,
+    // Logon sessions correlated from Security events, written instead of the events themselves
+    sessions: Option<Mutex<SessionTracker>>,
+    // Process creations and exits, written as trees once all events are read
+    process_trees: Option<Mutex<ProcessTrees>>,
+    // PowerShell script blocks split over several events, written once all of their parts are read
+    script_blocks: Option<Mutex<ScriptBlocks>>,
+    evtx_writer: Option<Mutex<EvtxWriter>>,
+}
+
+#[cfg(windows)]
+impl RenderingConfig {
+    // Providers extracted offline without their registration are keyed by {GUID}
+    pub fn provider_metadata(&self, provider_name: &str, provider_guid: Option<&str>) -> Option<ProviderMetadataRef<'_>> {
+        let guid_key = provider_guid.map(|guid| format!("{{{}}}", guid.trim_matches(&['{', '}'][..]).to_uppercase()));
+        for key in std::iter::once(provider_name).chain(guid_key.as_deref()) {
+            if let Some(prov_meta) = self.metadata.get(key) {
+                return Some(ProviderMetadataRef::Loaded(prov_meta));
+            }
+            if let Some(prov_meta) = self.metadata_caches.iter().find_map(|cache| cache.get(key)) {
+                return Some(ProviderMetadataRef::Cached(prov_meta));
+            }
+        }
+        None
+    }
+
+    pub fn account_name(&self, sid: &str) -> Option<String> {
+        let mut account_names = match self.account_names.lock() {
+            Ok(a) => a,
+            Err(_) => return None,
+        };
+        account_names.entry(sid.to_owned()).or_insert_with(|| match lookup_account_sid(sid) {
+            Ok(name) => Some(name),
+            Err(e) => {
+                verbose!("Unable to resolve {}: {}", sid, e);
+                None
+            },
+        }).clone()
+    }
 }
 
 fn main() {
@@ -55,8 +189,204 @@ fn main() {
     });
 }
 
-pub fn run() -> Result<(), String> {
-    let args = App::new("evtq")
+// Reads a JSON metadata export, and records it as the source of definitions which don't have one
+fn read_metadata_file(in_path: &str) -> Result<Metadata, String> {
+    let mut in_file = match OpenOptions::new().read(true).open(in_path) {
+        Err(e) => return Err(format!("Could not open file {} : {}", in_path, e)),
+        Ok(f) => f,
+    };
+    let mut metadata = import_metadata_from_file(&mut in_file)?;
+    // Exports from older versions don't say where their definitions come from
+    set_metadata_source(&mut metadata, &MetadataSource {
+        os_build: None,
+        host: None,
+        file: Some(in_path.to_owned()),
+    });
+    Ok(metadata)
+}
+
+// Converts a JSON metadata export to an indexed binary file, or back
+fn convert_metadata(args: &clap::ArgMatches, json_pretty: bool) -> Result<(), String> {
+    let paths: Vec<&str> = args.values_of("convert-metadata").unwrap().collect();
+    let (in_path, out_path) = (paths[0], paths[1]);
+    let mut out_file = match OpenOptions::new().write(true).create(true).truncate(true).open(out_path) {
+        Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
+        Ok(f) => std::io::BufWriter::new(f),
+    };
+    if is_metadata_cache(in_path)? {
+        let metadata = MetadataCache::open(in_path)?.load_all()?;
+        info!("Converting metadata of {} providers to JSON", metadata.len());
+        return export_metadata_to_file(&metadata, &mut out_file, json_pretty);
+    }
+    let metadata = read_metadata_file(in_path)?;
+    info!("Converting metadata of {} providers to binary", metadata.len());
+    export_metadata_to_cache(&metadata, &mut out_file)
+}
+
+fn diff_metadata_files(args: &clap::ArgMatches) -> Result<(), String> {
+    let paths: Vec<&str> = args.values_of("metadata-diff").unwrap().collect();
+    let mut exports = Vec::new();
+    for in_path in &paths {
+        if is_metadata_cache(in_path)? {
+            exports.push(MetadataCache::open(in_path)?.load_all()?);
+        } else {
+            exports.push(read_metadata_file(in_path)?);
+        }
+    }
+    for line in diff_metadata(&exports[0], &exports[1]) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+// Imports metadata from exports, provider files and hives, which doesn't require any Windows API
+fn import_offline_metadata(args: &clap::ArgMatches,
+                           metadata: &mut Metadata,
+                           metadata_caches: &mut Vec<MetadataCache>,
+                           merge_policy: MergePolicy) -> Result<(), String> {
+    if let Some(in_paths) = args.values_of("import-metadata") {
+        for in_path in in_paths {
+            if is_metadata_cache(in_path)? {
+                metadata_caches.push(MetadataCache::open(in_path)?);
+                continue;
+            }
+            let imported_field_defs = read_metadata_file(in_path)?;
+            update_metadata_with(metadata, &imported_field_defs, merge_policy);
+        }
+    }
+
+    if args.occurrences_of("extract-metadata") > 0 {
+        let paths: Vec<&str> = args.values_of("extract-metadata").unwrap().collect();
+        let software_hive = args.value_of("software-hive").map(std::path::Path::new);
+        let extracted_metadata = import_metadata_from_pe_files(&paths, software_hive)?;
+        update_metadata_with(metadata, &extracted_metadata, merge_policy);
+    }
+
+    if let Some(image_root) = args.value_of("image-root") {
+        let image_root = std::path::Path::new(image_root);
+        let (found_software, found_system) = find_image_hives(image_root);
+        let software_hive = match (args.value_of("software-hive"), found_software) {
+            (Some(path), _) => std::path::PathBuf::from(path),
+            (None, Some(path)) => path,
+            (None, None) => return Err(format!("No SOFTWARE hive found in {}, use --software-hive", image_root.display())),
+        };
+        let system_hive = match (args.value_of("system-hive"), found_system) {
+            (Some(path), _) => Some(std::path::PathBuf::from(path)),
+            (None, found) => found,
+        };
+        if system_hive.is_none() {
+            warn!("No SYSTEM hive found in {}, classic event sources will be missing", image_root.display());
+        }
+        let hive_metadata = import_metadata_from_hives(image_root, &software_hive, system_hive.as_deref())?;
+        update_metadata_with(metadata, &hive_metadata, merge_policy);
+    }
+    Ok(())
+}
+
+fn export_metadata(args: &clap::ArgMatches,
+                   metadata: &mut Metadata,
+                   metadata_caches: &[MetadataCache],
+                   append: bool,
+                   json_pretty: bool) -> Result<(), String> {
+    let out_path = args.value_of("export-metadata").unwrap();
+    let mut out_file : Box<dyn std::io::Write + Send> = if out_path.eq("stdout") {
+        Box::from(io::stdout())
+    } else {
+        match OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(out_path) {
+            Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
+            Ok(f) => Box::from(f),
+        }
+    };
+    // Binary metadata files have a lower priority than other sources, like when rendering
+    for cache in metadata_caches {
+        update_metadata_with(metadata, &cache.load_all()?, MergePolicy::Fill);
+    }
+    export_metadata_to_file(metadata, &mut out_file, json_pretty)
+}
+
+// Logs totals of the run, and reports them per Channel/Provider if requested
+#[cfg(windows)]
+fn write_run_summary(args: &clap::ArgMatches, render_cfg: &RenderingConfig) -> Result<(), String> {
+    let elapsed = Instant::now().duration_since(render_cfg.rendering_start);
+    let summary = match render_cfg.summary.lock() {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Failed to acquire lock to run summary: {}", e)),
+    };
+    let total = summary_total(&summary);
+    info!("{}{} events read, {} rendered, {} filtered, {} failed in {:.2}s ({:.2}/s)",
+          if stop_requested() { "Stopped early: " } else { "" },
+          total.read, total.rendered, total.filtered, total.failed, elapsed.as_secs_f64(),
+          (total.rendered as f64)/elapsed.as_secs_f64());
+    if args.occurrences_of("summary") == 1 {
+        let out_path = args.value_of("summary").unwrap();
+        if out_path.eq("stderr") {
+            write_summary_report(&summary, &mut io::stderr())?;
+        } else {
+            let mut out_file = match OpenOptions::new().write(true).create(true).truncate(true).open(out_path) {
+                Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
+                Ok(f) => f,
+            };
+            write_summary_report(&summary, &mut out_file)?;
+        }
+    }
+    if let Some(out_path) = args.value_of("summary-json") {
+        let mut out_file = match OpenOptions::new().write(true).create(true).truncate(true).open(out_path) {
+            Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
+            Ok(f) => f,
+        };
+        export_summary_to_file(&summary, elapsed, stop_requested(), &mut out_file)?;
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn csv_config_from_args(args: &clap::ArgMatches, origins: &OptionOrigins, default_delimiter: char, default_escaping: CsvEscaping) -> Result<CsvConfig, String> {
+    let delimiter = match args.value_of("csv-delimiter") {
+        Some(d) => parse_csv_char(&origins.name("csv-delimiter"), d)?,
+        None => default_delimiter,
+    };
+    let quote = parse_csv_char(&origins.name("csv-quote"), args.value_of("csv-quote").unwrap())?;
+    let line_terminator = match args.value_of("csv-line-terminator").unwrap().to_lowercase().as_str() {
+        "lf" => "\n",
+        "crlf" => "\r\n",
+        other => return origins.check("csv-line-terminator", Err(format!("Unknown line terminator '{}' (expected lf or crlf)", other))),
+    };
+    let escaping = match args.value_of("csv-escaping") {
+        Some(e) => origins.check("csv-escaping", CsvEscaping::from_str(e))?,
+        None => default_escaping,
+    };
+    let header = if args.occurrences_of("csv-header") > 0 {
+        Some(origins.check("csv-header", CsvHeader::from_str(args.value_of("csv-header").unwrap()))?)
+    } else {
+        None
+    };
+    if delimiter == quote && escaping == CsvEscaping::Quote {
+        return Err(format!("CSV delimiter and quote cannot both be '{}'", delimiter));
+    }
+    Ok(CsvConfig::new(delimiter, quote, line_terminator, escaping, header))
+}
+
+// Files appended to already start with a header row
+#[cfg(windows)]
+fn write_csv_header_unless_appended(render_cfg: &RenderingConfig, out_path: &str, append: bool) -> Result<(), String> {
+    if render_cfg.csv.header != Some(CsvHeader::Columns) {
+        return Ok(());
+    }
+    if append && !out_path.eq("stdout") {
+        if let Ok(meta) = std::fs::metadata(out_path) {
+            if meta.len() > 0 {
+                return Ok(());
+            }
+        }
+    }
+    write_csv_header(render_cfg)
+}
+
+// Parses options from the command line, the configuration file and the preset, and sets up
+// logging. Returns None if there is nothing else to do (e.g. presets were listed)
+// Also returns where options come from when they were not given on the command line
+fn parse_arguments() -> Result<Option<(clap::ArgMatches<'static>, OptionOrigins)>, String> {
+    let app = App::new("evtq")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about("Windows EventLog fetcher, parser, filter and formatter")
@@ -68,9 +398,14 @@ INPUT:
     --from-host [URI, default is localhost]  Read events as they happen on a live host via RPC
                                              URI format: domain/username:password@hostname
     --from-backup <filename.evt(x)> Read events from a backup .evtx or .evt
+    --threads <count>               Render events from backups with this many workers (default: 1)
+    --unordered                     With --threads, write events as soon as they are rendered instead of in
+                                    the order of the backup (--to-evtx always uses a single worker)
     --dump-existing                 Also process existing (past) events from the queried host
     --no-wait                       Don't wait for future events to arrive from the queried host
     --list-channels                 Don't dump events, just list available channels from the host
+      Ctrl-C stops reading events, writes the ones already read and closes outputs (press it twice to
+      exit immediately)
 
 FILTERING:
  -i --include <filter>              Only render events matching this filter (default: */*/*/*)
@@ -83,13 +418,86 @@ OUTPUT:
     --to-xml  [output.xml]          Render events as lines of raw XML (default: stdout)
     --to-csv  [output.csv]          Render events as lines of comma-separated columns (default: stdout)
     --to-tsv  [output.tsv]          Render events as lines of tab-separated columns (default: stdout)
+    --to-evtx <output.evtx>         Write events to a new .evtx file which can be opened in Event Viewer
+    --evtx-renumber                 With --to-evtx, number records from 1 instead of keeping their record IDs
     --json-pretty                   Add spaces and line feeds to JSON outputs
+    --xml-document                  Render XML outputs as a single well-formed document, with events in an
+                                    <Events> root, each with a <RenderingInfo> (message, level, task, opcode,
+                                    keyword names) and names of unnamed fields, like EvtFormatMessageXml
+    --xml-canonical                 Same as --xml-document, without whitespace between elements, with sorted
+                                    attributes, explicit end tags and only the namespace declarations needed
+    --csv-header [columns|fields]   Start CSV/TSV outputs with a header row: column names and aliases as given
+                                    in -O (default), or field names of events, repeated when they change
+    --csv-delimiter <char|tab>      Column separator in CSV/TSV outputs (default: comma, or tab for TSV)
+    --csv-quote <char>              Quote character in CSV/TSV outputs (default: ")
+    --csv-line-terminator <lf|crlf> End of line in CSV/TSV outputs (default: lf)
+    --csv-escaping <quote|backslash|replace>
+                                    How delimiters, quotes and line breaks in values are written: quoted and
+                                    doubled quotes as in RFC 4180 (default for CSV), backslash escapes like
+                                    \t and \n (default for TSV), or replaced with spaces
+    --coverage [report.txt]         Don't render events, report how many of them had a matching definition
+                                    in metadata, used generic fieldN names, or failed message formatting,
+                                    per Provider/EventID/Version (default: stdout)
+    --export-skeletons <file.json>  With --coverage, export definitions of events which had none, to be
+                                    completed and imported with --import-metadata
+    --process-tree [trees.json]     Don't render events, rebuild process trees per host and boot from process
+                                    creations and exits (4688, 4689, and Sysmon 1 and 5 when present), written
+                                    once all their processes exited, after a day without any of their events,
+                                    or once all events are read (default: stdout, default filter: these events
+                                    and boot events 4608 and Kernel-General 12)
+    --tree-format <json|text|dot>   Write process trees as JSON objects, one per root process (default), as
+                                    indented text, or as a Graphviz DOT graph
+    --tree-root <image|pid>         Only write subtrees rooted at processes with this image (full path or file
+                                    name, * as a wildcard, e.g. winword.exe) or PID (can be repeated)
+    --script-blocks [scripts.json]  Don't render events, reassemble PowerShell scripts logged in several 4104
+                                    events, written as JSON once all of their parts are read (or at exit, marked
+                                    incomplete and without hashes) with their path, first/last timestamps,
+                                    SHA-1 and SHA-256
+                                    (default: stdout, default filter: 4104 of Windows PowerShell and PowerShell 6+)
+    --scripts-dir <dir>             With --script-blocks, write each script to a .ps1 file in this directory,
+                                    instead of in its JSON record
+    --decode-commands               With --script-blocks, also decode base64 -EncodedCommand arguments found in
+                                    scripts, and in command lines of 4688 and 4103 events (also read by default)
+    --summary [report.txt]          At exit, report how many events were read, rendered, filtered (e.g.
+                                    forwarding bookmarks) and failed, per channel and provider (default: stderr)
+    --summary-json <summary.json>   At exit, write the same counts, elapsed time and throughput as JSON
+    --errors-to <errors.jsonl>      Record events which failed (or were written without a formatted message or
+                                    with values which could not be read), one JSON object per line with their
+                                    source, channel, provider, record ID, stage (subscription,
+                                    common_properties, rendering, variant_unwrap, message_format, write),
+                                    error message, and XML when it can still be rendered
+    --fail-on-error [count]         Stop reading events and exit with an error once more than this many events
+                                    failed at any stage, each counted once (default: 0)
+    --sessions                      Don't render events, correlate logons, logoffs, special privileges and
+                                    explicit credentials (4624, 4634, 4647, 4672, 4648) into logon sessions,
+                                    written when they end: user, domain, logon type, source IP/workstation,
+                                    elevation, start, end, duration and related events, as JSON (default) or
+                                    with --to-csv/--to-tsv (default filter: these events, in Security)
+    --session-timeout <seconds>     Consider sessions without any event for this long ended, e.g. when their
+                                    logoff was not logged (default: 86400)
  -a --append                        Don't overwrite output files if they exist
 
 COMMON:
  -h --help                          Display this help text
  -V --version                       Display the current version
+    --config <evtq.toml>            Read options from a TOML file, with long option names as keys (e.g.
+                                    include = ["Security/*/4624"], to-csv = "out.csv", json-pretty = true,
+                                    verbose = 2) and ${NAME} references to environment variables in strings.
+                                    Options given on the command line override the ones of the file
+    --profile <name>                Also read the options of [profiles.<name>] in the --config file, which
+                                    override the ones at its top level
+    --preset <name>                 Use the filters and columns of a built-in preset: logons, process-creation,
+                                    service-installs, scheduled-tasks, powershell, kerberos, account-changes,
+                                    log-clearing (other options, e.g. -O or -i, override the preset's ones)
+    --list-presets                  List built-in presets (with their options, with -v)
  -v --verbose                       Increase verbosity (can be repeated for extra information)
+ -q --quiet                         Decrease verbosity (only log warnings)
+    --log-level <levels>            Comma-separated log levels (warn, info, verbose, debug), global or per
+                                    module, overriding -v and -q (e.g. info,metacache=debug,windows=warn)
+    --log-format <text|json>        Log lines as text (default), or as JSON objects with a timestamp, level,
+                                    module and message
+    --log-file <file.log>           Append logs to this file instead of stderr, with timestamps (logs are
+                                    never written to stdout, where events can be)
  -O --columns                       Comma-separated list of columns to output in JSON, CSV, or TSV
       (default: hostname,recordid,timestamp,provider,eventid,version,level_name,task_name,
                 keyword_names,formatted_message,variant1,...,variant15)
@@ -97,12 +505,47 @@ COMMON:
                         recordid           eventid               task          task_name
                         timestamp          version               opcode        opcode_name
                         formatted_message  unformatted_message   keywords      keyword_names
+                        channel            provider_guid         qualifiers    user_id
+                        process_id         thread_id             activity_id   related_activity_id
                         variant1           variant2              variant3  ..  variant15
+      (event fields can also be referenced by name, e.g. TargetUserName)
+      (columns can be renamed with key=column, e.g. user=TargetUserName)
+      (items of array values with name[N], members of JSON objects in string values with name.member,
+       which are null when the value is not a string holding a JSON object)
+      (literals: 'text' or 123, and computed columns: date(timestamp), lower(x), upper(x),
+       coalesce(x,y,...), lookup(sid) to resolve a SID to an account name of this host)
     --no-system-metadata            Don't load field names, types, and message strings from the live OS
     --export-metadata <meta.json>   Export metadata to file
-    --import-metadata <meta.json>   Import and use metadata from file
-    --datefmt                       Change the default format for all date-times
+    --import-metadata <meta.json>   Import and use metadata from file (can be repeated to merge several files)
+    --extract-metadata <file.dll>   Extract metadata from provider DLLs (e.g. from a disk image), without
+                                    any Windows API (can be repeated, usually used with --export-metadata)
+    --image-root <dir>              Extract metadata of all providers registered in the SOFTWARE and SYSTEM
+                                    hives of a mounted Windows image, from the provider files it contains
+    --software-hive <file>          Use this SOFTWARE hive instead of the one found in --image-root (with
+                                    --extract-metadata, to name providers, which are keyed by GUID otherwise)
+    --system-hive <file>            Use this SYSTEM hive instead of the one found in --image-root
+    --merge-policy <policy>         How to merge definitions of the same event from several sources:
+                                      fill     keep known values, only fill blanks
+                                      keep     never modify known definitions
+                                      replace  replace known values by the ones imported last (default)
+    --metadata-diff <old.json> <new.json>  List differences between two metadata exports
+    --convert-metadata <in> <out>   Convert a JSON metadata export to an indexed binary file, which is much
+                                    faster to import (providers are only decoded when used), or back to JSON
+    --infer-fields                  Name fields of events without metadata after the names found in their XML
+                                    (<Data Name="..."> or UserData elements), instead of field1..fieldN
+    --learn-fields <overlay.json>   Infer field names like --infer-fields and save them to this metadata file
+                                    (and reuse them in later runs, so that events keep consistent names)
+    --expand-parameters             Replace %%N references in event fields with their parameter string
+    --datefmt                       Change the default format for all date-times, either a strftime format
+                                    (e.g. %Y-%m-%d %H:%M:%S%.9f, %.7f is not supported, use rfc3339) or one of:
+                                      rfc3339   2006-01-02T15:04:05.1234567Z
+                                      iso8601   2006-01-02T15:04:05.1234567+00:00
+                                      epoch     seconds since 1970-01-01 UTC
+                                      epoch_ms  milliseconds since 1970-01-01 UTC
+                                      filetime  100ns intervals since 1601-01-01 UTC, as stored in logs
         (default: %Y-%m-%dT%H:%M:%S%.3f%z)
+    --timezone <name>               Render date-times in this time zone: UTC (default), local, or an IANA
+                                    time zone name (e.g. Europe/Paris)
 
 EXAMPLES:
 
@@ -120,11 +563,61 @@ EXAMPLES:
 
 # Dump events as they happen on localhost, in CSV format, removing columns you don't use
     .\evtq.exe --to-csv .\all.csv -O timestamp,provider,eventid,version,variant1,...,variant15
+
+# List failed logons with a few named fields, renamed, as CSV with a header row
+    .\evtq.exe --from-backup .\security.evtx -i Security/*/4625 -O "timestamp,user=lower(TargetUserName),domain=coalesce(TargetDomainName,'-'),ip=IpAddress" --to-csv .\failed.csv --csv-header
+
+# Export successful and failed logons from a backup to a new .evtx file
+    .\evtq.exe --from-backup .\security.evtx -i Security/*/4624 -i Security/*/4625 --to-evtx .\logons.evtx
+
+# List processes created, with their command line, from a backup, as CSV
+    .\evtq.exe --from-backup .\security.evtx --preset process-creation --to-csv .\processes.csv --csv-header
+
+# List logon sessions found in a backed-up Security eventlog, as CSV with a header row
+    .\evtq.exe --from-backup .\security.evtx --sessions --to-csv .\sessions.csv --csv-header
+
+# Show the processes started by Word in a backup, as an indented tree
+    .\evtq.exe --from-backup .\security.evtx --process-tree --tree-format text --tree-root winword.exe
+
+# Reassemble PowerShell scripts from a backup to .ps1 files, with their hashes and decoded commands
+    .\evtq.exe --from-backup .\powershell.evtx --script-blocks .\scripts.json --scripts-dir .\scripts --decode-commands
+
+# Run the "logons" profile of a configuration file on a backup, writing to another file than configured
+    .\evtq.exe --config .\evtq.toml --profile logons --from-backup .\security.evtx --to-csv .\today.csv
         "#)
         .arg(Arg::with_name("verbosity")
             .short("v")
             .long("verbose")
             .multiple(true))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .multiple(true))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .value_name("levels")
+            .takes_value(true))
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .default_value("text"))
+        .arg(Arg::with_name("log-file")
+            .long("log-file")
+            .value_name("file.log")
+            .takes_value(true))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("evtq.toml")
+            .takes_value(true))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .value_name("name")
+            .takes_value(true))
+        .arg(Arg::with_name("preset")
+            .long("preset")
+            .value_name("name")
+            .takes_value(true))
+        .arg(Arg::with_name("list-presets")
+            .long("list-presets"))
         .arg(Arg::with_name("version")
             .short("V")
             .long("version"))
@@ -135,13 +628,52 @@ EXAMPLES:
             .help(""))
         .arg(Arg::with_name("import-metadata")
             .long("import-metadata")
-            .value_name("meta.json"))
+            .value_name("meta.json")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("merge-policy")
+            .long("merge-policy")
+            .value_name("policy")
+            .default_value("replace"))
+        .arg(Arg::with_name("convert-metadata")
+            .long("convert-metadata")
+            .value_names(&["in", "out"])
+            .number_of_values(2))
+        .arg(Arg::with_name("metadata-diff")
+            .long("metadata-diff")
+            .value_names(&["old.json", "new.json"])
+            .number_of_values(2))
+        .arg(Arg::with_name("extract-metadata")
+            .long("extract-metadata")
+            .value_name("file.dll")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("image-root")
+            .long("image-root")
+            .value_name("dir")
+            .takes_value(true))
+        .arg(Arg::with_name("software-hive")
+            .long("software-hive")
+            .value_name("file")
+            .takes_value(true))
+        .arg(Arg::with_name("system-hive")
+            .long("system-hive")
+            .value_name("file")
+            .takes_value(true)
+            .requires("image-root"))
         .arg(Arg::with_name("from-host")
             .long("from-host")
             .default_value("localhost"))
         .arg(Arg::with_name("from-backup")
             .long("from-backup")
             .takes_value(true))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .takes_value(true))
+        .arg(Arg::with_name("unordered")
+            .long("unordered"))
         .arg(Arg::with_name("to-json")
             .long("to-json")
             .default_value("stdout"))
@@ -154,6 +686,75 @@ EXAMPLES:
         .arg(Arg::with_name("to-tsv")
             .long("to-tsv")
             .default_value("stdout"))
+        .arg(Arg::with_name("csv-header")
+            .long("csv-header")
+            .default_value("columns"))
+        .arg(Arg::with_name("csv-delimiter")
+            .long("csv-delimiter")
+            .takes_value(true))
+        .arg(Arg::with_name("csv-quote")
+            .long("csv-quote")
+            .default_value("\""))
+        .arg(Arg::with_name("csv-line-terminator")
+            .long("csv-line-terminator")
+            .default_value("lf"))
+        .arg(Arg::with_name("csv-escaping")
+            .long("csv-escaping")
+            .takes_value(true))
+        .arg(Arg::with_name("to-evtx")
+            .long("to-evtx")
+            .value_name("output.evtx")
+            .takes_value(true))
+        .arg(Arg::with_name("evtx-renumber")
+            .long("evtx-renumber"))
+        .arg(Arg::with_name("coverage")
+            .long("coverage")
+            .default_value("stdout"))
+        .arg(Arg::with_name("export-skeletons")
+            .long("export-skeletons")
+            .value_name("file.json")
+            .takes_value(true))
+        .arg(Arg::with_name("process-tree")
+            .long("process-tree")
+            .default_value("stdout"))
+        .arg(Arg::with_name("tree-format")
+            .long("tree-format")
+            .default_value("json"))
+        .arg(Arg::with_name("tree-root")
+            .long("tree-root")
+            .value_name("image|pid")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("script-blocks")
+            .long("script-blocks")
+            .default_value("stdout"))
+        .arg(Arg::with_name("scripts-dir")
+            .long("scripts-dir")
+            .value_name("dir")
+            .takes_value(true))
+        .arg(Arg::with_name("decode-commands")
+            .long("decode-commands"))
+        .arg(Arg::with_name("summary")
+            .long("summary")
+            .default_value("stderr"))
+        .arg(Arg::with_name("summary-json")
+            .long("summary-json")
+            .value_name("summary.json")
+            .takes_value(true))
+        .arg(Arg::with_name("errors-to")
+            .long("errors-to")
+            .value_name("errors.jsonl")
+            .takes_value(true))
+        .arg(Arg::with_name("fail-on-error")
+            .long("fail-on-error")
+            .default_value("0"))
+        .arg(Arg::with_name("sessions")
+            .long("sessions"))
+        .arg(Arg::with_name("session-timeout")
+            .long("session-timeout")
+            .value_name("seconds")
+            .default_value("86400"))
         .arg(Arg::with_name("append")
             .long("append")
             .short("a"))
@@ -164,10 +765,26 @@ EXAMPLES:
         .arg(Arg::with_name("datefmt")
             .long("datefmt")
             .default_value("%Y-%m-%dT%H:%M:%S%.3f%z"))
+        .arg(Arg::with_name("timezone")
+            .long("timezone")
+            .value_name("name")
+            .default_value("UTC"))
         .arg(Arg::with_name("json-pretty")
             .long("json-pretty"))
+        .arg(Arg::with_name("xml-document")
+            .long("xml-document"))
+        .arg(Arg::with_name("xml-canonical")
+            .long("xml-canonical"))
         .arg(Arg::with_name("no-system-metadata")
             .long("no-system-metadata"))
+        .arg(Arg::with_name("infer-fields")
+            .long("infer-fields"))
+        .arg(Arg::with_name("learn-fields")
+            .long("learn-fields")
+            .value_name("overlay.json")
+            .takes_value(true))
+        .arg(Arg::with_name("expand-parameters")
+            .long("expand-parameters"))
         .arg(Arg::with_name("list-channels")
             .long("list-channels"))
         .arg(Arg::with_name("include")
@@ -199,142 +816,352 @@ EXAMPLES:
             .default_value("hostname,recordid,timestamp,provider,eventid,version,level_name,task_name,keyword_names,formatted_message,variant1,...,variant15"))
         //.group(ArgGroup::with_name("source").args(&["from-host", "from-backup"]))
         //TODO: ArgGroups with mutual exclusion
-        .get_matches();
+        ;
+
+    // Options from the configuration file, then from the preset (which can be selected in the
+    // configuration file), are inserted before the ones of the command line
+    let cli_args: Vec<OsString> = std::env::args_os().collect();
+    let mut args = app.clone().get_matches_from(&cli_args);
+    let mut config_args = vec![];
+    let mut origins = OptionOrigins::default();
+    let with_config_args = |config_args: &[String]| -> Vec<OsString> {
+        cli_args[..1].iter().cloned()
+            .chain(config_args.iter().map(OsString::from))
+            .chain(cli_args[1..].iter().cloned())
+            .collect()
+    };
+    if let Some(config_path) = args.value_of("config") {
+        let (file_args, file_origins) = config_arguments(config_path, args.value_of("profile"), &args)?;
+        config_args = file_args;
+        origins = file_origins;
+        args = app.clone().get_matches_from(with_config_args(&config_args));
+    }
+    else if args.is_present("profile") {
+        return Err(format!("--profile requires a --config file"));
+    }
+    if let Some(preset) = args.value_of("preset") {
+        let (mut preset_args, mut preset_origins) = preset_arguments(preset, &args)?;
+        preset_args.extend(config_args);
+        config_args = preset_args;
+        preset_origins.extend(origins);
+        origins = preset_origins;
+        args = app.get_matches_from(with_config_args(&config_args));
+    }
 
-    set_log_level(args.occurrences_of("verbosity") as u8);
+    set_log_level((LOG_LEVEL_INFO + args.occurrences_of("verbosity") as u8).saturating_sub(args.occurrences_of("quiet") as u8));
+    if let Some(levels) = args.value_of("log-level") {
+        origins.check("log-level", set_log_levels(levels))?;
+    }
+    set_log_format(origins.check("log-format", LogFormat::from_str(args.value_of("log-format").unwrap()))?);
+    if let Some(path) = args.value_of("log-file") {
+        set_log_file(path)?;
+    }
+    if !config_args.is_empty() {
+        verbose!("Options from configuration and preset: {}", config_args.join(" "));
+    }
+    if args.is_present("list-presets") {
+        for (name, description, options) in preset_descriptions()? {
+            println!("{:<20} {}", name, description);
+            if log_enabled(LOG_LEVEL_VERBOSE, module_path!()) {
+                for option in options.lines() {
+                    println!("{:<20}   {}", "", option);
+                }
+            }
+        }
+        return Ok(None);
+    }
+    Ok(Some((args, origins)))
+}
+
+#[cfg(windows)]
+pub fn run() -> Result<(), String> {
+    let (args, origins) = match parse_arguments()? {
+        Some(parsed) => parsed,
+        None => return Ok(()),
+    };
 
     let mut render_cfg = RenderingConfig {
         render_callback: render_event_json,
-        output_file: Box::new(Mutex::new(std::io::stdout())),
-        datefmt: "".to_string(),
+        output: OutputSink::stdout(),
+        threads: 1,
+        ordered: true,
+        datefmt: DateConfig::rfc3339_utc(),
         metadata: BTreeMap::new(),
-        field_separator: '\0',
+        metadata_caches: vec![],
+        csv: CsvConfig::new(',', '"', "\n", CsvEscaping::Quote, None),
         json_pretty: false,
+        xml_document: false,
+        xml_canonical: false,
+        expand_parameters: false,
+        infer_fields: false,
+        learned_metadata: Mutex::new(BTreeMap::new()),
         columns: vec![],
+        account_names: Mutex::new(HashMap::new()),
         rendering_start: std::time::Instant::now(),
         event_counter: AtomicU64::new(0),
+        coverage: Mutex::new(BTreeMap::new()),
+        summary: Mutex::new(BTreeMap::new()),
+        source: String::new(),
+        errors: None,
+        failed_events: AtomicU64::new(0),
+        max_failed_events: None,
+        sessions: None,
+        process_trees: None,
+        script_blocks: None,
+        evtx_writer: None,
     };
 
     let list_channels = args.occurrences_of("list-channels") != 0;
     let do_import_system_fields = args.occurrences_of("no-system-metadata") == 0 && !list_channels;
-    render_cfg.datefmt = args.value_of("datefmt").unwrap().to_owned();
-    render_cfg.columns = parse_column_names(args.value_of("columns").unwrap())?;
+    render_cfg.datefmt = DateConfig {
+        format: origins.check("datefmt", DateFormat::parse(args.value_of("datefmt").unwrap()))?,
+        timezone: origins.check("timezone", OutputTimeZone::parse(args.value_of("timezone").unwrap()))?,
+    };
+    render_cfg.columns = origins.check("columns", parse_column_names(args.value_of("columns").unwrap()))?;
     render_cfg.json_pretty = args.occurrences_of("json-pretty") > 0;
+    render_cfg.xml_canonical = args.occurrences_of("xml-canonical") > 0;
+    render_cfg.xml_document = args.occurrences_of("xml-document") > 0 || render_cfg.xml_canonical;
+    render_cfg.expand_parameters = args.occurrences_of("expand-parameters") > 0;
+    // Inferred names depend on the events seen, so they are only used when asked for
+    render_cfg.infer_fields = args.occurrences_of("infer-fields") > 0 || args.occurrences_of("learn-fields") > 0;
+    render_cfg.threads = match args.value_of("threads") {
+        Some(threads) => match usize::from_str(threads) {
+            Ok(threads) if threads > 0 => threads,
+            _ => return origins.check("threads", Err(format!("Invalid number of threads '{}'", threads))),
+        },
+        None => 1,
+    };
+    render_cfg.ordered = args.occurrences_of("unordered") == 0;
+    if args.occurrences_of("fail-on-error") == 1 {
+        let max = args.value_of("fail-on-error").unwrap();
+        render_cfg.max_failed_events = match u64::from_str(max) {
+            Ok(max) => Some(max),
+            Err(_) => return Err(format!("Invalid number of events for {} '{}'", origins.name("fail-on-error"), max)),
+        };
+    }
+    let learn_fields_path = args.value_of("learn-fields");
+    if let Some(in_path) = learn_fields_path {
+        // The overlay is created on first use
+        if std::path::Path::new(in_path).exists() {
+            render_cfg.learned_metadata = Mutex::new(read_metadata_file(in_path)?);
+        }
+    }
 
     let append = args.occurrences_of("append") > 0;
     let dump_existing = args.occurrences_of("dump-existing") > 0;
     let tail_follow = args.occurrences_of("no-wait") == 0;
     let mut system_field_defs_read = false;
-    let include: Vec<&str> = args.values_of("include").unwrap().collect();
+    let mut xml_document = None;
+    let sessions = args.occurrences_of("sessions") > 0;
+    let include: Vec<&str> = if sessions && args.occurrences_of("include") == 0 {
+        SESSION_EVENT_FILTERS.to_vec()
+    } else if args.occurrences_of("process-tree") == 1 && args.occurrences_of("include") == 0 {
+        PROCESS_EVENT_FILTERS.to_vec()
+    } else if args.occurrences_of("script-blocks") == 1 && args.occurrences_of("include") == 0 {
+        let mut filters = SCRIPT_BLOCK_FILTERS.to_vec();
+        if args.occurrences_of("decode-commands") > 0 {
+            filters.extend(ENCODED_COMMAND_FILTERS);
+        }
+        filters
+    } else {
+        args.values_of("include").unwrap().collect()
+    };
     let exclude: Vec<&str> = if args.occurrences_of("exclude") > 0 {
         args.values_of("exclude").unwrap().collect()
     } else {
         vec![]
     };
 
-    if args.occurrences_of("import-metadata") == 1 {
-        let in_path = args.value_of("import-metadata").unwrap();
-        let mut in_file = match OpenOptions::new().read(true).open(in_path) {
-            Err(e) => return Err(format !("Could not open file {} : {}", in_path, e)),
-            Ok(f) => f,
-        };
+    let merge_policy = origins.check("merge-policy", MergePolicy::from_str(args.value_of("merge-policy").unwrap()))?;
 
-        if do_import_system_fields && !system_field_defs_read {
-            match import_metadata_from_system() {
-                Ok(system_metadata) => update_metadata_with(&mut render_cfg.metadata, &system_metadata),
-                Err(e) => warn!("Could not import system metadata: only using the given export ({})", e),
-            }
-            system_field_defs_read = true;
+    if args.occurrences_of("convert-metadata") > 0 {
+        return convert_metadata(&args, render_cfg.json_pretty);
+    }
+    if args.occurrences_of("metadata-diff") > 0 {
+        return diff_metadata_files(&args);
+    }
+
+    // System metadata has a lower priority than imported and extracted metadata
+    let offline_metadata = args.occurrences_of("import-metadata") > 0 || args.occurrences_of("extract-metadata") > 0 ||
+        args.occurrences_of("image-root") > 0;
+    if offline_metadata && do_import_system_fields && !system_field_defs_read {
+        match import_metadata_from_system() {
+            Ok(system_metadata) => update_metadata_with(&mut render_cfg.metadata, &system_metadata, merge_policy),
+            Err(e) => warn!("Could not import system metadata: only using the given export ({})", e),
         }
-        let imported_field_defs = import_metadata_from_file(&mut in_file)?;
-        update_metadata_with(&mut render_cfg.metadata, &imported_field_defs);
+        system_field_defs_read = true;
     }
+    import_offline_metadata(&args, &mut render_cfg.metadata, &mut render_cfg.metadata_caches, merge_policy)?;
 
     if args.occurrences_of("export-metadata") == 1 {
-        let out_path = args.value_of("export-metadata").unwrap();
-        let mut out_file : Box<dyn std::io::Write> = if out_path.eq("stdout") {
-            Box::from(io::stdout())
-        } else {
-            match OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(out_path) {
-                Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
-                Ok(f) => Box::from(f),
-            }
-        };
         if do_import_system_fields && !system_field_defs_read {
             match import_metadata_from_system() {
-                Ok(system_field_defs) => update_metadata_with(&mut render_cfg.metadata, &system_field_defs),
+                Ok(system_field_defs) => update_metadata_with(&mut render_cfg.metadata, &system_field_defs, merge_policy),
                 Err(e) => warn!("Some fields will be left unnamed: unable to read metadata from system, {}", e),
             }
-            system_field_defs_read = true;
         }
-        return export_metadata_to_file(&render_cfg.metadata, &mut out_file, render_cfg.json_pretty);
+        return export_metadata(&args, &mut render_cfg.metadata, &render_cfg.metadata_caches, append, render_cfg.json_pretty);
+    }
+
+    if sessions {
+        if args.occurrences_of("to-xml") == 1 || args.occurrences_of("to-evtx") == 1 || args.occurrences_of("coverage") == 1 ||
+                args.occurrences_of("process-tree") == 1 || args.occurrences_of("script-blocks") == 1 {
+            return Err(format!("Logon sessions can only be written as JSON, CSV or TSV"));
+        }
+        let timeout = args.value_of("session-timeout").unwrap();
+        let timeout = match u64::from_str(timeout) {
+            Ok(timeout) if timeout > 0 => timeout,
+            _ => return origins.check("session-timeout", Err(format!("Invalid session timeout '{}'", timeout))),
+        };
+        let csv = args.occurrences_of("to-csv") == 1 || args.occurrences_of("to-tsv") == 1;
+        render_cfg.sessions = Some(Mutex::new(SessionTracker::new(timeout, csv)));
+    }
+
+    if args.occurrences_of("process-tree") == 1 {
+        if args.occurrences_of("to-csv") == 1 || args.occurrences_of("to-tsv") == 1 || args.occurrences_of("to-xml") == 1 ||
+                args.occurrences_of("to-evtx") == 1 || args.occurrences_of("coverage") == 1 || args.occurrences_of("script-blocks") == 1 {
+            return Err(format!("Process trees can only be written as JSON, text or DOT"));
+        }
+    }
+
+    if args.occurrences_of("script-blocks") == 1 {
+        if args.occurrences_of("to-csv") == 1 || args.occurrences_of("to-tsv") == 1 || args.occurrences_of("to-xml") == 1 ||
+                args.occurrences_of("to-evtx") == 1 || args.occurrences_of("coverage") == 1 {
+            return Err(format!("Script blocks can only be written as JSON"));
+        }
     }
 
     if args.occurrences_of("to-xml") == 1 {
         let out_path = args.value_of("to-xml").unwrap();
-        let out_file = if out_path.eq("stdout") {
-            Box::from(io::stdout()) as Box<dyn std::io::Write>
-        } else {
-            match OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(out_path) {
-                Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
-                Ok(f) => Box::from(f) as Box<dyn std::io::Write>,
+        if render_cfg.xml_document && append && std::fs::metadata(out_path).map(|m| m.len() > 0).unwrap_or(false) {
+            return Err(format!("Cannot append to {} and keep it a well-formed XML document", out_path));
+        }
+        let output = OutputSink::open(out_path, append)?;
+        if render_cfg.xml_document && do_import_system_fields && !system_field_defs_read {
+            match import_metadata_from_system() {
+                Ok(system_field_defs) => update_metadata_with(&mut render_cfg.metadata, &system_field_defs, merge_policy),
+                Err(e) => warn!("XML output will not have messages: unable to read event definitions from system, {}", e),
             }
-        };
+        }
         render_cfg.render_callback = render_event_xml;
-        render_cfg.output_file = Box::from(Mutex::new(out_file));
+        render_cfg.output = output;
+        if render_cfg.xml_document {
+            xml_document = Some(XmlDocument::start(&render_cfg.output)?);
+        }
     }
     else if args.occurrences_of("to-csv") == 1 {
         let out_path = args.value_of("to-csv").unwrap();
-        let out_file = if out_path.eq("stdout") {
-            Box::from(io::stdout()) as Box<dyn std::io::Write>
-        } else {
-            match OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(out_path) {
-                Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
-                Ok(f) => Box::from(f) as Box<dyn std::io::Write>,
+        let output = OutputSink::open(out_path, append)?;
+        if columns_reference_field_names(&render_cfg.columns) && do_import_system_fields && !system_field_defs_read {
+            match import_metadata_from_system() {
+                Ok(system_field_defs) => update_metadata_with(&mut render_cfg.metadata, &system_field_defs, merge_policy),
+                Err(e) => {
+                    warn!("Inferring field names of named columns: unable to read event definitions from system, {}", e);
+                    render_cfg.infer_fields = true;
+                },
             }
-        };
+        }
         render_cfg.render_callback = render_event_csv;
-        render_cfg.output_file = Box::from(Mutex::new(out_file));
-        render_cfg.field_separator = ',';
+        render_cfg.output = output;
+        render_cfg.csv = csv_config_from_args(&args, &origins, ',', CsvEscaping::Quote)?;
+        write_csv_header_unless_appended(&render_cfg, out_path, append)?;
     }
     else if args.occurrences_of("to-tsv") == 1 {
         let out_path = args.value_of("to-tsv").unwrap();
-        let out_file = if out_path.eq("stdout") {
-            Box::from(io::stdout()) as Box<dyn std::io::Write>
-        } else {
-            match OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(out_path) {
-                Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
-                Ok(f) => Box::from(f) as Box<dyn std::io::Write>,
+        let output = OutputSink::open(out_path, append)?;
+        if columns_reference_field_names(&render_cfg.columns) && do_import_system_fields && !system_field_defs_read {
+            match import_metadata_from_system() {
+                Ok(system_field_defs) => update_metadata_with(&mut render_cfg.metadata, &system_field_defs, merge_policy),
+                Err(e) => {
+                    warn!("Inferring field names of named columns: unable to read event definitions from system, {}", e);
+                    render_cfg.infer_fields = true;
+                },
             }
-        };
+        }
         render_cfg.render_callback = render_event_csv;
-        render_cfg.output_file = Box::from(Mutex::new(out_file));
-        render_cfg.field_separator = '\t';
+        render_cfg.output = output;
+        render_cfg.csv = csv_config_from_args(&args, &origins, '\t', CsvEscaping::Backslash)?;
+        write_csv_header_unless_appended(&render_cfg, out_path, append)?;
+    }
+    else if let Some(out_path) = args.value_of("to-evtx") {
+        // Chunks and the file header are rewritten in place, so they cannot be appended to
+        if append {
+            return Err(format!("Cannot append events to an existing EVTX file"));
+        }
+        render_cfg.render_callback = render_event_evtx;
+        render_cfg.evtx_writer = Some(Mutex::new(EvtxWriter::create(out_path, args.occurrences_of("evtx-renumber") > 0)?));
+        // Records are numbered and chunked in the order they are written, which must be the order of the input
+        if render_cfg.threads > 1 {
+            warn!("--threads is ignored with --to-evtx, events are written by a single worker");
+        }
+        render_cfg.threads = 1;
+    }
+    else if args.occurrences_of("process-tree") == 1 {
+        let out_path = args.value_of("process-tree").unwrap();
+        let output = OutputSink::open(out_path, append)?;
+        let format = origins.check("tree-format", ProcessTreeFormat::from_str(args.value_of("tree-format").unwrap()))?;
+        let roots: Vec<String> = match args.values_of("tree-root") {
+            Some(roots) => roots.map(|root| root.to_owned()).collect(),
+            None => vec![],
+        };
+        render_cfg.render_callback = render_event_process_tree;
+        render_cfg.output = output;
+        render_cfg.process_trees = Some(Mutex::new(ProcessTrees::new(format, roots)));
+    }
+    else if args.occurrences_of("script-blocks") == 1 {
+        let out_path = args.value_of("script-blocks").unwrap();
+        let output = OutputSink::open(out_path, append)?;
+        let script_blocks = ScriptBlocks::new(args.value_of("scripts-dir"), args.occurrences_of("decode-commands") > 0)?;
+        render_cfg.render_callback = render_event_script_blocks;
+        render_cfg.output = output;
+        render_cfg.script_blocks = Some(Mutex::new(script_blocks));
+    }
+    else if args.occurrences_of("coverage") == 1 {
+        let out_path = args.value_of("coverage").unwrap();
+        let output = OutputSink::open(out_path, append)?;
+        if do_import_system_fields && !system_field_defs_read {
+            match import_metadata_from_system() {
+                Ok(system_field_defs) => update_metadata_with(&mut render_cfg.metadata, &system_field_defs, merge_policy),
+                Err(e) => warn!("Coverage will be computed without system metadata: unable to read event definitions from system, {}", e),
+            }
+        }
+        render_cfg.render_callback = render_event_coverage;
+        render_cfg.output = output;
     }
     else {
         let out_path = args.value_of("to-json").unwrap();
-        let out_file = if out_path.eq("stdout") {
-            Box::from(io::stdout()) as Box<dyn std::io::Write>
-        } else {
-            match OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(out_path) {
-                Err(e) => return Err(format !("Could not open file {} : {}", out_path, e)),
-                Ok(f) => Box::from(f) as Box<dyn std::io::Write>,
-            }
-        };
+        let output = OutputSink::open(out_path, append)?;
         if do_import_system_fields && !system_field_defs_read {
             match import_metadata_from_system() {
-                Ok(system_field_defs) => update_metadata_with(&mut render_cfg.metadata, &system_field_defs),
+                Ok(system_field_defs) => update_metadata_with(&mut render_cfg.metadata, &system_field_defs, merge_policy),
                 Err(e) => warn!("JSON output will have generic field names: unable to read event definitions from system, {}", e),
             }
-            system_field_defs_read = true;
         }
         render_cfg.render_callback = render_event_json;
-        render_cfg.output_file = Box::from(Mutex::new(out_file));
+        render_cfg.output = output;
+    }
+    if render_cfg.sessions.is_some() {
+        render_cfg.render_callback = render_event_sessions;
+        // Events are correlated in the order they were logged
+        render_cfg.threads = 1;
+    }
+    info!("Imported metadata from {} providers", render_cfg.metadata.len() +
+          render_cfg.metadata_caches.iter().map(|cache| cache.len()).sum::<usize>());
+    if let Err(e) = render_cfg.output.flush_on_interrupt() {
+        warn!("Events still buffered will be lost if interrupted: {}", e);
+    }
+    if let Some(out_path) = args.value_of("errors-to") {
+        render_cfg.errors = Some(OutputSink::open(out_path, append)?);
     }
-    info!("Imported metadata from {} providers", render_cfg.metadata.len());
+    if let Err(e) = stop_on_interrupt() {
+        warn!("Outputs will not be closed properly if interrupted: {}", e);
+    }
+    // Throughput is measured from here, not including the time taken to load metadata
+    render_cfg.rendering_start = Instant::now();
 
     if args.occurrences_of("from-backup") == 1 {
         let path = args.value_of("from-backup").unwrap();
+        render_cfg.source = path.to_owned();
         let xml_filter = xml_query_from_filters(&include, &exclude, None)?;
         let xml_filter = xml_filter.get("*").unwrap_or(&None);
         verbose!("Opening file {}...", path);
@@ -348,6 +1175,7 @@ EXAMPLES:
         let uri = args.value_of("from-host").unwrap();
         let parts : Vec<&str> = uri.rsplitn(2,"@").collect();
         let hostname = *parts.get(0).unwrap();
+        render_cfg.source = hostname.to_owned();
         let rpc_creds;
         let rpc_creds = if parts.len() == 1 {
             info!("Authenticating to {} with implicit credentials...", hostname);
@@ -417,6 +1245,21 @@ EXAMPLES:
         while subscriptions.len() > 0 {
             std::thread::sleep(std::time::Duration::from_secs(1));
             let current_event_count = render_cfg.event_counter.load(Relaxed);
+            // Events are buffered, and would otherwise only be written once more arrive. Existing
+            // events may still be delivered: only once none came are they all read, so that
+            // sessions and process trees can expire against the current time
+            let caught_up = current_event_count == last_event_count;
+            let wall_clock = if caught_up { Some(current_filetime()) } else { None };
+            if render_cfg.sessions.is_some() {
+                render_cfg.output.write_event(expire_sessions(&render_cfg, wall_clock)?)?;
+            }
+            else if render_cfg.process_trees.is_some() {
+                render_cfg.output.write_event(expire_process_trees(&render_cfg, wall_clock)?)?;
+            }
+            render_cfg.output.flush()?;
+            if render_cfg.output.is_closed() || stop_requested() {
+                break;
+            }
             if current_event_count == last_event_count {
                 if tail_follow {
                     debug!("Waiting for more events");
@@ -428,10 +1271,96 @@ EXAMPLES:
             }
             last_event_count = current_event_count;
         }
-        // Close all handles when all events have been received, not before
+        // Close all handles when all events have been received, not before. EvtClose() waits
+        // for callbacks in progress, so events already delivered are written before outputs are closed
         info!("Done. Cleaning up all channel subscriptions...");
         std::mem::drop(subscriptions);
     }
 
-    Ok(())
+    if let Some(out_path) = learn_fields_path {
+        let learned = match render_cfg.learned_metadata.lock() {
+            Ok(l) => l,
+            Err(e) => return Err(format!("Failed to acquire lock to learned metadata: {}", e)),
+        };
+        let mut out_file = match OpenOptions::new().write(true).create(true).truncate(true).open(out_path) {
+            Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
+            Ok(f) => f,
+        };
+        export_metadata_to_file(&learned, &mut out_file, true)?;
+    }
+
+    if args.occurrences_of("coverage") == 1 {
+        let coverage = match render_cfg.coverage.lock() {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Failed to acquire lock to coverage statistics: {}", e)),
+        };
+        let mut report = vec![];
+        write_coverage_report(&coverage, &mut report)?;
+        render_cfg.output.write_all(&report)?;
+        if let Some(out_path) = args.value_of("export-skeletons") {
+            let skeletons = coverage_skeletons(&coverage);
+            info!("Exporting skeleton definitions for {} providers", skeletons.len());
+            let mut out_file = match OpenOptions::new().write(true).create(true).truncate(true).open(out_path) {
+                Err(e) => return Err(format!("Could not open file {} : {}", out_path, e)),
+                Ok(f) => f,
+            };
+            export_metadata_to_file(&skeletons, &mut out_file, true)?;
+        }
+    }
+    else if let Some(xml_document) = xml_document.take() {
+        xml_document.finish()?;
+    }
+    else if let Some(writer) = &render_cfg.evtx_writer {
+        match writer.lock() {
+            Ok(mut writer) => writer.finish()?,
+            Err(e) => return Err(format!("Failed to acquire lock to EVTX file: {}", e)),
+        }
+    }
+    else if render_cfg.sessions.is_some() {
+        render_cfg.output.write_event(finish_sessions(&render_cfg)?)?;
+    }
+    else if render_cfg.script_blocks.is_some() {
+        render_cfg.output.write_event(finish_script_blocks(&render_cfg)?)?;
+    }
+    else if render_cfg.process_trees.is_some() {
+        render_cfg.output.write_event(finish_process_trees(&render_cfg)?)?;
+    }
+    render_cfg.output.flush()?;
+    if let Some(errors) = &render_cfg.errors {
+        errors.flush()?;
+    }
+    write_run_summary(&args, &render_cfg)?;
+    let failed_events = render_cfg.failed_events.load(Relaxed);
+    match render_cfg.max_failed_events {
+        Some(max) if failed_events > max => Err(format!("{} events failed, more than the {} allowed by {}",
+                                                        failed_events, max, origins.name("fail-on-error"))),
+        _ => Ok(()),
+    }
+}
+
+// Events can only be read through the Windows EventLog API, but metadata can be extracted from
+// a copy of a Windows system's files on any platform
+#[cfg(not(windows))]
+pub fn run() -> Result<(), String> {
+    let (args, origins) = match parse_arguments()? {
+        Some(parsed) => parsed,
+        None => return Ok(()),
+    };
+    let json_pretty = args.occurrences_of("json-pretty") > 0;
+    let merge_policy = origins.check("merge-policy", MergePolicy::from_str(args.value_of("merge-policy").unwrap()))?;
+
+    if args.occurrences_of("convert-metadata") > 0 {
+        return convert_metadata(&args, json_pretty);
+    }
+    if args.occurrences_of("metadata-diff") > 0 {
+        return diff_metadata_files(&args);
+    }
+
+    let mut metadata = BTreeMap::new();
+    let mut metadata_caches = vec![];
+    import_offline_metadata(&args, &mut metadata, &mut metadata_caches, merge_policy)?;
+    if args.occurrences_of("export-metadata") == 1 {
+        return export_metadata(&args, &mut metadata, &metadata_caches, args.occurrences_of("append") > 0, json_pretty);
+    }
+    Err(format!("Events can only be read on Windows, on this platform metadata can only be extracted, converted or compared (e.g. --image-root <dir> --export-metadata <meta.json>)"))
 }
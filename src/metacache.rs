@@ -0,0 +1,257 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+use memmap::Mmap;
+use crate::metadata::{Metadata, ProviderMetadata};
+
+/*
+ * Indexed binary metadata file, which can be memory-mapped and only decodes the definitions of
+ * a provider the first time one of its events is rendered, instead of parsing a whole JSON
+ * export at startup.
+ *
+ * "EVTQMETA" u32 format_version, u32 provider_count
+ *     { u16 name_length, UTF-8 name, u64 offset, u64 size } [provider_count]
+ * followed by each provider's ProviderMetadata, serialized with bincode, at the given offset
+ * (relative to the start of the file).
+ */
+
+const MAGIC: &[u8; 8] = b"EVTQMETA";
+const FORMAT_VERSION: u32 = 2;
+
+pub struct MetadataCache {
+    path: String,
+    mapping: Mmap,
+    index: BTreeMap<String, (usize, usize)>,
+    decoded: RwLock<HashMap<String, Option<Arc<ProviderMetadata>>>>,
+}
+
+// Metadata of a provider, either from metadata loaded at startup or decoded from a cache
+pub enum ProviderMetadataRef<'a> {
+    Loaded(&'a ProviderMetadata),
+    Cached(Arc<ProviderMetadata>),
+}
+
+impl<'a> Deref for ProviderMetadataRef<'a> {
+    type Target = ProviderMetadata;
+
+    fn deref(&self) -> &ProviderMetadata {
+        match self {
+            ProviderMetadataRef::Loaded(prov_meta) => prov_meta,
+            ProviderMetadataRef::Cached(prov_meta) => prov_meta,
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+// Checks whether the given metadata file is in the binary format (as opposed to a JSON export)
+pub fn is_metadata_cache(path: &str) -> Result<bool, String> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Could not open file {} : {}", path, e)),
+    };
+    let mut magic = [0u8; 8];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(_) => Ok(false),
+    }
+}
+
+impl MetadataCache {
+    pub fn open(path: &str) -> Result<MetadataCache, String> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Could not open file {} : {}", path, e)),
+        };
+        // The file must not be modified while it is mapped, which is the case for metadata
+        // files which are only written by --convert-metadata
+        let mapping = match unsafe { Mmap::map(&file) } {
+            Ok(m) => m,
+            Err(e) => return Err(format!("Could not map file {} : {}", path, e)),
+        };
+        if mapping.get(0..8) != Some(&MAGIC[..]) {
+            return Err(format!("{} is not a binary metadata file", path));
+        }
+        match read_u32(&mapping, 8) {
+            Some(FORMAT_VERSION) => (),
+            other => return Err(format!("Unsupported binary metadata format version {:?} in {}", other, path)),
+        }
+        let provider_count = read_u32(&mapping, 12).ok_or("Truncated binary metadata header")? as usize;
+        let mut index = BTreeMap::new();
+        let mut pos = 16;
+        for _ in 0..provider_count {
+            let name_len = read_u16(&mapping, pos).ok_or("Truncated binary metadata index")? as usize;
+            let name = match mapping.get(pos + 2..pos + 2 + name_len) {
+                Some(b) => String::from_utf8_lossy(b).into_owned(),
+                None => return Err(format!("Truncated binary metadata index in {}", path)),
+            };
+            pos += 2 + name_len;
+            let (offset, size) = match (read_u64(&mapping, pos), read_u64(&mapping, pos + 8)) {
+                (Some(offset), Some(size)) if offset.saturating_add(size) <= mapping.len() as u64 => (offset as usize, size as usize),
+                _ => return Err(format!("Invalid binary metadata index entry for {} in {}", name, path)),
+            };
+            pos += 16;
+            index.insert(name, (offset, size));
+        }
+        info!("Opened binary metadata with {} providers from {}", index.len(), path);
+        Ok(MetadataCache {
+            path: path.to_owned(),
+            mapping,
+            index,
+            decoded: RwLock::new(HashMap::new()),
+        })
+    }
+
+    fn decode(&self, provider_name: &str) -> Result<Option<ProviderMetadata>, String> {
+        let (offset, size) = match self.index.get(provider_name) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        match bincode::deserialize(&self.mapping[offset..offset + size]) {
+            Ok(prov_meta) => Ok(Some(prov_meta)),
+            Err(e) => Err(format!("Unable to decode provider {} metadata from {} : {}", provider_name, self.path, e)),
+        }
+    }
+
+    // Returns the metadata of the given provider, decoding it on first use
+    pub fn get(&self, provider_name: &str) -> Option<Arc<ProviderMetadata>> {
+        if let Some(prov_meta) = self.decoded.read().unwrap().get(provider_name) {
+            return prov_meta.clone();
+        }
+        let prov_meta = match self.decode(provider_name) {
+            Ok(prov_meta) => prov_meta.map(Arc::new),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            },
+        };
+        if prov_meta.is_some() {
+            debug!("Decoded metadata of provider {}", provider_name);
+        }
+        self.decoded.write().unwrap().insert(provider_name.to_owned(), prov_meta.clone());
+        prov_meta
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    // Decodes all providers, e.g. to convert them back to JSON
+    pub fn load_all(&self) -> Result<Metadata, String> {
+        let mut metadata = BTreeMap::new();
+        for provider_name in self.index.keys() {
+            if let Some(prov_meta) = self.decode(provider_name)? {
+                metadata.insert(provider_name.to_owned(), prov_meta);
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+pub fn export_metadata_to_cache(metadata: &Metadata, out_file: &mut dyn std::io::Write) -> Result<(), String> {
+    let mut blobs = Vec::with_capacity(metadata.len());
+    for (provider_name, prov_meta) in metadata {
+        if provider_name.len() > u16::MAX as usize {
+            return Err(format!("Provider name too long to be exported: {}", provider_name));
+        }
+        match bincode::serialize(prov_meta) {
+            Ok(blob) => blobs.push((provider_name, blob)),
+            Err(e) => return Err(format!("Unable to serialize provider {} metadata: {}", provider_name, e)),
+        }
+    }
+    let index_size: usize = blobs.iter().map(|(name, _)| 2 + name.len() + 16).sum();
+    let mut header = Vec::with_capacity(16 + index_size);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header.extend_from_slice(&(blobs.len() as u32).to_le_bytes());
+    let mut offset = (16 + index_size) as u64;
+    for (name, blob) in &blobs {
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(&(blob.len() as u64).to_le_bytes());
+        offset += blob.len() as u64;
+    }
+    let res = out_file.write_all(&header)
+        .and_then(|_| blobs.iter().try_for_each(|(_, blob)| out_file.write_all(blob)))
+        .and_then(|_| out_file.flush());
+    match res {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Unable to write binary metadata: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offline::import_metadata_from_pe_files;
+
+    // Generated by tests/fixtures/make_provider_dll.py
+    const PROVIDER_DLL: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/provider.dll");
+
+    fn test_metadata() -> Metadata {
+        let mut metadata = import_metadata_from_pe_files(&[PROVIDER_DLL], None).unwrap();
+        let mut renamed = metadata.values().next().unwrap().clone();
+        renamed.parameters.insert(1842, "Yes".to_owned());
+        renamed.events.clear();
+        metadata.insert("Évtq-Other".to_owned(), renamed);
+        metadata
+    }
+
+    fn write_cache(name: &str, metadata: &Metadata) -> String {
+        let path = std::env::temp_dir().join(format!("evtq-test-{}-{}.bin", std::process::id(), name));
+        let mut file = File::create(&path).unwrap();
+        export_metadata_to_cache(metadata, &mut file).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn round_trips_metadata() {
+        let metadata = test_metadata();
+        let path = write_cache("roundtrip", &metadata);
+        assert!(is_metadata_cache(&path).unwrap());
+        let cache = MetadataCache::open(&path).unwrap();
+        assert_eq!(cache.len(), 2);
+        let loaded = cache.load_all().unwrap();
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&metadata).unwrap());
+
+        // Providers are decoded on demand, once
+        let prov_meta = cache.get("Évtq-Other").unwrap();
+        assert_eq!(prov_meta.parameters.get(&1842).map(String::as_str), Some("Yes"));
+        assert!(Arc::ptr_eq(&prov_meta, &cache.get("Évtq-Other").unwrap()));
+        assert!(cache.get("Unknown").is_none());
+        drop(cache);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_invalid_caches() {
+        let path = write_cache("truncated", &test_metadata());
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(MetadataCache::open(&path).is_err());
+
+        let mut other_version = data.clone();
+        other_version[8] = other_version[8].wrapping_add(1);
+        std::fs::write(&path, &other_version).unwrap();
+        assert!(MetadataCache::open(&path).is_err());
+
+        std::fs::write(&path, b"{}").unwrap();
+        assert!(!is_metadata_cache(&path).unwrap());
+        assert!(MetadataCache::open(&path).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
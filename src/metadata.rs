@@ -1,7 +1,29 @@
 use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
-use crate::windows::{get_evt_provider_handle, get_evt_provider_metadata, format_message};
+#[cfg(windows)]
+use std::collections::HashMap;
+#[cfg(windows)]
+use crate::winmeta::{
+    resolve_standard_name,
+    resolve_opcode_name,
+    resolve_keyword_names,
+    SYSTEM_LEVELS,
+    SYSTEM_TASKS,
+};
+#[cfg(windows)]
+use crate::windows::{
+    get_evt_provider_handle,
+    get_evt_provider_metadata,
+    get_evt_provider_name_tables,
+    format_message,
+    load_message_table,
+    get_os_build,
+};
+#[cfg(windows)]
 use winapi::shared::winerror::{ERROR_EVT_MESSAGE_NOT_FOUND, ERROR_EVT_MESSAGE_LOCALE_NOT_FOUND};
+#[cfg(windows)]
 use winapi::um::winevt::{
     EvtPublisherMetadataPublisherGuid,
     EvtPublisherMetadataResourceFilePath,
@@ -9,15 +31,45 @@ use winapi::um::winevt::{
     EvtPublisherMetadataMessageFilePath,
     EvtPublisherMetadataPublisherMessageID,
 };
-use crate::formatting::EvtVariant;
+#[cfg(windows)]
+use crate::formatting::{EvtVariant, CommonEventProperties};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct EventFieldDefinition {
     pub name: String,
     pub out_type: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// Where a definition was read from, so that definitions from several OS builds can be merged
+// into a single file and conflicts can be traced back
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MetadataSource {
+    pub os_build: Option<String>,
+    pub host: Option<String>,
+    pub file: Option<String>,
+}
+
+impl fmt::Display for MetadataSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(host) = &self.host {
+            parts.push(format!("host {}", host));
+        }
+        if let Some(os_build) = &self.os_build {
+            parts.push(format!("build {}", os_build));
+        }
+        if let Some(file) = &self.file {
+            parts.push(format!("file {}", file));
+        }
+        if parts.is_empty() {
+            write!(f, "unknown source")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct EventDefinition {
     pub channel: Option<String>,
     pub message: Option<String>,
@@ -30,6 +82,35 @@ pub struct EventDefinition {
     pub keywords: u64,
     pub keyword_names: Vec<String>,
     pub fields: Vec<EventFieldDefinition>,
+    #[serde(default)]
+    pub sources: Vec<MetadataSource>,
+}
+
+impl EventDefinition {
+    // Definition of an event nothing is known about, its values are named fieldN
+    pub fn empty() -> Self {
+        EventDefinition {
+            channel: None,
+            message: None,
+            level: 0,
+            level_name: None,
+            opcode: 0,
+            opcode_name: None,
+            task: 0,
+            task_name: None,
+            keywords: 0,
+            keyword_names: vec![],
+            fields: vec![],
+            sources: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChannelConfig {
+    pub enabled: bool,
+    pub channel_type: Option<String>,
+    pub log_file_path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -39,13 +120,150 @@ pub struct ProviderMetadata {
     pub parameter_file_path: Option<String>,
     pub message_file_path: Option<String>,
     pub message: Option<String>,
+    // Parameter message strings referenced as %%N in messages and field values
+    #[serde(default)]
+    pub parameters: BTreeMap<u32, String>,
+    // Channels owned by this provider, only known when read from registry hives
+    #[serde(default)]
+    pub channels: BTreeMap<String, ChannelConfig>,
+    // Names of the levels, tasks, opcodes and keywords defined by this provider, to resolve
+    // the values found in each event record
+    #[serde(default)]
+    pub levels: BTreeMap<u64, String>,
+    #[serde(default)]
+    pub tasks: BTreeMap<u64, String>,
+    #[serde(default)]
+    pub opcodes: BTreeMap<u64, String>,
+    #[serde(default)]
+    pub keywords: BTreeMap<u64, String>,
     pub events: BTreeMap<u64, BTreeMap<u64, EventDefinition>>,
 }
 
+impl ProviderMetadata {
+    // Metadata of a provider nothing is known about yet, to be filled as events are seen
+    pub fn empty() -> Self {
+        ProviderMetadata {
+            guid: None,
+            resource_file_path: None,
+            parameter_file_path: None,
+            message_file_path: None,
+            message: None,
+            parameters: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            levels: BTreeMap::new(),
+            tasks: BTreeMap::new(),
+            opcodes: BTreeMap::new(),
+            keywords: BTreeMap::new(),
+            events: BTreeMap::new(),
+        }
+    }
+}
+
 pub type Metadata = BTreeMap<String, ProviderMetadata>;
 
+// Level, task, opcode and keywords of an event record, with their names
+pub struct EventClassification {
+    pub level: u64,
+    pub level_name: Option<String>,
+    pub task: u64,
+    pub task_name: Option<String>,
+    pub opcode: u64,
+    pub opcode_name: Option<String>,
+    pub keywords: u64,
+    pub keyword_names: Vec<String>,
+}
+
+// Events with the same definition can be logged with different values (e.g. Security events
+// with either the Audit Success or Audit Failure keyword), so values are taken from the record
+// itself when present. Names from the definition are only used if the values match, otherwise
+// they are resolved from the provider's tables, then the ones standardized by Windows.
+#[cfg(windows)]
+pub fn classify_event(common_props: &CommonEventProperties,
+                      event_def: &EventDefinition,
+                      prov_meta: Option<&ProviderMetadata>) -> EventClassification {
+    let no_names = BTreeMap::new();
+    let (prov_levels, prov_tasks, prov_opcodes, prov_keywords) = match prov_meta {
+        Some(p) => (&p.levels, &p.tasks, &p.opcodes, &p.keywords),
+        None => (&no_names, &no_names, &no_names, &no_names),
+    };
+
+    let level = common_props.level.unwrap_or(event_def.level as u64);
+    let level_name = match &event_def.level_name {
+        Some(name) if level == event_def.level as u64 => Some(name.to_owned()),
+        _ => resolve_standard_name(prov_levels, SYSTEM_LEVELS, level),
+    };
+    let task = common_props.task.unwrap_or(event_def.task as u64);
+    let task_name = match &event_def.task_name {
+        Some(name) if task == event_def.task as u64 => Some(name.to_owned()),
+        _ => resolve_standard_name(prov_tasks, SYSTEM_TASKS, task),
+    };
+    let opcode = common_props.opcode.unwrap_or(event_def.opcode as u64);
+    let opcode_name = match &event_def.opcode_name {
+        Some(name) if opcode == event_def.opcode as u64 => Some(name.to_owned()),
+        _ => resolve_opcode_name(prov_opcodes, task, opcode),
+    };
+    let keywords = common_props.keywords.unwrap_or(event_def.keywords);
+    let keyword_names = if keywords == event_def.keywords && !event_def.keyword_names.is_empty() {
+        event_def.keyword_names.to_owned()
+    } else {
+        resolve_keyword_names(prov_keywords, keywords)
+    };
+    EventClassification { level, level_name, task, task_name, opcode, opcode_name, keywords, keyword_names }
+}
+
+// What to do when merging a definition of an event which is already known
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergePolicy {
+    // Keep known values, only fill the blanks (missing messages, names, or trailing fields)
+    Fill,
+    // Never modify known providers and definitions, only add unknown events and versions
+    Keep,
+    // Replace known values with new ones when they conflict
+    Replace,
+}
+
+impl FromStr for MergePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fill" => Ok(MergePolicy::Fill),
+            "keep" => Ok(MergePolicy::Keep),
+            "replace" => Ok(MergePolicy::Replace),
+            _ => Err(format!("Unknown merge policy '{}' (expected fill, keep, or replace)", s)),
+        }
+    }
+}
+
+// Loads parameter message strings from the given (semicolon-separated list of) message files.
+// Most providers share the same few parameter files (e.g. msobjs.dll), so they are only parsed once.
+#[cfg(windows)]
+fn load_parameter_messages(paths: &str, cache: &mut HashMap<String, BTreeMap<u32, String>>) -> BTreeMap<u32, String> {
+    let mut parameters = BTreeMap::new();
+    for path in paths.split(';').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        if !cache.contains_key(path) {
+            let messages = match load_message_table(path) {
+                Ok(messages) => messages.into_iter()
+                    .map(|(id, text)| (id, text.trim_end_matches(&['\r', '\n'][..]).to_owned()))
+                    .collect(),
+                Err(e) => {
+                    verbose!("Unable to load parameter messages from {} : {}", path, e);
+                    BTreeMap::new()
+                },
+            };
+            cache.insert(path.to_owned(), messages);
+        }
+        for (id, text) in &cache[path] {
+            parameters.entry(*id).or_insert(text.to_owned());
+        }
+    }
+    parameters
+}
+
+#[cfg(windows)]
 pub fn import_metadata_from_system() -> Result<Metadata, String> {
     let mut metadata = BTreeMap::new();
+    let mut parameter_files = HashMap::new();
 
     info!("Importing metadata from live system, this may take a while...
        (use --no-system-metadata if you don't care about message strings, field names
@@ -61,7 +279,8 @@ pub fn import_metadata_from_system() -> Result<Metadata, String> {
                 continue;
             },
         };
-        let events = match crate::windows::get_evt_provider_events(&provider_name, &h_provmeta, &h_evtenum) {
+        let names = get_evt_provider_name_tables(&provider_name, &h_provmeta);
+        let events = match crate::windows::get_evt_provider_events(&provider_name, &h_provmeta, &h_evtenum, &names) {
             Ok(map) => map,
             Err(e) => {
                 warn!("Unable to enumerate events from provider '{}': error {}",
@@ -88,6 +307,10 @@ pub fn import_metadata_from_system() -> Result<Metadata, String> {
             Ok(o) => { warn!("Unexpected type for provider {} parameter filepath: {:?}", provider_name, o); None },
             Err(e) => { warn!("Unable to query provider {} parameter filepath: {}", provider_name, e); None },
         };
+        let parameters = match &parameter_file_path {
+            Some(paths) => load_parameter_messages(paths, &mut parameter_files),
+            None => BTreeMap::new(),
+        };
         let message_file_path = match get_evt_provider_metadata(&h_provmeta, EvtPublisherMetadataMessageFilePath) {
             Ok(EvtVariant::String(s)) => Some(s),
             // Docs say EvtPublisherMetadataMessageFilePath is a String, actually it can also be Null
@@ -124,38 +347,232 @@ pub fn import_metadata_from_system() -> Result<Metadata, String> {
             parameter_file_path,
             message_file_path,
             message,
+            parameters,
+            channels: BTreeMap::new(),
+            levels: names.levels,
+            tasks: names.tasks,
+            opcodes: names.opcodes,
+            keywords: names.keywords,
             events,
         });
     }
+    set_metadata_source(&mut metadata, &get_system_metadata_source());
     info!("Metadata import successful.");
     Ok(metadata)
 }
 
-pub fn update_metadata_with(known_meta: &mut Metadata, new_meta: &Metadata) {
+fn format_sources(sources: &[MetadataSource]) -> String {
+    if sources.is_empty() {
+        return "unknown source".to_owned();
+    }
+    sources.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(" + ")
+}
+
+fn format_fields(fields: &[EventFieldDefinition]) -> String {
+    format!("[{}]", fields.iter().map(|f| format!("{}:{}", f.name, f.out_type)).collect::<Vec<String>>().join(", "))
+}
+
+// Sets the source of all definitions which don't have one yet (i.e. which were just read)
+pub fn set_metadata_source(metadata: &mut Metadata, source: &MetadataSource) {
+    for prov_meta in metadata.values_mut() {
+        for versions in prov_meta.events.values_mut() {
+            for event_def in versions.values_mut() {
+                if event_def.sources.is_empty() {
+                    event_def.sources.push(source.to_owned());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn get_system_metadata_source() -> MetadataSource {
+    MetadataSource {
+        os_build: match get_os_build() {
+            Ok(build) => Some(build),
+            Err(e) => { verbose!("Unable to query OS build number: {}", e); None },
+        },
+        host: std::env::var("COMPUTERNAME").ok(),
+        file: None,
+    }
+}
+
+fn fill_blank<T: Clone>(known: &mut Option<T>, new: &Option<T>, policy: MergePolicy) {
+    match policy {
+        MergePolicy::Keep => (),
+        MergePolicy::Fill => if known.is_none() { *known = new.to_owned() },
+        MergePolicy::Replace => if new.is_some() { *known = new.to_owned() },
+    }
+}
+
+// Merges entries of a table of names or settings (e.g. channels or parameter messages)
+fn merge_table<K: Ord + Clone, V: Clone>(known: &mut BTreeMap<K, V>, new: &BTreeMap<K, V>, policy: MergePolicy) {
+    for (key, value) in new {
+        match policy {
+            MergePolicy::Keep => (),
+            MergePolicy::Fill => { known.entry(key.to_owned()).or_insert_with(|| value.to_owned()); },
+            MergePolicy::Replace => { known.insert(key.to_owned(), value.to_owned()); },
+        }
+    }
+}
+
+// Merges new_def into event_def according to the given policy. Returns a description of
+// the conflict if both definitions disagree on anything else than blanks.
+fn merge_event_definition(event_def: &mut EventDefinition, new_def: &EventDefinition, policy: MergePolicy) -> Option<String> {
+    let mut conflicts = vec![];
+    if let (Some(known), Some(new)) = (&event_def.message, &new_def.message) {
+        if known != new {
+            conflicts.push("message".to_owned());
+        }
+    }
+    // A definition which has the same fields as another, plus some extra trailing ones, is
+    // usually just a newer version of the same event which was extended
+    let common_len = std::cmp::min(event_def.fields.len(), new_def.fields.len());
+    let fields_extend = event_def.fields[..common_len] == new_def.fields[..common_len];
+    if !fields_extend {
+        conflicts.push(format!("fields {} vs {}", format_fields(&event_def.fields), format_fields(&new_def.fields)));
+    }
+    if policy == MergePolicy::Keep {
+        return if conflicts.is_empty() { None } else { Some(conflicts.join(", ")) };
+    }
+
+    fill_blank(&mut event_def.message, &new_def.message, policy);
+    fill_blank(&mut event_def.channel, &new_def.channel, policy);
+    fill_blank(&mut event_def.level_name, &new_def.level_name, policy);
+    fill_blank(&mut event_def.opcode_name, &new_def.opcode_name, policy);
+    fill_blank(&mut event_def.task_name, &new_def.task_name, policy);
+    if event_def.keyword_names.is_empty() || (policy == MergePolicy::Replace && !new_def.keyword_names.is_empty()) {
+        event_def.keyword_names = new_def.keyword_names.to_owned();
+    }
+    if fields_extend && new_def.fields.len() > event_def.fields.len() {
+        event_def.fields.extend_from_slice(&new_def.fields[common_len..]);
+    } else if !fields_extend && policy == MergePolicy::Replace {
+        event_def.fields = new_def.fields.to_owned();
+    }
+
+    if !conflicts.is_empty() && policy == MergePolicy::Replace {
+        event_def.sources = new_def.sources.to_owned();
+    } else if conflicts.is_empty() {
+        for source in &new_def.sources {
+            if !event_def.sources.contains(source) {
+                event_def.sources.push(source.to_owned());
+            }
+        }
+    }
+    if conflicts.is_empty() { None } else { Some(conflicts.join(", ")) }
+}
+
+pub fn update_metadata_with(known_meta: &mut Metadata, new_meta: &Metadata, policy: MergePolicy) {
+    let mut conflict_count = 0;
     for (provider_name, new_prov_meta) in new_meta {
         let known_prov_meta = known_meta.entry(provider_name.to_owned()).or_insert(new_prov_meta.to_owned());
-        if let Some(guid) = &new_prov_meta.guid {
-            known_prov_meta.guid = Some(guid.to_owned());
+        fill_blank(&mut known_prov_meta.guid, &new_prov_meta.guid, policy);
+        fill_blank(&mut known_prov_meta.resource_file_path, &new_prov_meta.resource_file_path, policy);
+        fill_blank(&mut known_prov_meta.parameter_file_path, &new_prov_meta.parameter_file_path, policy);
+        fill_blank(&mut known_prov_meta.message_file_path, &new_prov_meta.message_file_path, policy);
+        fill_blank(&mut known_prov_meta.message, &new_prov_meta.message, policy);
+        merge_table(&mut known_prov_meta.channels, &new_prov_meta.channels, policy);
+        merge_table(&mut known_prov_meta.parameters, &new_prov_meta.parameters, policy);
+        for (known_names, new_names) in [
+            (&mut known_prov_meta.levels, &new_prov_meta.levels),
+            (&mut known_prov_meta.tasks, &new_prov_meta.tasks),
+            (&mut known_prov_meta.opcodes, &new_prov_meta.opcodes),
+            (&mut known_prov_meta.keywords, &new_prov_meta.keywords),
+        ] {
+            merge_table(known_names, new_names, policy);
         }
         for (eventid, new_versions) in &new_prov_meta.events {
-            let known_versions = known_prov_meta.events.entry(eventid.to_owned()).or_insert(BTreeMap::new());
+            let known_versions = known_prov_meta.events.entry(eventid.to_owned()).or_default();
             for (version, new_def) in new_versions {
                 match known_versions.get_mut(version) {
                     // If we didn't know anything about that event, use it, it can't be worse
                     None => { known_versions.insert(version.to_owned(), new_def.to_owned()); },
-                    // We knew about this event, use new_meta to fill the blanks
+                    // We knew about this event, merge both according to the policy
                     Some(event_def) => {
-                        if let Some(message) = &new_def.message {
-                            event_def.message = Some(message.to_owned());
-                        }
-                        for (i, def) in new_def.fields.iter().enumerate() {
-                            event_def.fields[i] = def.to_owned();
+                        let known_sources = format_sources(&event_def.sources);
+                        if let Some(conflict) = merge_event_definition(event_def, new_def, policy) {
+                            conflict_count += 1;
+                            verbose!("Conflicting definitions of {} event {} version {} ({}) from {} and {}, {}",
+                                     provider_name, eventid, version, conflict, known_sources,
+                                     format_sources(&new_def.sources),
+                                     if policy == MergePolicy::Replace { "using the latter" } else { "using the former" });
                         }
                     }
                 }
             }
         }
     }
+    if conflict_count > 0 {
+        info!("{} conflicting event definitions merged using policy {:?} (use -v for details)", conflict_count, policy);
+    }
+}
+
+// Lists differences between two sets of metadata, one per line, prefixed with + (only in
+// new_meta), - (only in old_meta), or ~ (in both, but different)
+pub fn diff_metadata(old_meta: &Metadata, new_meta: &Metadata) -> Vec<String> {
+    let mut res = vec![];
+    let mut provider_names: Vec<&String> = old_meta.keys().chain(new_meta.keys()).collect();
+    provider_names.sort();
+    provider_names.dedup();
+    for provider_name in provider_names {
+        let (old_prov_meta, new_prov_meta) = match (old_meta.get(provider_name), new_meta.get(provider_name)) {
+            (Some(old), Some(new)) => (old, new),
+            (Some(old), None) => {
+                res.push(format!("- {} ({} events)", provider_name, old.events.len()));
+                continue;
+            },
+            (None, Some(new)) => {
+                res.push(format!("+ {} ({} events)", provider_name, new.events.len()));
+                continue;
+            },
+            (None, None) => continue,
+        };
+        if old_prov_meta.guid != new_prov_meta.guid {
+            res.push(format!("~ {}: guid {:?} -> {:?}", provider_name, old_prov_meta.guid, new_prov_meta.guid));
+        }
+        let mut events: Vec<(u64, u64)> = old_prov_meta.events.iter().chain(new_prov_meta.events.iter())
+            .flat_map(|(id, versions)| versions.keys().map(move |version| (*id, *version)))
+            .collect();
+        events.sort();
+        events.dedup();
+        for (id, version) in events {
+            let old_def = old_prov_meta.events.get(&id).and_then(|v| v.get(&version));
+            let new_def = new_prov_meta.events.get(&id).and_then(|v| v.get(&version));
+            let name = format!("{}/{}/{}", provider_name, id, version);
+            let (old_def, new_def) = match (old_def, new_def) {
+                (Some(old), Some(new)) => (old, new),
+                (Some(old), None) => {
+                    res.push(format!("- {} (from {})", name, format_sources(&old.sources)));
+                    continue;
+                },
+                (None, Some(new)) => {
+                    res.push(format!("+ {} (from {})", name, format_sources(&new.sources)));
+                    continue;
+                },
+                (None, None) => continue,
+            };
+            if old_def.message != new_def.message {
+                res.push(format!("~ {}: message {:?} -> {:?}", name, old_def.message, new_def.message));
+            }
+            if old_def.fields != new_def.fields {
+                res.push(format!("~ {}: fields {} -> {}", name, format_fields(&old_def.fields), format_fields(&new_def.fields)));
+            }
+            for (attribute, old_value, new_value) in &[
+                ("channel", &old_def.channel, &new_def.channel),
+                ("level", &old_def.level_name, &new_def.level_name),
+                ("opcode", &old_def.opcode_name, &new_def.opcode_name),
+                ("task", &old_def.task_name, &new_def.task_name),
+            ] {
+                if old_value != new_value {
+                    res.push(format!("~ {}: {} {:?} -> {:?}", name, attribute, old_value, new_value));
+                }
+            }
+            if old_def.keyword_names != new_def.keyword_names {
+                res.push(format!("~ {}: keywords {:?} -> {:?}", name, old_def.keyword_names, new_def.keyword_names));
+            }
+        }
+    }
+    res
 }
 
 pub fn export_metadata_to_file(metadata: &Metadata,
@@ -169,11 +586,11 @@ pub fn export_metadata_to_file(metadata: &Metadata,
     };
     let json = match json {
         Ok(s) => s,
-        Err(e) => return Err(format!("Unable to serialize metadata to JSON: {}", e.to_string())),
+        Err(e) => return Err(format!("Unable to serialize metadata to JSON: {}", e)),
     };
-    match out_file.write(json.as_bytes()) {
+    match out_file.write_all(json.as_bytes()) {
         Ok(_) => Ok(()),
-        Err(e) => Err(format!("Unable to write serialized metadata: {}", e.to_string())),
+        Err(e) => Err(format!("Unable to write serialized metadata: {}", e)),
     }
 }
 
@@ -182,7 +599,133 @@ pub fn import_metadata_from_file(in_file: &mut std::fs::File) -> Result<Metadata
     let mut buf_read = std::io::BufReader::new(in_file);
     let metadata : Metadata = match serde_json::from_reader(&mut buf_read) {
         Ok(v) => v,
-        Err(e) => return Err(format!("Cannot deserialize JSON metadata from file: {}", e.to_string())),
+        Err(e) => return Err(format!("Cannot deserialize JSON metadata from file: {}", e)),
     };
     Ok(metadata)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str) -> EventFieldDefinition {
+        EventFieldDefinition { name: name.to_owned(), out_type: "xs:string".to_owned() }
+    }
+
+    fn event(message: Option<&str>, task_name: Option<&str>, fields: &[&str], host: &str) -> EventDefinition {
+        EventDefinition {
+            channel: Some("Security".to_owned()),
+            message: message.map(|m| m.to_owned()),
+            level: 0,
+            level_name: None,
+            opcode: 0,
+            opcode_name: None,
+            task: 12544,
+            task_name: task_name.map(|t| t.to_owned()),
+            keywords: 0,
+            keyword_names: vec![],
+            fields: fields.iter().map(|f| field(f)).collect(),
+            sources: vec![MetadataSource { os_build: None, host: Some(host.to_owned()), file: None }],
+        }
+    }
+
+    fn provider(guid: Option<&str>, tasks: &[(u64, &str)], events: Vec<(u64, EventDefinition)>) -> Metadata {
+        let prov_meta = ProviderMetadata {
+            guid: guid.map(|g| g.to_owned()),
+            resource_file_path: None,
+            parameter_file_path: None,
+            message_file_path: None,
+            message: None,
+            parameters: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            levels: BTreeMap::new(),
+            tasks: tasks.iter().map(|(value, name)| (*value, name.to_string())).collect(),
+            opcodes: BTreeMap::new(),
+            keywords: BTreeMap::new(),
+            events: events.into_iter().map(|(id, def)| (id, vec![(0, def)].into_iter().collect())).collect(),
+        };
+        vec![("Provider".to_owned(), prov_meta)].into_iter().collect()
+    }
+
+    // Merges a definition from a second host into one from a first host, with conflicting
+    // messages and fields, a blank task name, an unknown event and an unknown task name
+    fn merge(policy: MergePolicy) -> ProviderMetadata {
+        let mut known = provider(None, &[(1, "Logon")], vec![
+            (4624, event(Some("Logged on"), None, &["SubjectUserSid", "TargetUserSid"], "first")),
+        ]);
+        let new = provider(Some("{GUID}"), &[(1, "Logon/Logoff"), (2, "Logoff")], vec![
+            (4624, event(Some("An account was logged on"), Some("Logon"), &["SubjectUserSid", "LogonType"], "second")),
+            (4634, event(Some("Logged off"), None, &[], "second")),
+        ]);
+        update_metadata_with(&mut known, &new, policy);
+        known.remove("Provider").unwrap()
+    }
+
+    fn names(fields: &[EventFieldDefinition]) -> Vec<&str> {
+        fields.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    #[test]
+    fn fill_only_fills_blanks() {
+        let merged = merge(MergePolicy::Fill);
+        assert_eq!(merged.guid.as_deref(), Some("{GUID}"));
+        assert_eq!(merged.tasks.get(&1).map(String::as_str), Some("Logon"));
+        assert_eq!(merged.tasks.get(&2).map(String::as_str), Some("Logoff"));
+        let def = &merged.events[&4624][&0];
+        assert_eq!(def.message.as_deref(), Some("Logged on"));
+        assert_eq!(def.task_name.as_deref(), Some("Logon"));
+        assert_eq!(names(&def.fields), vec!["SubjectUserSid", "TargetUserSid"]);
+        assert_eq!(def.sources.len(), 1);
+        assert!(merged.events.contains_key(&4634));
+    }
+
+    #[test]
+    fn keep_never_modifies_known_definitions() {
+        let merged = merge(MergePolicy::Keep);
+        assert_eq!(merged.guid, None);
+        assert_eq!(merged.tasks.len(), 1);
+        assert_eq!(merged.tasks.get(&1).map(String::as_str), Some("Logon"));
+        let def = &merged.events[&4624][&0];
+        assert_eq!(def.message.as_deref(), Some("Logged on"));
+        assert_eq!(def.task_name, None);
+        assert_eq!(names(&def.fields), vec!["SubjectUserSid", "TargetUserSid"]);
+        assert_eq!(def.sources.len(), 1);
+        // Unknown events are still added
+        assert!(merged.events.contains_key(&4634));
+    }
+
+    #[test]
+    fn replace_uses_the_last_definition() {
+        let merged = merge(MergePolicy::Replace);
+        assert_eq!(merged.guid.as_deref(), Some("{GUID}"));
+        assert_eq!(merged.tasks.get(&1).map(String::as_str), Some("Logon/Logoff"));
+        assert_eq!(merged.tasks.get(&2).map(String::as_str), Some("Logoff"));
+        let def = &merged.events[&4624][&0];
+        assert_eq!(def.message.as_deref(), Some("An account was logged on"));
+        assert_eq!(def.task_name.as_deref(), Some("Logon"));
+        assert_eq!(names(&def.fields), vec!["SubjectUserSid", "LogonType"]);
+        assert_eq!(def.sources[0].host.as_deref(), Some("second"));
+        assert!(merged.events.contains_key(&4634));
+    }
+
+    #[test]
+    fn extended_definitions_are_merged() {
+        for policy in [MergePolicy::Fill, MergePolicy::Replace] {
+            let mut known = provider(None, &[], vec![(1, event(None, None, &["A"], "first"))]);
+            let new = provider(None, &[], vec![(1, event(Some("Message"), None, &["A", "B"], "second"))]);
+            update_metadata_with(&mut known, &new, policy);
+            let def = &known["Provider"].events[&1][&0];
+            assert_eq!(names(&def.fields), vec!["A", "B"], "{:?}", policy);
+            assert_eq!(def.message.as_deref(), Some("Message"), "{:?}", policy);
+            assert_eq!(def.sources.len(), 2, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn parses_merge_policies() {
+        assert_eq!(MergePolicy::from_str("Fill"), Ok(MergePolicy::Fill));
+        assert_eq!(MergePolicy::from_str("keep"), Ok(MergePolicy::Keep));
+        assert_eq!(MergePolicy::from_str("REPLACE"), Ok(MergePolicy::Replace));
+        assert!(MergePolicy::from_str("merge").is_err());
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::BTreeMap;
+
+/*
+ * Platform-neutral implementation of the FormatMessage() template syntax, so that messages
+ * can be formatted offline from exported metadata, without access to the provider's DLLs.
+ * See https://docs.microsoft.com/en-us/windows/win32/api/winbase/nf-winbase-formatmessage
+ *
+ * Supported syntax:
+ * %1 .. %99          insert argument N (as if it was %N!s!)
+ * %N!printf-spec!    insert argument N formatted with a printf() specification (flags, width,
+ *                    precision, * for width or precision taken from the next argument(s),
+ *                    size prefixes h l ll w I I32 I64, and types c C d i o u x X e E f g G a A p s S Z)
+ * %%N                insert parameter message N (as used by event providers, e.g. %%1842)
+ * %n %r %t           CRLF, bare CR, tab
+ * %0                 end the message here
+ * %. %! %% %<space>  literal '.', '!', '%', ' '
+ */
+
+#[derive(Debug, Clone)]
+pub enum MessageArg {
+    String(String),
+    Int(i64),
+    UInt(u64),
+    Double(f64),
+}
+
+// Widths and precisions can come from event values: larger ones are rejected instead of padding
+// values with that many characters
+const MAX_WIDTH: u64 = 4096;
+
+#[derive(Debug, Default)]
+struct FormatSpec {
+    left_align: bool,
+    plus_sign: bool,
+    space_sign: bool,
+    alternate: bool,
+    zero_pad: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+    wide_int: bool, // argument is a 64-bit integer (ll, I64, or I on 64-bit hosts)
+    conversion: char,
+}
+
+impl MessageArg {
+    fn as_i64(&self) -> Option<i64> {
+        match self {
+            MessageArg::Int(i) => Some(*i),
+            MessageArg::UInt(u) => Some(*u as i64),
+            MessageArg::Double(d) => Some(*d as i64),
+            MessageArg::String(s) => parse_integer(s),
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            MessageArg::Int(i) => Some(*i as f64),
+            MessageArg::UInt(u) => Some(*u as f64),
+            MessageArg::Double(d) => Some(*d),
+            MessageArg::String(s) => s.trim().parse::<f64>().ok(),
+        }
+    }
+
+    fn to_display_string(&self) -> String {
+        match self {
+            MessageArg::String(s) => s.to_owned(),
+            MessageArg::Int(i) => i.to_string(),
+            MessageArg::UInt(u) => u.to_string(),
+            MessageArg::Double(d) => d.to_string(),
+        }
+    }
+}
+
+// Event fields are very often rendered as strings by the EventLog API, even when a numeric
+// format is requested in the template: accept decimal and 0x-prefixed hexadecimal strings.
+fn parse_integer(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let value = if digits.starts_with("0x") || digits.starts_with("0X") {
+        u64::from_str_radix(&digits[2..], 16).ok()? as i64
+    } else {
+        digits.parse::<u64>().ok()? as i64
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+fn bounded_width(value: u64, what: &str) -> Result<usize, String> {
+    if value > MAX_WIDTH {
+        return Err(format!("Format {} {} is larger than {}", what, value, MAX_WIDTH));
+    }
+    Ok(value as usize)
+}
+
+fn parse_spec(spec: &str) -> Result<(FormatSpec, bool, bool), String> {
+    let mut res = FormatSpec::default();
+    let chars: Vec<char> = spec.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '-' => res.left_align = true,
+            '+' => res.plus_sign = true,
+            ' ' => res.space_sign = true,
+            '#' => res.alternate = true,
+            '0' => res.zero_pad = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let mut width_from_arg = false;
+    if i < chars.len() && chars[i] == '*' {
+        width_from_arg = true;
+        i += 1;
+    } else {
+        let start = i;
+        while i < chars.len() && chars[i].is_ascii_digit() {
+            i += 1;
+        }
+        if i > start {
+            let width = chars[start..i].iter().collect::<String>().parse().unwrap_or(u64::MAX);
+            res.width = Some(bounded_width(width, "width")?);
+        }
+    }
+    let mut precision_from_arg = false;
+    if i < chars.len() && chars[i] == '.' {
+        i += 1;
+        if i < chars.len() && chars[i] == '*' {
+            precision_from_arg = true;
+            i += 1;
+        } else {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let precision = match chars[start..i].iter().collect::<String>() {
+                digits if digits.is_empty() => 0,
+                digits => digits.parse().unwrap_or(u64::MAX),
+            };
+            res.precision = Some(bounded_width(precision, "precision")?);
+        }
+    }
+    let rest: String = chars[i..].iter().collect();
+    let size_prefixes: &[(&str, bool)] = &[("I64", true), ("I32", false), ("ll", true), ("hh", false),
+        ("h", false), ("l", false), ("L", false), ("w", false), ("z", true), ("j", true), ("t", true),
+        ("I", cfg!(target_pointer_width = "64"))];
+    let mut rest = &rest[..];
+    for (prefix, wide) in size_prefixes {
+        if rest.starts_with(prefix) && rest.len() > prefix.len() {
+            res.wide_int = *wide;
+            rest = &rest[prefix.len()..];
+            break;
+        }
+    }
+    let mut rest = rest.chars();
+    res.conversion = match (rest.next(), rest.next()) {
+        (Some(c), None) if "cCdiouxXeEfgGaApnsSZ".contains(c) => c,
+        _ => return Err(format!("Unsupported format specification '!{}!'", spec)),
+    };
+    Ok((res, width_from_arg, precision_from_arg))
+}
+
+// Pads an already formatted (sign + prefix + digits) number, zero padding going after the
+// sign and prefix as printf() does
+fn pad_number(sign_and_prefix: &str, digits: &str, spec: &FormatSpec, allow_zero_pad: bool) -> String {
+    let len = sign_and_prefix.chars().count() + digits.chars().count();
+    let width = spec.width.unwrap_or(0);
+    if len >= width {
+        return format!("{}{}", sign_and_prefix, digits);
+    }
+    let padding = width - len;
+    if spec.left_align {
+        format!("{}{}{}", sign_and_prefix, digits, " ".repeat(padding))
+    } else if spec.zero_pad && allow_zero_pad {
+        format!("{}{}{}", sign_and_prefix, "0".repeat(padding), digits)
+    } else {
+        format!("{}{}{}", " ".repeat(padding), sign_and_prefix, digits)
+    }
+}
+
+fn pad_string(s: &str, spec: &FormatSpec) -> String {
+    let s: String = match spec.precision {
+        Some(p) => s.chars().take(p).collect(),
+        None => s.to_owned(),
+    };
+    let len = s.chars().count();
+    let width = spec.width.unwrap_or(0);
+    if len >= width {
+        s
+    } else if spec.left_align {
+        format!("{}{}", s, " ".repeat(width - len))
+    } else {
+        format!("{}{}", " ".repeat(width - len), s)
+    }
+}
+
+// Formats a float in C's %e style: at least two exponent digits, with an explicit sign
+fn format_exponent(value: f64, precision: usize, upper: bool) -> String {
+    let s = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = s.split_at(s.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap_or(0);
+    let res = format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs());
+    if upper { res.to_uppercase() } else { res }
+}
+
+fn strip_trailing_zeros(s: &str) -> String {
+    let (mantissa, exponent) = match s.find(['e', 'E']) {
+        Some(pos) => s.split_at(pos),
+        None => (s, ""),
+    };
+    if !mantissa.contains('.') {
+        return s.to_owned();
+    }
+    format!("{}{}", mantissa.trim_end_matches('0').trim_end_matches('.'), exponent)
+}
+
+fn format_float(value: f64, spec: &FormatSpec) -> String {
+    let upper = spec.conversion.is_ascii_uppercase();
+    if !value.is_finite() {
+        let s = if value.is_nan() { "nan" } else { "inf" };
+        let s = if upper { s.to_uppercase() } else { s.to_owned() };
+        let sign = if value.is_sign_negative() && !value.is_nan() { "-" } else { "" };
+        return pad_number(sign, &s, spec, false);
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let digits = match spec.conversion {
+        'f' => format!("{:.*}", precision, value.abs()),
+        'e' | 'E' => format_exponent(value.abs(), precision, upper),
+        'a' | 'A' => {
+            // Hexadecimal floating point is rare enough in message templates that we only
+            // render its exact value, in the same form as printf() without precision
+            let bits = value.abs().to_bits();
+            let exponent = ((bits >> 52) & 0x7FF) as i64;
+            let mantissa = bits & 0xF_FFFF_FFFF_FFFF;
+            let s = if exponent == 0 && mantissa == 0 {
+                "0x0p+0".to_owned()
+            } else {
+                let (lead, exponent) = if exponent == 0 { (0, -1022) } else { (1, exponent - 1023) };
+                let mantissa = format!("{:013x}", mantissa);
+                let mantissa = mantissa.trim_end_matches('0');
+                let dot = if !mantissa.is_empty() { "." } else { "" };
+                format!("0x{}{}{}p{}{}", lead, dot, mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+            };
+            if upper { s.to_uppercase() } else { s }
+        },
+        _ => {
+            // %g: precision is the number of significant digits, and the shortest of %e and
+            // %f is chosen depending on the exponent
+            let precision = if precision == 0 { 1 } else { precision };
+            let exponent = if value == 0.0 {
+                0
+            } else {
+                let s = format_exponent(value.abs(), precision - 1, false);
+                s[s.find('e').unwrap() + 1..].parse::<i32>().unwrap_or(0)
+            };
+            let s = if exponent < -4 || exponent >= precision as i32 {
+                format_exponent(value.abs(), precision - 1, upper)
+            } else {
+                format!("{:.*}", (precision as i32 - 1 - exponent).max(0) as usize, value.abs())
+            };
+            if spec.alternate { s } else { strip_trailing_zeros(&s) }
+        },
+    };
+    let sign = if value.is_sign_negative() && value != 0.0 {
+        "-"
+    } else if spec.plus_sign {
+        "+"
+    } else if spec.space_sign {
+        " "
+    } else {
+        ""
+    };
+    pad_number(sign, &digits, spec, true)
+}
+
+fn format_integer(value: i64, spec: &FormatSpec) -> String {
+    let (sign, digits, prefix) = match spec.conversion {
+        'd' | 'i' => {
+            let value = if spec.wide_int { value } else { value as i32 as i64 };
+            let sign = if value < 0 {
+                "-"
+            } else if spec.plus_sign {
+                "+"
+            } else if spec.space_sign {
+                " "
+            } else {
+                ""
+            };
+            (sign, (value as i128).abs().to_string(), "")
+        },
+        // Pointers are not truncated by size prefixes (events are almost always from 64-bit hosts)
+        'p' => ("", format!("{:016X}", value as u64), ""),
+        conversion => {
+            let value = if spec.wide_int { value as u64 } else { value as u32 as u64 };
+            match conversion {
+                'o' => ("", format!("{:o}", value), if spec.alternate && value != 0 { "0" } else { "" }),
+                'x' => ("", format!("{:x}", value), if spec.alternate && value != 0 { "0x" } else { "" }),
+                'X' => ("", format!("{:X}", value), if spec.alternate && value != 0 { "0X" } else { "" }),
+                _ => ("", value.to_string(), ""), // 'u'
+            }
+        },
+    };
+    // An explicit precision is the minimal number of digits, and disables zero padding
+    let digits = match spec.precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(p) if digits.len() < p => format!("{}{}", "0".repeat(p - digits.len()), digits),
+        _ => digits,
+    };
+    pad_number(&format!("{}{}", sign, prefix), &digits, spec, spec.precision.is_none())
+}
+
+fn format_arg(arg: &MessageArg, spec: &FormatSpec) -> String {
+    match spec.conversion {
+        's' | 'S' | 'Z' => pad_string(&arg.to_display_string(), spec),
+        'c' | 'C' => {
+            let c = match arg {
+                MessageArg::String(s) => s.chars().next(),
+                other => other.as_i64().and_then(|i| std::char::from_u32(i as u32)),
+            };
+            pad_string(&c.map(|c| c.to_string()).unwrap_or_default(), spec)
+        },
+        'n' => String::new(),
+        'e' | 'E' | 'f' | 'g' | 'G' | 'a' | 'A' => match arg.as_f64() {
+            Some(f) => format_float(f, spec),
+            None => pad_string(&arg.to_display_string(), spec),
+        },
+        _ => match arg.as_i64() {
+            Some(i) => format_integer(i, spec),
+            None => pad_string(&arg.to_display_string(), spec),
+        },
+    }
+}
+
+fn take_digits(chars: &[char], start: usize, max: usize) -> usize {
+    chars[start..].iter().take(max).take_while(|c| c.is_ascii_digit()).count()
+}
+
+// Formats a message template. get_arg is called with 1-indexed argument numbers, and can fail
+// (e.g. when the referenced argument does not exist or cannot be decoded).
+pub fn format_message_template<F>(template: &str,
+                                  parameters: &BTreeMap<u32, String>,
+                                  mut get_arg: F) -> Result<String, String>
+    where F: FnMut(u32) -> Result<MessageArg, String>
+{
+    let chars: Vec<char> = template.chars().collect();
+    let mut res = String::with_capacity(template.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '%' {
+            res.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let c = match chars.get(i) {
+            Some(c) => *c,
+            None => break, // a trailing lone % is ignored
+        };
+        match c {
+            'n' => res.push_str("\r\n"),
+            'r' => res.push('\r'),
+            't' => res.push('\t'),
+            '0' => return Ok(res),
+            '%' => {
+                let digits = take_digits(&chars, i + 1, 10);
+                if digits > 0 {
+                    let reference: String = chars[i + 1..i + 1 + digits].iter().collect();
+                    match reference.parse::<u32>().ok().and_then(|id| parameters.get(&id)) {
+                        Some(text) => res.push_str(text),
+                        // Keep unresolved references as-is, they are more useful than a lone %N
+                        None => { res.push_str("%%"); res.push_str(&reference); },
+                    }
+                    i += digits;
+                } else {
+                    res.push('%');
+                }
+            },
+            c if c.is_ascii_digit() => {
+                let digits = take_digits(&chars, i, 2);
+                let mut arg_num: u32 = chars[i..i + digits].iter().collect::<String>().parse().unwrap();
+                i += digits - 1;
+                let mut spec = FormatSpec { conversion: 's', ..FormatSpec::default() };
+                if chars.get(i + 1) == Some(&'!') {
+                    let end = match chars[i + 2..].iter().position(|&c| c == '!') {
+                        Some(len) => i + 2 + len,
+                        None => return Err(format!("Unterminated format specification after %{}", arg_num)),
+                    };
+                    let (parsed, width_from_arg, precision_from_arg) =
+                        parse_spec(&chars[i + 2..end].iter().collect::<String>())?;
+                    spec = parsed;
+                    if width_from_arg {
+                        let width = get_arg(arg_num)?.as_i64().unwrap_or(0);
+                        if width < 0 {
+                            spec.left_align = true;
+                        }
+                        spec.width = Some(bounded_width(width.unsigned_abs(), "width")?);
+                        arg_num += 1;
+                    }
+                    if precision_from_arg {
+                        let precision = get_arg(arg_num)?.as_i64().unwrap_or(0);
+                        spec.precision = if precision < 0 { None } else { Some(bounded_width(precision as u64, "precision")?) };
+                        arg_num += 1;
+                    }
+                    i = end;
+                }
+                let arg = get_arg(arg_num)?;
+                let formatted = format_arg(&arg, &spec);
+                // Inserted values can themselves reference parameter messages (e.g. "%%8100")
+                match arg {
+                    MessageArg::String(_) => res.push_str(&expand_parameter_messages(&formatted, parameters)),
+                    _ => res.push_str(&formatted),
+                }
+            },
+            // %. %! % and any other escaped character are output literally
+            other => res.push(other),
+        }
+        i += 1;
+    }
+    Ok(res)
+}
+
+// Replaces %%N references to parameter message strings (e.g. "%%8100" in Security events) with
+// their text from the provider's parameter message file. Unknown references are left as-is.
+// Unlike templates, inserted values and event fields have no escapes of their own.
+pub fn expand_parameter_messages(s: &str, parameters: &BTreeMap<u32, String>) -> String {
+    if parameters.is_empty() || !s.contains("%%") {
+        return s.to_owned();
+    }
+    let mut res = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find("%%") {
+        res.push_str(&rest[..pos]);
+        let digits = rest[pos + 2..].chars().take_while(|c| c.is_ascii_digit()).count();
+        match rest[pos + 2..pos + 2 + digits].parse::<u32>().ok().and_then(|id| parameters.get(&id)) {
+            Some(text) => res.push_str(text),
+            None => res.push_str(&rest[pos..pos + 2 + digits]),
+        }
+        rest = &rest[pos + 2 + digits..];
+    }
+    res.push_str(rest);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(template: &str, args: &[MessageArg]) -> Result<String, String> {
+        let parameters: BTreeMap<u32, String> = vec![(1842, "Yes".to_owned()), (8100, "Enabled".to_owned())].into_iter().collect();
+        format_message_template(template, &parameters, |n| match args.get(n as usize - 1) {
+            Some(arg) => Ok(arg.clone()),
+            None => Err(format!("No argument {}", n)),
+        })
+    }
+
+    fn s(text: &str) -> MessageArg {
+        MessageArg::String(text.to_owned())
+    }
+
+    #[test]
+    fn formats_inserts() {
+        let cases: &[(&str, &[MessageArg], &str)] = &[
+            ("%1", &[s("abc")], "abc"),
+            ("%1!s!", &[s("abc")], "abc"),
+            ("[%1!5s!]", &[s("abc")], "[  abc]"),
+            ("[%1!-5s!]", &[s("abc")], "[abc  ]"),
+            ("[%1!.2s!]", &[s("abc")], "[ab]"),
+            ("[%1!5.2s!]", &[s("abc")], "[   ab]"),
+            ("[%1!*s!]", &[MessageArg::Int(4), s("ab")], "[  ab]"),
+            ("[%1!*s!]", &[MessageArg::Int(-4), s("ab")], "[ab  ]"),
+            ("[%1!.*s!]", &[MessageArg::Int(1), s("ab")], "[a]"),
+            ("%2 %1", &[s("a"), s("b")], "b a"),
+            ("%10", &[s("1"), s("2"), s("3"), s("4"), s("5"), s("6"), s("7"), s("8"), s("9"), s("10")], "10"),
+            ("%1%2", &[s("a"), s("b")], "ab"),
+            ("%1!c!", &[MessageArg::UInt(65)], "A"),
+        ];
+        for (template, args, expected) in cases {
+            assert_eq!(format(template, args).as_deref(), Ok(*expected), "{}", template);
+        }
+    }
+
+    #[test]
+    fn rejects_huge_widths() {
+        let huge = MessageArg::Int(1 << 62);
+        assert_eq!(format("[%1!*s!]", &[huge.clone(), s("ab")]), Err("Format width 4611686018427387904 is larger than 4096".to_owned()));
+        assert_eq!(format("[%1!*s!]", &[MessageArg::Int(-(1 << 62)), s("ab")]), Err("Format width 4611686018427387904 is larger than 4096".to_owned()));
+        assert_eq!(format("[%1!.*f!]", &[huge, MessageArg::Double(1.0)]), Err("Format precision 4611686018427387904 is larger than 4096".to_owned()));
+        assert!(format("%1!99999999999999999999d!", &[MessageArg::Int(1)]).is_err());
+        assert!(format("%1!.5000s!", &[s("ab")]).is_err());
+        assert_eq!(format("[%1!*s!]", &[MessageArg::Int(4096), s("")]).map(|s| s.len()), Ok(4098));
+    }
+
+    #[test]
+    fn formats_numbers() {
+        let cases: &[(&str, MessageArg, &str)] = &[
+            ("%1!d!", MessageArg::Int(-42), "-42"),
+            ("%1!d!", s("42"), "42"),
+            ("%1!d!", s("0x2A"), "42"),
+            ("%1!+d!", MessageArg::Int(42), "+42"),
+            ("%1! d!", MessageArg::Int(42), " 42"),
+            ("[%1!5d!]", MessageArg::Int(-42), "[  -42]"),
+            ("[%1!-5d!]", MessageArg::Int(42), "[42   ]"),
+            ("[%1!05d!]", MessageArg::Int(-42), "[-0042]"),
+            ("[%1!.4d!]", MessageArg::Int(42), "[0042]"),
+            ("[%1!06.4d!]", MessageArg::Int(42), "[  0042]"),
+            ("[%1!.0d!]", MessageArg::Int(0), "[]"),
+            ("%1!d!", MessageArg::UInt(0xFFFF_FFFF), "-1"),
+            ("%1!I64d!", MessageArg::UInt(0xFFFF_FFFF), "4294967295"),
+            ("%1!u!", MessageArg::Int(-1), "4294967295"),
+            ("%1!llu!", MessageArg::Int(-1), "18446744073709551615"),
+            ("%1!x!", MessageArg::UInt(0x1_0000_00AB), "ab"),
+            ("%1!I64X!", MessageArg::UInt(0x1_0000_00AB), "1000000AB"),
+            ("%1!#x!", MessageArg::UInt(255), "0xff"),
+            ("%1!#X!", MessageArg::UInt(0), "0"),
+            ("[%1!#08x!]", MessageArg::UInt(255), "[0x0000ff]"),
+            ("%1!o!", MessageArg::UInt(8), "10"),
+            ("%1!#o!", MessageArg::UInt(8), "010"),
+            ("%1!f!", MessageArg::Double(1.5), "1.500000"),
+            ("%1!.2f!", MessageArg::Double(-1.005), "-1.00"),
+            ("[%1!8.3f!]", s("3.14159"), "[   3.142]"),
+            ("%1!e!", MessageArg::Double(12345.678), "1.234568e+04"),
+            ("%1!.2E!", MessageArg::Double(0.00012), "1.20E-04"),
+            ("%1!g!", MessageArg::Double(100000.0), "100000"),
+            ("%1!g!", MessageArg::Double(1000000.0), "1e+06"),
+            ("%1!g!", MessageArg::Double(0.0001), "0.0001"),
+            ("%1!G!", MessageArg::Double(0.00001), "1E-05"),
+            ("%1!#g!", MessageArg::Double(1.5), "1.50000"),
+            ("%1!a!", MessageArg::Double(1.0), "0x1p+0"),
+            ("%1!f!", MessageArg::Double(f64::NAN), "nan"),
+            ("%1!d!", s("not a number"), "not a number"),
+        ];
+        for (template, arg, expected) in cases {
+            assert_eq!(format(template, std::slice::from_ref(arg)).as_deref(), Ok(*expected), "{}", template);
+        }
+    }
+
+    #[test]
+    fn formats_pointers_without_truncation() {
+        let cases: &[(&str, MessageArg, &str)] = &[
+            ("%1!p!", MessageArg::UInt(0xDEAD_BEEF), "00000000DEADBEEF"),
+            ("%1!p!", MessageArg::UInt(0x7FF6_1234_5678), "00007FF612345678"),
+            ("%1!hp!", MessageArg::UInt(0x7FF6_1234_5678), "00007FF612345678"),
+            ("%1!I32p!", MessageArg::UInt(0x7FF6_1234_5678), "00007FF612345678"),
+            ("%1!p!", s("0x1000"), "0000000000001000"),
+        ];
+        for (template, arg, expected) in cases {
+            assert_eq!(format(template, std::slice::from_ref(arg)).as_deref(), Ok(*expected), "{}", template);
+        }
+    }
+
+    #[test]
+    fn formats_escapes_and_parameters() {
+        let cases: &[(&str, &str)] = &[
+            ("100%% sure", "100% sure"),
+            ("a%nb", "a\r\nb"),
+            ("a%rb%tc", "a\rb\tc"),
+            ("a%0b", "a"),
+            ("%.%!% %x", ".! x"),
+            ("trailing %", "trailing "),
+            ("%%1842", "Yes"),
+            ("%%1842%%8100", "YesEnabled"),
+            ("%%9999", "%%9999"),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(format(template, &[]).as_deref(), Ok(*expected), "{}", template);
+        }
+        // Inserted strings can reference parameter messages, but numbers are not expanded
+        assert_eq!(format("%1 %2", &[s("%%8100"), MessageArg::UInt(5)]).as_deref(), Ok("Enabled 5"));
+        assert_eq!(expand_parameter_messages("%%1842 and %%1", &BTreeMap::new()), "%%1842 and %%1");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(format("%1!s", &[s("a")]).is_err());
+        assert!(format("%1!k!", &[s("a")]).is_err());
+        assert!(format("%1!5!", &[s("a")]).is_err());
+        assert!(format("%2", &[s("a")]).is_err());
+        // Arguments are numbered up to 99, so %11 is not %1 followed by 1
+        assert!(format("%11", &[s("a")]).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+// Flag set in MESSAGE_RESOURCE_ENTRY.Flags when the text is UTF-16 encoded
+const MESSAGE_RESOURCE_UNICODE: u16 = 0x0001;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+/*
+ * Parses the contents of a RT_MESSAGETABLE resource, as laid out in memory
+ * (see MESSAGE_RESOURCE_DATA in winnt.h):
+ *
+ * u32 NumberOfBlocks
+ * MESSAGE_RESOURCE_BLOCK[NumberOfBlocks] { u32 LowId, u32 HighId, u32 OffsetToEntries }
+ * For each block, (HighId - LowId + 1) consecutive MESSAGE_RESOURCE_ENTRY {
+ *     u16 Length (including this header), u16 Flags, u8 Text[Length - 4]
+ * }
+ *
+ * Texts are returned verbatim, without their NULL padding.
+ */
+pub fn parse_message_table(data: &[u8]) -> Result<BTreeMap<u32, String>, String> {
+    let mut messages = BTreeMap::new();
+    let block_count = match read_u32(data, 0) {
+        Some(n) => n as usize,
+        None => return Err(format!("Message table too short ({} bytes)", data.len())),
+    };
+    for block_num in 0..block_count {
+        let block_offset = 4 + block_num * 12;
+        let (low_id, high_id, mut entry_offset) = match (read_u32(data, block_offset),
+                                                         read_u32(data, block_offset + 4),
+                                                         read_u32(data, block_offset + 8)) {
+            (Some(low), Some(high), Some(offset)) if low <= high => (low, high, offset as usize),
+            _ => return Err(format!("Invalid message table block #{}", block_num)),
+        };
+        for message_id in low_id..=high_id {
+            let (length, flags) = match (read_u16(data, entry_offset), read_u16(data, entry_offset + 2)) {
+                (Some(length), Some(flags)) if length >= 4 => (length as usize, flags),
+                _ => return Err(format!("Invalid message table entry for message ID {}", message_id)),
+            };
+            let text = match data.get(entry_offset + 4..entry_offset + length) {
+                Some(t) => t,
+                None => return Err(format!("Message ID {} overflows its message table", message_id)),
+            };
+            let text = if (flags & MESSAGE_RESOURCE_UNICODE) != 0 {
+                let text: Vec<u16> = text.chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&text)
+            } else {
+                // ANSI entries use the code page of the resource, which we can't know: only
+                // ASCII is reliably decoded
+                text.iter().map(|&b| b as char).collect()
+            };
+            messages.insert(message_id, text.trim_end_matches('\0').to_owned());
+            entry_offset += length;
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flags and text bytes
+    type Entry = (u16, Vec<u8>);
+
+    // Message table with one block per (low ID, entries)
+    fn message_table(blocks: &[(u32, Vec<Entry>)]) -> Vec<u8> {
+        let mut data = (blocks.len() as u32).to_le_bytes().to_vec();
+        let mut entries = vec![];
+        let mut entry_offset = 4 + blocks.len() * 12;
+        for (low_id, block_entries) in blocks {
+            data.extend_from_slice(&low_id.to_le_bytes());
+            data.extend_from_slice(&(low_id + block_entries.len() as u32 - 1).to_le_bytes());
+            data.extend_from_slice(&(entry_offset as u32).to_le_bytes());
+            for (flags, text) in block_entries {
+                entries.extend_from_slice(&(text.len() as u16 + 4).to_le_bytes());
+                entries.extend_from_slice(&flags.to_le_bytes());
+                entries.extend_from_slice(text);
+                entry_offset += text.len() + 4;
+            }
+        }
+        data.extend(entries);
+        data
+    }
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn parses_ansi_and_unicode_entries() {
+        let data = message_table(&[
+            (0x10, vec![(0, b"Ansi %1\r\n\0\0".to_vec()), (MESSAGE_RESOURCE_UNICODE, utf16("Unicode \u{e9}%2\0"))]),
+            (0x4000_0100, vec![(MESSAGE_RESOURCE_UNICODE, utf16("Other block\0\0\0"))]),
+        ]);
+        let messages = parse_message_table(&data).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[&0x10], "Ansi %1\r\n");
+        assert_eq!(messages[&0x11], "Unicode \u{e9}%2");
+        assert_eq!(messages[&0x4000_0100], "Other block");
+    }
+
+    #[test]
+    fn rejects_truncated_tables() {
+        assert!(parse_message_table(&[1, 0]).is_err());
+        let data = message_table(&[(1, vec![(0, b"First\0\0\0".to_vec()), (0, b"Second\0\0".to_vec())])]);
+        // Block header cut
+        assert!(parse_message_table(&data[..10]).is_err());
+        // Last entry cut, in its header then in its text
+        assert!(parse_message_table(&data[..data.len() - 10]).is_err());
+        assert!(parse_message_table(&data[..data.len() - 2]).is_err());
+        assert!(parse_message_table(&data).is_ok());
+    }
+
+    #[test]
+    fn rejects_id_ranges_beyond_entries() {
+        // One entry, but IDs up to the largest one: fails on the first missing entry
+        let mut data = message_table(&[(0xFFFF_FFF0, vec![(0, b"Text".to_vec())])]);
+        data[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(parse_message_table(&data), Err("Invalid message table entry for message ID 4294967281".to_owned()));
+        // Range with its low ID above its high ID
+        data[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        data[8..12].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(parse_message_table(&data), Err("Invalid message table block #0".to_owned()));
+    }
+}
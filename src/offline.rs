@@ -0,0 +1,606 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use crate::pe::{PeFile, ResourceId, RT_MESSAGETABLE};
+use crate::wevt::{parse_wevt_template, WevtProvider};
+use crate::msgtable::parse_message_table;
+use crate::metadata::{Metadata, ProviderMetadata, EventDefinition, ChannelConfig, MetadataSource};
+use crate::regf::{Hive, RegKey};
+use crate::winmeta::{
+    resolve_standard_name,
+    resolve_opcode_name,
+    resolve_keyword_names,
+    SYSTEM_CHANNELS,
+    SYSTEM_LEVELS,
+    SYSTEM_TASKS,
+};
+
+// Language used when a message table is available in more than one language
+const PREFERRED_LANGUAGE_ID: u32 = 0x0409; // en-US
+const PREFERRED_LANGUAGE_DIR: &str = "en-US";
+
+// Paths found in a copy of a Windows filesystem don't necessarily have the same case as
+// the one referenced in metadata (e.g. System32 vs system32)
+pub fn find_file_case_insensitive(dir: &Path, name: &str) -> Option<PathBuf> {
+    let candidate = dir.join(name);
+    if candidate.exists() {
+        return Some(candidate);
+    }
+    let entries = std::fs::read_dir(dir).ok()?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        if entry.file_name().to_string_lossy().eq_ignore_ascii_case(name) {
+            return Some(entry.path());
+        }
+    }
+    None
+}
+
+// Localized message tables are not stored in the DLL itself on Vista and later, but in a
+// <lang>\<name>.mui file next to it
+fn find_mui_files(path: &Path) -> Vec<PathBuf> {
+    let (dir, file_name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, format!("{}.mui", name.to_string_lossy())),
+        _ => return vec![],
+    };
+    let mut res = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return res,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        if !entry.path().is_dir() {
+            continue;
+        }
+        if let Some(mui) = find_file_case_insensitive(&entry.path(), &file_name) {
+            if entry.file_name().to_string_lossy().eq_ignore_ascii_case(PREFERRED_LANGUAGE_DIR) {
+                res.insert(0, mui);
+            } else {
+                res.push(mui);
+            }
+        }
+    }
+    res
+}
+
+fn read_pe_message_table(data: &[u8]) -> Result<BTreeMap<u32, String>, String> {
+    let pe = PeFile::parse(data)?;
+    let mut tables = pe.resources(&ResourceId::Id(RT_MESSAGETABLE))?;
+    // Parse tables in the preferred language first, so that their messages take precedence
+    tables.sort_by_key(|t| t.language != PREFERRED_LANGUAGE_ID);
+    let mut messages = BTreeMap::new();
+    for table in tables {
+        let entries = parse_message_table(table.data)
+            .map_err(|e| format!("Message table {:?} (language {}) : {}", table.name, table.language, e))?;
+        for (id, text) in entries {
+            messages.entry(id).or_insert(text);
+        }
+    }
+    Ok(messages)
+}
+
+// Reads all message strings from a PE file and its MUI files, if any
+pub fn load_pe_message_table(path: &Path) -> Result<BTreeMap<u32, String>, String> {
+    let mut messages = BTreeMap::new();
+    for candidate in find_mui_files(path).iter().map(|p| p.as_path()).chain(std::iter::once(path)) {
+        let data = match std::fs::read(candidate) {
+            Ok(d) => d,
+            Err(e) => return Err(format!("Unable to read {} : {}", candidate.display(), e)),
+        };
+        match read_pe_message_table(&data) {
+            Ok(table) => for (id, text) in table {
+                messages.entry(id).or_insert(text);
+            },
+            Err(e) => verbose!("No message table in {} : {}", candidate.display(), e),
+        }
+    }
+    Ok(messages)
+}
+
+fn trimmed_message(messages: &BTreeMap<u32, String>, id: Option<u32>) -> Option<String> {
+    id.and_then(|id| messages.get(&id))
+        .map(|text| text.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn provider_metadata_from_wevt(provider: WevtProvider,
+                               messages: &BTreeMap<u32, String>,
+                               resource_file_path: &Path,
+                               message_file_path: &Path) -> ProviderMetadata {
+    let mut events = BTreeMap::new();
+    for event in provider.events {
+        let event_def = EventDefinition {
+            channel: resolve_standard_name(&provider.channels, SYSTEM_CHANNELS, event.channel as u64),
+            message: trimmed_message(messages, event.message_id),
+            level: event.level as u32,
+            level_name: resolve_standard_name(&provider.levels, SYSTEM_LEVELS, event.level as u64),
+            opcode: event.opcode as u32,
+            opcode_name: resolve_opcode_name(&provider.opcodes, event.task as u64, event.opcode as u64),
+            task: event.task as u32,
+            task_name: resolve_standard_name(&provider.tasks, SYSTEM_TASKS, event.task as u64),
+            keywords: event.keywords,
+            keyword_names: resolve_keyword_names(&provider.keywords, event.keywords),
+            fields: event.fields,
+            sources: vec![MetadataSource {
+                os_build: None,
+                host: None,
+                file: Some(resource_file_path.display().to_string()),
+            }],
+        };
+        let versions = events.entry(event.id as u64).or_insert(BTreeMap::new());
+        if versions.insert(event.version as u64, event_def).is_some() {
+            warn!("Event {} ID={} version={} defined more than once in {}",
+                  provider.guid, event.id, event.version, resource_file_path.display());
+        }
+    }
+    ProviderMetadata {
+        guid: Some(provider.guid),
+        resource_file_path: Some(resource_file_path.display().to_string()),
+        parameter_file_path: None,
+        message_file_path: Some(message_file_path.display().to_string()),
+        message: trimmed_message(messages, provider.message_id),
+        parameters: BTreeMap::new(),
+        channels: BTreeMap::new(),
+        levels: provider.levels,
+        tasks: provider.tasks,
+        opcodes: provider.opcodes,
+        keywords: provider.keywords,
+        events,
+    }
+}
+
+// Extracts metadata of all providers defined in the WEVT_TEMPLATE resource of the given file,
+// using message strings from message_file_path (which is often the same file)
+pub fn extract_pe_providers(resource_file_path: &Path, message_file_path: &Path) -> Result<Vec<ProviderMetadata>, String> {
+    let data = match std::fs::read(resource_file_path) {
+        Ok(d) => d,
+        Err(e) => return Err(format!("Unable to read {} : {}", resource_file_path.display(), e)),
+    };
+    let pe = PeFile::parse(&data)?;
+    let templates = pe.resources(&ResourceId::Name("WEVT_TEMPLATE".to_owned()))?;
+    if templates.is_empty() {
+        return Ok(vec![]);
+    }
+    let messages = load_pe_message_table(message_file_path)?;
+    let mut res = Vec::new();
+    for template in templates {
+        let providers = parse_wevt_template(template.data)
+            .map_err(|e| format!("WEVT_TEMPLATE {:?} (language {}) : {}", template.name, template.language, e))?;
+        for provider in providers {
+            res.push(provider_metadata_from_wevt(provider, &messages, resource_file_path, message_file_path));
+        }
+    }
+    Ok(res)
+}
+
+// Reads the names of the providers registered in WINEVT\Publishers of a SOFTWARE hive, by GUID
+pub fn read_publisher_names(software_hive: &Path) -> Result<HashMap<String, String>, String> {
+    let mut names_by_guid = HashMap::new();
+    let software = Hive::open(software_hive)?;
+    let publishers = match software.root().subkey_path(r"Microsoft\Windows\CurrentVersion\WINEVT\Publishers")? {
+        Some(key) => key,
+        None => return Err(format!("No WINEVT\\Publishers key in {}, is this a SOFTWARE hive?", software_hive.display())),
+    };
+    for publisher in publishers.subkeys()? {
+        let guid = publisher.name()?.to_uppercase();
+        if let Some(name) = publisher.string_value("")? {
+            names_by_guid.insert(guid, name);
+        }
+    }
+    Ok(names_by_guid)
+}
+
+// Provider names are not part of the WEVT_TEMPLATE resource, only their GUID: providers are
+// named after their registration in software_hive if given, otherwise they are keyed by GUID
+pub fn import_metadata_from_pe_files(paths: &[&str], software_hive: Option<&Path>) -> Result<Metadata, String> {
+    let names_by_guid = match software_hive {
+        Some(path) => read_publisher_names(path)?,
+        None => HashMap::new(),
+    };
+    let mut metadata = BTreeMap::new();
+    for path in paths {
+        verbose!("Extracting metadata from {}", path);
+        let path = Path::new(path);
+        let providers = extract_pe_providers(path, path)?;
+        if providers.is_empty() {
+            warn!("No event provider defined in {} (no WEVT_TEMPLATE resource)", path.display());
+        }
+        for prov_meta in providers {
+            let guid = match &prov_meta.guid {
+                Some(guid) => guid.to_uppercase(),
+                None => continue,
+            };
+            let name = match names_by_guid.get(&guid) {
+                Some(name) => name.to_owned(),
+                None => {
+                    if software_hive.is_some() {
+                        warn!("Provider {} defined in {} is not registered, keyed by GUID", guid, path.display());
+                    }
+                    guid
+                },
+            };
+            metadata.insert(name, prov_meta);
+        }
+    }
+    info!("Extracted metadata from {} providers", metadata.len());
+    Ok(metadata)
+}
+
+// Environment variables found in registered paths, relative to the root of the system drive
+const IMAGE_ENVIRONMENT: &[(&str, &str)] = &[
+    ("systemroot", "Windows"),
+    ("windir", "Windows"),
+    ("systemdrive", ""),
+    ("programfiles", "Program Files"),
+    ("programfiles(x86)", "Program Files (x86)"),
+    ("programw6432", "Program Files"),
+    ("commonprogramfiles", r"Program Files\Common Files"),
+    ("commonprogramfiles(x86)", r"Program Files (x86)\Common Files"),
+    ("programdata", "ProgramData"),
+];
+
+// Translates a path registered on the imaged host (e.g. %SystemRoot%\system32\msobjs.dll or
+// C:\Windows\...) to the corresponding file under image_root, if it exists
+pub fn resolve_image_path(image_root: &Path, path: &str) -> Option<PathBuf> {
+    let mut expanded = String::new();
+    let mut parts = path.trim().split('%');
+    expanded.push_str(parts.next().unwrap_or(""));
+    while let Some(var) = parts.next() {
+        match (IMAGE_ENVIRONMENT.iter().find(|(name, _)| name.eq_ignore_ascii_case(var)), parts.next()) {
+            (Some((_, value)), Some(rest)) => {
+                expanded.push_str(value);
+                expanded.push_str(rest);
+            },
+            _ => {
+                verbose!("Unable to resolve environment variable in {}", path);
+                return None;
+            },
+        }
+    }
+    let mut relative = expanded.trim_start_matches(r"\??\").trim_start_matches(r"\\?\");
+    if relative.len() >= 2 && relative.as_bytes()[1] == b':' {
+        relative = &relative[2..];
+    }
+    let relative = relative.trim_start_matches('\\');
+    let relative = if relative.len() >= 11 && relative[..11].eq_ignore_ascii_case(r"SystemRoot\") {
+        format!(r"Windows\{}", &relative[11..])
+    } else if !relative.contains('\\') {
+        // Bare file names are searched in the system directory
+        format!(r"Windows\System32\{}", relative)
+    } else {
+        relative.to_owned()
+    };
+    // Registered paths come from the image, they must not point outside of it
+    let mut resolved = image_root.to_path_buf();
+    for component in relative.split('\\').filter(|c| !c.is_empty() && *c != ".") {
+        match Path::new(component).components().collect::<Vec<_>>()[..] {
+            [std::path::Component::Normal(_)] => (),
+            _ => {
+                verbose!("Ignoring path {} which is not relative to the image root", path);
+                return None;
+            },
+        }
+        resolved = find_file_case_insensitive(&resolved, component)?;
+    }
+    // Symbolic links in the image could still point outside of it
+    match (resolved.canonicalize(), image_root.canonicalize()) {
+        (Ok(canonical), Ok(root)) if canonical.starts_with(&root) => Some(resolved),
+        _ => {
+            verbose!("Ignoring path {} which resolves outside of the image root", path);
+            None
+        },
+    }
+}
+
+fn channel_config(key: &RegKey) -> Result<ChannelConfig, String> {
+    let channel_type = match key.uint_value("Type")? {
+        Some(0) => Some("Admin".to_owned()),
+        Some(1) => Some("Operational".to_owned()),
+        Some(2) => Some("Analytic".to_owned()),
+        Some(3) => Some("Debug".to_owned()),
+        _ => None,
+    };
+    Ok(ChannelConfig {
+        // Classic logs under Services\EventLog have no Enabled value, and are always enabled
+        enabled: key.uint_value("Enabled")?.unwrap_or(1) != 0,
+        channel_type,
+        log_file_path: key.string_value("File")?,
+    })
+}
+
+struct HiveImporter<'a> {
+    image_root: &'a Path,
+    extracted: HashMap<(PathBuf, PathBuf), Vec<ProviderMetadata>>,
+    parameter_files: HashMap<PathBuf, BTreeMap<u32, String>>,
+}
+
+impl<'a> HiveImporter<'a> {
+    fn resolve(&self, provider_name: &str, path: &Option<String>) -> Option<PathBuf> {
+        let path = path.as_ref()?;
+        // Only the first file is used when several are registered
+        let first = path.split(';').next().unwrap_or("");
+        let resolved = resolve_image_path(self.image_root, first);
+        if resolved.is_none() {
+            verbose!("Provider {} file {} not found in {}", provider_name, first, self.image_root.display());
+        }
+        resolved
+    }
+
+    fn load_parameters(&mut self, provider_name: &str, paths: &Option<String>) -> BTreeMap<u32, String> {
+        let mut parameters = BTreeMap::new();
+        for path in paths.iter().flat_map(|p| p.split(';')).filter(|p| !p.trim().is_empty()) {
+            let resolved = match self.resolve(provider_name, &Some(path.to_owned())) {
+                Some(p) => p,
+                None => continue,
+            };
+            if !self.parameter_files.contains_key(&resolved) {
+                let messages = match load_pe_message_table(&resolved) {
+                    Ok(messages) => messages.into_iter()
+                        .map(|(id, text)| (id, text.trim_end_matches(&['\r', '\n'][..]).to_owned()))
+                        .collect(),
+                    Err(e) => {
+                        verbose!("Unable to load parameter messages from {} : {}", resolved.display(), e);
+                        BTreeMap::new()
+                    },
+                };
+                self.parameter_files.insert(resolved.clone(), messages);
+            }
+            for (id, text) in &self.parameter_files[&resolved] {
+                parameters.entry(*id).or_insert(text.to_owned());
+            }
+        }
+        parameters
+    }
+
+    // Returns the definitions of the given provider from its (resolved) resource file
+    fn extract_provider(&mut self, provider_name: &str, guid: &str, resource_file_path: &Option<String>,
+                        message_file_path: &Option<String>) -> Option<ProviderMetadata> {
+        let resource_file = self.resolve(provider_name, resource_file_path)?;
+        let message_file = self.resolve(provider_name, message_file_path).unwrap_or_else(|| resource_file.clone());
+        let key = (resource_file, message_file);
+        if !self.extracted.contains_key(&key) {
+            let providers = match extract_pe_providers(&key.0, &key.1) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("Unable to extract provider {} metadata from {} : {}", provider_name, key.0.display(), e);
+                    vec![]
+                },
+            };
+            self.extracted.insert(key.clone(), providers);
+        }
+        self.extracted[&key].iter()
+            .find(|p| p.guid.as_ref().map(|g| g.eq_ignore_ascii_case(guid)).unwrap_or(false))
+            .cloned()
+    }
+}
+
+// Reads provider registrations (WINEVT\Publishers, WINEVT\Channels and Services\EventLog) from
+// the SOFTWARE and SYSTEM hives of an offline system, and extracts their metadata from the
+// provider files found under image_root
+pub fn import_metadata_from_hives(image_root: &Path, software_hive: &Path, system_hive: Option<&Path>) -> Result<Metadata, String> {
+    let mut metadata = BTreeMap::new();
+    let mut importer = HiveImporter {
+        image_root,
+        extracted: HashMap::new(),
+        parameter_files: HashMap::new(),
+    };
+    let mut names_by_guid = HashMap::new();
+
+    verbose!("Reading provider registrations from {}", software_hive.display());
+    let software = Hive::open(software_hive)?;
+    let winevt = match software.root().subkey_path(r"Microsoft\Windows\CurrentVersion\WINEVT")? {
+        Some(key) => key,
+        None => return Err(format!("No WINEVT key in {}, is this a SOFTWARE hive?", software_hive.display())),
+    };
+    let mut source = MetadataSource { os_build: None, host: None, file: None };
+    if let Some(current_version) = software.root().subkey_path(r"Microsoft\Windows NT\CurrentVersion")? {
+        source.os_build = match (current_version.string_value("CurrentBuildNumber")?, current_version.uint_value("UBR")?) {
+            (Some(build), Some(ubr)) => Some(format!("{}.{}", build, ubr)),
+            (build, _) => build,
+        };
+    }
+    if let Some(publishers) = winevt.subkey("Publishers")? {
+        for publisher in publishers.subkeys()? {
+            let guid = publisher.name()?.to_uppercase();
+            let name = publisher.string_value("")?.unwrap_or_else(|| guid.clone());
+            verbose!("Querying provider {}", name);
+            let resource_file_path = publisher.string_value("ResourceFileName")?;
+            let message_file_path = publisher.string_value("MessageFileName")?;
+            let parameter_file_path = publisher.string_value("ParameterFileName")?;
+            let extracted = importer.extract_provider(&name, &guid, &resource_file_path, &message_file_path);
+            let parameters = importer.load_parameters(&name, &parameter_file_path);
+            names_by_guid.insert(guid.clone(), name.clone());
+            metadata.insert(name, match extracted {
+                Some(prov_meta) => ProviderMetadata {
+                    guid: Some(guid),
+                    resource_file_path,
+                    parameter_file_path,
+                    message_file_path,
+                    parameters,
+                    ..prov_meta
+                },
+                None => ProviderMetadata {
+                    guid: Some(guid),
+                    resource_file_path,
+                    parameter_file_path,
+                    message_file_path,
+                    message: None,
+                    parameters,
+                    channels: BTreeMap::new(),
+                    levels: BTreeMap::new(),
+                    tasks: BTreeMap::new(),
+                    opcodes: BTreeMap::new(),
+                    keywords: BTreeMap::new(),
+                    events: BTreeMap::new(),
+                },
+            });
+        }
+    }
+    if let Some(channels) = winevt.subkey("Channels")? {
+        for channel in channels.subkeys()? {
+            let owner = match channel.string_value("OwningPublisher")? {
+                Some(guid) => guid.to_uppercase(),
+                None => continue,
+            };
+            if let Some(prov_meta) = names_by_guid.get(&owner).and_then(|name| metadata.get_mut(name)) {
+                prov_meta.channels.insert(channel.name()?, channel_config(&channel)?);
+            }
+        }
+    }
+
+    if let Some(system_hive) = system_hive {
+        verbose!("Reading classic event sources from {}", system_hive.display());
+        let system = Hive::open(system_hive)?;
+        let current = system.root().subkey("Select")?
+            .map(|select| select.uint_value("Current")).transpose()?.flatten().unwrap_or(1);
+        let eventlog_path = format!(r"ControlSet{:03}\Services\EventLog", current);
+        let eventlog = match system.root().subkey_path(&eventlog_path)? {
+            Some(key) => key,
+            None => return Err(format!("No {} key in {}, is this a SYSTEM hive?", eventlog_path, system_hive.display())),
+        };
+        source.host = system.root().subkey_path(&format!(r"ControlSet{:03}\Control\ComputerName\ComputerName", current))?
+            .map(|key| key.string_value("ComputerName")).transpose()?.flatten();
+        for log in eventlog.subkeys()? {
+            let log_name = log.name()?;
+            let log_config = channel_config(&log)?;
+            for source in log.subkeys()? {
+                let name = source.name()?;
+                // Sources backed by a manifest-based provider are already known from WINEVT\Publishers
+                let known = match source.string_value("ProviderGuid")? {
+                    Some(guid) => names_by_guid.get(&guid.to_uppercase()).cloned(),
+                    None => None,
+                };
+                if let Some(prov_meta) = known.and_then(|n| metadata.get_mut(&n)) {
+                    prov_meta.channels.entry(log_name.clone()).or_insert(log_config.clone());
+                    continue;
+                }
+                let message_file_path = source.string_value("EventMessageFile")?;
+                let parameter_file_path = source.string_value("ParameterMessageFile")?;
+                let parameters = importer.load_parameters(&name, &parameter_file_path);
+                let mut channels = BTreeMap::new();
+                channels.insert(log_name.clone(), log_config.clone());
+                metadata.entry(name).or_insert(ProviderMetadata {
+                    guid: source.string_value("ProviderGuid")?,
+                    resource_file_path: None,
+                    parameter_file_path,
+                    message_file_path,
+                    message: None,
+                    parameters,
+                    channels,
+                    levels: BTreeMap::new(),
+                    tasks: BTreeMap::new(),
+                    opcodes: BTreeMap::new(),
+                    keywords: BTreeMap::new(),
+                    events: BTreeMap::new(),
+                });
+            }
+        }
+    }
+
+    // Definitions were extracted from files, record which system they belong to
+    for prov_meta in metadata.values_mut() {
+        for event_def in prov_meta.events.values_mut().flat_map(|versions| versions.values_mut()) {
+            for event_source in event_def.sources.iter_mut() {
+                event_source.os_build = source.os_build.clone();
+                event_source.host = source.host.clone();
+            }
+        }
+    }
+    info!("Imported metadata from {} providers registered in offline hives", metadata.len());
+    Ok(metadata)
+}
+
+// Locates the SOFTWARE and SYSTEM hives of a mounted Windows image
+pub fn find_image_hives(image_root: &Path) -> (Option<PathBuf>, Option<PathBuf>) {
+    let config = resolve_image_path(image_root, r"%SystemRoot%\System32\config");
+    let software = config.as_ref().and_then(|dir| find_file_case_insensitive(dir, "SOFTWARE"));
+    let system = config.as_ref().and_then(|dir| find_file_case_insensitive(dir, "SYSTEM"));
+    (software, system)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated by tests/fixtures/make_provider_dll.py
+    const PROVIDER_DLL: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/provider.dll");
+
+    // Creates an empty directory for a test, with the given files in it
+    fn test_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("evtq-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn extracts_provider_from_dll() {
+        let path = Path::new(PROVIDER_DLL);
+        let providers = extract_pe_providers(path, path).unwrap();
+        assert_eq!(providers.len(), 1);
+        let provider = &providers[0];
+        assert_eq!(provider.guid.as_deref(), Some("{12345678-1234-5678-9ABC-DEF012345678}"));
+        assert_eq!(provider.message.as_deref(), Some("Evtq Test Provider (display name)"));
+
+        let connect = &provider.events[&100][&0];
+        assert_eq!(connect.message.as_deref(), Some("Connected to %1 on port %2"));
+        assert_eq!(connect.channel.as_deref(), Some("Evtq-Test/Operational"));
+        assert_eq!(connect.level_name.as_deref(), Some("win:Informational"));
+        assert_eq!(connect.task_name.as_deref(), Some("Connect"));
+        assert_eq!(connect.opcode_name.as_deref(), Some("Handshake"));
+        assert_eq!(connect.keyword_names, vec!["Network".to_owned()]);
+
+        // Same opcode, different task
+        let disconnect = &provider.events[&101][&1];
+        assert_eq!(disconnect.level_name.as_deref(), Some("evtq:Trace"));
+        assert_eq!(disconnect.opcode_name.as_deref(), Some("Teardown"));
+        assert_eq!(disconnect.keyword_names, vec!["Network".to_owned(), "Authentication".to_owned()]);
+
+        // Global opcode, without task
+        let retry = &provider.events[&102][&0];
+        assert_eq!(retry.level_name.as_deref(), Some("win:Error"));
+        assert_eq!(retry.opcode_name.as_deref(), Some("Retry"));
+        assert_eq!(retry.message, None);
+    }
+
+    #[test]
+    fn keys_unregistered_providers_by_guid() {
+        let metadata = import_metadata_from_pe_files(&[PROVIDER_DLL], None).unwrap();
+        assert_eq!(metadata.keys().collect::<Vec<_>>(), vec!["{12345678-1234-5678-9ABC-DEF012345678}"]);
+    }
+
+    #[test]
+    fn resolves_image_paths() {
+        let dir = test_dir("resolve", &["image/Windows/System32/Provider.dll", "image/Program Files/App/app.dll"]);
+        let root = dir.join("image");
+        let system32 = root.join("Windows").join("System32");
+        for path in [r"%SystemRoot%\system32\provider.DLL", r"C:\Windows\System32\Provider.dll",
+                     r"\SystemRoot\System32\Provider.dll", r"\??\C:\Windows\System32\Provider.dll",
+                     "provider.dll", r"%windir%\.\System32\Provider.dll"] {
+            assert_eq!(resolve_image_path(&root, path), Some(system32.join("Provider.dll")), "{}", path);
+        }
+        assert_eq!(resolve_image_path(&root, r"%ProgramFiles%\App\APP.dll"),
+                   Some(root.join("Program Files").join("App").join("app.dll")));
+        assert_eq!(resolve_image_path(&root, r"%Unknown%\Provider.dll"), None);
+        assert_eq!(resolve_image_path(&root, r"C:\Windows\Missing.dll"), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_paths_outside_of_the_image() {
+        let dir = test_dir("outside", &["image/Windows/System32/Provider.dll", "outside.dll"]);
+        let root = dir.join("image");
+        for path in [r"C:\..\outside.dll", r"%SystemRoot%\..\..\outside.dll", r"C:\Windows\System32\..\..\..\outside.dll",
+                     "../outside.dll", r"C:\Windows/../../outside.dll"] {
+            assert_eq!(resolve_image_path(&root, path), None, "{}", path);
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("outside.dll"), root.join("Windows").join("link.dll")).unwrap();
+            assert_eq!(resolve_image_path(&root, r"C:\Windows\link.dll"), None);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
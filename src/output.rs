@@ -0,0 +1,190 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+#[cfg(windows)]
+use std::sync::Weak;
+#[cfg(windows)]
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE};
+#[cfg(windows)]
+use crate::windows::set_console_ctrl_handler;
+use crate::EventOutput;
+
+const BUFFER_SIZE: usize = 256 * 1024;
+// Buffered events are written at least this often, when they arrive slowly (e.g. from live hosts)
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+struct SinkState {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    last_flush: Instant,
+    // Last header written before events (e.g. CSV field names), only written again when it changes
+    last_header: Option<String>,
+}
+
+// Output file (or stdout) shared by all rendering threads
+#[derive(Clone)]
+pub struct OutputSink {
+    name: String,
+    state: Arc<Mutex<SinkState>>,
+    // Set when whatever reads our output went away (e.g. piped to head), so that rendering stops
+    closed: Arc<AtomicBool>,
+}
+
+// Flushed by the console control handler, so that buffered events are not lost on Ctrl-C.
+// Not kept alive by this reference, so that it is still flushed when dropped after errors.
+#[cfg(windows)]
+static FLUSHED_ON_INTERRUPT: Mutex<Option<Weak<Mutex<SinkState>>>> = Mutex::new(None);
+
+#[cfg(windows)]
+unsafe extern "system" fn flush_on_ctrl_event(_ctrl_type: DWORD) -> BOOL {
+    if let Ok(sink) = FLUSHED_ON_INTERRUPT.lock() {
+        if let Some(state) = sink.as_ref().and_then(|state| state.upgrade()) {
+            if let Ok(mut state) = state.lock() {
+                let _ = state.writer.flush();
+            }
+        }
+    }
+    FALSE // let the default handler terminate the process
+}
+
+impl OutputSink {
+    fn new(name: &str, writer: Box<dyn Write + Send>) -> OutputSink {
+        OutputSink {
+            name: name.to_owned(),
+            state: Arc::new(Mutex::new(SinkState {
+                writer: BufWriter::with_capacity(BUFFER_SIZE, writer),
+                last_flush: Instant::now(),
+                last_header: None,
+            })),
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn stdout() -> OutputSink {
+        OutputSink::new("stdout", Box::new(std::io::stdout()))
+    }
+
+    pub fn open(path: &str, append: bool) -> Result<OutputSink, String> {
+        if path.eq("stdout") {
+            return Ok(OutputSink::stdout());
+        }
+        match OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(path) {
+            Ok(f) => Ok(OutputSink::new(path, Box::new(f))),
+            Err(e) => Err(format!("Could not open file {} : {}", path, e)),
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn flush_on_interrupt(&self) -> Result<(), String> {
+        match FLUSHED_ON_INTERRUPT.lock() {
+            Ok(mut sink) => *sink = Some(Arc::downgrade(&self.state)),
+            Err(e) => return Err(format!("Failed to acquire lock to interrupt handler state: {}", e)),
+        }
+        set_console_ctrl_handler(Some(flush_on_ctrl_event))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Relaxed)
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, SinkState>, String> {
+        match self.state.lock() {
+            Ok(state) => Ok(state),
+            Err(e) => Err(format!("Failed to acquire lock to output file: {}", e)),
+        }
+    }
+
+    // Write errors because the reading end of a pipe was closed are not errors: there is just
+    // no point in rendering further events
+    fn check_result(&self, res: std::io::Result<()>) -> Result<(), String> {
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                if !self.closed.swap(true, Relaxed) {
+                    verbose!("Output {} was closed, stopping", self.name);
+                }
+                Ok(())
+            },
+            Err(e) => Err(format!("Unable to write to {}: {}", self.name, e)),
+        }
+    }
+
+    fn write_locked(&self, state: &mut SinkState, data: &[u8]) -> Result<(), String> {
+        if self.is_closed() {
+            return Ok(());
+        }
+        self.check_result(state.writer.write_all(data))?;
+        if state.last_flush.elapsed() >= FLUSH_INTERVAL {
+            state.last_flush = Instant::now();
+            self.check_result(state.writer.flush())?;
+        }
+        Ok(())
+    }
+
+    pub fn write_all(&self, data: &[u8]) -> Result<(), String> {
+        let mut state = self.lock()?;
+        self.write_locked(&mut state, data)
+    }
+
+    pub fn write_event(&self, output: EventOutput) -> Result<(), String> {
+        if output.header.is_none() && output.body.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.lock()?;
+        if let Some(header) = output.header {
+            if state.last_header.as_ref() != Some(&header) {
+                self.write_locked(&mut state, header.as_bytes())?;
+                state.last_header = Some(header);
+            }
+        }
+        self.write_locked(&mut state, output.body.as_bytes())
+    }
+
+    pub fn flush(&self) -> Result<(), String> {
+        if self.is_closed() {
+            return Ok(());
+        }
+        let mut state = self.lock()?;
+        state.last_flush = Instant::now();
+        self.check_result(state.writer.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writer whose contents can still be read once moved into a sink
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn event(header: Option<&str>, body: &str) -> EventOutput {
+        EventOutput { header: header.map(|h| h.to_owned()), body: body.to_owned() }
+    }
+
+    #[test]
+    fn writes_headers_when_they_change() {
+        let buffer = SharedBuffer::default();
+        let sink = OutputSink::new("test", Box::new(buffer.clone()));
+        sink.write_event(event(Some("a,b\n"), "1,2\n")).unwrap();
+        sink.write_event(event(Some("a,b\n"), "3,4\n")).unwrap();
+        sink.write_event(event(Some("a,c\n"), "5,6\n")).unwrap();
+        sink.write_event(event(None, "")).unwrap();
+        sink.write_event(event(Some("a,b\n"), "7,8\n")).unwrap();
+        sink.write_event(event(None, "9\n")).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+                   "a,b\n1,2\n3,4\na,c\n5,6\na,b\n7,8\n9\n");
+    }
+}
@@ -1,5 +1,20 @@
+#[cfg(windows)]
+use std::collections::BTreeMap;
+#[cfg(windows)]
+use winapi::um::winevt::EVT_VARIANT;
+#[cfg(windows)]
+use crate::RenderingConfig;
+#[cfg(windows)]
+use crate::errors::{record_error, ErrorStage};
+#[cfg(windows)]
+use crate::formatting::{unwrap_variant_contents, variant_as_string, CommonEventProperties, DateFormat, EvtVariant};
+#[cfg(windows)]
+use crate::metadata::{EventDefinition, EventClassification};
+use crate::metadata::EventFieldDefinition;
+#[cfg(windows)]
+use crate::msgformat::expand_parameter_messages;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum OutputColumn {
     // Generic columns found in all events
     Hostname,
@@ -8,6 +23,14 @@ pub enum OutputColumn {
     Provider,
     EventID,
     Version,
+    Channel,
+    ProviderGUID,
+    Qualifiers,
+    ProcessID,
+    ThreadID,
+    ActivityID,
+    RelatedActivityID,
+    UserID,
     Level,
     LevelName,
     Task,
@@ -17,68 +40,601 @@ pub enum OutputColumn {
     Keywords,
     KeywordNames,
     EventSpecific(u32), // 1-indexed event-specific data field
+    EventField(String), // Event-specific data field, by name
     UnformattedMessage, // Template string, if any
     FormattedMessage, // Formatted template string, if any
 }
 
-pub fn parse_column_names(names: &str) -> Result<Vec<OutputColumn>, String> {
-    let column_names : Vec<&str> = names.split(",").collect();
-    let mut columns = Vec::new();
-    let mut last_prop_num : Option<u32> = None;
-    let mut expand_next_prop_num : bool = false;
+// Generic columns, by name
+fn generic_column(name: &str) -> Option<OutputColumn> {
+    Some(match name {
+        "hostname" => OutputColumn::Hostname,
+        "recordid" => OutputColumn::RecordID,
+        "timestamp" => OutputColumn::Timestamp,
+        "provider" => OutputColumn::Provider,
+        "eventid" => OutputColumn::EventID,
+        "version" => OutputColumn::Version,
+        "channel" => OutputColumn::Channel,
+        "provider_guid" => OutputColumn::ProviderGUID,
+        "qualifiers" => OutputColumn::Qualifiers,
+        "process_id" => OutputColumn::ProcessID,
+        "thread_id" => OutputColumn::ThreadID,
+        "activity_id" => OutputColumn::ActivityID,
+        "related_activity_id" => OutputColumn::RelatedActivityID,
+        "user_id" => OutputColumn::UserID,
+        "level" => OutputColumn::Level,
+        "level_name" => OutputColumn::LevelName,
+        "task" => OutputColumn::Task,
+        "task_name" => OutputColumn::TaskName,
+        "opcode" => OutputColumn::Opcode,
+        "opcode_name" => OutputColumn::OpcodeName,
+        "keywords" => OutputColumn::Keywords,
+        "keyword_names" => OutputColumn::KeywordNames,
+        "unformatted_message" => OutputColumn::UnformattedMessage,
+        "formatted_message" => OutputColumn::FormattedMessage,
+        _ => return None,
+    })
+}
 
-    for name in column_names {
-        if name.eq("...") {
-            expand_next_prop_num = true;
-            continue;
-        }
-        let col = match name {
-            "hostname" => OutputColumn::Hostname,
-            "recordid" => OutputColumn::RecordID,
-            "timestamp" => OutputColumn::Timestamp,
-            "provider" => OutputColumn::Provider,
-            "eventid" => OutputColumn::EventID,
-            "version" => OutputColumn::Version,
-            "level" => OutputColumn::Level,
-            "level_name" => OutputColumn::LevelName,
-            "task" => OutputColumn::Task,
-            "task_name" => OutputColumn::TaskName,
-            "opcode" => OutputColumn::Opcode,
-            "opcode_name" => OutputColumn::OpcodeName,
-            "keywords" => OutputColumn::Keywords,
-            "keyword_names" => OutputColumn::KeywordNames,
-            "unformatted_message" => OutputColumn::UnformattedMessage,
-            "formatted_message" => OutputColumn::FormattedMessage,
-            s if s.starts_with("variant") => {
-                let s = s.replace("variant", "");
-                let prop_num = match s.parse::<u32>() {
-                    Ok(i) => i,
-                    Err(e) => return Err(
-                        format!("Unexpected output column name '{}' : {}", s, e)),
-                };
-                if expand_next_prop_num {
-                    let last_prop_num = match last_prop_num {
-                        Some(i) => i,
-                        None => return Err(format!("Expecting variantN column name after '...'")),
-                    };
-                    for i in (last_prop_num+1)..prop_num {
-                        columns.push(OutputColumn::EventSpecific(i));
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnFunction {
+    Date, // date part of a timestamp, in the output time zone
+    Lower,
+    Upper,
+    Coalesce, // first argument which is neither null nor empty
+    Lookup, // account name of a SID
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ColumnExpr {
+    Column(OutputColumn),
+    Literal(String),
+    Index(Box<ColumnExpr>, usize), // item of an array value
+    Member(Box<ColumnExpr>, String), // member of a structured value
+    Call(ColumnFunction, Vec<ColumnExpr>),
+}
+
+#[derive(Debug)]
+pub struct ColumnSpec {
+    // Key in JSON outputs and header in CSV outputs, if given as alias=expression
+    pub alias: Option<String>,
+    pub expr: ColumnExpr,
+    // Expression as written, used as key of computed columns without alias
+    pub text: String,
+}
+
+impl ColumnSpec {
+    // Name of the column as given by the user, regardless of events
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.text)
+    }
+
+    fn variant_number(&self) -> Option<u32> {
+        match (&self.alias, &self.expr) {
+            (None, ColumnExpr::Column(OutputColumn::EventSpecific(prop_num))) => Some(*prop_num),
+            _ => None,
+        }
+    }
+}
+
+fn references_field_names(expr: &ColumnExpr) -> bool {
+    match expr {
+        ColumnExpr::Column(OutputColumn::EventField(_)) => true,
+        ColumnExpr::Column(_) | ColumnExpr::Literal(_) => false,
+        ColumnExpr::Index(expr, _) | ColumnExpr::Member(expr, _) => references_field_names(expr),
+        ColumnExpr::Call(_, args) => args.iter().any(references_field_names),
+    }
+}
+
+// Whether columns reference event fields by name, which requires them to be known
+pub fn columns_reference_field_names(columns: &[ColumnSpec]) -> bool {
+    columns.iter().any(|spec| references_field_names(&spec.expr))
+}
+
+/*
+ * Column list syntax:
+ *   columns := column ("," column)*
+ *   column  := "..." | [name "="] expr
+ *   expr    := "'" literal "'" | number | function "(" [expr ("," expr)*] ")" | name ("[" index "]" | "." name)*
+ * where name is either a generic column name, variantN, or the name of an event field.
+ * Members (".name") can only be read from string values which hold a JSON object, they are
+ * null for any other value.
+ */
+struct ColumnParser<'a> {
+    spec: &'a str,
+    pos: usize,
+}
+
+impl<'a> ColumnParser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.spec[self.pos..].chars().next()
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_spaces();
+        if self.peek() == Some(expected) {
+            self.pos += expected.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(format!("Expecting '{}' at position {} of column list '{}'", expected, self.pos + 1, self.spec))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        self.skip_spaces();
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !(c.is_alphanumeric() || c == '_' || c == '-') {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+        if self.pos == start {
+            return Err(format!("Expecting column or field name at position {} of column list '{}'", start + 1, self.spec));
+        }
+        Ok(self.spec[start..self.pos].to_owned())
+    }
+
+    // Quoted literal, with '' to represent a single quote
+    fn literal(&mut self) -> Result<String, String> {
+        self.expect('\'')?;
+        let mut literal = String::new();
+        loop {
+            match self.peek() {
+                Some('\'') if self.spec[self.pos + 1..].starts_with('\'') => {
+                    literal.push('\'');
+                    self.pos += 2;
+                },
+                Some('\'') => {
+                    self.pos += 1;
+                    return Ok(literal);
+                },
+                Some(c) => {
+                    literal.push(c);
+                    self.pos += c.len_utf8();
+                },
+                None => return Err(format!("Unterminated literal in column list '{}'", self.spec)),
+            }
+        }
+    }
+
+    fn expression(&mut self) -> Result<ColumnExpr, String> {
+        self.skip_spaces();
+        if self.peek() == Some('\'') {
+            return Ok(ColumnExpr::Literal(self.literal()?));
+        }
+        let name = self.name()?;
+        if name.chars().all(|c| c.is_ascii_digit()) {
+            return Ok(ColumnExpr::Literal(name));
+        }
+        if self.eat('(') {
+            let function = match name.to_lowercase().as_str() {
+                "date" => ColumnFunction::Date,
+                "lower" => ColumnFunction::Lower,
+                "upper" => ColumnFunction::Upper,
+                "coalesce" => ColumnFunction::Coalesce,
+                "lookup" => ColumnFunction::Lookup,
+                other => return Err(format!("Unknown column function '{}'", other)),
+            };
+            let mut args = vec![];
+            if !self.eat(')') {
+                loop {
+                    args.push(self.expression()?);
+                    if self.eat(')') {
+                        break;
                     }
+                    self.expect(',')?;
+                }
+            }
+            match (function, args.len()) {
+                (ColumnFunction::Coalesce, n) if n > 0 => (),
+                (ColumnFunction::Coalesce, _) => return Err(format!("coalesce() expects at least one argument")),
+                (_, 1) => (),
+                (_, n) => return Err(format!("{}() expects one argument, got {}", name, n)),
+            }
+            return Ok(ColumnExpr::Call(function, args));
+        }
+
+        let mut expr = ColumnExpr::Column(match generic_column(&name) {
+            Some(column) => column,
+            None if name.starts_with("variant") => match name["variant".len()..].parse::<u32>() {
+                Ok(prop_num) if prop_num > 0 => OutputColumn::EventSpecific(prop_num),
+                Ok(_) => return Err(format!("Event fields are numbered from variant1")),
+                Err(e) => return Err(format!("Unexpected output column name '{}' : {}", name, e)),
+            },
+            None => OutputColumn::EventField(name),
+        });
+        loop {
+            if self.eat('[') {
+                self.skip_spaces();
+                let start = self.pos;
+                while self.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                    self.pos += 1;
                 }
-                OutputColumn::EventSpecific(prop_num)
+                let index = match self.spec[start..self.pos].parse::<usize>() {
+                    Ok(i) => i,
+                    Err(_) => return Err(format!("Expecting array index at position {} of column list '{}'", start + 1, self.spec)),
+                };
+                self.expect(']')?;
+                expr = ColumnExpr::Index(Box::new(expr), index);
+            } else if self.eat('.') {
+                expr = ColumnExpr::Member(Box::new(expr), self.name()?);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn column(&mut self) -> Result<ColumnSpec, String> {
+        self.skip_spaces();
+        let start = self.pos;
+        let alias = match self.name() {
+            Ok(name) if self.eat('=') => Some(name),
+            _ => {
+                self.pos = start;
+                None
             },
-            other => return Err(format!("Unexpected output column name '{}'", other))
-        };
-        expand_next_prop_num = false;
-        last_prop_num = match col {
-            OutputColumn::EventSpecific(prop_num) => Some(prop_num),
-            _ => None,
         };
-        columns.push(col);
+        let expr_start = self.pos;
+        let expr = self.expression()?;
+        Ok(ColumnSpec {
+            alias,
+            expr,
+            text: self.spec[expr_start..self.pos].trim().to_owned(),
+        })
+    }
+}
+
+pub fn parse_column_names(names: &str) -> Result<Vec<ColumnSpec>, String> {
+    let mut parser = ColumnParser { spec: names, pos: 0 };
+    let mut columns : Vec<ColumnSpec> = Vec::new();
+    let mut expand_next_prop_num : bool = false;
+
+    loop {
+        parser.skip_spaces();
+        if parser.spec[parser.pos..].starts_with("...") {
+            parser.pos += 3;
+            expand_next_prop_num = true;
+        } else {
+            let col = parser.column()?;
+            if expand_next_prop_num {
+                let last_prop_num = match columns.last().and_then(|c| c.variant_number()) {
+                    Some(i) => i,
+                    None => return Err(format!("Expecting variantN column name before '...'")),
+                };
+                let prop_num = match col.variant_number() {
+                    Some(i) => i,
+                    None => return Err(format!("Expecting variantN column name after '...'")),
+                };
+                for i in (last_prop_num+1)..prop_num {
+                    columns.push(ColumnSpec {
+                        alias: None,
+                        expr: ColumnExpr::Column(OutputColumn::EventSpecific(i)),
+                        text: format!("variant{}", i),
+                    });
+                }
+            }
+            expand_next_prop_num = false;
+            columns.push(col);
+        }
+        parser.skip_spaces();
+        if parser.pos == parser.spec.len() {
+            break;
+        }
+        parser.expect(',')?;
     }
 
     if expand_next_prop_num {
         return Err(format!("Expecting output column name after '...'"));
     }
     Ok(columns)
-}
\ No newline at end of file
+}
+
+// Member of a string value holding a JSON object (e.g. fields written by some providers as
+// {"Key":"Value",...}): it is null for any other string, and non-string members are returned
+// as their JSON text
+fn json_member(s: &str, name: &str) -> Option<String> {
+    match serde_json::from_str::<serde_json::Value>(s) {
+        Ok(serde_json::Value::Object(mut members)) => match members.remove(name) {
+            Some(serde_json::Value::String(s)) => Some(s),
+            Some(serde_json::Value::Null) | None => None,
+            Some(other) => Some(other.to_string()),
+        },
+        _ => None,
+    }
+}
+
+// Index of the field a named column refers to, matching its case exactly first
+pub fn field_index(fields: &[EventFieldDefinition], name: &str) -> Option<usize> {
+    fields.iter().position(|f| f.name == name)
+        .or_else(|| fields.iter().position(|f| f.name.eq_ignore_ascii_case(name)))
+}
+
+// Everything needed to compute the values of columns for a given event
+#[cfg(windows)]
+pub struct EventColumns<'a> {
+    pub common_props: &'a CommonEventProperties,
+    pub render_cfg: &'a RenderingConfig,
+    pub event_def: &'a EventDefinition,
+    pub parameters: &'a BTreeMap<u32, String>,
+    pub classification: &'a EventClassification,
+    pub variants: *const EVT_VARIANT,
+    pub variant_count: u32,
+}
+
+#[cfg(windows)]
+impl<'a> EventColumns<'a> {
+    // Key of the column in JSON outputs
+    pub fn key(&self, spec: &ColumnSpec) -> String {
+        if let Some(alias) = &spec.alias {
+            return alias.to_owned();
+        }
+        match &spec.expr {
+            ColumnExpr::Column(OutputColumn::EventSpecific(prop_num)) => match self.event_def.fields.get((*prop_num - 1) as usize) {
+                Some(field_def) => field_def.name.to_owned(),
+                None => format!("field{}", prop_num),
+            },
+            ColumnExpr::Column(OutputColumn::EventField(name)) => name.to_owned(),
+            ColumnExpr::Column(OutputColumn::UnformattedMessage) |
+            ColumnExpr::Column(OutputColumn::FormattedMessage) => "message".to_owned(),
+            _ => spec.text.to_owned(),
+        }
+    }
+
+    // Whether the column references something this event doesn't have, and should be omitted
+    // from JSON outputs (instead of e.g. "fieldN": null)
+    pub fn is_absent(&self, spec: &ColumnSpec) -> bool {
+        match &spec.expr {
+            ColumnExpr::Column(OutputColumn::EventSpecific(prop_num)) => *prop_num > self.variant_count,
+            ColumnExpr::Column(OutputColumn::UnformattedMessage) |
+            ColumnExpr::Column(OutputColumn::FormattedMessage) => self.event_def.message.is_none(),
+            _ => false,
+        }
+    }
+
+    pub fn value(&self, spec: &ColumnSpec) -> Result<EvtVariant, String> {
+        self.evaluate(&spec.expr)
+    }
+
+    fn evaluate(&self, expr: &ColumnExpr) -> Result<EvtVariant, String> {
+        Ok(match expr {
+            ColumnExpr::Column(column) => self.column_value(column)?,
+            ColumnExpr::Literal(s) => EvtVariant::String(s.to_owned()),
+            ColumnExpr::Index(expr, index) => match self.evaluate(expr)? {
+                EvtVariant::Array(mut items) if *index < items.len() => items.swap_remove(*index),
+                _ => EvtVariant::Null,
+            },
+            ColumnExpr::Member(expr, name) => match self.evaluate(expr)? {
+                EvtVariant::String(s) => json_member(&s, name).map(EvtVariant::String).unwrap_or(EvtVariant::Null),
+                _ => EvtVariant::Null,
+            },
+            ColumnExpr::Call(function, args) => self.call(*function, args)?,
+        })
+    }
+
+    fn call(&self, function: ColumnFunction, args: &[ColumnExpr]) -> Result<EvtVariant, String> {
+        if function == ColumnFunction::Coalesce {
+            for arg in args {
+                match self.evaluate(arg)? {
+                    EvtVariant::Null => continue,
+                    EvtVariant::String(s) if s.is_empty() => continue,
+                    value => return Ok(value),
+                }
+            }
+            return Ok(EvtVariant::Null);
+        }
+        Ok(match (function, self.evaluate(&args[0])?) {
+            (_, EvtVariant::Null) => EvtVariant::Null,
+            (ColumnFunction::Date, EvtVariant::DateTime(d)) =>
+                EvtVariant::String(variant_as_string(EvtVariant::DateTime(d),
+                                                     &self.render_cfg.datefmt.with_format(DateFormat::Strftime("%Y-%m-%d".to_owned())))),
+            (ColumnFunction::Lower, value) => EvtVariant::String(variant_as_string(value, &self.render_cfg.datefmt).to_lowercase()),
+            (ColumnFunction::Upper, value) => EvtVariant::String(variant_as_string(value, &self.render_cfg.datefmt).to_uppercase()),
+            (ColumnFunction::Lookup, EvtVariant::String(sid)) => match self.render_cfg.account_name(&sid) {
+                Some(name) => EvtVariant::String(name),
+                None => EvtVariant::String(sid),
+            },
+            (_, value) => value,
+        })
+    }
+
+    // Values which can't be unwrapped are recorded as errors, and written as null
+    fn field_value(&self, field_idx: usize) -> EvtVariant {
+        if field_idx >= self.variant_count as usize {
+            return EvtVariant::Null;
+        }
+        let prop : EVT_VARIANT = unsafe { std::ptr::read(self.variants.add(field_idx)) };
+        let type_hint = self.event_def.fields.get(field_idx).map(|field_def| &field_def.out_type[..]).unwrap_or("xs:string");
+        let value = match unwrap_variant_contents(&prop, Some(type_hint)) {
+            Ok(value) => value,
+            Err(e) => {
+                let e = format!("Unable to unwrap value {} as {}: {}", field_idx + 1, type_hint, e);
+                warn!("{} in event {}/{}/{}", e, self.common_props.provider,
+                      self.common_props.eventid, self.common_props.version);
+                record_error(self.render_cfg, None, Some(self.common_props), ErrorStage::VariantUnwrap, &e);
+                return EvtVariant::Null;
+            },
+        };
+        match value {
+            EvtVariant::String(s) if self.render_cfg.expand_parameters =>
+                EvtVariant::String(expand_parameter_messages(&s, self.parameters)),
+            value => value,
+        }
+    }
+
+    pub fn column_value(&self, column: &OutputColumn) -> Result<EvtVariant, String> {
+        let common_props = self.common_props;
+        let classification = self.classification;
+        let optional_string = |s: &Option<String>| s.to_owned().map(EvtVariant::String).unwrap_or(EvtVariant::Null);
+        let optional_uint = |u: Option<u64>| u.map(EvtVariant::UInt).unwrap_or(EvtVariant::Null);
+        Ok(match column {
+            OutputColumn::Hostname => EvtVariant::String(common_props.hostname.to_owned()),
+            OutputColumn::RecordID => EvtVariant::UInt(common_props.recordid),
+            OutputColumn::Timestamp => EvtVariant::DateTime(common_props.timestamp),
+            OutputColumn::Provider => EvtVariant::String(common_props.provider.to_owned()),
+            OutputColumn::EventID => EvtVariant::UInt(common_props.eventid),
+            OutputColumn::Version => EvtVariant::UInt(common_props.version),
+            OutputColumn::Channel => optional_string(&common_props.channel),
+            OutputColumn::ProviderGUID => optional_string(&common_props.provider_guid),
+            OutputColumn::Qualifiers => optional_uint(common_props.qualifiers),
+            OutputColumn::ProcessID => optional_uint(common_props.process_id),
+            OutputColumn::ThreadID => optional_uint(common_props.thread_id),
+            OutputColumn::ActivityID => optional_string(&common_props.activity_id),
+            OutputColumn::RelatedActivityID => optional_string(&common_props.related_activity_id),
+            OutputColumn::UserID => optional_string(&common_props.user_id),
+            OutputColumn::Level => EvtVariant::UInt(classification.level),
+            OutputColumn::LevelName => optional_string(&classification.level_name),
+            OutputColumn::Task => EvtVariant::UInt(classification.task),
+            OutputColumn::TaskName => optional_string(&classification.task_name),
+            OutputColumn::Opcode => EvtVariant::UInt(classification.opcode),
+            OutputColumn::OpcodeName => optional_string(&classification.opcode_name),
+            OutputColumn::Keywords => EvtVariant::UInt(classification.keywords),
+            OutputColumn::KeywordNames => EvtVariant::Array(classification.keyword_names.iter()
+                .map(|name| EvtVariant::String(name.to_owned())).collect()),
+            OutputColumn::EventSpecific(prop_num) => self.field_value((*prop_num - 1) as usize),
+            OutputColumn::EventField(name) => match field_index(&self.event_def.fields, name) {
+                Some(field_idx) => self.field_value(field_idx),
+                None => EvtVariant::Null,
+            },
+            OutputColumn::UnformattedMessage => optional_string(&self.event_def.message),
+            OutputColumn::FormattedMessage => match &self.event_def.message {
+                Some(template) => match crate::windows::format_event_message(self.event_def, self.parameters, self.variants, self.variant_count) {
+                    Ok(message) => EvtVariant::String(message),
+                    Err(e) => {
+                        warn!("Unable to format template \"{}\" of event {}/{}/{}: {}",
+                              template, common_props.provider, common_props.eventid,
+                              common_props.version, e);
+                        record_error(self.render_cfg, None, Some(common_props), ErrorStage::MessageFormat, &e);
+                        EvtVariant::String(template.to_owned())
+                    },
+                },
+                None => EvtVariant::Null,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str) -> ColumnExpr {
+        ColumnExpr::Column(OutputColumn::EventField(name.to_owned()))
+    }
+
+    fn exprs(columns: &[ColumnSpec]) -> Vec<&ColumnExpr> {
+        columns.iter().map(|c| &c.expr).collect()
+    }
+
+    #[test]
+    fn parses_generic_and_named_columns() {
+        let columns = parse_column_names("timestamp, provider,eventid , variant2,TargetUserName,Sub-Field_1").unwrap();
+        assert_eq!(exprs(&columns), vec![
+            &ColumnExpr::Column(OutputColumn::Timestamp),
+            &ColumnExpr::Column(OutputColumn::Provider),
+            &ColumnExpr::Column(OutputColumn::EventID),
+            &ColumnExpr::Column(OutputColumn::EventSpecific(2)),
+            &field("TargetUserName"),
+            &field("Sub-Field_1"),
+        ]);
+        assert_eq!(columns.iter().map(|c| c.name()).collect::<Vec<_>>(),
+                   vec!["timestamp", "provider", "eventid", "variant2", "TargetUserName", "Sub-Field_1"]);
+        assert!(columns_reference_field_names(&columns));
+        assert!(!columns_reference_field_names(&parse_column_names("timestamp,variant1").unwrap()));
+    }
+
+    #[test]
+    fn expands_variant_ranges() {
+        let columns = parse_column_names("eventid,variant2,...,variant5").unwrap();
+        assert_eq!(columns.iter().map(|c| c.name()).collect::<Vec<_>>(),
+                   vec!["eventid", "variant2", "variant3", "variant4", "variant5"]);
+        assert!(parse_column_names("eventid,...,variant5").is_err());
+        assert!(parse_column_names("variant1,...,eventid").is_err());
+        assert!(parse_column_names("variant1,...").is_err());
+        assert!(parse_column_names("user=variant1,...,variant3").is_err());
+    }
+
+    #[test]
+    fn parses_aliases_literals_and_functions() {
+        let columns = parse_column_names("user=lower(TargetUserName), domain = coalesce(TargetDomainName, '-'), 'it''s', 42, d=date(timestamp)").unwrap();
+        assert_eq!(columns.iter().map(|c| c.alias.as_deref()).collect::<Vec<_>>(),
+                   vec![Some("user"), Some("domain"), None, None, Some("d")]);
+        assert_eq!(exprs(&columns), vec![
+            &ColumnExpr::Call(ColumnFunction::Lower, vec![field("TargetUserName")]),
+            &ColumnExpr::Call(ColumnFunction::Coalesce, vec![field("TargetDomainName"), ColumnExpr::Literal("-".to_owned())]),
+            &ColumnExpr::Literal("it's".to_owned()),
+            &ColumnExpr::Literal("42".to_owned()),
+            &ColumnExpr::Call(ColumnFunction::Date, vec![ColumnExpr::Column(OutputColumn::Timestamp)]),
+        ]);
+        // Computed columns without alias are named after their expression, as written
+        assert_eq!(columns[2].name(), "'it''s'");
+        assert_eq!(parse_column_names("UPPER( x )").unwrap()[0].name(), "UPPER( x )");
+    }
+
+    #[test]
+    fn parses_indexes_and_members() {
+        let columns = parse_column_names("Addresses[1], Details.User.Name, Items[0].id").unwrap();
+        assert_eq!(exprs(&columns), vec![
+            &ColumnExpr::Index(Box::new(field("Addresses")), 1),
+            &ColumnExpr::Member(Box::new(ColumnExpr::Member(Box::new(field("Details")), "User".to_owned())), "Name".to_owned()),
+            &ColumnExpr::Member(Box::new(ColumnExpr::Index(Box::new(field("Items")), 0)), "id".to_owned()),
+        ]);
+        assert_eq!(columns[1].name(), "Details.User.Name");
+        assert!(columns_reference_field_names(&parse_column_names("lower(Details.User)").unwrap()));
+    }
+
+    #[test]
+    fn rejects_invalid_column_lists() {
+        for columns in ["", "timestamp,", "timestamp,,eventid", "variant0", "variantX", "unknown(x)",
+                        "lower()", "lower(a,b)", "coalesce()", "'unterminated", "a[", "a[x]", "a[1", "a.", "a=",
+                        "a b", "lower(a"] {
+            assert!(parse_column_names(columns).is_err(), "{}", columns);
+        }
+    }
+
+    #[test]
+    fn reads_members_of_json_strings() {
+        let json = r#"{"User":"alice","Id":42,"Admin":true,"Groups":["a","b"],"Manager":null}"#;
+        assert_eq!(json_member(json, "User").as_deref(), Some("alice"));
+        assert_eq!(json_member(json, "Id").as_deref(), Some("42"));
+        assert_eq!(json_member(json, "Admin").as_deref(), Some("true"));
+        assert_eq!(json_member(json, "Groups").as_deref(), Some(r#"["a","b"]"#));
+        assert_eq!(json_member(json, "Manager"), None);
+        assert_eq!(json_member(json, "user"), None);
+        // Only strings holding a JSON object have members
+        for not_an_object in ["alice", "User=alice", "42", r#"["User"]"#, r#""User""#, r#"{"User":"#] {
+            assert_eq!(json_member(not_an_object, "User"), None, "{}", not_an_object);
+        }
+    }
+
+    #[test]
+    fn resolves_named_columns_with_event_definitions() {
+        // Definitions as read from the system or extracted from binaries, e.g. services.exe
+        let providers = crate::wevt::parse_wevt_template(include_bytes!("../tests/fixtures/services_wevt_template.bin")).unwrap();
+        let event_def = providers.iter().flat_map(|p| &p.events).find(|e| e.id == 7045).unwrap();
+        let columns = parse_column_names("eventid,ImagePath,accountname,Missing").unwrap();
+        let indexes: Vec<Option<usize>> = columns.iter().map(|c| match &c.expr {
+            ColumnExpr::Column(OutputColumn::EventField(name)) => field_index(&event_def.fields, name),
+            _ => None,
+        }).collect();
+        assert_eq!(indexes, vec![None, Some(1), Some(4), None]);
+        assert_eq!(field_index(&[], "ImagePath"), None);
+    }
+}
@@ -0,0 +1,180 @@
+use std::convert::TryInto;
+
+/*
+ * Minimal reader for the resource section of PE files (DLL, EXE, and MUI files), which does not
+ * depend on any Windows API so that message catalogs can be extracted from a copy of a system's
+ * files. Only what's required to locate resources is parsed:
+ *
+ * IMAGE_DOS_HEADER.e_lfanew -> "PE\0\0" + IMAGE_FILE_HEADER + IMAGE_OPTIONAL_HEADER(32|64)
+ *   -> DataDirectory[IMAGE_DIRECTORY_ENTRY_RESOURCE] (an RVA, translated using section headers)
+ *   -> IMAGE_RESOURCE_DIRECTORY (types) -> IMAGE_RESOURCE_DIRECTORY (names) -> IMAGE_RESOURCE_DIRECTORY
+ *      (languages) -> IMAGE_RESOURCE_DATA_ENTRY (RVA and size of the resource contents)
+ */
+
+const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x10b;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+
+pub const RT_MESSAGETABLE: u32 = 11;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResourceId {
+    Id(u32),
+    Name(String),
+}
+
+pub struct Resource<'a> {
+    pub name: ResourceId,
+    pub language: u32,
+    pub data: &'a [u8],
+}
+
+struct Section {
+    virtual_address: u32,
+    virtual_size: u32,
+    raw_offset: u32,
+    raw_size: u32,
+}
+
+pub struct PeFile<'a> {
+    data: &'a [u8],
+    sections: Vec<Section>,
+    resource_dir: Option<(u32, u32)>, // RVA and size
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+impl<'a> PeFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<PeFile<'a>, String> {
+        if data.get(0..2) != Some(b"MZ") {
+            return Err(format!("Not a PE file (invalid DOS header signature)"));
+        }
+        let nt_offset = read_u32(data, 0x3C).ok_or("Truncated DOS header")? as usize;
+        if data.get(nt_offset..nt_offset + 4) != Some(b"PE\0\0") {
+            return Err(format!("Not a PE file (invalid NT header signature)"));
+        }
+        let file_header = nt_offset + 4;
+        let section_count = read_u16(data, file_header + 2).ok_or("Truncated file header")? as usize;
+        let optional_header_size = read_u16(data, file_header + 16).ok_or("Truncated file header")? as usize;
+        let optional_header = file_header + 20;
+        let (rva_count_offset, data_dirs_offset) = match read_u16(data, optional_header) {
+            Some(IMAGE_NT_OPTIONAL_HDR32_MAGIC) => (92, 96),
+            Some(IMAGE_NT_OPTIONAL_HDR64_MAGIC) => (108, 112),
+            other => return Err(format!("Unsupported PE optional header magic {:?}", other)),
+        };
+        let rva_count = read_u32(data, optional_header + rva_count_offset).ok_or("Truncated optional header")? as usize;
+        let resource_dir = if rva_count > IMAGE_DIRECTORY_ENTRY_RESOURCE {
+            let dir_offset = optional_header + data_dirs_offset + IMAGE_DIRECTORY_ENTRY_RESOURCE * 8;
+            match (read_u32(data, dir_offset), read_u32(data, dir_offset + 4)) {
+                (Some(rva), Some(size)) if rva != 0 && size != 0 => Some((rva, size)),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        let mut sections = Vec::new();
+        let sections_offset = optional_header + optional_header_size;
+        for i in 0..section_count {
+            let header = sections_offset + i * 40;
+            match (read_u32(data, header + 8), read_u32(data, header + 12),
+                   read_u32(data, header + 16), read_u32(data, header + 20)) {
+                (Some(virtual_size), Some(virtual_address), Some(raw_size), Some(raw_offset)) =>
+                    sections.push(Section { virtual_address, virtual_size, raw_offset, raw_size }),
+                _ => return Err(format!("Truncated section header #{}", i)),
+            }
+        }
+        Ok(PeFile { data, sections, resource_dir })
+    }
+
+    fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        for section in &self.sections {
+            let size = std::cmp::max(section.virtual_size, section.raw_size);
+            if rva >= section.virtual_address && rva < section.virtual_address.saturating_add(size) {
+                let delta = rva - section.virtual_address;
+                if delta >= section.raw_size {
+                    return None; // uninitialized data, not backed by the file
+                }
+                return Some((section.raw_offset + delta) as usize);
+            }
+        }
+        None
+    }
+
+    fn resource_dir_entries(&self, dir_start: usize, dir_offset: u32) -> Result<Vec<(ResourceId, u32, bool)>, String> {
+        let dir = dir_start + (dir_offset as usize);
+        let (named_count, id_count) = match (read_u16(self.data, dir + 12), read_u16(self.data, dir + 14)) {
+            (Some(n), Some(i)) => (n as usize, i as usize),
+            _ => return Err(format!("Truncated resource directory at offset {}", dir)),
+        };
+        let mut entries = Vec::new();
+        for i in 0..(named_count + id_count) {
+            let entry = dir + 16 + i * 8;
+            let (name, offset) = match (read_u32(self.data, entry), read_u32(self.data, entry + 4)) {
+                (Some(n), Some(o)) => (n, o),
+                _ => return Err(format!("Truncated resource directory entry at offset {}", entry)),
+            };
+            let name = if (name & 0x8000_0000) != 0 {
+                // Names are stored as a u16 length followed by as many UTF-16 characters
+                let name_offset = dir_start + (name & 0x7FFF_FFFF) as usize;
+                let len = read_u16(self.data, name_offset).ok_or("Truncated resource name")? as usize;
+                let chars: Vec<u16> = match self.data.get(name_offset + 2..name_offset + 2 + len * 2) {
+                    Some(b) => b.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect(),
+                    None => return Err(format!("Truncated resource name at offset {}", name_offset)),
+                };
+                ResourceId::Name(String::from_utf16_lossy(&chars))
+            } else {
+                ResourceId::Id(name)
+            };
+            entries.push((name, offset & 0x7FFF_FFFF, (offset & 0x8000_0000) != 0));
+        }
+        Ok(entries)
+    }
+
+    // Returns all resources of the given type, in all languages
+    pub fn resources(&self, res_type: &ResourceId) -> Result<Vec<Resource<'a>>, String> {
+        let mut res = Vec::new();
+        let dir_start = match self.resource_dir {
+            Some((rva, _)) => match self.rva_to_offset(rva) {
+                Some(offset) => offset,
+                None => return Err(format!("Resource directory RVA {:#x} is outside of all sections", rva)),
+            },
+            None => return Ok(res),
+        };
+        for (type_id, type_offset, type_is_dir) in self.resource_dir_entries(dir_start, 0)? {
+            if &type_id != res_type || !type_is_dir {
+                continue;
+            }
+            for (name, name_offset, name_is_dir) in self.resource_dir_entries(dir_start, type_offset)? {
+                if !name_is_dir {
+                    continue;
+                }
+                for (language, data_entry_offset, is_dir) in self.resource_dir_entries(dir_start, name_offset)? {
+                    if is_dir {
+                        continue;
+                    }
+                    let language = match language {
+                        ResourceId::Id(i) => i,
+                        ResourceId::Name(_) => 0,
+                    };
+                    let data_entry = dir_start + data_entry_offset as usize;
+                    let (data_rva, data_size) = match (read_u32(self.data, data_entry), read_u32(self.data, data_entry + 4)) {
+                        (Some(rva), Some(size)) => (rva, size as usize),
+                        _ => return Err(format!("Truncated resource data entry at offset {}", data_entry)),
+                    };
+                    let data = match self.rva_to_offset(data_rva).and_then(|o| self.data.get(o..o + data_size)) {
+                        Some(d) => d,
+                        None => return Err(format!("Resource {:?} data is outside of the file", name)),
+                    };
+                    res.push(Resource { name: name.clone(), language, data });
+                }
+            }
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::mpsc::sync_channel;
+
+/*
+ * Rendering with several workers: batches of events are fetched by the calling thread, rendered
+ * by a pool of workers, and written by a single thread, either in the order they were fetched or
+ * as soon as they are rendered. Channels between stages are bounded, so that a slow output
+ * doesn't fill memory.
+ *
+ * Each stage stops when the next one went away: the writer drops its receiver when writing fails,
+ * so that workers stop, and workers share the only receiver of batches, so that fetching stops
+ * once they are all gone instead of blocking on a full channel.
+ */
+
+// Fetches batches with next_batch() until it returns None, renders each item with a renderer
+// from new_worker() (which returns None for items which must be skipped), one per worker so that
+// they can keep their own state, and passes results to write()
+pub fn run_pipeline<I, O, N, F, R, W>(threads: usize, ordered: bool, mut next_batch: N, new_worker: F, mut write: W) -> Result<(), String>
+    where I: Send,
+          O: Send,
+          N: FnMut() -> Result<Option<Vec<I>>, String>,
+          F: Fn() -> R + Sync,
+          R: FnMut(I) -> Option<O>,
+          W: FnMut(O) -> Result<(), String> + Send {
+    let (batch_tx, batch_rx) = sync_channel::<(u64, Vec<I>)>(threads * 2);
+    let batch_rx = Arc::new(Mutex::new(batch_rx));
+    let (output_tx, output_rx) = sync_channel::<(u64, Vec<O>)>(threads * 2);
+    // Set when writing failed, so that no more batches are fetched
+    let failed = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            let batch_rx = Arc::clone(&batch_rx);
+            let output_tx = output_tx.clone();
+            let new_worker = &new_worker;
+            scope.spawn(move || {
+                let mut render = new_worker();
+                loop {
+                    let received = match batch_rx.lock() {
+                        Ok(rx) => rx.recv(),
+                        Err(_) => break,
+                    };
+                    let (batch_num, items) = match received {
+                        Ok(batch) => batch,
+                        Err(_) => break, // no more events to render
+                    };
+                    let outputs : Vec<O> = items.into_iter().filter_map(&mut render).collect();
+                    if output_tx.send((batch_num, outputs)).is_err() {
+                        break; // the writer stopped, because of an error
+                    }
+                }
+            });
+        }
+        drop(batch_rx);
+        drop(output_tx);
+
+        let failed_ref = &failed;
+        let writer = scope.spawn(move || -> Result<(), String> {
+            let mut write_batch = |outputs: Vec<O>| -> Result<(), String> {
+                for output in outputs {
+                    if let Err(e) = write(output) {
+                        failed_ref.store(true, Relaxed);
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            };
+            let mut pending = BTreeMap::new();
+            let mut next_batch_num = 0;
+            for (batch_num, outputs) in output_rx {
+                if !ordered {
+                    write_batch(outputs)?;
+                    continue;
+                }
+                pending.insert(batch_num, outputs);
+                while let Some(outputs) = pending.remove(&next_batch_num) {
+                    write_batch(outputs)?;
+                    next_batch_num += 1;
+                }
+            }
+            Ok(())
+        });
+
+        let mut res = Ok(());
+        let mut batch_num = 0;
+        while !failed.load(Relaxed) {
+            let items = match next_batch() {
+                Ok(Some(items)) => items,
+                Ok(None) => break,
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                },
+            };
+            if batch_tx.send((batch_num, items)).is_err() {
+                break; // all workers stopped, the writer's error is reported below
+            }
+            batch_num += 1;
+        }
+        drop(batch_tx);
+        match writer.join() {
+            Ok(Ok(())) => res,
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err("Output writer thread panicked".to_owned()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const BATCH_SIZE: u64 = 7;
+
+    // Batches of 0..count, then None
+    fn batches(count: u64) -> impl FnMut() -> Result<Option<Vec<u64>>, String> {
+        let mut next = 0;
+        move || {
+            if next >= count {
+                return Ok(None);
+            }
+            let batch = (next..std::cmp::min(next + BATCH_SIZE, count)).collect();
+            next += BATCH_SIZE;
+            Ok(Some(batch))
+        }
+    }
+
+    // Later batches are rendered faster, so that they are ready first
+    fn render(i: u64) -> Option<u64> {
+        std::thread::sleep(Duration::from_micros(50 * ((1000 - i) % 5)));
+        Some(i)
+    }
+
+    #[test]
+    fn writes_in_order() {
+        let mut written = vec![];
+        run_pipeline(4, true, batches(1000), || render, |i| {
+            written.push(i);
+            Ok(())
+        }).unwrap();
+        assert_eq!(written, (0..1000).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn writes_unordered_and_skips() {
+        let mut written = vec![];
+        run_pipeline(3, false, batches(1000), || |i| if i % 10 == 0 { None } else { render(i) }, |i| {
+            written.push(i);
+            Ok(())
+        }).unwrap();
+        written.sort_unstable();
+        assert_eq!(written, (0..1000).filter(|i| i % 10 != 0).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn stops_when_writing_fails() {
+        for &ordered in &[true, false] {
+            // Events keep coming: this only returns if fetching stops after the writer failed
+            let mut fetched = 0;
+            let next_batch = || {
+                fetched += 1;
+                Ok(Some(vec![0u64; BATCH_SIZE as usize]))
+            };
+            let mut written = 0;
+            let res = run_pipeline(4, ordered, next_batch, || Some, |_| {
+                written += 1;
+                if written > 50 {
+                    return Err("disk full".to_owned());
+                }
+                Ok(())
+            });
+            assert_eq!(res, Err("disk full".to_owned()));
+            assert_eq!(written, 51);
+            // At most what fills the channels and workers, plus the batch being sent
+            assert!(fetched <= 51 / BATCH_SIZE as usize + 1 + 4 * 2 * 2 + 4 + 1, "fetched {} batches", fetched);
+        }
+    }
+
+    #[test]
+    fn returns_fetching_errors() {
+        let mut fetched = 0;
+        let next_batch = || {
+            fetched += 1;
+            if fetched > 3 {
+                return Err("EvtNext() failed".to_owned());
+            }
+            Ok(Some(vec![fetched]))
+        };
+        let mut written = vec![];
+        let res = run_pipeline(2, true, next_batch, || Some, |i| {
+            written.push(i);
+            Ok(())
+        });
+        assert_eq!(res, Err("EvtNext() failed".to_owned()));
+        assert_eq!(written, vec![1, 2, 3]);
+    }
+
+    // Reports how many items a worker rendered when it stops, like counters merged at the end
+    struct WorkerCount<'a> {
+        count: u64,
+        counts: &'a Mutex<Vec<u64>>,
+    }
+
+    impl<'a> WorkerCount<'a> {
+        fn add(&mut self) {
+            self.count += 1;
+        }
+    }
+
+    impl<'a> Drop for WorkerCount<'a> {
+        fn drop(&mut self) {
+            self.counts.lock().unwrap().push(self.count);
+        }
+    }
+
+    #[test]
+    fn keeps_worker_state() {
+        let counts = Mutex::new(vec![]);
+        let new_worker = || {
+            let mut worker = WorkerCount { count: 0, counts: &counts };
+            move |i: u64| {
+                worker.add();
+                render(i)
+            }
+        };
+        run_pipeline(3, true, batches(1000), new_worker, |_| Ok(())).unwrap();
+        let counts = counts.into_inner().unwrap();
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts.iter().sum::<u64>(), 1000);
+    }
+}
@@ -0,0 +1,954 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::str::FromStr;
+#[cfg(windows)]
+use std::sync::Mutex;
+#[cfg(windows)]
+use crate::windows::EvtHandle;
+#[cfg(windows)]
+use crate::RenderingConfig;
+use crate::EventOutput;
+use crate::formatting::{CommonEventProperties, DateConfig, format_filetime, FILETIME_TICKS_PER_SEC};
+#[cfg(windows)]
+use crate::formatting::expiry_time;
+use crate::inference::data_value;
+#[cfg(windows)]
+use crate::inference::event_data_values;
+use crate::json::date_json_value;
+#[cfg(windows)]
+use crate::xml::render_xml_string;
+
+// Events which start and end processes and boot sessions, read with these filters by default
+pub const PROCESS_EVENT_FILTERS: &[&str] = &[
+    "Security/Microsoft-Windows-Security-Auditing/4608", // Windows is starting up
+    "Security/Microsoft-Windows-Security-Auditing/4688", // process created
+    "Security/Microsoft-Windows-Security-Auditing/4689", // process exited
+    "*/Microsoft-Windows-Sysmon/1", // process created
+    "*/Microsoft-Windows-Sysmon/5", // process terminated
+    "System/Microsoft-Windows-Kernel-General/12", // operating system started
+];
+
+const SECURITY_PROVIDER: &str = "Microsoft-Windows-Security-Auditing";
+const SYSMON_PROVIDER: &str = "Microsoft-Windows-Sysmon";
+const KERNEL_GENERAL_PROVIDER: &str = "Microsoft-Windows-Kernel-General";
+
+// The same process created with both auditing and Sysmon is logged by each within this delay
+const DUPLICATE_WINDOW_SECS: u64 = 2;
+// Boot events of both Security and System are logged within this delay
+const BOOT_WINDOW_SECS: u64 = 300;
+// Channels are not read in time order: events are replayed in time order once events this much
+// later were read, and events read later than that are replayed as they come
+const REORDER_WINDOW_SECS: u64 = 600;
+// Trees with processes still running are written anyway after this long without any event, and
+// checked for it at this interval
+const IDLE_TREE_SECS: u64 = 86400;
+const IDLE_CHECK_INTERVAL_SECS: u64 = 60;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProcessTreeFormat {
+    Json,
+    // Indented like ps --forest, one process per line
+    Text,
+    // Graphviz digraph, with a cluster per host and boot session
+    Dot,
+}
+
+impl FromStr for ProcessTreeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(ProcessTreeFormat::Json),
+            "text" => Ok(ProcessTreeFormat::Text),
+            "dot" => Ok(ProcessTreeFormat::Dot),
+            _ => Err(format!("Unknown process tree format '{}' (expected json, text or dot)", s)),
+        }
+    }
+}
+
+enum ProcessEventKind {
+    Boot,
+    Start,
+    Exit,
+}
+
+// Values of an event, kept until it can be replayed in time order
+struct ProcessEvent {
+    kind: ProcessEventKind,
+    hostname: String,
+    timestamp: u64,
+    recordid: u64,
+    eventid: u64,
+    sysmon: bool,
+    pid: u64,
+    parent_pid: Option<u64>,
+    image: Option<String>,
+    parent_image: Option<String>,
+    command_line: Option<String>,
+    user: Option<String>,
+    logon_id: Option<String>,
+    integrity: Option<String>,
+    guid: Option<String>,
+    parent_guid: Option<String>,
+    exit_status: Option<String>,
+}
+
+#[derive(Default)]
+struct ProcessNode {
+    pid: u64,
+    image: Option<String>,
+    command_line: Option<String>,
+    user: Option<String>,
+    logon_id: Option<String>,
+    integrity: Option<String>,
+    guid: Option<String>,
+    start: Option<u64>,
+    end: Option<u64>,
+    exit_status: Option<String>,
+    // Its exit was logged, or its PID was reused
+    exited: bool,
+    // Only known as the parent of other processes, e.g. started before the first event read
+    inferred: bool,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    // Event ID and record ID of events about this process
+    events: Vec<(u64, u64)>,
+    // Whether each creation of this process was logged by Sysmon (or by auditing)
+    sources: Vec<bool>,
+}
+
+// Processes of a tree not exited yet, and timestamp of its latest event
+struct TreeState {
+    open: usize,
+    last_seen: u64,
+}
+
+// Processes of a host since it booted, or of trees of it which ended and are written
+struct BootSession {
+    hostname: String,
+    // 0 for processes seen before the first boot event of the host
+    index: usize,
+    start: Option<u64>,
+    processes: HashMap<usize, ProcessNode>,
+    next_idx: usize,
+    // Processes currently running with each PID, and by Sysmon GUID
+    running: HashMap<u64, usize>,
+    guids: HashMap<String, usize>,
+    // Trees not written yet, by root
+    trees: HashMap<usize, TreeState>,
+}
+
+pub struct ProcessTrees {
+    // Events not replayed yet, in time order then in the order they were read
+    pending: BTreeMap<(u64, u64), ProcessEvent>,
+    read_events: u64,
+    // Timestamp of the latest event read, and up to which events were replayed
+    latest: u64,
+    replayed: u64,
+    last_idle_check: u64,
+    late_events: u64,
+    // Current boot session of each host
+    sessions: HashMap<String, BootSession>,
+    format: ProcessTreeFormat,
+    // Only subtrees rooted at processes matching one of these image patterns or PIDs are written
+    roots: Vec<String>,
+    written_trees: usize,
+    // The DOT graph is opened with the first tree written, and closed once all events are read
+    dot_started: bool,
+}
+
+fn secs_ticks(secs: u64) -> u64 {
+    secs * FILETIME_TICKS_PER_SEC as u64
+}
+
+// Auditing logs PIDs in hexadecimal, Sysmon in decimal
+fn parse_pid(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16).ok()
+    } else {
+        u64::from_str(value).ok()
+    }
+}
+
+fn pid_value(data: &HashMap<String, String>, name: &str) -> Option<u64> {
+    data_value(data, name).and_then(|pid| parse_pid(&pid))
+}
+
+fn account_name(data: &HashMap<String, String>, prefix: &str) -> Option<String> {
+    match (data_value(data, &format!("{}DomainName", prefix)), data_value(data, &format!("{}UserName", prefix))) {
+        (Some(domain), Some(user)) => Some(format!("{}\\{}", domain, user)),
+        (None, user) => user,
+        (Some(_), None) => None,
+    }
+}
+
+fn image_name(image: &str) -> &str {
+    image.rsplit('\\').next().unwrap_or(image)
+}
+
+fn same_image(a: &Option<String>, b: &Option<String>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        _ => true,
+    }
+}
+
+// Case-insensitive match with * wildcards
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !value.starts_with(first) || value.len() < first.len() + last.len() || !value.ends_with(last) {
+        return false;
+    }
+    let mut rest = &value[first.len()..value.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+fn process_event(common_props: &CommonEventProperties, data: &HashMap<String, String>) -> Option<ProcessEvent> {
+    let sysmon = common_props.provider == SYSMON_PROVIDER;
+    let kind = match (&common_props.provider[..], common_props.eventid) {
+        (SECURITY_PROVIDER, 4608) | (KERNEL_GENERAL_PROVIDER, 12) => ProcessEventKind::Boot,
+        (SECURITY_PROVIDER, 4688) | (SYSMON_PROVIDER, 1) => ProcessEventKind::Start,
+        (SECURITY_PROVIDER, 4689) | (SYSMON_PROVIDER, 5) => ProcessEventKind::Exit,
+        _ => return None,
+    };
+    let mut event = ProcessEvent {
+        kind,
+        hostname: common_props.hostname.to_owned(),
+        timestamp: common_props.timestamp,
+        recordid: common_props.recordid,
+        eventid: common_props.eventid,
+        sysmon,
+        pid: 0,
+        parent_pid: None,
+        image: None,
+        parent_image: None,
+        command_line: None,
+        user: None,
+        logon_id: None,
+        integrity: None,
+        guid: None,
+        parent_guid: None,
+        exit_status: None,
+    };
+    match (&event.kind, sysmon) {
+        (ProcessEventKind::Boot, _) => return Some(event),
+        (ProcessEventKind::Start, false) => {
+            event.pid = pid_value(data, "NewProcessId")?;
+            event.parent_pid = pid_value(data, "ProcessId");
+            event.image = data_value(data, "NewProcessName");
+            event.parent_image = data_value(data, "ParentProcessName");
+            event.command_line = data_value(data, "CommandLine");
+            // Since version 2, processes created with another account have it as target
+            event.user = account_name(data, "Target").or_else(|| account_name(data, "Subject"));
+            event.logon_id = data_value(data, "TargetLogonId").filter(|id| id != "0x0")
+                .or_else(|| data_value(data, "SubjectLogonId"));
+            event.integrity = data_value(data, "MandatoryLabel");
+        },
+        (ProcessEventKind::Start, true) => {
+            event.pid = pid_value(data, "ProcessId")?;
+            event.parent_pid = pid_value(data, "ParentProcessId");
+            event.image = data_value(data, "Image");
+            event.parent_image = data_value(data, "ParentImage");
+            event.command_line = data_value(data, "CommandLine");
+            event.user = data_value(data, "User");
+            event.logon_id = data_value(data, "LogonId");
+            event.integrity = data_value(data, "IntegrityLevel");
+            event.guid = data_value(data, "ProcessGuid");
+            event.parent_guid = data_value(data, "ParentProcessGuid");
+        },
+        (ProcessEventKind::Exit, false) => {
+            event.pid = pid_value(data, "ProcessId")?;
+            event.image = data_value(data, "ProcessName");
+            event.exit_status = data_value(data, "Status");
+        },
+        (ProcessEventKind::Exit, true) => {
+            event.pid = pid_value(data, "ProcessId")?;
+            event.image = data_value(data, "Image");
+            event.guid = data_value(data, "ProcessGuid");
+        },
+    }
+    Some(event)
+}
+
+impl BootSession {
+    fn new(hostname: &str, index: usize, start: Option<u64>) -> BootSession {
+        BootSession {
+            hostname: hostname.to_owned(),
+            index,
+            start,
+            processes: HashMap::new(),
+            next_idx: 0,
+            running: HashMap::new(),
+            guids: HashMap::new(),
+            trees: HashMap::new(),
+        }
+    }
+
+    fn add_node(&mut self, node: ProcessNode, timestamp: u64) -> usize {
+        let idx = self.next_idx;
+        self.next_idx += 1;
+        self.running.insert(node.pid, idx);
+        if let Some(guid) = &node.guid {
+            self.guids.insert(guid.to_owned(), idx);
+        }
+        match node.parent {
+            Some(parent) => {
+                let root = self.tree_root(parent);
+                if let Some(tree) = self.trees.get_mut(&root) {
+                    tree.open += 1;
+                }
+                self.processes.get_mut(&parent).unwrap().children.push(idx);
+                self.touch(root, timestamp);
+            },
+            None => {
+                self.trees.insert(idx, TreeState { open: 1, last_seen: timestamp });
+            },
+        }
+        self.processes.insert(idx, node);
+        idx
+    }
+
+    fn tree_root(&self, mut idx: usize) -> usize {
+        while let Some(parent) = self.processes[&idx].parent {
+            idx = parent;
+        }
+        idx
+    }
+
+    fn touch(&mut self, root: usize, timestamp: u64) {
+        if let Some(tree) = self.trees.get_mut(&root) {
+            tree.last_seen = std::cmp::max(tree.last_seen, timestamp);
+        }
+    }
+
+    // Returns the root of the tree of a process if all of its processes exited
+    fn mark_exited(&mut self, idx: usize, timestamp: u64) -> Option<usize> {
+        let root = self.tree_root(idx);
+        self.touch(root, timestamp);
+        let node = self.processes.get_mut(&idx).unwrap();
+        if node.exited {
+            return None;
+        }
+        node.exited = true;
+        let tree = self.trees.get_mut(&root)?;
+        tree.open -= 1;
+        if tree.open == 0 { Some(root) } else { None }
+    }
+
+    // Returns the running process which created a new one, or one inferred from the event
+    fn parent_of(&mut self, event: &ProcessEvent) -> Option<usize> {
+        if let Some(idx) = event.parent_guid.as_ref().and_then(|guid| self.guids.get(guid)) {
+            return Some(*idx);
+        }
+        let parent_pid = event.parent_pid?;
+        if let Some(idx) = self.running.get(&parent_pid) {
+            if same_image(&self.processes[idx].image, &event.parent_image) {
+                return Some(*idx);
+            }
+        }
+        Some(self.add_node(ProcessNode {
+            pid: parent_pid,
+            image: event.parent_image.clone(),
+            guid: event.parent_guid.clone(),
+            inferred: true,
+            ..Default::default()
+        }, event.timestamp))
+    }
+
+    // Returns the root of a tree which ended, when the process reused the PID of its last process
+    fn start_process(&mut self, event: ProcessEvent) -> Option<usize> {
+        let mut ended = None;
+        // Creations logged by both auditing and Sysmon complete each other
+        if let Some(idx) = self.running.get(&event.pid).copied() {
+            let node = self.processes.get_mut(&idx).unwrap();
+            let duplicate = !node.inferred && !node.sources.contains(&event.sysmon) && same_image(&node.image, &event.image) &&
+                node.start.map(|start| start.saturating_add(secs_ticks(DUPLICATE_WINDOW_SECS)) >= event.timestamp).unwrap_or(false);
+            if duplicate {
+                node.command_line = node.command_line.take().or(event.command_line);
+                node.user = node.user.take().or(event.user);
+                node.logon_id = node.logon_id.take().or(event.logon_id);
+                node.integrity = node.integrity.take().or(event.integrity);
+                node.events.push((event.eventid, event.recordid));
+                node.sources.push(event.sysmon);
+                if let Some(guid) = event.guid {
+                    node.guid = Some(guid.to_owned());
+                    self.guids.insert(guid, idx);
+                }
+                let root = self.tree_root(idx);
+                self.touch(root, event.timestamp);
+                return None;
+            }
+            // Otherwise the PID was reused, after its previous process exited without being logged
+            self.running.remove(&event.pid);
+            ended = self.mark_exited(idx, event.timestamp);
+        }
+        let parent = self.parent_of(&event);
+        let timestamp = event.timestamp;
+        self.add_node(ProcessNode {
+            pid: event.pid,
+            image: event.image,
+            command_line: event.command_line,
+            user: event.user,
+            logon_id: event.logon_id,
+            integrity: event.integrity,
+            guid: event.guid,
+            start: Some(timestamp),
+            parent,
+            events: vec![(event.eventid, event.recordid)],
+            sources: vec![event.sysmon],
+            ..Default::default()
+        }, timestamp);
+        // The new process may have been created by a process of that tree
+        ended.filter(|root| self.trees.get(root).map(|tree| tree.open == 0).unwrap_or(false))
+    }
+
+    // Returns the root of the tree of the process if all of its processes exited
+    fn exit_process(&mut self, event: ProcessEvent) -> Option<usize> {
+        let idx = *event.guid.as_ref().and_then(|guid| self.guids.get(guid)).or_else(|| self.running.get(&event.pid))?;
+        let node = self.processes.get_mut(&idx).unwrap();
+        if node.end.is_none() {
+            node.end = Some(event.timestamp);
+        }
+        node.exit_status = node.exit_status.take().or(event.exit_status);
+        node.events.push((event.eventid, event.recordid));
+        if self.running.get(&event.pid) == Some(&idx) {
+            self.running.remove(&event.pid);
+        }
+        self.mark_exited(idx, event.timestamp)
+    }
+
+    // Removes a tree, as a session of its own to be written
+    fn take_tree(&mut self, root: usize) -> BootSession {
+        let mut tree = BootSession::new(&self.hostname, self.index, self.start);
+        self.trees.remove(&root);
+        let mut stack = vec![root];
+        while let Some(idx) = stack.pop() {
+            let node = match self.processes.remove(&idx) {
+                Some(node) => node,
+                None => continue,
+            };
+            if self.running.get(&node.pid) == Some(&idx) {
+                self.running.remove(&node.pid);
+            }
+            if let Some(guid) = &node.guid {
+                if self.guids.get(guid) == Some(&idx) {
+                    self.guids.remove(guid);
+                }
+            }
+            stack.extend(node.children.iter().copied());
+            tree.processes.insert(idx, node);
+        }
+        tree
+    }
+
+    // Removes trees without any event since the given time, or all of them, in the order they started
+    fn take_trees(&mut self, idle_since: Option<u64>) -> Vec<BootSession> {
+        let mut roots: Vec<(u64, usize)> = self.trees.iter()
+            .filter(|(_, tree)| idle_since.map(|since| tree.last_seen < since).unwrap_or(true))
+            .map(|(root, _)| (self.processes[root].start.unwrap_or(0), *root))
+            .collect();
+        roots.sort_unstable();
+        roots.into_iter().map(|(_, root)| self.take_tree(root)).collect()
+    }
+}
+
+impl ProcessTrees {
+    pub fn new(format: ProcessTreeFormat, roots: Vec<String>) -> ProcessTrees {
+        ProcessTrees {
+            pending: BTreeMap::new(),
+            read_events: 0,
+            latest: 0,
+            replayed: 0,
+            last_idle_check: 0,
+            late_events: 0,
+            sessions: HashMap::new(),
+            format,
+            roots,
+            written_trees: 0,
+            dot_started: false,
+        }
+    }
+
+    // Returns trees which ended or were idle for too long once the event is read
+    fn add_event(&mut self, event: ProcessEvent) -> Vec<BootSession> {
+        let mut ended = vec![];
+        self.latest = std::cmp::max(self.latest, event.timestamp);
+        if event.timestamp < self.replayed {
+            self.late_events += 1;
+            self.replay(event, &mut ended);
+        } else {
+            self.pending.insert((event.timestamp, self.read_events), event);
+        }
+        self.read_events += 1;
+        ended.extend(self.advance(self.latest));
+        ended
+    }
+
+    // Replays events read long enough before the given time, and returns trees which ended or
+    // were idle for too long
+    fn advance(&mut self, now: u64) -> Vec<BootSession> {
+        let horizon = now.saturating_sub(secs_ticks(REORDER_WINDOW_SECS));
+        let mut ended = vec![];
+        while self.pending.keys().next().map(|(timestamp, _)| *timestamp <= horizon).unwrap_or(false) {
+            if let Some((_, event)) = self.pending.pop_first() {
+                self.replay(event, &mut ended);
+            }
+        }
+        self.replayed = std::cmp::max(self.replayed, horizon);
+        if horizon >= self.last_idle_check.saturating_add(secs_ticks(IDLE_CHECK_INTERVAL_SECS)) {
+            self.last_idle_check = horizon;
+            let idle_since = horizon.saturating_sub(secs_ticks(IDLE_TREE_SECS));
+            for session in self.sessions.values_mut() {
+                ended.extend(session.take_trees(Some(idle_since)));
+            }
+        }
+        ended
+    }
+
+    // Replays an event into the current boot session of its host, starting a new session at each boot
+    fn replay(&mut self, event: ProcessEvent, ended: &mut Vec<BootSession>) {
+        let hostname = event.hostname.to_owned();
+        let session = self.sessions.entry(hostname).or_insert_with(|| BootSession::new(&event.hostname, 0, None));
+        let root = match event.kind {
+            ProcessEventKind::Boot => {
+                let same_boot = session.start
+                    .map(|start| start.saturating_add(secs_ticks(BOOT_WINDOW_SECS)) >= event.timestamp).unwrap_or(false);
+                if !same_boot {
+                    // Processes don't survive a reboot
+                    let mut previous = std::mem::replace(session, BootSession::new(&event.hostname, session.index + 1, Some(event.timestamp)));
+                    ended.extend(previous.take_trees(None));
+                }
+                None
+            },
+            ProcessEventKind::Start => session.start_process(event),
+            ProcessEventKind::Exit => session.exit_process(event),
+        };
+        if let Some(root) = root {
+            ended.push(session.take_tree(root));
+        }
+    }
+
+    // Replays all events left, and returns all trees not written yet
+    fn finish(&mut self) -> Vec<BootSession> {
+        let mut ended = self.advance(u64::MAX);
+        let mut hostnames: Vec<String> = self.sessions.keys().cloned().collect();
+        hostnames.sort();
+        for hostname in hostnames {
+            if let Some(session) = self.sessions.get_mut(&hostname) {
+                ended.extend(session.take_trees(None));
+            }
+        }
+        if self.late_events > 0 {
+            warn!("{} events were read more than {} seconds after later ones, their process trees may be split",
+                  self.late_events, REORDER_WINDOW_SECS);
+        }
+        ended
+    }
+
+    fn is_root(&self, session: &BootSession, idx: usize) -> bool {
+        let node = &session.processes[&idx];
+        if self.roots.is_empty() {
+            return node.parent.is_none();
+        }
+        let matches = |node: &ProcessNode| self.roots.iter().any(|pattern| match parse_pid(pattern) {
+            Some(pid) => node.pid == pid,
+            None => node.image.as_ref().map(|image| wildcard_match(pattern, image) || wildcard_match(pattern, image_name(image))).unwrap_or(false),
+        });
+        // Subtrees of matching processes are only written once, with their matching ancestor
+        let mut ancestor = node.parent;
+        while let Some(parent) = ancestor {
+            if matches(&session.processes[&parent]) {
+                return false;
+            }
+            ancestor = session.processes[&parent].parent;
+        }
+        matches(node)
+    }
+}
+
+fn node_json(session: &BootSession, idx: usize, datefmt: &DateConfig) -> serde_json::Value {
+    let node = &session.processes[&idx];
+    let date = |filetime: Option<u64>| filetime.map(|t| date_json_value(t, datefmt)).unwrap_or(serde_json::Value::Null);
+    let mut json = serde_json::Map::new();
+    json.insert("pid".to_owned(), serde_json::Value::from(node.pid));
+    json.insert("image".to_owned(), serde_json::Value::from(node.image.clone()));
+    json.insert("command_line".to_owned(), serde_json::Value::from(node.command_line.clone()));
+    json.insert("user".to_owned(), serde_json::Value::from(node.user.clone()));
+    json.insert("logon_id".to_owned(), serde_json::Value::from(node.logon_id.clone()));
+    json.insert("integrity".to_owned(), serde_json::Value::from(node.integrity.clone()));
+    json.insert("guid".to_owned(), serde_json::Value::from(node.guid.clone()));
+    json.insert("start".to_owned(), date(node.start));
+    json.insert("end".to_owned(), date(node.end));
+    json.insert("exit_status".to_owned(), serde_json::Value::from(node.exit_status.clone()));
+    json.insert("inferred".to_owned(), serde_json::Value::from(node.inferred));
+    json.insert("events".to_owned(), serde_json::Value::Array(node.events.iter().map(|(eventid, recordid)| {
+        let mut json = serde_json::Map::new();
+        json.insert("eventid".to_owned(), serde_json::Value::from(*eventid));
+        json.insert("recordid".to_owned(), serde_json::Value::from(*recordid));
+        serde_json::Value::Object(json)
+    }).collect()));
+    json.insert("children".to_owned(), serde_json::Value::Array(node.children.iter()
+        .map(|child| node_json(session, *child, datefmt)).collect()));
+    serde_json::Value::Object(json)
+}
+
+fn node_text(session: &BootSession, idx: usize, depth: usize, datefmt: &DateConfig, out: &mut dyn Write) -> Result<(), String> {
+    let node = &session.processes[&idx];
+    let indent = if depth == 0 { "  ".to_owned() } else { format!("  {}\\_ ", "   ".repeat(depth - 1)) };
+    let times = if node.inferred {
+        "not logged".to_owned()
+    } else {
+        format!("{} - {}",
+                node.start.map(|t| format_filetime(t, datefmt)).unwrap_or_else(|| "?".to_owned()),
+                node.end.map(|t| format_filetime(t, datefmt)).unwrap_or_else(|| "running".to_owned()))
+    };
+    let user = node.user.as_ref().map(|user| format!("{}, ", user)).unwrap_or_default();
+    let line = format!("{}{} {} ({}{}) {}", indent, node.pid, node.image.as_deref().unwrap_or("?"), user, times,
+                       node.command_line.as_deref().unwrap_or_default());
+    if let Err(e) = writeln!(out, "{}", line.trim_end()) {
+        return Err(format!("Unable to write process tree: {}", e));
+    }
+    for child in &node.children {
+        node_text(session, *child, depth + 1, datefmt, out)?;
+    }
+    Ok(())
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\r', "").replace('\n', "\\n")
+}
+
+fn node_dot(session: &BootSession, idx: usize, prefix: &str, datefmt: &DateConfig, out: &mut dyn Write) -> Result<(), String> {
+    let node = &session.processes[&idx];
+    let mut label = format!("{} {}", node.pid, node.image.as_deref().map(image_name).unwrap_or("?"));
+    if let Some(user) = &node.user {
+        label.push_str(&format!("\n{}", user));
+    }
+    if let Some(start) = node.start {
+        label.push_str(&format!("\n{}", format_filetime(start, datefmt)));
+    }
+    let style = if node.inferred { ", style=dashed" } else { "" };
+    let res = writeln!(out, "    \"{}{}\" [label=\"{}\", tooltip=\"{}\"{}];", prefix, idx, dot_escape(&label),
+                       dot_escape(node.command_line.as_deref().or(node.image.as_deref()).unwrap_or_default()), style)
+        .and_then(|_| node.children.iter().try_for_each(|child| writeln!(out, "    \"{}{}\" -> \"{}{}\";", prefix, idx, prefix, child)));
+    if let Err(e) = res {
+        return Err(format!("Unable to write process tree: {}", e));
+    }
+    for child in &node.children {
+        node_dot(session, *child, prefix, datefmt, out)?;
+    }
+    Ok(())
+}
+
+fn session_title(session: &BootSession, datefmt: &DateConfig) -> String {
+    match session.start {
+        Some(start) => format!("{}, boot {} (started {})", session.hostname, session.index, format_filetime(start, datefmt)),
+        None => format!("{}, before the first boot logged", session.hostname),
+    }
+}
+
+#[cfg(windows)]
+fn lock_trees(render_cfg: &RenderingConfig) -> Result<std::sync::MutexGuard<'_, ProcessTrees>, String> {
+    let trees: &Mutex<ProcessTrees> = match &render_cfg.process_trees {
+        Some(t) => t,
+        None => return Err(format!("Process trees are not reconstructed")),
+    };
+    match trees.lock() {
+        Ok(t) => Ok(t),
+        Err(e) => Err(format!("Failed to acquire lock to process trees: {}", e)),
+    }
+}
+
+// Correlates an event into process trees, and renders the trees which ended
+#[cfg(windows)]
+pub fn render_event_process_tree(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    if common_props.provider != SECURITY_PROVIDER && common_props.provider != SYSMON_PROVIDER && common_props.provider != KERNEL_GENERAL_PROVIDER {
+        return Ok(EventOutput::default());
+    }
+    let data = event_data_values(&render_xml_string(h_event)?)?;
+    let event = match process_event(common_props, &data) {
+        Some(event) => event,
+        None => return Ok(EventOutput::default()),
+    };
+    let mut trees = lock_trees(render_cfg)?;
+    let ended = trees.add_event(event);
+    trees.write_trees(ended, &render_cfg.datefmt, render_cfg.json_pretty, false)
+}
+
+// Renders trees which ended or were idle for too long
+#[cfg(windows)]
+pub fn expire_process_trees(render_cfg: &RenderingConfig, wall_clock: Option<u64>) -> Result<EventOutput, String> {
+    let mut trees = lock_trees(render_cfg)?;
+    let now = expiry_time(trees.latest, wall_clock);
+    let ended = trees.advance(now);
+    trees.write_trees(ended, &render_cfg.datefmt, render_cfg.json_pretty, false)
+}
+
+// Renders trees not written yet once all events have been read
+#[cfg(windows)]
+pub fn finish_process_trees(render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let mut trees = lock_trees(render_cfg)?;
+    let ended = trees.finish();
+    let output = trees.write_trees(ended, &render_cfg.datefmt, render_cfg.json_pretty, true)?;
+    info!("Wrote {} process trees", trees.written_trees);
+    Ok(output)
+}
+
+impl ProcessTrees {
+    // Renders the subtrees of trees which are selected by roots, and ends the DOT graph at the end
+    fn write_trees(&mut self, ended: Vec<BootSession>, datefmt: &DateConfig, json_pretty: bool, last: bool) -> Result<EventOutput, String> {
+        let write_error = |e: std::io::Error| format!("Unable to write process tree: {}", e);
+        let mut out: Vec<u8> = vec![];
+        let start_dot = |out: &mut Vec<u8>, dot_started: &mut bool| -> Result<(), String> {
+            if !*dot_started {
+                *dot_started = true;
+                writeln!(out, "digraph processes {{\n  node [shape=box, fontname=\"Consolas\"];").map_err(write_error)?;
+            }
+            Ok(())
+        };
+        for session in &ended {
+            let mut roots: Vec<usize> = session.processes.keys().copied().filter(|idx| self.is_root(session, *idx)).collect();
+            if roots.is_empty() {
+                continue;
+            }
+            roots.sort_by_key(|idx| (session.processes[idx].start.unwrap_or(0), *idx));
+            self.written_trees += roots.len();
+            match self.format {
+                ProcessTreeFormat::Json => roots.iter().try_for_each(|idx| {
+                    let mut json = serde_json::Map::new();
+                    json.insert("hostname".to_owned(), serde_json::Value::from(&session.hostname[..]));
+                    json.insert("boot".to_owned(), serde_json::Value::from(session.index));
+                    json.insert("boot_start".to_owned(), session.start.map(|t| date_json_value(t, datefmt)).unwrap_or(serde_json::Value::Null));
+                    if let serde_json::Value::Object(node) = node_json(session, *idx, datefmt) {
+                        json.extend(node);
+                    }
+                    let json = if json_pretty {
+                        serde_json::to_string_pretty(&json)
+                    } else {
+                        serde_json::to_string(&json)
+                    };
+                    match json {
+                        Ok(s) => writeln!(out, "{}", s).map_err(write_error),
+                        Err(e) => Err(format!("JSON serialization failed: {}", e)),
+                    }
+                })?,
+                ProcessTreeFormat::Text => {
+                    writeln!(out, "{}", session_title(session, datefmt)).map_err(write_error)?;
+                    roots.iter().try_for_each(|idx| node_text(session, *idx, 0, datefmt, &mut out))?;
+                    writeln!(out).map_err(write_error)?;
+                },
+                ProcessTreeFormat::Dot => {
+                    start_dot(&mut out, &mut self.dot_started)?;
+                    // Each tree is written as a cluster of its own, with the title of its session
+                    let cluster = self.written_trees;
+                    writeln!(out, "  subgraph \"cluster_{}\" {{\n    label=\"{}\";", cluster, dot_escape(&session_title(session, datefmt)))
+                        .map_err(write_error)?;
+                    roots.iter().try_for_each(|idx| node_dot(session, *idx, &format!("{}/", cluster), datefmt, &mut out))?;
+                    writeln!(out, "  }}").map_err(write_error)?;
+                },
+            }
+        }
+        if last && self.format == ProcessTreeFormat::Dot {
+            start_dot(&mut out, &mut self.dot_started)?;
+            writeln!(out, "}}").map_err(write_error)?;
+        }
+        match String::from_utf8(out) {
+            Ok(body) => Ok(EventOutput { header: None, body }),
+            Err(e) => Err(format!("Unable to write process tree: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECS: u64 = FILETIME_TICKS_PER_SEC as u64;
+    const START: u64 = 132_593_643_670_000_000;
+
+    fn add(trees: &mut ProcessTrees, provider: &str, eventid: u64, secs: u64, data: &[(&str, &str)]) -> Vec<BootSession> {
+        let common_props = CommonEventProperties {
+            timestamp: START + secs * SECS,
+            hostname: "ws01".to_owned(),
+            recordid: 1000 + secs,
+            provider: provider.to_owned(),
+            eventid,
+            ..Default::default()
+        };
+        let data: HashMap<String, String> = data.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        trees.add_event(process_event(&common_props, &data).unwrap())
+    }
+
+    fn created(pid: &str, image: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("NewProcessId", pid.to_owned()),
+            ("NewProcessName", image.to_owned()),
+            ("ProcessId", "0x100".to_owned()),
+            ("ParentProcessName", "C:\\Windows\\explorer.exe".to_owned()),
+            ("SubjectUserName", "alice".to_owned()),
+            ("SubjectDomainName", "CORP".to_owned()),
+            ("SubjectLogonId", "0x3e7a".to_owned()),
+            ("TargetUserName", "-".to_owned()),
+            ("TargetLogonId", "0x0".to_owned()),
+        ]
+    }
+
+    fn data<'a>(values: &'a [(&'static str, String)]) -> Vec<(&'static str, &'a str)> {
+        values.iter().map(|(k, v)| (*k, v.as_str())).collect()
+    }
+
+    // Processes of the trees with this image name, in the order they started
+    fn nodes<'a>(sessions: &'a [BootSession], image: &str) -> Vec<&'a ProcessNode> {
+        let mut nodes: Vec<&ProcessNode> = sessions.iter().flat_map(|session| session.processes.values())
+            .filter(|node| node.image.as_deref().map(image_name) == Some(image))
+            .collect();
+        nodes.sort_by_key(|node| node.start);
+        nodes
+    }
+
+    #[test]
+    fn reuses_pids_in_time_order() {
+        // The exit of the first process is read after the creation of the next one with its PID
+        let mut trees = ProcessTrees::new(ProcessTreeFormat::Json, vec![]);
+        add(&mut trees, SECURITY_PROVIDER, 4688, 300, &data(&created("0x1a0", "C:\\Windows\\notepad.exe")));
+        add(&mut trees, SECURITY_PROVIDER, 4689, 200, &[("ProcessId", "0x1a0"), ("ProcessName", "C:\\Windows\\System32\\cmd.exe"), ("Status", "0x1")]);
+        add(&mut trees, SECURITY_PROVIDER, 4688, 100, &data(&created("0x1a0", "C:\\Windows\\System32\\cmd.exe")));
+        // And the exit of this one was not logged at all
+        add(&mut trees, SECURITY_PROVIDER, 4688, 400, &data(&created("0x1a0", "C:\\Windows\\System32\\calc.exe")));
+        let sessions = trees.finish();
+
+        let cmd = nodes(&sessions, "cmd.exe");
+        assert_eq!(cmd.len(), 1);
+        assert_eq!((cmd[0].start, cmd[0].end, cmd[0].exit_status.as_deref()), (Some(START + 100 * SECS), Some(START + 200 * SECS), Some("0x1")));
+        let notepad = nodes(&sessions, "notepad.exe");
+        assert_eq!((notepad[0].start, notepad[0].end, notepad[0].exited), (Some(START + 300 * SECS), None, true));
+        let calc = nodes(&sessions, "calc.exe");
+        assert_eq!((calc[0].end, calc[0].exited), (None, false));
+
+        // All of them were created by the same explorer.exe, inferred from their creation
+        let explorer = nodes(&sessions, "explorer.exe");
+        assert_eq!(explorer.len(), 1);
+        assert!(explorer[0].inferred);
+        assert_eq!(explorer[0].children.len(), 3);
+        assert_eq!(trees.late_events, 0);
+    }
+
+    #[test]
+    fn merges_creations_logged_by_auditing_and_sysmon() {
+        let mut trees = ProcessTrees::new(ProcessTreeFormat::Json, vec![]);
+        add(&mut trees, SECURITY_PROVIDER, 4688, 100, &data(&created("0x1a0", "C:\\Windows\\System32\\cmd.exe")));
+        add(&mut trees, SYSMON_PROVIDER, 1, 101, &[
+            ("ProcessId", "416"), ("Image", "C:\\WINDOWS\\system32\\cmd.exe"), ("CommandLine", "cmd.exe /c dir"),
+            ("ProcessGuid", "{b1}"), ("ParentProcessId", "256"), ("ParentImage", "C:\\Windows\\explorer.exe"),
+            ("ParentProcessGuid", "{a1}"), ("User", "CORP\\alice"), ("IntegrityLevel", "High"),
+        ]);
+        add(&mut trees, SYSMON_PROVIDER, 5, 150, &[("ProcessId", "416"), ("Image", "C:\\WINDOWS\\system32\\cmd.exe"), ("ProcessGuid", "{b1}")]);
+        // Logged by auditing again later than the delay, the PID was reused
+        add(&mut trees, SECURITY_PROVIDER, 4688, 200, &data(&created("0x1a0", "C:\\Windows\\System32\\cmd.exe")));
+        add(&mut trees, SECURITY_PROVIDER, 4688, 203, &data(&created("0x1a0", "C:\\Windows\\System32\\cmd.exe")));
+        let sessions = trees.finish();
+
+        let cmd = nodes(&sessions, "cmd.exe");
+        assert_eq!(cmd.len(), 3);
+        assert_eq!(cmd[0].events, vec![(4688, 1100), (1, 1101), (5, 1150)]);
+        assert_eq!(cmd[0].sources, vec![false, true]);
+        assert_eq!((cmd[0].user.as_deref(), cmd[0].logon_id.as_deref()), (Some("CORP\\alice"), Some("0x3e7a")));
+        assert_eq!((cmd[0].command_line.as_deref(), cmd[0].integrity.as_deref()), (Some("cmd.exe /c dir"), Some("High")));
+        assert_eq!((cmd[0].guid.as_deref(), cmd[0].end), (Some("{b1}"), Some(START + 150 * SECS)));
+        assert_eq!((cmd[1].events.len(), cmd[2].events.len()), (1, 1));
+    }
+
+    #[test]
+    fn replays_events_after_the_reorder_window() {
+        let mut trees = ProcessTrees::new(ProcessTreeFormat::Json, vec![]);
+        let mut root = created("0x10", "C:\\Windows\\System32\\svchost.exe");
+        root.retain(|(name, _)| *name != "ProcessId");
+        assert!(add(&mut trees, SECURITY_PROVIDER, 4689, 10, &[("ProcessId", "0x10")]).is_empty());
+        assert!(add(&mut trees, SECURITY_PROVIDER, 4688, 0, &data(&root)).is_empty());
+        // Its tree ended, but is only written once events this much later were read
+        assert!(add(&mut trees, SECURITY_PROVIDER, 4688, 609, &data(&created("0x20", "C:\\Windows\\notepad.exe"))).is_empty());
+        let ended = add(&mut trees, SECURITY_PROVIDER, 4688, 610, &data(&created("0x30", "C:\\Windows\\notepad.exe")));
+        assert_eq!(ended.len(), 1);
+        let svchost = nodes(&ended, "svchost.exe");
+        assert_eq!((svchost[0].start, svchost[0].end), (Some(START), Some(START + 10 * SECS)));
+
+        // Events read later than that are replayed as they come
+        add(&mut trees, SECURITY_PROVIDER, 4688, 5, &data(&created("0x40", "C:\\Windows\\System32\\calc.exe")));
+        assert_eq!(trees.late_events, 1);
+        assert_eq!(nodes(&trees.finish(), "notepad.exe").len(), 2);
+    }
+
+    #[test]
+    fn matches_tree_roots() {
+        assert!(wildcard_match("c:\\windows\\*.exe", "C:\\Windows\\System32\\cmd.exe"));
+        assert!(wildcard_match("*WORD*", "winword.exe"));
+        assert!(wildcard_match("a*b*a", "aba"));
+        assert!(!wildcard_match("a*a", "a"));
+        assert!(!wildcard_match("cmd.exe", "C:\\Windows\\System32\\cmd.exe"));
+
+        let mut trees = ProcessTrees::new(ProcessTreeFormat::Json, vec!["cmd.exe".to_owned(), "power*".to_owned(), "0x30".to_owned()]);
+        add(&mut trees, SECURITY_PROVIDER, 4688, 0, &data(&created("0x10", "C:\\Windows\\System32\\cmd.exe")));
+        let mut child = created("0x20", "C:\\Windows\\System32\\WindowsPowerShell\\v1.0\\powershell.exe");
+        child[2].1 = "0x10".to_owned();
+        child[3].1 = "C:\\Windows\\System32\\cmd.exe".to_owned();
+        add(&mut trees, SECURITY_PROVIDER, 4688, 1, &data(&child));
+        add(&mut trees, SECURITY_PROVIDER, 4688, 2, &data(&created("0x30", "C:\\Windows\\notepad.exe")));
+        let sessions = trees.finish();
+        let session = &sessions[0];
+        let mut roots: Vec<&str> = session.processes.keys().filter(|idx| trees.is_root(session, **idx))
+            .map(|idx| image_name(session.processes[idx].image.as_deref().unwrap()))
+            .collect();
+        roots.sort_unstable();
+        // powershell.exe is only written in the tree of cmd.exe
+        assert_eq!(roots, vec!["cmd.exe", "notepad.exe"]);
+    }
+
+    fn written_trees(format: ProcessTreeFormat) -> String {
+        let mut trees = ProcessTrees::new(format, vec![]);
+        add(&mut trees, KERNEL_GENERAL_PROVIDER, 12, 0, &[]);
+        let mut cmd = created("0x1a0", "C:\\Windows\\System32\\cmd.exe");
+        cmd.push(("CommandLine", "cmd.exe /c \"dir\"".to_owned()));
+        add(&mut trees, SECURITY_PROVIDER, 4688, 10, &data(&cmd));
+        add(&mut trees, SECURITY_PROVIDER, 4689, 20, &[("ProcessId", "0x1a0"), ("Status", "0x0")]);
+        let ended = trees.finish();
+        trees.write_trees(ended, &DateConfig::rfc3339_utc(), false, true).unwrap().body
+    }
+
+    #[test]
+    fn writes_process_trees() {
+        assert_eq!(written_trees(ProcessTreeFormat::Text), concat!(
+            "ws01, boot 1 (started 2021-03-04T20:46:07.0000000Z)\n",
+            "  256 C:\\Windows\\explorer.exe (not logged)\n",
+            "  \\_ 416 C:\\Windows\\System32\\cmd.exe (CORP\\alice, 2021-03-04T20:46:17.0000000Z - 2021-03-04T20:46:27.0000000Z) cmd.exe /c \"dir\"\n",
+            "\n"));
+
+        let json: serde_json::Value = serde_json::from_str(&written_trees(ProcessTreeFormat::Json)).unwrap();
+        assert_eq!((&json["hostname"], &json["boot"], &json["boot_start"]), (&"ws01".into(), &1.into(), &"2021-03-04T20:46:07.0000000Z".into()));
+        assert_eq!((&json["pid"], &json["inferred"], &json["start"]), (&256.into(), &true.into(), &serde_json::Value::Null));
+        let cmd = &json["children"][0];
+        assert_eq!((&cmd["pid"], &cmd["user"], &cmd["exit_status"]), (&416.into(), &"CORP\\alice".into(), &"0x0".into()));
+        assert_eq!(cmd["events"], serde_json::json!([{"eventid": 4688, "recordid": 1010}, {"eventid": 4689, "recordid": 1020}]));
+        assert_eq!(cmd["children"], serde_json::json!([]));
+
+        let dot = written_trees(ProcessTreeFormat::Dot);
+        assert!(dot.starts_with("digraph processes {\n"));
+        assert!(dot.contains("  subgraph \"cluster_1\" {\n    label=\"ws01, boot 1 (started 2021-03-04T20:46:07.0000000Z)\";\n"));
+        assert!(dot.contains("    \"1/0\" [label=\"256 explorer.exe\", tooltip=\"C:\\\\Windows\\\\explorer.exe\", style=dashed];\n"));
+        assert!(dot.contains("    \"1/0\" -> \"1/1\";\n"));
+        assert!(dot.contains("tooltip=\"cmd.exe /c \\\"dir\\\"\"];\n"));
+        assert!(dot.ends_with("  }\n}\n"));
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+/*
+ * Minimal read-only parser for registry hive files (e.g. Windows\System32\config\SOFTWARE),
+ * which does not depend on any Windows API so that provider registrations can be read from
+ * a copy of a system's files. Transaction logs (.LOG1/.LOG2) are not replayed, so a hive
+ * copied from a running system may lack its most recent changes.
+ *
+ * Base block (4096 bytes): "regf" ... u32 root_cell_offset @0x24
+ * Followed by "hbin" blocks containing cells: { i32 size (negative if allocated), data }
+ * Cell offsets are relative to the first hbin (file offset 4096).
+ * "nk" u16 flags, u64 last_write, u32 access, u32 parent, u32 subkey_count, u32 volatile_count,
+ *      u32 subkeys_offset, u32 volatile_offset, u32 value_count, u32 values_offset, ...,
+ *      u16 name_length @72, u16 class_length, name @76 (Latin-1 if flags & KEY_COMP_NAME)
+ * "lf"/"lh" u16 count { u32 offset, u32 hash } [count]
+ * "li" u16 count { u32 offset } [count]
+ * "ri" u16 count { u32 offset of another subkey list } [count]
+ * Value list: { u32 offset } [value_count]
+ * "vk" u16 name_length, u32 data_size, u32 data_offset, u32 type, u16 flags, u16 spare, name
+ *      (data is stored in data_offset itself if data_size has its high bit set, and in
+ *      "db" u16 segment_count, u32 segments_offset cells if larger than 16344 bytes)
+ *
+ * See https://github.com/msuhanov/regf/blob/master/Windows%20registry%20file%20format%20specification.md
+ */
+
+const HBIN_START: usize = 4096;
+const KEY_COMP_NAME: u16 = 0x0020;
+const VALUE_COMP_NAME: u16 = 0x0001;
+const DATA_INLINE: u32 = 0x8000_0000;
+const BIG_DATA_SEGMENT_SIZE: usize = 16344;
+const MAX_SUBKEY_LIST_DEPTH: usize = 8;
+
+const REG_SZ: u32 = 1;
+const REG_EXPAND_SZ: u32 = 2;
+const REG_DWORD: u32 = 4;
+const REG_MULTI_SZ: u32 = 7;
+const REG_QWORD: u32 = 11;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegValue {
+    String(String),
+    MultiString(Vec<String>),
+    UInt(u64),
+    Binary(Vec<u8>),
+}
+
+pub struct Hive {
+    data: Vec<u8>,
+    root: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct RegKey<'a> {
+    hive: &'a Hive,
+    offset: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn decode_name(raw: &[u8], compressed: bool) -> String {
+    if compressed {
+        raw.iter().map(|b| *b as char).collect()
+    } else {
+        let chars: Vec<u16> = raw.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        String::from_utf16_lossy(&chars)
+    }
+}
+
+fn decode_utf16_strings(raw: &[u8]) -> Vec<String> {
+    let chars: Vec<u16> = raw.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    chars.split(|c| *c == 0).map(String::from_utf16_lossy).collect()
+}
+
+impl Hive {
+    pub fn open(path: &std::path::Path) -> Result<Hive, String> {
+        match std::fs::read(path) {
+            Ok(data) => Hive::parse(data),
+            Err(e) => Err(format!("Unable to read {} : {}", path.display(), e)),
+        }
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Hive, String> {
+        if data.get(0..4) != Some(b"regf") {
+            return Err(format!("Not a registry hive (invalid base block signature)"));
+        }
+        let root = read_u32(&data, 0x24).ok_or("Truncated base block")? as usize;
+        let hive = Hive { data, root };
+        hive.cell(root, Some(b"nk"))?;
+        Ok(hive)
+    }
+
+    pub fn root(&self) -> RegKey<'_> {
+        RegKey { hive: self, offset: self.root }
+    }
+
+    // Returns the contents of the cell at the given offset (relative to the first hbin)
+    fn cell(&self, offset: usize, signature: Option<&[u8; 2]>) -> Result<&[u8], String> {
+        let start = HBIN_START + offset;
+        let size = match self.data.get(start..start + 4) {
+            Some(b) => i32::from_le_bytes(b.try_into().unwrap()).unsigned_abs() as usize,
+            None => return Err(format!("Cell offset {:#x} is outside of the hive", offset)),
+        };
+        let cell = match self.data.get(start + 4..start + size.max(4)) {
+            Some(c) => c,
+            None => return Err(format!("Truncated cell at offset {:#x}", offset)),
+        };
+        if let Some(signature) = signature {
+            if cell.get(0..2) != Some(&signature[..]) {
+                return Err(format!("Expected {} cell at offset {:#x}", String::from_utf8_lossy(signature), offset));
+            }
+        }
+        Ok(cell)
+    }
+
+    // "ri" lists only reference other lists: the depth and visited lists are tracked so that a
+    // corrupted hive with a loop in its lists is rejected instead of overflowing the stack
+    fn subkey_offsets(&self, list_offset: usize, depth: usize, visited: &mut HashSet<usize>, res: &mut Vec<usize>) -> Result<(), String> {
+        if depth > MAX_SUBKEY_LIST_DEPTH {
+            return Err(format!("Subkey lists nested too deeply at offset {:#x}", list_offset));
+        }
+        if !visited.insert(list_offset) {
+            return Err(format!("Subkey list at offset {:#x} references itself", list_offset));
+        }
+        let list = self.cell(list_offset, None)?;
+        let count = read_u16(list, 2).ok_or("Truncated subkey list")? as usize;
+        let (entry_size, recurse) = match list.get(0..2) {
+            Some(b"lf") | Some(b"lh") => (8, false),
+            Some(b"li") => (4, false),
+            Some(b"ri") => (4, true),
+            other => return Err(format!("Unknown subkey list type {:?} at offset {:#x}", other, list_offset)),
+        };
+        for i in 0..count {
+            let offset = read_u32(list, 4 + i * entry_size).ok_or("Truncated subkey list")? as usize;
+            if recurse {
+                self.subkey_offsets(offset, depth + 1, visited, res)?;
+            } else {
+                res.push(offset);
+            }
+        }
+        Ok(())
+    }
+
+    fn value_data(&self, vk: &[u8]) -> Result<Vec<u8>, String> {
+        let size = read_u32(vk, 4).ok_or("Truncated value")?;
+        if (size & DATA_INLINE) != 0 {
+            let size = ((size & !DATA_INLINE) as usize).min(4);
+            return match vk.get(8..8 + size) {
+                Some(d) => Ok(d.to_vec()),
+                None => Err(format!("Truncated value")),
+            };
+        }
+        let size = size as usize;
+        let offset = read_u32(vk, 8).ok_or("Truncated value")? as usize;
+        let cell = self.cell(offset, None)?;
+        if size > BIG_DATA_SEGMENT_SIZE && cell.get(0..2) == Some(b"db") {
+            let count = read_u16(cell, 2).ok_or("Truncated big data cell")? as usize;
+            let segments = self.cell(read_u32(cell, 4).ok_or("Truncated big data cell")? as usize, None)?;
+            let mut data = Vec::with_capacity(size);
+            for i in 0..count {
+                let segment = read_u32(segments, i * 4).ok_or("Truncated big data segment list")? as usize;
+                let segment = self.cell(segment, None)?;
+                let remaining = size - data.len();
+                data.extend_from_slice(&segment[..remaining.min(BIG_DATA_SEGMENT_SIZE).min(segment.len())]);
+            }
+            return Ok(data);
+        }
+        match cell.get(..size) {
+            Some(d) => Ok(d.to_vec()),
+            None => Err(format!("Value data at offset {:#x} is larger than its cell", offset)),
+        }
+    }
+}
+
+impl<'a> RegKey<'a> {
+    fn nk(&self) -> Result<&'a [u8], String> {
+        self.hive.cell(self.offset, Some(b"nk"))
+    }
+
+    pub fn name(&self) -> Result<String, String> {
+        let nk = self.nk()?;
+        let flags = read_u16(nk, 2).ok_or("Truncated key")?;
+        let len = read_u16(nk, 72).ok_or("Truncated key")? as usize;
+        match nk.get(76..76 + len) {
+            Some(raw) => Ok(decode_name(raw, (flags & KEY_COMP_NAME) != 0)),
+            None => Err(format!("Truncated key name at offset {:#x}", self.offset)),
+        }
+    }
+
+    pub fn subkeys(&self) -> Result<Vec<RegKey<'a>>, String> {
+        let nk = self.nk()?;
+        let count = read_u32(nk, 20).ok_or("Truncated key")?;
+        let mut offsets = Vec::new();
+        if count > 0 {
+            self.hive.subkey_offsets(read_u32(nk, 28).ok_or("Truncated key")? as usize, 0, &mut HashSet::new(), &mut offsets)?;
+        }
+        Ok(offsets.into_iter().map(|offset| RegKey { hive: self.hive, offset }).collect())
+    }
+
+    pub fn subkey(&self, name: &str) -> Result<Option<RegKey<'a>>, String> {
+        for subkey in self.subkeys()? {
+            if subkey.name()?.eq_ignore_ascii_case(name) {
+                return Ok(Some(subkey));
+            }
+        }
+        Ok(None)
+    }
+
+    // Follows a backslash-separated path of subkeys (case insensitive, like the registry)
+    pub fn subkey_path(&self, path: &str) -> Result<Option<RegKey<'a>>, String> {
+        let mut key = *self;
+        for name in path.split('\\').filter(|n| !n.is_empty()) {
+            key = match key.subkey(name)? {
+                Some(k) => k,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(key))
+    }
+
+    // Returns the value with the given name, or the default value of the key if name is empty
+    pub fn value(&self, name: &str) -> Result<Option<RegValue>, String> {
+        let nk = self.nk()?;
+        let count = read_u32(nk, 36).ok_or("Truncated key")? as usize;
+        if count == 0 {
+            return Ok(None);
+        }
+        let list = self.hive.cell(read_u32(nk, 40).ok_or("Truncated key")? as usize, None)?;
+        for i in 0..count {
+            let vk = self.hive.cell(read_u32(list, i * 4).ok_or("Truncated value list")? as usize, Some(b"vk"))?;
+            let name_len = read_u16(vk, 2).ok_or("Truncated value")? as usize;
+            let flags = read_u16(vk, 16).ok_or("Truncated value")?;
+            let value_name = match vk.get(20..20 + name_len) {
+                Some(raw) => decode_name(raw, (flags & VALUE_COMP_NAME) != 0),
+                None => return Err(format!("Truncated value name in key at offset {:#x}", self.offset)),
+            };
+            if !value_name.eq_ignore_ascii_case(name) {
+                continue;
+            }
+            let data = self.hive.value_data(vk)?;
+            let value = match read_u32(vk, 12).ok_or("Truncated value")? {
+                REG_SZ | REG_EXPAND_SZ => RegValue::String(decode_utf16_strings(&data).swap_remove(0)),
+                REG_MULTI_SZ => RegValue::MultiString(decode_utf16_strings(&data).into_iter().filter(|s| !s.is_empty()).collect()),
+                REG_DWORD if data.len() >= 4 => RegValue::UInt(read_u32(&data, 0).unwrap() as u64),
+                REG_QWORD if data.len() >= 8 => RegValue::UInt(u64::from_le_bytes(data[0..8].try_into().unwrap())),
+                _ => RegValue::Binary(data),
+            };
+            return Ok(Some(value));
+        }
+        Ok(None)
+    }
+
+    pub fn string_value(&self, name: &str) -> Result<Option<String>, String> {
+        Ok(match self.value(name)? {
+            Some(RegValue::String(s)) if !s.is_empty() => Some(s),
+            Some(RegValue::MultiString(v)) if !v.is_empty() => Some(v.join(";")),
+            _ => None,
+        })
+    }
+
+    pub fn uint_value(&self, name: &str) -> Result<Option<u64>, String> {
+        Ok(match self.value(name)? {
+            Some(RegValue::UInt(n)) => Some(n),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds hives in memory, with a single hbin and compressed (Latin-1) names
+    struct HiveBuilder {
+        cells: Vec<u8>,
+    }
+
+    impl HiveBuilder {
+        fn new() -> HiveBuilder {
+            let mut cells = b"hbin".to_vec();
+            cells.resize(32, 0);
+            HiveBuilder { cells }
+        }
+
+        fn cell(&mut self, data: &[u8]) -> u32 {
+            let offset = self.cells.len() as u32;
+            let size = (4 + data.len() + 7) & !7;
+            self.cells.extend_from_slice(&(-(size as i32)).to_le_bytes());
+            self.cells.extend_from_slice(data);
+            self.cells.resize(offset as usize + size, 0);
+            offset
+        }
+
+        fn list(&mut self, signature: &[u8; 2], offsets: &[u32]) -> u32 {
+            let mut data = signature.to_vec();
+            data.extend_from_slice(&(offsets.len() as u16).to_le_bytes());
+            for offset in offsets {
+                data.extend_from_slice(&offset.to_le_bytes());
+                if signature != b"li" && signature != b"ri" {
+                    data.extend_from_slice(&[0; 4]);
+                }
+            }
+            self.cell(&data)
+        }
+
+        fn key(&mut self, name: &str, subkey_count: u32, subkeys: u32, values: &[u32]) -> u32 {
+            let values_offset = self.cell(&values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+            let mut nk = vec![0; 76];
+            nk[0..2].copy_from_slice(b"nk");
+            nk[2..4].copy_from_slice(&KEY_COMP_NAME.to_le_bytes());
+            nk[20..24].copy_from_slice(&subkey_count.to_le_bytes());
+            nk[28..32].copy_from_slice(&subkeys.to_le_bytes());
+            nk[36..40].copy_from_slice(&(values.len() as u32).to_le_bytes());
+            nk[40..44].copy_from_slice(&values_offset.to_le_bytes());
+            nk[72..74].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            nk.extend_from_slice(name.as_bytes());
+            self.cell(&nk)
+        }
+
+        // Builds a key from its subkeys, listed in a single "lh" list
+        fn key_with_subkeys(&mut self, name: &str, subkeys: &[u32], values: &[u32]) -> u32 {
+            let list = if subkeys.is_empty() { 0 } else { self.list(b"lh", subkeys) };
+            self.key(name, subkeys.len() as u32, list, values)
+        }
+
+        fn string_value(&mut self, name: &str, value: &str) -> u32 {
+            let data: Vec<u8> = value.encode_utf16().chain(std::iter::once(0)).flat_map(|c| c.to_le_bytes()).collect();
+            let data_offset = self.cell(&data);
+            self.value(name, REG_SZ, data.len() as u32, data_offset)
+        }
+
+        fn dword_value(&mut self, name: &str, value: u32) -> u32 {
+            self.value(name, REG_DWORD, 4 | DATA_INLINE, value)
+        }
+
+        fn value(&mut self, name: &str, value_type: u32, size: u32, data: u32) -> u32 {
+            let mut vk = vec![0; 20];
+            vk[0..2].copy_from_slice(b"vk");
+            vk[2..4].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            vk[4..8].copy_from_slice(&size.to_le_bytes());
+            vk[8..12].copy_from_slice(&data.to_le_bytes());
+            vk[12..16].copy_from_slice(&value_type.to_le_bytes());
+            vk[16..18].copy_from_slice(&VALUE_COMP_NAME.to_le_bytes());
+            vk.extend_from_slice(name.as_bytes());
+            self.cell(&vk)
+        }
+
+        fn build(self, root: u32) -> Vec<u8> {
+            let mut data = b"regf".to_vec();
+            data.resize(HBIN_START, 0);
+            data[0x24..0x28].copy_from_slice(&root.to_le_bytes());
+            data.extend_from_slice(&self.cells);
+            data
+        }
+    }
+
+    fn subkey_names(key: &RegKey) -> Result<Vec<String>, String> {
+        key.subkeys()?.iter().map(|k| k.name()).collect()
+    }
+
+    #[test]
+    fn reads_keys_and_values() {
+        let mut builder = HiveBuilder::new();
+        let default = builder.string_value("", "Provider");
+        let enabled = builder.dword_value("Enabled", 1);
+        let child = builder.key_with_subkeys("Child", &[], &[default, enabled]);
+        let other = builder.key_with_subkeys("Other", &[], &[]);
+        let parent = builder.key_with_subkeys("Parent", &[child, other], &[]);
+        let root = builder.key_with_subkeys("ROOT", &[parent], &[]);
+        let hive = Hive::parse(builder.build(root)).unwrap();
+
+        let child = hive.root().subkey_path(r"parent\CHILD").unwrap().unwrap();
+        assert_eq!(child.string_value("").unwrap().as_deref(), Some("Provider"));
+        assert_eq!(child.uint_value("enabled").unwrap(), Some(1));
+        assert_eq!(child.value("Missing").unwrap(), None);
+        assert!(hive.root().subkey_path(r"Parent\Missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn follows_index_roots() {
+        let mut builder = HiveBuilder::new();
+        let keys: Vec<u32> = (0..4).map(|i| builder.key_with_subkeys(&format!("Key{}", i), &[], &[])).collect();
+        let first = builder.list(b"lf", &keys[..2]);
+        let second = builder.list(b"li", &keys[2..]);
+        let index = builder.list(b"ri", &[first, second]);
+        let root = builder.key("ROOT", 4, index, &[]);
+        let hive = Hive::parse(builder.build(root)).unwrap();
+        assert_eq!(subkey_names(&hive.root()).unwrap(), vec!["Key0", "Key1", "Key2", "Key3"]);
+    }
+
+    #[test]
+    fn rejects_index_root_loops() {
+        let mut builder = HiveBuilder::new();
+        // The list is the first cell after the hbin header, at offset 0x20
+        let index = builder.list(b"ri", &[0x20]);
+        assert_eq!(index, 0x20);
+        let root = builder.key("ROOT", 1, index, &[]);
+        let hive = Hive::parse(builder.build(root)).unwrap();
+        assert!(subkey_names(&hive.root()).is_err());
+    }
+
+    #[test]
+    fn rejects_deeply_nested_index_roots() {
+        let mut builder = HiveBuilder::new();
+        let key = builder.key_with_subkeys("Key", &[], &[]);
+        let mut list = builder.list(b"lh", &[key]);
+        for _ in 0..=MAX_SUBKEY_LIST_DEPTH {
+            list = builder.list(b"ri", &[list]);
+        }
+        let root = builder.key("ROOT", 1, list, &[]);
+        let hive = Hive::parse(builder.build(root)).unwrap();
+        assert!(subkey_names(&hive.root()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_hives() {
+        assert!(Hive::parse(b"not a hive".to_vec()).is_err());
+        let mut builder = HiveBuilder::new();
+        let value = builder.dword_value("NotAKey", 0);
+        assert!(Hive::parse(builder.build(value)).is_err());
+    }
+}
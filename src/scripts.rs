@@ -0,0 +1,399 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+#[cfg(windows)]
+use crate::windows::EvtHandle;
+#[cfg(windows)]
+use crate::{RenderingConfig, EventOutput};
+use crate::formatting::{CommonEventProperties, DateConfig};
+#[cfg(windows)]
+use crate::inference::event_data_values;
+use crate::json::date_json_value;
+#[cfg(windows)]
+use crate::xml::render_xml_string;
+
+// Script blocks logged by Windows PowerShell and PowerShell 6+, read with these filters by default
+pub const SCRIPT_BLOCK_FILTERS: &[&str] = &[
+    "*/Microsoft-Windows-PowerShell/4104",
+    "*/PowerShellCore/4104",
+];
+// Events with command lines which may contain encoded commands, also read with --decode-commands
+pub const ENCODED_COMMAND_FILTERS: &[&str] = &[
+    "Security/Microsoft-Windows-Security-Auditing/4688", // CommandLine
+    "*/Microsoft-Windows-PowerShell/4103", // Host Application in ContextInfo
+    "*/PowerShellCore/4103",
+];
+
+// PowerShell logs script blocks containing suspicious keywords (e.g. of known attacks) as warnings
+const LEVEL_WARNING: u64 = 3;
+// Parts are logged with their number and the total from the event, which can't be trusted: scripts
+// split in more parts than this (of about 20000 characters each) are not expected
+const MAX_MESSAGE_TOTAL: u64 = 10_000;
+
+struct ScriptBlock {
+    hostname: String,
+    script_block_id: String,
+    path: Option<String>,
+    total: u64,
+    parts: BTreeMap<u64, String>,
+    first: u64,
+    last: u64,
+    warning: bool,
+    user_id: Option<String>,
+    process_id: Option<u64>,
+    record_ids: Vec<u64>,
+}
+
+pub struct ScriptBlocks {
+    blocks: HashMap<(String, String), ScriptBlock>,
+    // Script blocks already written, whose parts may be read again (e.g. from a forwarded copy)
+    written: HashSet<(String, String)>,
+    // Scripts are written to files in this directory instead of in JSON records
+    dir: Option<PathBuf>,
+    decode_commands: bool,
+}
+
+// Returns commands given base64-encoded in UTF-16 with -EncodedCommand (or any abbreviation,
+// e.g. -enc or -e, or -ec) to powershell.exe and pwsh.exe
+pub fn decode_encoded_commands(command_line: &str) -> Vec<String> {
+    let mut decoded = vec![];
+    let mut args = command_line.split_whitespace().map(|arg| arg.trim_matches(|c| c == '"' || c == '\''));
+    while let Some(arg) = args.next() {
+        let name = arg.to_lowercase();
+        let name = match name.strip_prefix('-').or_else(|| name.strip_prefix('/')) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        if name != "ec" && !(name.starts_with('e') && "encodedcommand".starts_with(&name[..])) {
+            continue;
+        }
+        // Encoded commands are padded, which avoids decoding words following -e in other commands
+        let bytes = match args.next().filter(|arg| arg.len() % 4 == 0).and_then(|arg| base64::decode(arg).ok()) {
+            Some(bytes) if !bytes.is_empty() && bytes.len() % 2 == 0 => bytes,
+            _ => continue,
+        };
+        let utf16: Vec<u16> = bytes.chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        match String::from_utf16(&utf16) {
+            Ok(command) if !command.chars().any(|c| c.is_control() && !c.is_whitespace()) => decoded.push(command),
+            _ => (),
+        }
+    }
+    decoded
+}
+
+// Keeps file names portable whatever the host name and script block ID
+fn file_name_part(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect()
+}
+
+impl ScriptBlocks {
+    pub fn new(dir: Option<&str>, decode_commands: bool) -> Result<ScriptBlocks, String> {
+        let dir = match dir {
+            Some(dir) => {
+                if let Err(e) = std::fs::create_dir_all(dir) {
+                    return Err(format!("Could not create directory {} : {}", dir, e));
+                }
+                Some(PathBuf::from(dir))
+            },
+            None => None,
+        };
+        Ok(ScriptBlocks {
+            blocks: HashMap::new(),
+            written: HashSet::new(),
+            dir,
+            decode_commands,
+        })
+    }
+
+    fn script_json(&self, block: ScriptBlock, datefmt: &DateConfig) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+        let complete = (1..=block.total).all(|n| block.parts.contains_key(&n));
+        let missing: Vec<u64> = (1..=block.total).filter(|n| !block.parts.contains_key(n)).collect();
+        let mut script = String::new();
+        for n in 1..=block.total.max(block.parts.keys().next_back().copied().unwrap_or(0)) {
+            match block.parts.get(&n) {
+                Some(part) => script.push_str(part),
+                None => script.push_str(&format!("\n# [part {} of {} of this script block was not logged]\n", n, block.total)),
+            }
+        }
+
+        let mut json = serde_json::Map::new();
+        json.insert("type".to_owned(), serde_json::Value::from("script_block"));
+        json.insert("hostname".to_owned(), serde_json::Value::from(&block.hostname[..]));
+        json.insert("script_block_id".to_owned(), serde_json::Value::from(&block.script_block_id[..]));
+        json.insert("path".to_owned(), serde_json::Value::from(block.path));
+        json.insert("first".to_owned(), date_json_value(block.first, datefmt));
+        json.insert("last".to_owned(), date_json_value(block.last, datefmt));
+        json.insert("parts".to_owned(), serde_json::Value::from(block.parts.len()));
+        json.insert("total".to_owned(), serde_json::Value::from(block.total));
+        json.insert("complete".to_owned(), serde_json::Value::from(complete));
+        json.insert("missing".to_owned(), serde_json::Value::from(missing));
+        json.insert("warning".to_owned(), serde_json::Value::from(block.warning));
+        json.insert("user_id".to_owned(), serde_json::Value::from(block.user_id));
+        json.insert("process_id".to_owned(), serde_json::Value::from(block.process_id));
+        json.insert("record_ids".to_owned(), serde_json::Value::from(block.record_ids));
+        json.insert("length".to_owned(), serde_json::Value::from(script.len()));
+        // Hashes of a script with missing parts would not match the script which was run
+        let hash = |hash_fn: &dyn Fn(&[u8]) -> String| if complete { serde_json::Value::from(hash_fn(script.as_bytes())) } else { serde_json::Value::Null };
+        json.insert("sha1".to_owned(), hash(&|data| format!("{:x}", Sha1::digest(data))));
+        json.insert("sha256".to_owned(), hash(&|data| format!("{:x}", Sha256::digest(data))));
+        if self.decode_commands {
+            json.insert("decoded_commands".to_owned(), serde_json::Value::from(decode_encoded_commands(&script)));
+        }
+        match &self.dir {
+            Some(dir) => {
+                let file_name = format!("{}_{}.ps1", file_name_part(&block.hostname), file_name_part(&block.script_block_id));
+                let path = dir.join(file_name);
+                let res = OpenOptions::new().write(true).create(true).truncate(true).open(&path)
+                    .and_then(|mut f| f.write_all(script.as_bytes()));
+                if let Err(e) = res {
+                    return Err(format!("Could not write script to {} : {}", path.display(), e));
+                }
+                json.insert("file".to_owned(), serde_json::Value::from(path.to_string_lossy().into_owned()));
+            },
+            None => {
+                json.insert("script".to_owned(), serde_json::Value::from(script));
+            },
+        }
+        Ok(json)
+    }
+
+    // Adds a part of a script block, and returns the script block once all of its parts are read
+    fn add_part(&mut self, common_props: &CommonEventProperties, data: &HashMap<String, String>) -> Result<Option<ScriptBlock>, String> {
+        let script_block_id = match data.get("ScriptBlockId") {
+            Some(id) => id.trim().to_lowercase(),
+            None => return Err(format!("No ScriptBlockId in event {}", common_props.recordid)),
+        };
+        let number = data.get("MessageNumber").and_then(|n| n.trim().parse::<u64>().ok()).unwrap_or(1);
+        let total = data.get("MessageTotal").and_then(|n| n.trim().parse::<u64>().ok()).unwrap_or(1);
+        if number == 0 || number > total || total > MAX_MESSAGE_TOTAL {
+            return Err(format!("Invalid part {} of {} of script block {} in event {}", number, total,
+                               script_block_id, common_props.recordid));
+        }
+        let key = (common_props.hostname.to_owned(), script_block_id.to_owned());
+        if self.written.contains(&key) {
+            return Ok(None);
+        }
+        let block = self.blocks.entry(key.clone()).or_insert_with(|| ScriptBlock {
+            hostname: common_props.hostname.to_owned(),
+            script_block_id,
+            path: None,
+            total,
+            parts: BTreeMap::new(),
+            first: common_props.timestamp,
+            last: common_props.timestamp,
+            warning: false,
+            user_id: common_props.user_id.clone(),
+            process_id: common_props.process_id,
+            record_ids: vec![],
+        });
+        if block.parts.contains_key(&number) {
+            return Ok(None);
+        }
+        block.parts.insert(number, data.get("ScriptBlockText").cloned().unwrap_or_default());
+        block.total = block.total.max(total);
+        block.first = block.first.min(common_props.timestamp);
+        block.last = block.last.max(common_props.timestamp);
+        block.warning |= common_props.level == Some(LEVEL_WARNING);
+        if block.path.is_none() {
+            block.path = data.get("Path").map(|p| p.trim().to_owned()).filter(|p| !p.is_empty());
+        }
+        block.record_ids.push(common_props.recordid);
+        if (block.parts.len() as u64) < block.total {
+            return Ok(None);
+        }
+        let block = self.blocks.remove(&key);
+        self.written.insert(key);
+        Ok(block)
+    }
+}
+
+#[cfg(windows)]
+fn json_output(records: Vec<serde_json::Map<String, serde_json::Value>>, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let mut body = String::new();
+    for record in records {
+        let json = if render_cfg.json_pretty {
+            serde_json::to_string_pretty(&record)
+        } else {
+            serde_json::to_string(&record)
+        };
+        match json {
+            Ok(s) => body.push_str(&(s + "\n")),
+            Err(e) => return Err(format!("JSON serialization failed: {}", e)),
+        }
+    }
+    Ok(EventOutput { header: None, body })
+}
+
+#[cfg(windows)]
+fn lock_script_blocks(render_cfg: &RenderingConfig) -> Result<std::sync::MutexGuard<'_, ScriptBlocks>, String> {
+    match &render_cfg.script_blocks {
+        Some(blocks) => match blocks.lock() {
+            Ok(b) => Ok(b),
+            Err(e) => Err(format!("Failed to acquire lock to script blocks: {}", e)),
+        },
+        None => Err(format!("Script blocks are not reassembled")),
+    }
+}
+
+// Adds a part of a script block, and renders the script once all of its parts are read.
+// Command lines of other events are rendered with the commands they encode, if any.
+#[cfg(windows)]
+pub fn render_event_script_blocks(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let powershell = common_props.provider == "Microsoft-Windows-PowerShell" || common_props.provider == "PowerShellCore";
+    let command_field = match (&common_props.provider[..], common_props.eventid) {
+        (_, 4104) if powershell => None,
+        (_, 4103) if powershell => Some("ContextInfo"),
+        ("Microsoft-Windows-Security-Auditing", 4688) => Some("CommandLine"),
+        _ => return Ok(EventOutput::default()),
+    };
+    let blocks = lock_script_blocks(render_cfg)?;
+    if command_field.is_some() && !blocks.decode_commands {
+        return Ok(EventOutput::default());
+    }
+    // Not locked while rendering, which takes most of the time
+    std::mem::drop(blocks);
+    let data = event_data_values(&render_xml_string(h_event)?)?;
+
+    if let Some(field) = command_field {
+        let command_line = match data.get(field) {
+            Some(c) => c,
+            None => return Ok(EventOutput::default()),
+        };
+        let decoded = decode_encoded_commands(command_line);
+        if decoded.is_empty() {
+            return Ok(EventOutput::default());
+        }
+        let mut json = serde_json::Map::new();
+        json.insert("type".to_owned(), serde_json::Value::from("encoded_command"));
+        json.insert("hostname".to_owned(), serde_json::Value::from(&common_props.hostname[..]));
+        json.insert("timestamp".to_owned(), date_json_value(common_props.timestamp, &render_cfg.datefmt));
+        json.insert("recordid".to_owned(), serde_json::Value::from(common_props.recordid));
+        json.insert("eventid".to_owned(), serde_json::Value::from(common_props.eventid));
+        json.insert("user_id".to_owned(), serde_json::Value::from(common_props.user_id.clone()));
+        json.insert("command_line".to_owned(), serde_json::Value::from(&command_line[..]));
+        json.insert("decoded_commands".to_owned(), serde_json::Value::from(decoded));
+        return json_output(vec![json], render_cfg);
+    }
+
+    let mut blocks = lock_script_blocks(render_cfg)?;
+    match blocks.add_part(common_props, &data)? {
+        Some(block) => {
+            let json = blocks.script_json(block, &render_cfg.datefmt)?;
+            json_output(vec![json], render_cfg)
+        },
+        None => Ok(EventOutput::default()),
+    }
+}
+
+// Renders script blocks still incomplete once all events are read, in the order they started
+#[cfg(windows)]
+pub fn finish_script_blocks(render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let mut blocks = lock_script_blocks(render_cfg)?;
+    let mut incomplete: Vec<ScriptBlock> = blocks.blocks.drain().map(|(_, block)| block).collect();
+    if !incomplete.is_empty() {
+        warn!("{} script blocks are missing some of their parts", incomplete.len());
+    }
+    incomplete.sort_by_key(|block| (block.first, block.script_block_id.to_owned()));
+    let mut records = vec![];
+    for block in incomplete {
+        records.push(blocks.script_json(block, &render_cfg.datefmt)?);
+    }
+    json_output(records, render_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatting::FILETIME_TICKS_PER_SEC;
+
+    const START: u64 = 132_593_643_670_000_000;
+    const ID: &str = "{6A2B8C1D-0E3F-4A5B-9C7D-1E2F3A4B5C6D}";
+
+    fn add(blocks: &mut ScriptBlocks, secs: u64, number: &str, total: &str, text: &str) -> Result<Option<ScriptBlock>, String> {
+        let common_props = CommonEventProperties {
+            timestamp: START + secs * FILETIME_TICKS_PER_SEC as u64,
+            hostname: "ws01".to_owned(),
+            recordid: 1000 + secs,
+            provider: "Microsoft-Windows-PowerShell".to_owned(),
+            eventid: 4104,
+            level: Some(if secs == 2 { LEVEL_WARNING } else { 5 }),
+            ..Default::default()
+        };
+        let data: HashMap<String, String> = [
+            ("MessageNumber", number), ("MessageTotal", total), ("ScriptBlockText", text),
+            ("ScriptBlockId", ID), ("Path", "C:\\Users\\alice\\run.ps1"),
+        ].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        blocks.add_part(&common_props, &data)
+    }
+
+    #[test]
+    fn reassembles_parts_read_out_of_order() {
+        let mut blocks = ScriptBlocks::new(None, false).unwrap();
+        assert!(add(&mut blocks, 2, "2", "3", "Write-Output 2\n").unwrap().is_none());
+        assert!(add(&mut blocks, 3, "3", "3", "Write-Output 3\n").unwrap().is_none());
+        let block = add(&mut blocks, 1, "1", "3", "Write-Output 1\n").unwrap().unwrap();
+        assert_eq!(block.record_ids, vec![1002, 1003, 1001]);
+        assert!(blocks.blocks.is_empty());
+
+        let json = blocks.script_json(block, &DateConfig::rfc3339_utc()).unwrap();
+        assert_eq!(json["script"], "Write-Output 1\nWrite-Output 2\nWrite-Output 3\n");
+        assert_eq!(json["script_block_id"], ID.to_lowercase());
+        assert_eq!((&json["first"], &json["last"]), (&"2021-03-04T20:46:08.0000000Z".into(), &"2021-03-04T20:46:10.0000000Z".into()));
+        assert_eq!((&json["complete"], &json["warning"], &json["path"]), (&true.into(), &true.into(), &"C:\\Users\\alice\\run.ps1".into()));
+        assert_eq!(json["sha1"], "a372daed552bc0d2eaf4df62c2ae7f78afc36165");
+        assert_eq!(json["sha256"], "16b560a97b065a80d60f70b55917804aeee2ad486333534f5f24764ee0f8e64f");
+    }
+
+    #[test]
+    fn marks_missing_parts() {
+        let mut blocks = ScriptBlocks::new(None, false).unwrap();
+        add(&mut blocks, 1, "1", "3", "Write-Output 1\n").unwrap();
+        add(&mut blocks, 3, "3", "3", "Write-Output 3\n").unwrap();
+        let block = blocks.blocks.drain().next().unwrap().1;
+        let json = blocks.script_json(block, &DateConfig::rfc3339_utc()).unwrap();
+        assert_eq!(json["script"], "Write-Output 1\n\n# [part 2 of 3 of this script block was not logged]\nWrite-Output 3\n");
+        assert_eq!((&json["complete"], &json["missing"], &json["parts"]), (&false.into(), &serde_json::json!([2]), &2.into()));
+        // Hashes would not be the ones of the script which was run
+        assert_eq!((&json["sha1"], &json["sha256"]), (&serde_json::Value::Null, &serde_json::Value::Null));
+    }
+
+    #[test]
+    fn rejects_invalid_parts() {
+        let mut blocks = ScriptBlocks::new(None, false).unwrap();
+        assert!(add(&mut blocks, 1, "0", "2", "").is_err());
+        assert!(add(&mut blocks, 1, "3", "2", "").is_err());
+        assert!(add(&mut blocks, 1, "1", "10001", "").is_err());
+        assert!(add(&mut blocks, 1, "1", "10000", "").unwrap().is_none());
+        assert_eq!(blocks.blocks[&("ws01".to_owned(), ID.to_lowercase())].total, MAX_MESSAGE_TOTAL);
+    }
+
+    #[test]
+    fn ignores_parts_read_again() {
+        let mut blocks = ScriptBlocks::new(None, false).unwrap();
+        add(&mut blocks, 1, "1", "2", "Write-Output 1\n").unwrap();
+        assert!(add(&mut blocks, 5, "1", "2", "Write-Output 1\n").unwrap().is_none());
+        assert!(add(&mut blocks, 2, "2", "2", "Write-Output 2\n").unwrap().is_some());
+        // e.g. from a forwarded copy of the same log, once the script was written
+        assert!(add(&mut blocks, 6, "2", "2", "Write-Output 2\n").unwrap().is_none());
+        assert!(add(&mut blocks, 7, "1", "2", "Write-Output 1\n").unwrap().is_none());
+        assert!(blocks.blocks.is_empty());
+    }
+
+    #[test]
+    fn decodes_encoded_commands() {
+        let encoded = "RwBlAHQALQBQAHIAbwBjAGUAcwBzAA==";
+        for arg in &["-EncodedCommand", "-enc", "-e", "/ec", "-EC"] {
+            assert_eq!(decode_encoded_commands(&format!("powershell.exe -NoProfile {} {}", arg, encoded)), vec!["Get-Process"]);
+        }
+        assert_eq!(decode_encoded_commands(&format!("\"pwsh.exe\" -enc \"{}\" -nop -e SQBFAFgAIAAoAE4AZQB3AC0ATwBiAGoAZQBjAHQAIABOAGUAdAAuAFcAZQBiAEMAbABpAGUAbgB0ACkA", encoded)),
+                   vec!["Get-Process", "IEX (New-Object Net.WebClient)"]);
+        // Other options starting with e, words which are not base64, odd lengths and control characters
+        assert!(decode_encoded_commands(&format!("powershell.exe -ExecutionPolicy {}", encoded)).is_empty());
+        assert!(decode_encoded_commands("grep -e test file.txt").is_empty());
+        assert!(decode_encoded_commands("powershell.exe -enc RwBlAHQ").is_empty());
+        assert!(decode_encoded_commands("powershell.exe -enc AQACAA==").is_empty());
+    }
+}
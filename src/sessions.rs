@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+#[cfg(windows)]
+use std::sync::Mutex;
+#[cfg(windows)]
+use crate::windows::EvtHandle;
+#[cfg(windows)]
+use crate::{RenderingConfig, EventOutput};
+use crate::formatting::{CommonEventProperties, expiry_time, FILETIME_TICKS_PER_SEC};
+#[cfg(windows)]
+use crate::csv::CsvHeader;
+use crate::inference::data_value;
+#[cfg(windows)]
+use crate::inference::event_data_values;
+#[cfg(windows)]
+use crate::json::date_json_value;
+#[cfg(windows)]
+use crate::xml::render_xml_string;
+
+// Events correlated into logon sessions by their logon ID, read with these filters by default
+pub const SESSION_EVENT_FILTERS: &[&str] = &[
+    "Security/Microsoft-Windows-Security-Auditing/4624", // logon
+    "Security/Microsoft-Windows-Security-Auditing/4634", // logoff
+    "Security/Microsoft-Windows-Security-Auditing/4647", // logoff initiated by the user
+    "Security/Microsoft-Windows-Security-Auditing/4648", // logon with explicit credentials, from the session
+    "Security/Microsoft-Windows-Security-Auditing/4672", // special privileges assigned to the new session
+];
+
+// Events of long-lived sessions (e.g. of SYSTEM) are only counted past this number
+const MAX_SESSION_EVENTS: usize = 1000;
+// Sessions are checked for timeouts at most this often (in event time)
+const EXPIRY_INTERVAL_SECS: u64 = 60;
+
+const SESSION_COLUMNS: &[&str] = &["hostname", "logon_id", "user", "domain", "sid", "logon_type", "logon_type_name",
+    "source_ip", "source_port", "workstation", "auth_package", "logon_process", "elevated", "privileges",
+    "linked_logon_id", "start", "end", "duration", "end_reason", "event_count", "event_ids", "record_ids"];
+
+struct SessionEvent {
+    timestamp: u64,
+    recordid: u64,
+    eventid: u64,
+    // Account and server of 4648 events
+    target_user: Option<String>,
+    target_server: Option<String>,
+}
+
+#[derive(Default)]
+struct LogonSession {
+    hostname: String,
+    logon_id: String,
+    user: Option<String>,
+    domain: Option<String>,
+    sid: Option<String>,
+    logon_type: Option<u64>,
+    source_ip: Option<String>,
+    source_port: Option<String>,
+    workstation: Option<String>,
+    auth_package: Option<String>,
+    logon_process: Option<String>,
+    elevated: bool,
+    privileges: Option<String>,
+    linked_logon_id: Option<String>,
+    // Unknown if the logon happened before the first event read
+    start: Option<u64>,
+    end: Option<u64>,
+    end_reason: Option<&'static str>,
+    last_seen: u64,
+    event_count: u64,
+    events: Vec<SessionEvent>,
+}
+
+pub struct SessionTracker {
+    sessions: HashMap<(String, String), LogonSession>,
+    // In FILETIME ticks, since the last event of a session
+    timeout: u64,
+    last_expiry: u64,
+    // Timestamp of the latest event, which is the current time while events are caught up with
+    latest_event: u64,
+    csv: bool,
+}
+
+// Columns of sessions written as CSV, instead of the ones given with --columns
+pub fn session_column_names() -> Vec<String> {
+    SESSION_COLUMNS.iter().map(|name| name.to_string()).collect()
+}
+
+fn logon_type_name(logon_type: u64) -> Option<&'static str> {
+    Some(match logon_type {
+        0 => "System",
+        2 => "Interactive",
+        3 => "Network",
+        4 => "Batch",
+        5 => "Service",
+        7 => "Unlock",
+        8 => "NetworkCleartext",
+        9 => "NewCredentials",
+        10 => "RemoteInteractive",
+        11 => "CachedInteractive",
+        12 => "CachedRemoteInteractive",
+        13 => "CachedUnlock",
+        _ => return None,
+    })
+}
+
+// Linked logon IDs are logged as a null logon ID when absent
+fn logon_id(data: &HashMap<String, String>, name: &str) -> Option<String> {
+    match data_value(data, name) {
+        Some(id) if id != "0x0" => Some(id.to_lowercase()),
+        _ => None,
+    }
+}
+
+impl SessionTracker {
+    pub fn new(timeout_secs: u64, csv: bool) -> SessionTracker {
+        SessionTracker {
+            sessions: HashMap::new(),
+            timeout: timeout_secs * FILETIME_TICKS_PER_SEC as u64,
+            last_expiry: 0,
+            latest_event: 0,
+            csv,
+        }
+    }
+
+    fn session(&mut self, hostname: &str, logon_id: &str) -> &mut LogonSession {
+        self.sessions.entry((hostname.to_owned(), logon_id.to_owned())).or_insert_with(|| LogonSession {
+            hostname: hostname.to_owned(),
+            logon_id: logon_id.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    // Returns the sessions which ended with this event
+    fn add_event(&mut self, common_props: &CommonEventProperties, data: &HashMap<String, String>) -> Vec<LogonSession> {
+        let mut ended = vec![];
+        self.latest_event = std::cmp::max(self.latest_event, common_props.timestamp);
+        let id_field = match common_props.eventid {
+            4624 | 4634 | 4647 => "TargetLogonId",
+            4648 | 4672 => "SubjectLogonId",
+            _ => return ended,
+        };
+        let id = match logon_id(data, id_field) {
+            Some(id) => id,
+            None => return ended,
+        };
+        let key = (common_props.hostname.to_owned(), id.to_owned());
+        // Logon IDs can be reused after a reboot
+        if common_props.eventid == 4624 && self.sessions.get(&key).map(|s| s.start.is_some()).unwrap_or(false) {
+            if let Some(mut session) = self.sessions.remove(&key) {
+                if session.end.is_none() {
+                    session.end_reason = Some("reused");
+                }
+                ended.push(session);
+            }
+        }
+        let session = self.session(&common_props.hostname, &id);
+        let user_prefix = if id_field == "TargetLogonId" { "Target" } else { "Subject" };
+        let timestamp = common_props.timestamp;
+        if session.user.is_none() {
+            session.user = data_value(data, &format!("{}UserName", user_prefix));
+            session.domain = data_value(data, &format!("{}DomainName", user_prefix));
+            session.sid = data_value(data, &format!("{}UserSid", user_prefix));
+        }
+        match common_props.eventid {
+            4624 => {
+                session.start = Some(timestamp);
+                session.logon_type = data_value(data, "LogonType").and_then(|t| t.parse().ok());
+                session.source_ip = data_value(data, "IpAddress");
+                session.source_port = data_value(data, "IpPort").filter(|port| port != "0");
+                session.workstation = data_value(data, "WorkstationName");
+                session.auth_package = data_value(data, "AuthenticationPackageName");
+                session.logon_process = data_value(data, "LogonProcessName");
+                session.linked_logon_id = logon_id(data, "TargetLinkedLogonId");
+                // %%1842 is "Yes", in version 2 of the event
+                if data_value(data, "ElevatedToken").as_deref() == Some("%%1842") {
+                    session.elevated = true;
+                }
+            },
+            4672 => {
+                session.elevated = true;
+                session.privileges = data_value(data, "PrivilegeList")
+                    .map(|list| list.split_whitespace().collect::<Vec<&str>>().join(" "));
+            },
+            4634 | 4647 if session.end.is_none() => {
+                session.end = Some(timestamp);
+                session.end_reason = Some(if common_props.eventid == 4647 { "user_logoff" } else { "logoff" });
+            },
+            _ => (),
+        }
+        session.last_seen = timestamp;
+        session.event_count += 1;
+        if session.events.len() < MAX_SESSION_EVENTS {
+            session.events.push(SessionEvent {
+                timestamp,
+                recordid: common_props.recordid,
+                eventid: common_props.eventid,
+                target_user: if common_props.eventid == 4648 { data_value(data, "TargetUserName") } else { None },
+                target_server: if common_props.eventid == 4648 { data_value(data, "TargetServerName") } else { None },
+            });
+        }
+        // 4647 is followed by a 4634 which ends the session
+        if common_props.eventid == 4634 {
+            if let Some(session) = self.sessions.remove(&key) {
+                ended.push(session);
+            }
+        }
+        ended
+    }
+
+    // Returns sessions without any event since the timeout
+    fn expire(&mut self, wall_clock: Option<u64>) -> Vec<LogonSession> {
+        let now = expiry_time(self.latest_event, wall_clock);
+        if now < self.last_expiry + EXPIRY_INTERVAL_SECS * FILETIME_TICKS_PER_SEC as u64 {
+            return vec![];
+        }
+        self.last_expiry = now;
+        let timeout = self.timeout;
+        let expired: Vec<(String, String)> = self.sessions.iter()
+            .filter(|(_, session)| session.last_seen + timeout < now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut ended = vec![];
+        for key in expired {
+            if let Some(mut session) = self.sessions.remove(&key) {
+                if session.end.is_none() {
+                    session.end = Some(session.last_seen);
+                    session.end_reason = Some("timeout");
+                }
+                ended.push(session);
+            }
+        }
+        ended
+    }
+}
+
+#[cfg(windows)]
+fn session_json(session: &LogonSession, render_cfg: &RenderingConfig) -> serde_json::Map<String, serde_json::Value> {
+    let mut json = serde_json::Map::new();
+    json.insert("hostname".to_owned(), serde_json::Value::from(&session.hostname[..]));
+    json.insert("logon_id".to_owned(), serde_json::Value::from(&session.logon_id[..]));
+    json.insert("user".to_owned(), serde_json::Value::from(session.user.clone()));
+    json.insert("domain".to_owned(), serde_json::Value::from(session.domain.clone()));
+    json.insert("sid".to_owned(), serde_json::Value::from(session.sid.clone()));
+    json.insert("logon_type".to_owned(), serde_json::Value::from(session.logon_type));
+    json.insert("logon_type_name".to_owned(), serde_json::Value::from(session.logon_type.and_then(logon_type_name)));
+    json.insert("source_ip".to_owned(), serde_json::Value::from(session.source_ip.clone()));
+    json.insert("source_port".to_owned(), serde_json::Value::from(session.source_port.clone()));
+    json.insert("workstation".to_owned(), serde_json::Value::from(session.workstation.clone()));
+    json.insert("auth_package".to_owned(), serde_json::Value::from(session.auth_package.clone()));
+    json.insert("logon_process".to_owned(), serde_json::Value::from(session.logon_process.clone()));
+    json.insert("elevated".to_owned(), serde_json::Value::from(session.elevated));
+    json.insert("privileges".to_owned(), serde_json::Value::from(session.privileges.clone()));
+    json.insert("linked_logon_id".to_owned(), serde_json::Value::from(session.linked_logon_id.clone()));
+    let date = |filetime: Option<u64>| filetime.map(|t| date_json_value(t, &render_cfg.datefmt)).unwrap_or(serde_json::Value::Null);
+    json.insert("start".to_owned(), date(session.start));
+    json.insert("end".to_owned(), date(session.end));
+    let duration = match (session.start, session.end) {
+        (Some(start), Some(end)) if end >= start => Some((end - start) as f64 / FILETIME_TICKS_PER_SEC as f64),
+        _ => None,
+    };
+    json.insert("duration".to_owned(), serde_json::Value::from(duration));
+    json.insert("end_reason".to_owned(), serde_json::Value::from(session.end_reason.unwrap_or("open")));
+    json.insert("event_count".to_owned(), serde_json::Value::from(session.event_count));
+    json.insert("events".to_owned(), serde_json::Value::Array(session.events.iter().map(|event| {
+        let mut json = serde_json::Map::new();
+        json.insert("timestamp".to_owned(), date_json_value(event.timestamp, &render_cfg.datefmt));
+        json.insert("recordid".to_owned(), serde_json::Value::from(event.recordid));
+        json.insert("eventid".to_owned(), serde_json::Value::from(event.eventid));
+        if let Some(target_user) = &event.target_user {
+            json.insert("target_user".to_owned(), serde_json::Value::from(&target_user[..]));
+        }
+        if let Some(target_server) = &event.target_server {
+            json.insert("target_server".to_owned(), serde_json::Value::from(&target_server[..]));
+        }
+        serde_json::Value::Object(json)
+    }).collect()));
+    json
+}
+
+#[cfg(windows)]
+fn sessions_output(sessions: Vec<LogonSession>, render_cfg: &RenderingConfig, csv: bool) -> Result<EventOutput, String> {
+    if sessions.is_empty() {
+        return Ok(EventOutput::default());
+    }
+    let mut body = String::new();
+    for session in &sessions {
+        let mut json = session_json(session, render_cfg);
+        if csv {
+            let events = match json.remove("events") {
+                Some(serde_json::Value::Array(events)) => events,
+                _ => vec![],
+            };
+            let list = |key: &str| events.iter().map(|event| event[key].to_string()).collect::<Vec<String>>().join(" ");
+            json.insert("event_ids".to_owned(), serde_json::Value::from(list("eventid")));
+            json.insert("record_ids".to_owned(), serde_json::Value::from(list("recordid")));
+            let row: Vec<String> = SESSION_COLUMNS.iter().map(|column| match &json[*column] {
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::String(s) => s.to_owned(),
+                other => other.to_string(),
+            }).collect();
+            body.push_str(&render_cfg.csv.row(&row));
+        } else {
+            let json = if render_cfg.json_pretty {
+                serde_json::to_string_pretty(&json)
+            } else {
+                serde_json::to_string(&json)
+            };
+            match json {
+                Ok(s) => body.push_str(&(s + "\n")),
+                Err(e) => return Err(format!("JSON serialization failed: {}", e)),
+            }
+        }
+    }
+    let header = if csv && render_cfg.csv.header == Some(CsvHeader::Fields) {
+        Some(render_cfg.csv.row(&session_column_names()))
+    } else {
+        None
+    };
+    Ok(EventOutput { header, body })
+}
+
+#[cfg(windows)]
+fn lock_tracker(render_cfg: &RenderingConfig) -> Result<std::sync::MutexGuard<'_, SessionTracker>, String> {
+    let tracker: &Mutex<SessionTracker> = match &render_cfg.sessions {
+        Some(t) => t,
+        None => return Err(format!("Logon sessions are not tracked")),
+    };
+    match tracker.lock() {
+        Ok(t) => Ok(t),
+        Err(e) => Err(format!("Failed to acquire lock to logon sessions: {}", e)),
+    }
+}
+
+// Correlates an event into logon sessions, and renders the sessions which ended
+#[cfg(windows)]
+pub fn render_event_sessions(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    if common_props.provider != "Microsoft-Windows-Security-Auditing" {
+        return Ok(EventOutput::default());
+    }
+    let data = event_data_values(&render_xml_string(h_event)?)?;
+    let mut tracker = lock_tracker(render_cfg)?;
+    let mut ended = tracker.add_event(common_props, &data);
+    ended.extend(tracker.expire(None));
+    let csv = tracker.csv;
+    sessions_output(ended, render_cfg, csv)
+}
+
+// Renders sessions without any event since the timeout
+#[cfg(windows)]
+pub fn expire_sessions(render_cfg: &RenderingConfig, wall_clock: Option<u64>) -> Result<EventOutput, String> {
+    let mut tracker = lock_tracker(render_cfg)?;
+    let ended = tracker.expire(wall_clock);
+    let csv = tracker.csv;
+    sessions_output(ended, render_cfg, csv)
+}
+
+// Renders sessions still open once all events have been read, ordered by start
+#[cfg(windows)]
+pub fn finish_sessions(render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let mut tracker = lock_tracker(render_cfg)?;
+    let mut open: Vec<LogonSession> = tracker.sessions.drain().map(|(_, session)| session).collect();
+    open.sort_by_key(|session| (session.start.unwrap_or(session.last_seen), session.logon_id.to_owned()));
+    let csv = tracker.csv;
+    sessions_output(open, render_cfg, csv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECS: u64 = FILETIME_TICKS_PER_SEC as u64;
+    const START: u64 = 132_593_643_670_000_000;
+
+    fn event(eventid: u64, secs: u64, data: &[(&str, &str)]) -> (CommonEventProperties, HashMap<String, String>) {
+        let common_props = CommonEventProperties {
+            timestamp: START + secs * SECS,
+            hostname: "dc01".to_owned(),
+            recordid: 1000 + secs,
+            provider: "Microsoft-Windows-Security-Auditing".to_owned(),
+            eventid,
+            ..Default::default()
+        };
+        (common_props, data.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+    }
+
+    fn add(tracker: &mut SessionTracker, eventid: u64, secs: u64, data: &[(&str, &str)]) -> Vec<LogonSession> {
+        let (common_props, data) = event(eventid, secs, data);
+        tracker.add_event(&common_props, &data)
+    }
+
+    fn logon(logon_id: &str, user: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("TargetLogonId", logon_id.to_owned()),
+            ("TargetUserName", user.to_owned()),
+            ("TargetDomainName", "CORP".to_owned()),
+            ("LogonType", "10".to_owned()),
+            ("IpAddress", "10.0.0.5".to_owned()),
+            ("IpPort", "0".to_owned()),
+            ("WorkstationName", "-".to_owned()),
+            ("TargetLinkedLogonId", "0x0".to_owned()),
+        ]
+    }
+
+    fn data<'a>(values: &'a [(&'static str, String)]) -> Vec<(&'static str, &'a str)> {
+        values.iter().map(|(k, v)| (*k, v.as_str())).collect()
+    }
+
+    #[test]
+    fn ends_sessions_whose_logon_id_is_reused() {
+        let mut tracker = SessionTracker::new(3600, false);
+        assert!(add(&mut tracker, 4624, 0, &data(&logon("0x3E7A", "alice"))).is_empty());
+        let ended = add(&mut tracker, 4624, 10, &data(&logon("0x3e7a", "bob")));
+        assert_eq!(ended.len(), 1);
+        assert_eq!((ended[0].user.as_deref(), ended[0].end, ended[0].end_reason), (Some("alice"), None, Some("reused")));
+        assert_eq!((ended[0].logon_type, ended[0].source_port.as_deref()), (Some(10), None));
+        assert_eq!((ended[0].workstation.as_deref(), ended[0].linked_logon_id.as_deref()), (None, None));
+
+        let session = &tracker.sessions[&("dc01".to_owned(), "0x3e7a".to_owned())];
+        assert_eq!((session.user.as_deref(), session.start), (Some("bob"), Some(START + 10 * SECS)));
+    }
+
+    #[test]
+    fn ends_sessions_with_logoffs() {
+        let mut tracker = SessionTracker::new(3600, false);
+        add(&mut tracker, 4624, 0, &data(&logon("0x1", "alice")));
+        add(&mut tracker, 4648, 5, &[("SubjectLogonId", "0x1"), ("TargetUserName", "admin"), ("TargetServerName", "fs01")]);
+        let ended = add(&mut tracker, 4634, 20, &[("TargetLogonId", "0x1")]);
+        assert_eq!(ended.len(), 1);
+        assert_eq!((ended[0].end, ended[0].end_reason), (Some(START + 20 * SECS), Some("logoff")));
+        assert_eq!(ended[0].events.iter().map(|e| e.eventid).collect::<Vec<_>>(), vec![4624, 4648, 4634]);
+        assert_eq!(ended[0].events[1].target_server.as_deref(), Some("fs01"));
+        assert!(tracker.sessions.is_empty());
+
+        // The 4634 which follows a 4647 ends the session, which the user logged off from
+        add(&mut tracker, 4624, 30, &data(&logon("0x2", "alice")));
+        assert!(add(&mut tracker, 4647, 40, &[("TargetLogonId", "0x2")]).is_empty());
+        let ended = add(&mut tracker, 4634, 41, &[("TargetLogonId", "0x2")]);
+        assert_eq!((ended[0].end, ended[0].end_reason), (Some(START + 40 * SECS), Some("user_logoff")));
+        assert_eq!(ended[0].event_count, 3);
+    }
+
+    #[test]
+    fn marks_elevated_sessions() {
+        let mut tracker = SessionTracker::new(3600, false);
+        let mut elevated = logon("0x10", "alice");
+        elevated.push(("ElevatedToken", "%%1842".to_owned()));
+        let mut limited = logon("0x11", "alice");
+        limited.push(("ElevatedToken", "%%1843".to_owned()));
+        add(&mut tracker, 4624, 0, &data(&elevated));
+        add(&mut tracker, 4624, 0, &data(&limited));
+        add(&mut tracker, 4624, 0, &data(&logon("0x12", "bob")));
+        add(&mut tracker, 4672, 0, &[("SubjectLogonId", "0x12"), ("PrivilegeList", "SeBackupPrivilege\r\n\t\t\tSeDebugPrivilege")]);
+
+        let session = |id: &str| &tracker.sessions[&("dc01".to_owned(), id.to_owned())];
+        assert!(session("0x10").elevated);
+        assert!(!session("0x11").elevated);
+        assert!(session("0x12").elevated);
+        assert_eq!(session("0x12").privileges.as_deref(), Some("SeBackupPrivilege SeDebugPrivilege"));
+    }
+
+    #[test]
+    fn expires_sessions_against_the_latest_event() {
+        let mut tracker = SessionTracker::new(600, false);
+        add(&mut tracker, 4624, 0, &data(&logon("0x1", "alice")));
+        add(&mut tracker, 4624, 500, &data(&logon("0x2", "bob")));
+        assert!(tracker.expire(Some(START + 500 * SECS)).is_empty());
+
+        // Reading old events, the wall clock is not given: the latest event is the time
+        add(&mut tracker, 4648, 700, &[("SubjectLogonId", "0x2"), ("TargetUserName", "admin")]);
+        let ended = tracker.expire(None);
+        assert_eq!(ended.len(), 1);
+        assert_eq!((ended[0].logon_id.as_str(), ended[0].end, ended[0].end_reason), ("0x1", Some(START), Some("timeout")));
+
+        // Then against the wall clock once it is later, at most once a minute
+        assert!(tracker.expire(Some(START + 1299 * SECS)).is_empty());
+        assert!(tracker.expire(Some(START + 1301 * SECS)).is_empty());
+        let ended = tracker.expire(Some(START + 1299 * SECS + EXPIRY_INTERVAL_SECS * SECS));
+        assert_eq!(ended.iter().map(|s| s.logon_id.as_str()).collect::<Vec<_>>(), vec!["0x2"]);
+        assert_eq!(ended[0].end, Some(START + 700 * SECS));
+        assert!(tracker.sessions.is_empty());
+    }
+}
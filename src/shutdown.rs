@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+use winapi::um::wincon::{CTRL_C_EVENT, CTRL_BREAK_EVENT};
+use crate::windows::set_console_ctrl_handler;
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// The first Ctrl-C stops reading events, so that the ones already read are rendered and outputs
+// are properly closed. Handlers are called from the most recently registered, so the next one
+// (flushing outputs) and then the default one (terminating the process) are called on the second.
+unsafe extern "system" fn stop_on_ctrl_event(ctrl_type: DWORD) -> BOOL {
+    if ctrl_type != CTRL_C_EVENT && ctrl_type != CTRL_BREAK_EVENT {
+        return FALSE;
+    }
+    if STOP_REQUESTED.swap(true, Relaxed) {
+        return FALSE;
+    }
+    info!("Stopping after events already read, press Ctrl-C again to exit immediately");
+    TRUE
+}
+
+pub fn stop_on_interrupt() -> Result<(), String> {
+    set_console_ctrl_handler(Some(stop_on_ctrl_event))
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Relaxed)
+}
+
+// Stops reading events as if interrupted, e.g. when too many of them failed
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Relaxed);
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use serde::Serialize;
+
+// What happened to the events of a given Channel/Provider
+#[derive(Default, Serialize, Clone)]
+pub struct EventCounts {
+    pub read: u64,
+    pub rendered: u64,
+    // Events received but not rendered (e.g. bookmarks inserted by event forwarding)
+    pub filtered: u64,
+    pub failed: u64,
+}
+
+pub type RunSummary = BTreeMap<(String, String), EventCounts>;
+
+pub enum EventOutcome {
+    Rendered,
+    Filtered,
+    Failed,
+}
+
+impl EventCounts {
+    fn add(&mut self, other: &EventCounts) {
+        self.read += other.read;
+        self.rendered += other.rendered;
+        self.filtered += other.filtered;
+        self.failed += other.failed;
+    }
+}
+
+// Counts of the events seen by one rendering thread, added to the run summary when dropped so
+// that workers don't contend on its lock for each event
+pub struct EventCounter<'a> {
+    summary: &'a Mutex<RunSummary>,
+    counts: RunSummary,
+}
+
+impl<'a> EventCounter<'a> {
+    pub fn new(summary: &'a Mutex<RunSummary>) -> Self {
+        EventCounter {
+            summary,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    // Channel and provider are "-" when they could not be read from the event
+    pub fn count(&mut self, channel: Option<&str>, provider: Option<&str>, outcome: EventOutcome) {
+        let key = (channel.unwrap_or("-").to_owned(), provider.unwrap_or("-").to_owned());
+        let counts = self.counts.entry(key).or_default();
+        counts.read += 1;
+        match outcome {
+            EventOutcome::Rendered => counts.rendered += 1,
+            EventOutcome::Filtered => counts.filtered += 1,
+            EventOutcome::Failed => counts.failed += 1,
+        }
+    }
+}
+
+impl<'a> Drop for EventCounter<'a> {
+    fn drop(&mut self) {
+        // Counts are still merged after another thread panicked while holding the lock
+        let mut summary = match self.summary.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for (key, counts) in std::mem::take(&mut self.counts) {
+            summary.entry(key).or_default().add(&counts);
+        }
+    }
+}
+
+pub fn summary_total(summary: &RunSummary) -> EventCounts {
+    let mut total = EventCounts::default();
+    for counts in summary.values() {
+        total.add(counts);
+    }
+    total
+}
+
+pub fn write_summary_report(summary: &RunSummary, out_file: &mut dyn std::io::Write) -> Result<(), String> {
+    let mut lines = vec![format!("{:<40} {:<60} {:>8} {:>8} {:>8} {:>8}", "Channel", "Provider", "Read", "Rendered", "Filtered", "Failed")];
+    for ((channel, provider), counts) in summary {
+        lines.push(format!("{:<40} {:<60} {:>8} {:>8} {:>8} {:>8}", channel, provider,
+                           counts.read, counts.rendered, counts.filtered, counts.failed));
+    }
+    let total = summary_total(summary);
+    lines.push(format!("{:<40} {:<60} {:>8} {:>8} {:>8} {:>8}", "Total", "", total.read, total.rendered, total.filtered, total.failed));
+    match out_file.write_all((lines.join("\n") + "\n").as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Unable to write run summary: {}", e)),
+    }
+}
+
+#[derive(Serialize)]
+struct SourceSummary<'a> {
+    channel: &'a str,
+    provider: &'a str,
+    #[serde(flatten)]
+    counts: &'a EventCounts,
+}
+
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    elapsed_seconds: f64,
+    events_per_second: f64,
+    interrupted: bool,
+    total: EventCounts,
+    sources: Vec<SourceSummary<'a>>,
+}
+
+pub fn export_summary_to_file(summary: &RunSummary, elapsed: Duration, interrupted: bool, out_file: &mut dyn std::io::Write) -> Result<(), String> {
+    let total = summary_total(summary);
+    let json = JsonSummary {
+        elapsed_seconds: elapsed.as_secs_f64(),
+        events_per_second: (total.rendered as f64) / elapsed.as_secs_f64(),
+        interrupted,
+        total,
+        sources: summary.iter().map(|((channel, provider), counts)| SourceSummary {
+            channel,
+            provider,
+            counts,
+        }).collect(),
+    };
+    let json = match serde_json::to_string_pretty(&json) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Unable to serialize run summary to JSON: {}", e)),
+    };
+    match out_file.write_all((json + "\n").as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Unable to write run summary: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_counters_when_dropped() {
+        let summary = Mutex::new(BTreeMap::new());
+        let mut first = EventCounter::new(&summary);
+        let mut second = EventCounter::new(&summary);
+        first.count(Some("Security"), Some("Microsoft-Windows-Security-Auditing"), EventOutcome::Rendered);
+        first.count(None, None, EventOutcome::Failed);
+        second.count(Some("Security"), Some("Microsoft-Windows-Security-Auditing"), EventOutcome::Filtered);
+        std::mem::drop(first);
+        assert_eq!(summary_total(&summary.lock().unwrap()).read, 2);
+        std::mem::drop(second);
+
+        let summary = summary.into_inner().unwrap();
+        let security = &summary[&("Security".to_owned(), "Microsoft-Windows-Security-Auditing".to_owned())];
+        assert_eq!((security.read, security.rendered, security.filtered, security.failed), (2, 1, 1, 0));
+        let unknown = &summary[&("-".to_owned(), "-".to_owned())];
+        assert_eq!((unknown.read, unknown.failed), (1, 1));
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use crate::metadata::EventFieldDefinition;
+
+/*
+ * Parser for the WEVT_TEMPLATE resource compiled by mc.exe from instrumentation manifests
+ * into provider DLLs, which holds everything the EventLog service knows about a provider
+ * (its channels, levels, tasks, opcodes, keywords, events and their templates). Message
+ * strings themselves are referenced by ID, and live in the MESSAGETABLE resource of the
+ * provider's message file. All offsets are relative to the start of the resource.
+ *
+ * "CRIM" u32 size, u16 major, u16 minor, u32 provider_count
+ *     { GUID provider_guid, u32 offset } [provider_count]
+ * "WEVT" u32 size, u32 message_id, u32 element_count, u32 unknown_count
+ *     { u32 offset, u32 unknown } [element_count]
+ * Elements:
+ * "CHAN" u32 size, u32 count { u32 value, u32 name_offset, u32 unknown, u32 message_id } [count]
+ * "LEVL" / "OPCO" u32 size, u32 count { u32 value, u32 message_id, u32 name_offset } [count]
+ * "TASK" u32 size, u32 count { u32 value, u32 message_id, GUID, u32 name_offset } [count]
+ * "KEYW" u32 size, u32 count { u64 mask, u32 message_id, u32 name_offset } [count]
+ * "EVNT" u32 size, u32 count, u32 unknown
+ *     { u16 id, u8 version, u8 channel, u8 level, u8 opcode, u16 task, u64 keywords,
+ *       u32 message_id, u32 template_offset, u32 opcode_offset, u32 level_offset,
+ *       u32 task_offset, u32 unknown_count, u32 unknown_offset, u32 flags } [count]
+ * "TTBL" u32 size, u32 count, followed by templates:
+ *     "TEMP" u32 size, u32 item_count, u32 name_count, u32 items_offset, u32 unknown, GUID,
+ *     followed by a BinXML fragment, and item descriptors at items_offset:
+ *     { u32 unknown, u8 in_type, u8 out_type, u16 unknown, u32 unknown, u16 count,
+ *       u16 length, u32 name_offset } [item_count]
+ * Names are stored as { u32 size (including itself), UTF-16 NULL-terminated string }.
+ *
+ * See https://github.com/libyal/libfwevt/blob/main/documentation/Windows%20Event%20manifest%20binary%20format.asciidoc
+ */
+
+pub struct WevtEvent {
+    pub id: u16,
+    pub version: u8,
+    pub channel: u8,
+    pub level: u8,
+    pub opcode: u8,
+    pub task: u16,
+    pub keywords: u64,
+    pub message_id: Option<u32>,
+    pub fields: Vec<EventFieldDefinition>,
+}
+
+pub struct WevtProvider {
+    pub guid: String,
+    pub message_id: Option<u32>,
+    pub channels: BTreeMap<u64, String>,
+    pub levels: BTreeMap<u64, String>,
+    pub opcodes: BTreeMap<u64, String>,
+    pub tasks: BTreeMap<u64, String>,
+    pub keywords: BTreeMap<u64, String>,
+    pub events: Vec<WevtEvent>,
+}
+
+// Names of the inType values in winmeta.xml, with the outType used when none is specified
+const IN_TYPES: &[(u8, &str, &str)] = &[
+    (1, "win:UnicodeString", "xs:string"),
+    (2, "win:AnsiString", "xs:string"),
+    (3, "win:Int8", "xs:byte"),
+    (4, "win:UInt8", "xs:unsignedByte"),
+    (5, "win:Int16", "xs:short"),
+    (6, "win:UInt16", "xs:unsignedShort"),
+    (7, "win:Int32", "xs:int"),
+    (8, "win:UInt32", "xs:unsignedInt"),
+    (9, "win:Int64", "xs:long"),
+    (10, "win:UInt64", "xs:unsignedLong"),
+    (11, "win:Float", "xs:float"),
+    (12, "win:Double", "xs:double"),
+    (13, "win:Boolean", "xs:boolean"),
+    (14, "win:Binary", "xs:hexBinary"),
+    (15, "win:GUID", "xs:GUID"),
+    (16, "win:Pointer", "win:HexInt64"),
+    (17, "win:FILETIME", "xs:dateTime"),
+    (18, "win:SYSTEMTIME", "xs:dateTime"),
+    (19, "win:SID", "xs:string"),
+    (20, "win:HexInt32", "win:HexInt32"),
+    (21, "win:HexInt64", "win:HexInt64"),
+];
+
+// Names of the outType values in winmeta.xml
+const OUT_TYPES: &[(u8, &str)] = &[
+    (1, "xs:string"),
+    (2, "xs:dateTime"),
+    (3, "xs:byte"),
+    (4, "xs:unsignedByte"),
+    (5, "xs:short"),
+    (6, "xs:unsignedShort"),
+    (7, "xs:int"),
+    (8, "xs:unsignedInt"),
+    (9, "xs:long"),
+    (10, "xs:unsignedLong"),
+    (11, "xs:float"),
+    (12, "xs:double"),
+    (13, "xs:boolean"),
+    (14, "xs:GUID"),
+    (15, "xs:hexBinary"),
+    (16, "win:HexInt8"),
+    (17, "win:HexInt16"),
+    (18, "win:HexInt32"),
+    (19, "win:HexInt64"),
+    (20, "win:PID"),
+    (21, "win:TID"),
+    (22, "win:Port"),
+    (23, "win:IPv4"),
+    (24, "win:IPv6"),
+    (25, "win:SocketAddress"),
+    (26, "win:CIMDateTime"),
+    (27, "win:ETWTIME"),
+    (28, "win:Xml"),
+    (29, "win:ErrorCode"),
+    (30, "win:Win32Error"),
+    (31, "win:NTSTATUS"),
+    (32, "win:HResult"),
+    (33, "win:DateTimeCultureInsensitive"),
+    (34, "win:Json"),
+    (35, "win:Utf8"),
+    (36, "win:Pkcs7WithTypeInfo"),
+    (37, "win:CodePointer"),
+    (38, "win:DateTimeUtc"),
+];
+
+fn read_u8(data: &[u8], offset: usize) -> Result<u8, String> {
+    data.get(offset).copied().ok_or_else(|| format!("WEVT_TEMPLATE truncated at offset {}", offset))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    match data.get(offset..offset + 2) {
+        Some(b) => Ok(u16::from_le_bytes(b.try_into().unwrap())),
+        None => Err(format!("WEVT_TEMPLATE truncated at offset {}", offset)),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    match data.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+        None => Err(format!("WEVT_TEMPLATE truncated at offset {}", offset)),
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, String> {
+    match data.get(offset..offset + 8) {
+        Some(b) => Ok(u64::from_le_bytes(b.try_into().unwrap())),
+        None => Err(format!("WEVT_TEMPLATE truncated at offset {}", offset)),
+    }
+}
+
+fn check_signature(data: &[u8], offset: usize, signature: &[u8; 4]) -> Result<(), String> {
+    match data.get(offset..offset + 4) {
+        Some(s) if s == signature => Ok(()),
+        other => Err(format!("Expected {} signature at offset {}, found {:?}",
+                             String::from_utf8_lossy(signature), offset, other)),
+    }
+}
+
+// Formats a GUID the same way as the EventLog API does in provider metadata
+pub fn read_guid(data: &[u8], offset: usize) -> Result<String, String> {
+    let b = match data.get(offset..offset + 16) {
+        Some(b) => b,
+        None => return Err(format!("WEVT_TEMPLATE truncated at offset {}", offset)),
+    };
+    Ok(format!("{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}}}",
+               read_u32(b, 0)?, read_u16(b, 4)?, read_u16(b, 6)?,
+               b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]))
+}
+
+fn read_name(data: &[u8], offset: u32) -> Result<String, String> {
+    let offset = offset as usize;
+    let size = read_u32(data, offset)? as usize;
+    if size < 4 {
+        return Err(format!("Invalid name size {} at offset {}", size, offset));
+    }
+    let chars: Vec<u16> = match data.get(offset + 4..offset + size) {
+        Some(b) => b.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect(),
+        None => return Err(format!("WEVT_TEMPLATE truncated in name at offset {}", offset)),
+    };
+    Ok(String::from_utf16_lossy(&chars).trim_end_matches('\0').to_owned())
+}
+
+fn message_id(id: u32) -> Option<u32> {
+    // (DWORD)(-1) means there is no message
+    if id == 0xFFFFFFFF { None } else { Some(id) }
+}
+
+fn parse_name_table(data: &[u8], offset: usize, entry_size: usize, name_pos: usize, wide_value: bool)
+    -> Result<BTreeMap<u64, String>, String> {
+    let mut res = BTreeMap::new();
+    let count = read_u32(data, offset + 8)? as usize;
+    for i in 0..count {
+        let entry = offset + 12 + i * entry_size;
+        let value = if wide_value { read_u64(data, entry)? } else { read_u32(data, entry)? as u64 };
+        let name_offset = read_u32(data, entry + name_pos)?;
+        if name_offset != 0 {
+            res.insert(value, read_name(data, name_offset)?);
+        }
+    }
+    Ok(res)
+}
+
+fn parse_template_fields(data: &[u8], offset: usize) -> Result<Vec<EventFieldDefinition>, String> {
+    check_signature(data, offset, b"TEMP")?;
+    let item_count = read_u32(data, offset + 8)? as usize;
+    let items_offset = read_u32(data, offset + 16)? as usize;
+    let mut fields = Vec::with_capacity(item_count);
+    for i in 0..item_count {
+        let item = items_offset + i * 20;
+        let in_type = read_u8(data, item + 4)?;
+        let out_type = read_u8(data, item + 5)?;
+        let name_offset = read_u32(data, item + 16)?;
+        let name = if name_offset != 0 {
+            read_name(data, name_offset)?
+        } else {
+            format!("field{}", i + 1)
+        };
+        let out_type = match OUT_TYPES.iter().find(|(v, _)| *v == out_type) {
+            Some((_, name)) => name.to_string(),
+            None => match IN_TYPES.iter().find(|(v, _, _)| *v == in_type) {
+                Some((_, _, default_out_type)) => default_out_type.to_string(),
+                None => "xs:string".to_owned(),
+            },
+        };
+        fields.push(EventFieldDefinition { name, out_type });
+    }
+    Ok(fields)
+}
+
+fn parse_events(data: &[u8], offset: usize) -> Result<Vec<WevtEvent>, String> {
+    let count = read_u32(data, offset + 8)? as usize;
+    let mut events = Vec::with_capacity(count);
+    for i in 0..count {
+        let entry = offset + 16 + i * 48;
+        let template_offset = read_u32(data, entry + 20)? as usize;
+        let fields = if template_offset != 0 {
+            parse_template_fields(data, template_offset)?
+        } else {
+            vec![]
+        };
+        events.push(WevtEvent {
+            id: read_u16(data, entry)?,
+            version: read_u8(data, entry + 2)?,
+            channel: read_u8(data, entry + 3)?,
+            level: read_u8(data, entry + 4)?,
+            opcode: read_u8(data, entry + 5)?,
+            task: read_u16(data, entry + 6)?,
+            keywords: read_u64(data, entry + 8)?,
+            message_id: message_id(read_u32(data, entry + 16)?),
+            fields,
+        });
+    }
+    Ok(events)
+}
+
+fn parse_provider(data: &[u8], guid: String, offset: usize) -> Result<WevtProvider, String> {
+    check_signature(data, offset, b"WEVT")?;
+    let mut provider = WevtProvider {
+        guid,
+        message_id: message_id(read_u32(data, offset + 8)?),
+        channels: BTreeMap::new(),
+        levels: BTreeMap::new(),
+        opcodes: BTreeMap::new(),
+        tasks: BTreeMap::new(),
+        keywords: BTreeMap::new(),
+        events: vec![],
+    };
+    let element_count = read_u32(data, offset + 12)? as usize;
+    for i in 0..element_count {
+        let element = read_u32(data, offset + 20 + i * 8)? as usize;
+        match data.get(element..element + 4) {
+            Some(b"CHAN") => provider.channels = parse_name_table(data, element, 16, 4, false)?,
+            Some(b"LEVL") => provider.levels = parse_name_table(data, element, 12, 8, false)?,
+            // Opcodes are stored as (opcode << 16 | task), the task being 0 unless they are task-specific
+            Some(b"OPCO") => provider.opcodes = parse_name_table(data, element, 12, 8, false)?,
+            Some(b"TASK") => provider.tasks = parse_name_table(data, element, 28, 24, false)?,
+            Some(b"KEYW") => provider.keywords = parse_name_table(data, element, 16, 12, true)?,
+            Some(b"EVNT") => provider.events = parse_events(data, element)?,
+            // Templates are parsed when referenced by events, maps and filters are not needed
+            _ => continue,
+        }
+    }
+    Ok(provider)
+}
+
+pub fn parse_wevt_template(data: &[u8]) -> Result<Vec<WevtProvider>, String> {
+    check_signature(data, 0, b"CRIM")?;
+    let provider_count = read_u32(data, 12)? as usize;
+    let mut providers = Vec::with_capacity(provider_count);
+    for i in 0..provider_count {
+        let entry = 16 + i * 20;
+        let guid = read_guid(data, entry)?;
+        let offset = read_u32(data, entry + 16)? as usize;
+        providers.push(parse_provider(data, guid, offset)?);
+    }
+    Ok(providers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated by tests/fixtures/make_provider_dll.py
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/wevt_template.bin");
+
+    fn fixture_provider() -> WevtProvider {
+        let mut providers = parse_wevt_template(FIXTURE).unwrap();
+        assert_eq!(providers.len(), 1);
+        providers.remove(0)
+    }
+
+    #[test]
+    fn parses_provider_names() {
+        let provider = fixture_provider();
+        assert_eq!(provider.guid, "{12345678-1234-5678-9ABC-DEF012345678}");
+        assert_eq!(provider.message_id, Some(0x90000001));
+        assert_eq!(provider.channels.get(&16).map(String::as_str), Some("Evtq-Test/Operational"));
+        assert_eq!(provider.levels.get(&16).map(String::as_str), Some("evtq:Trace"));
+        assert_eq!(provider.tasks.get(&1).map(String::as_str), Some("Connect"));
+        assert_eq!(provider.tasks.get(&2).map(String::as_str), Some("Disconnect"));
+        assert_eq!(provider.keywords.get(&0x1).map(String::as_str), Some("Network"));
+        assert_eq!(provider.keywords.get(&0x2).map(String::as_str), Some("Authentication"));
+    }
+
+    #[test]
+    fn keeps_task_specific_opcodes() {
+        let provider = fixture_provider();
+        assert_eq!(provider.opcodes.get(&(10 << 16)).map(String::as_str), Some("Retry"));
+        assert_eq!(provider.opcodes.get(&(11 << 16 | 1)).map(String::as_str), Some("Handshake"));
+        assert_eq!(provider.opcodes.get(&(11 << 16 | 2)).map(String::as_str), Some("Teardown"));
+        assert_eq!(provider.opcodes.len(), 3);
+    }
+
+    #[test]
+    fn parses_events_and_templates() {
+        let provider = fixture_provider();
+        let events: Vec<_> = provider.events.iter()
+            .map(|e| (e.id, e.version, e.level, e.opcode, e.task, e.keywords, e.message_id))
+            .collect();
+        assert_eq!(events, vec![
+            (100, 0, 4, 11, 1, 0x8000000000000001, Some(0xB0000064)),
+            (101, 1, 16, 11, 2, 0x8000000000000003, Some(0xB0000065)),
+            (102, 0, 2, 10, 0, 0, None),
+        ]);
+        assert!(provider.events.iter().all(|e| e.channel == 16));
+
+        let fields: Vec<Vec<(&str, &str)>> = provider.events.iter()
+            .map(|e| e.fields.iter().map(|f| (f.name.as_str(), f.out_type.as_str())).collect())
+            .collect();
+        assert_eq!(fields[0], vec![("Host", "xs:string"), ("Port", "win:Port")]);
+        assert_eq!(fields[1], vec![("field1", "xs:unsignedInt")]);
+        assert!(fields[2].is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_templates() {
+        for len in [0, 8, 16, 40, FIXTURE.len() / 2] {
+            assert!(parse_wevt_template(&FIXTURE[..len]).is_err(), "truncated at {}", len);
+        }
+        let mut bad_signature = FIXTURE.to_vec();
+        bad_signature[0] = b'X';
+        assert!(parse_wevt_template(&bad_signature).is_err());
+    }
+
+    // WEVT_TEMPLATE resource of services.exe from Windows, with the Service Control Manager provider
+    const SERVICES_FIXTURE: &[u8] = include_bytes!("../tests/fixtures/services_wevt_template.bin");
+
+    #[test]
+    fn parses_real_provider_template() {
+        let providers = parse_wevt_template(SERVICES_FIXTURE).unwrap();
+        let scm = providers.iter()
+            .find(|p| p.guid == "{555908D1-A6D7-4695-8E1E-26931D2012F4}")
+            .expect("Service Control Manager provider");
+        assert_eq!(scm.events.len(), 43);
+        assert_eq!(scm.keywords.get(&0x80000000000000).map(String::as_str), Some("win:EventlogClassic"));
+
+        let field_names = |id: u16| -> Vec<&str> {
+            let event = scm.events.iter().find(|e| e.id == id && e.version == 0).unwrap();
+            event.fields.iter().map(|f| f.name.as_str()).collect()
+        };
+        assert_eq!(field_names(7036), vec!["param1", "param2", "__binLength", "BinaryData"]);
+        assert_eq!(field_names(7045), vec!["ServiceName", "ImagePath", "ServiceType", "StartType", "AccountName"]);
+        let binary = scm.events.iter().find(|e| e.id == 7036).unwrap().fields.last().unwrap();
+        assert_eq!(binary.out_type, "xs:hexBinary");
+    }
+}
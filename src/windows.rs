@@ -3,10 +3,10 @@ use std::convert::TryFrom;
 use std::collections::BTreeMap;
 use std::time::Instant;
 use std::ops::Deref;
+use crate::pipeline::run_pipeline;
 use roxmltree;
 use winapi::ctypes::c_void;
 use winapi::um::errhandlingapi::GetLastError;
-use winapi::um::minwinbase::SYSTEMTIME;
 use std::sync::atomic::Ordering::Relaxed;
 use winapi::shared::winerror::{
     ERROR_NO_MORE_ITEMS,
@@ -20,67 +20,49 @@ use winapi::shared::winerror::{
     ERROR_EVT_UNRESOLVED_VALUE_INSERT,
     ERROR_EVT_MESSAGE_LOCALE_NOT_FOUND,
     ERROR_EVT_MESSAGE_NOT_FOUND,
+    ERROR_SUCCESS,
 };
 use winapi::um::winevt::*;
+use winapi::um::libloaderapi::{
+    LoadLibraryExW,
+    FindResourceW,
+    LoadResource,
+    LockResource,
+    SizeofResource,
+    FreeLibrary,
+    LOAD_LIBRARY_AS_DATAFILE,
+    LOAD_LIBRARY_AS_IMAGE_RESOURCE,
+};
+use winapi::um::processenv::ExpandEnvironmentStringsW;
+use winapi::um::winreg::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ, RRF_RT_REG_DWORD};
+use winapi::um::winbase::{LookupAccountSidW, LocalFree};
+use winapi::um::winnt::{PSID, SID_NAME_USE};
+use winapi::shared::sddl::ConvertStringSidToSidW;
+use winapi::um::consoleapi::SetConsoleCtrlHandler;
+use winapi::um::wincon::PHANDLER_ROUTINE;
+use winapi::um::winuser::MAKEINTRESOURCEW;
 use crate::log::*;
-use crate::RenderingConfig;
+use crate::{RenderingConfig, EventOutput};
 use crate::metadata::{EventFieldDefinition, EventDefinition};
-use crate::formatting::{EvtVariant, CommonEventProperties, get_event_common_properties, unwrap_variant_contents};
+use crate::winmeta::{resolve_standard_name, resolve_opcode_name, resolve_keyword_names, SYSTEM_CHANNELS, SYSTEM_LEVELS, SYSTEM_TASKS};
+use crate::formatting::{EvtVariant, get_event_common_properties, unwrap_variant_contents, variant_as_string, format_filetime, DateConfig};
+use crate::msgformat::{MessageArg, format_message_template};
+use crate::msgtable::parse_message_table;
+use crate::pe::RT_MESSAGETABLE;
+use crate::summary::{EventCounter, EventOutcome};
+use crate::shutdown::stop_requested;
+use crate::errors::{record_error, start_event, event_failed, ErrorStage};
 use winapi::shared::minwindef::DWORD;
-use std::str::FromStr;
 
 const INFINITE : u32 = 0xFFFFFFFF;
 
-// System-wide standard channels defined by Windows. Event-provider-specific channels
-// are queried at runtime.
-const SYSTEM_CHANNELS: &[(u32, &'static str, &'static str)] = &[
-    (0, "TraceClassic", "Events for Classic ETW tracing"),
-    (8, "System", "Events for all installed system services.  This channel is secured to applications running under system service accounts or user applications running under local adminstrator privileges"),
-    (9, "Application", "Events for all user-level applications. This channel is not secured and open to any applications. Applications which log extensive information should define an application-specific channel"),
-    (10, "Security", "The Windows Audit Log.  For exclusive use of the Windows Local Security Authority. User events may appear as audits if supported by the underlying application"),
-    (11, "TraceLogging", "Event contains provider traits and TraceLogging event metadata"),
-    (12, "ProviderMetadata", "Event contains provider traits"),
-];
-
-// System-wide standard levels defined by Windows. Event-provider-specific levels
-// are queried at runtime.
-const SYSTEM_LEVELS: &[(u32, &'static str, &'static str)] = &[
-    (0, "win:LogAlways", "Log Always"),
-    (1, "win:Critical", "Only critical errors"),
-    (2, "win:Error", "All errors, includes win:Critical"),
-    (3, "win:Warning", "All warnings, includes win:Error"),
-    (4, "win:Informational", "All informational content, including win:Warning"),
-    (5, "win:Verbose", "All tracing, including previous levels"),
-];
-
-// System-wide tasks defined by Windows. Event-provider-specific tasks
-// are queried at runtime.
-const SYSTEM_TASKS: &[(u32, &'static str, &'static str)] = &[
-    (0, "win:None", "undefined task"),
-];
-
-// System-wide opcodes defined by Windows. Event-provider-specific opcodes
-// are queried at runtime.
-const SYSTEM_OPCODES: &[(u32, &'static str, &'static str)] = &[
-    (0, "win:None", "An informational event"),
-    (1, "win:Start", "An activity start event"),
-    (2, "win:Stop", "An activity end event"),
-    (3, "win:DC_Start", "A trace collection start event"),
-    (4, "win:DC_Stop", "A trace collection end event"),
-    (5, "win:Extension", "An extensional event"),
-    (6, "win:Reply", "A reply event"),
-    (7, "win:Resume", "An event representing the activity resuming from the suspension"),
-    (8, "win:Suspend", "An event representing the activity is suspended, pending another activity's completion"),
-    (9, "win:Send", "An event representing the activity is transferred to another component, and can continue to work"),
-];
-
-#[derive(Debug, PartialEq)]
-enum EventFormatterState {
-    LookingForEndOfUnformattedChunk { chunk_start_pos: usize },
-    RightAfterPercentInUnformattedChunk { chunk_start_pos: usize },
-    LookingForEndOfFormatNumber { number_start_pos: usize },
-    LookingForEndOfFormatSpec,
-    EndOfString,
+// Names of the levels, opcodes, tasks, keywords and channels defined by a provider
+pub struct ProviderNameTables {
+    pub levels: BTreeMap<u64, String>,
+    pub opcodes: BTreeMap<u64, String>,
+    pub tasks: BTreeMap<u64, String>,
+    pub keywords: BTreeMap<u64, String>,
+    pub channels: BTreeMap<u64, String>,
 }
 
 #[derive(Debug)]
@@ -95,13 +77,6 @@ pub struct RpcCredentials<'a> {
     pub password: &'a str,
 }
 
-pub fn get_system_standard_val(arr: &'static [(u32, &'static str, &'static str)], val: u32) -> Option<(&'static str, &'static str)> {
-    match arr.binary_search_by(|(k, _, _)| k.cmp(&val)) {
-        Ok(i) => Some((&arr[i].1, &arr[i].2)),
-        _ => None,
-    }
-}
-
 impl EvtHandle {
     pub fn from_raw(handle: EVT_HANDLE) -> Result<EvtHandle, String> {
         match NonNull::new(handle) {
@@ -122,6 +97,9 @@ impl EvtHandle {
     }
 }
 
+// Event handles can be used from any thread, e.g. fetched by one and rendered by another
+unsafe impl Send for EvtHandle {}
+
 impl Drop for EvtHandle {
     fn drop(&mut self) {
         if !self.auto_free {
@@ -138,6 +116,13 @@ pub fn get_win32_errcode() -> u32 {
     unsafe { GetLastError() }
 }
 
+pub fn set_console_ctrl_handler(handler: PHANDLER_ROUTINE) -> Result<(), String> {
+    if unsafe { SetConsoleCtrlHandler(handler, 1) } == 0 {
+        return Err(format!("SetConsoleCtrlHandler() failed with code {}", get_win32_errcode()));
+    }
+    Ok(())
+}
+
 pub fn get_evt_publisher_enum_handle() -> Result<EvtHandle, String> {
     let handle = unsafe { EvtOpenPublisherEnum(null_mut(), 0) };
     if handle.is_null() {
@@ -409,16 +394,137 @@ pub fn format_message(h_provmeta: &EvtHandle, message_id: u32) -> Result<String,
     }
 }
 
-// Returns a map from Event ID -> Version -> EventDefinition, or an error String
-pub fn get_evt_provider_events(provider_name: &str,
-                               h_provmeta: &EvtHandle,
-                               h_evtenum: &EvtHandle,
-) -> Result<BTreeMap<u64, BTreeMap<u64, EventDefinition>>, String>
-{
-    let mut result = BTreeMap::new();
+pub fn expand_environment_strings(path: &str) -> Result<String, String> {
+    let mut path_u16 : Vec<u16> = path.encode_utf16().collect();
+    path_u16.resize(path_u16.len() + 1, 0); // append a terminating NULL character
+    let mut buffer : Vec<u16> = Vec::new();
+    loop {
+        let res = unsafe {
+            ExpandEnvironmentStringsW(path_u16.as_ptr(), buffer.as_mut_ptr(), buffer.len() as DWORD)
+        };
+        if res == 0 {
+            return Err(format!("ExpandEnvironmentStrings('{}') failed with code {}", path, get_win32_errcode()));
+        }
+        if (res as usize) <= buffer.len() {
+            // Remove the NULL terminator
+            buffer.resize((res as usize) - 1, 0);
+            return Ok(String::from_utf16_lossy(&buffer));
+        }
+        buffer.resize(res as usize, 0);
+    }
+}
+
+// Returns the build number of the running OS, with its update revision if any (e.g. 19045.3803)
+// Resolves a SID (e.g. S-1-5-18) to DOMAIN\name, using accounts known to the local host
+pub fn lookup_account_sid(string_sid: &str) -> Result<String, String> {
+    let mut sid_u16 : Vec<u16> = string_sid.encode_utf16().collect();
+    sid_u16.resize(sid_u16.len() + 1, 0); // append a terminating NULL character
+    let mut sid : PSID = null_mut();
+    if unsafe { ConvertStringSidToSidW(sid_u16.as_ptr(), &mut sid) } == 0 {
+        return Err(format!("ConvertStringSidToSid('{}') failed with code {}", string_sid, get_win32_errcode()));
+    }
+    let mut name : Vec<u16> = vec![0; 256];
+    let mut domain : Vec<u16> = vec![0; 256];
+    let res = loop {
+        let mut name_len = name.len() as DWORD;
+        let mut domain_len = domain.len() as DWORD;
+        let mut sid_use : SID_NAME_USE = 0;
+        let res = unsafe {
+            LookupAccountSidW(null_mut(), sid, name.as_mut_ptr(), &mut name_len,
+                              domain.as_mut_ptr(), &mut domain_len, &mut sid_use)
+        };
+        if res != 0 {
+            name.truncate(name_len as usize);
+            domain.truncate(domain_len as usize);
+            break Ok(());
+        }
+        // Buffers are too small, lengths were updated to the required ones
+        if get_win32_errcode() == ERROR_INSUFFICIENT_BUFFER && (name_len as usize > name.len() || domain_len as usize > domain.len()) {
+            name.resize(name_len as usize, 0);
+            domain.resize(domain_len as usize, 0);
+            continue;
+        }
+        break Err(format!("LookupAccountSid('{}') failed with code {}", string_sid, get_win32_errcode()));
+    };
+    unsafe { LocalFree(sid as *mut c_void) };
+    res?;
+    let name = String::from_utf16_lossy(&name);
+    let domain = String::from_utf16_lossy(&domain);
+    if domain.is_empty() {
+        Ok(name)
+    } else {
+        Ok(format!("{}\\{}", domain, name))
+    }
+}
 
+pub fn get_os_build() -> Result<String, String> {
+    let subkey : Vec<u16> = "SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\0".encode_utf16().collect();
+    let build_name : Vec<u16> = "CurrentBuildNumber\0".encode_utf16().collect();
+    let ubr_name : Vec<u16> = "UBR\0".encode_utf16().collect();
+    let mut buffer : Vec<u16> = vec![0; 32];
+    let mut size = (buffer.len() * 2) as DWORD;
+    let res = unsafe {
+        RegGetValueW(HKEY_LOCAL_MACHINE, subkey.as_ptr(), build_name.as_ptr(), RRF_RT_REG_SZ, null_mut(),
+                     buffer.as_mut_ptr() as *mut c_void, &mut size as *mut DWORD)
+    };
+    if res != ERROR_SUCCESS as i32 {
+        return Err(format!("RegGetValue(CurrentBuildNumber) failed with code {}", res));
+    }
+    // Remove the NULL terminator
+    buffer.resize((size as usize / 2).saturating_sub(1), 0);
+    let build = String::from_utf16_lossy(&buffer);
+    // The update build revision only exists since Windows 10
+    let mut ubr : DWORD = 0;
+    let mut size = std::mem::size_of::<DWORD>() as DWORD;
+    let res = unsafe {
+        RegGetValueW(HKEY_LOCAL_MACHINE, subkey.as_ptr(), ubr_name.as_ptr(), RRF_RT_REG_DWORD, null_mut(),
+                     &mut ubr as *mut DWORD as *mut c_void, &mut size as *mut DWORD)
+    };
+    if res == ERROR_SUCCESS as i32 {
+        Ok(format!("{}.{}", build, ubr))
+    } else {
+        Ok(build)
+    }
+}
+
+// Reads the RT_MESSAGETABLE resource of the given DLL or EXE, without executing anything from it.
+// The path can contain environment variables (e.g. %SystemRoot%), as found in provider metadata.
+pub fn load_message_table(path: &str) -> Result<BTreeMap<u32, String>, String> {
+    let expanded_path = expand_environment_strings(path)?;
+    let mut path_u16 : Vec<u16> = expanded_path.encode_utf16().collect();
+    path_u16.resize(path_u16.len() + 1, 0); // append a terminating NULL character
+    let h_module = unsafe {
+        LoadLibraryExW(path_u16.as_ptr(), null_mut(), LOAD_LIBRARY_AS_DATAFILE | LOAD_LIBRARY_AS_IMAGE_RESOURCE)
+    };
+    if h_module.is_null() {
+        return Err(format!("LoadLibraryEx('{}') failed with code {}", expanded_path, get_win32_errcode()));
+    }
+    let res = (|| {
+        // Message tables are always stored as resource ID 1 by the message compiler
+        let h_resinfo = unsafe { FindResourceW(h_module, MAKEINTRESOURCEW(1), MAKEINTRESOURCEW(RT_MESSAGETABLE as u16)) };
+        if h_resinfo.is_null() {
+            return Err(format!("FindResource('{}', RT_MESSAGETABLE) failed with code {}", expanded_path, get_win32_errcode()));
+        }
+        let size = unsafe { SizeofResource(h_module, h_resinfo) };
+        let h_resdata = unsafe { LoadResource(h_module, h_resinfo) };
+        if h_resdata.is_null() || size == 0 {
+            return Err(format!("LoadResource('{}', RT_MESSAGETABLE) failed with code {}", expanded_path, get_win32_errcode()));
+        }
+        let ptr = unsafe { LockResource(h_resdata) };
+        if ptr.is_null() {
+            return Err(format!("LockResource('{}', RT_MESSAGETABLE) failed with code {}", expanded_path, get_win32_errcode()));
+        }
+        let data = unsafe { std::slice::from_raw_parts(ptr as *const u8, size as usize) };
+        parse_message_table(data)
+    })();
+    unsafe { FreeLibrary(h_module) };
+    res
+}
+
+// Queries the resolved names of the levels, opcodes, tasks, keywords and channels defined by a provider
+pub fn get_evt_provider_name_tables(provider_name: &str, h_provmeta: &EvtHandle) -> ProviderNameTables {
     // Query all resolved level names of this provider, once
-    let prov_levels = match get_evt_prov_metadata_mapping(&h_provmeta,
+    let prov_levels = match get_evt_prov_metadata_mapping(h_provmeta,
                                                      provider_name,
                                                      EvtPublisherMetadataLevels,
                                                      EvtPublisherMetadataLevelValue,
@@ -431,7 +537,7 @@ pub fn get_evt_provider_events(provider_name: &str,
     };
 
     // Query all resolved opcode names of this provider, once
-    let prov_opcodes = match get_evt_prov_metadata_mapping(&h_provmeta,
+    let prov_opcodes = match get_evt_prov_metadata_mapping(h_provmeta,
                                                      provider_name,
                                                      EvtPublisherMetadataOpcodes,
                                                      EvtPublisherMetadataOpcodeValue,
@@ -444,7 +550,7 @@ pub fn get_evt_provider_events(provider_name: &str,
     };
 
     // Query all resolved task names of this provider, once
-    let prov_tasks = match get_evt_prov_metadata_mapping(&h_provmeta,
+    let prov_tasks = match get_evt_prov_metadata_mapping(h_provmeta,
                                                      provider_name,
                                                      EvtPublisherMetadataTasks,
                                                      EvtPublisherMetadataTaskValue,
@@ -457,7 +563,7 @@ pub fn get_evt_provider_events(provider_name: &str,
     };
 
     // Query all resolved keyword names of this provider, once
-    let prov_keywords = match get_evt_prov_metadata_mapping(&h_provmeta,
+    let prov_keywords = match get_evt_prov_metadata_mapping(h_provmeta,
                                                      provider_name,
                                                      EvtPublisherMetadataKeywords,
                                                      EvtPublisherMetadataKeywordValue,
@@ -470,7 +576,7 @@ pub fn get_evt_provider_events(provider_name: &str,
     };
 
     // Query all channels of this provider, once
-    let prov_channels = match get_evt_prov_metadata_mapping(&h_provmeta,
+    let prov_channels = match get_evt_prov_metadata_mapping(h_provmeta,
                                                      provider_name,
                                                      EvtPublisherMetadataChannelReferences,
                                                      EvtPublisherMetadataChannelReferenceID,
@@ -482,6 +588,24 @@ pub fn get_evt_provider_events(provider_name: &str,
         },
     };
 
+    ProviderNameTables {
+        levels: prov_levels,
+        opcodes: prov_opcodes,
+        tasks: prov_tasks,
+        keywords: prov_keywords,
+        channels: prov_channels,
+    }
+}
+
+// Returns a map from Event ID -> Version -> EventDefinition, or an error String
+pub fn get_evt_provider_events(provider_name: &str,
+                               h_provmeta: &EvtHandle,
+                               h_evtenum: &EvtHandle,
+                               names: &ProviderNameTables,
+) -> Result<BTreeMap<u64, BTreeMap<u64, EventDefinition>>, String>
+{
+    let mut result = BTreeMap::new();
+
     loop {
         let h_evt = unsafe {
             EvtNextEventMetadata(h_evtenum.as_ptr(), 0)
@@ -538,7 +662,7 @@ pub fn get_evt_provider_events(provider_name: &str,
             Ok(_) => return Err(format!("Unexpected value type returned for EventMetadataEventKeyword")),
             Err(e) => return Err(e),
         };
-        let channel = match get_evt_metadata(&h_evt, EventMetadataEventChannel) {
+        let channel_id = match get_evt_metadata(&h_evt, EventMetadataEventChannel) {
             Ok(EvtVariant::UInt(n)) if n <= u32::MAX as u64 => (n as u32),
             Ok(_) => return Err(format!("Unexpected value type returned for EventMetadataEventChannel")),
             Err(e) => return Err(e),
@@ -605,84 +729,31 @@ pub fn get_evt_provider_events(provider_name: &str,
         }
 
         // Resolve the channel ID to a name (the ID is useless otherwise)
-        let channel = match prov_channels.get(&(channel as u64)) {
-            Some(s) => Some(s.to_string()),
-            None => {
-                match get_system_standard_val(SYSTEM_CHANNELS, channel) {
-                    Some((name, _)) => Some(name.to_string()),
-                    None => {
-                        if channel != 0 {
-                            debug!("Event {}/{}/{} uses unknown channel ID {}",
-                               provider_name, event_id, version, channel);
-                        }
-                        None
-                    }
-                }
-            },
-        };
+        let channel = resolve_standard_name(&names.channels, SYSTEM_CHANNELS, channel_id as u64);
+        if channel.is_none() && channel_id != 0 {
+            debug!("Event {}/{}/{} uses unknown channel ID {}", provider_name, event_id, version, channel_id);
+        }
 
         // Resolve the level u32 to a name
-        let level_name = match prov_levels.get(&(level as u64)) {
-            Some(s) => Some(s.to_string()),
-            None => {
-                match get_system_standard_val(SYSTEM_LEVELS, level) {
-                    Some((name, _)) => Some(name.to_string()),
-                    None => {
-                        if level != 0 {
-                            debug!("Undocumented level {} in {}/{}/{}", level, provider_name, event_id, version);
-                        }
-                        None
-                    },
-                }
-            },
-        };
+        let level_name = resolve_standard_name(&names.levels, SYSTEM_LEVELS, level as u64);
+        if level_name.is_none() && level != 0 {
+            debug!("Undocumented level {} in {}/{}/{}", level, provider_name, event_id, version);
+        }
 
         // Resolve the opcode u32 to a name
-        let opcode_name = match prov_opcodes.get(&(opcode as u64)) {
-            Some(s) => Some(s.to_string()),
-            None => {
-                match get_system_standard_val(SYSTEM_OPCODES, opcode) {
-                    Some((name, _)) => Some(name.to_string()),
-                    None => {
-                        if opcode != 0 {
-                            debug!("Undocumented opcode {} in {}/{}/{}", opcode, provider_name, event_id, version);
-                        }
-                        None
-                    },
-                }
-            },
-        };
+        let opcode_name = resolve_opcode_name(&names.opcodes, task as u64, opcode as u64);
+        if opcode_name.is_none() && opcode != 0 {
+            debug!("Undocumented opcode {} in {}/{}/{}", opcode, provider_name, event_id, version);
+        }
 
         // Resolve the task u32 to a name
-        let task_name = match prov_tasks.get(&(task as u64)) {
-            Some(s) => Some(s.to_string()),
-            None => {
-                match get_system_standard_val(SYSTEM_TASKS, task) {
-                    Some((name, _)) => Some(name.to_string()),
-                    None => {
-                        if task != 0 {
-                            debug!("Undocumented task {} in {}/{}/{}", opcode, provider_name, event_id, task);
-                        }
-                        None
-                    },
-                }
-            },
-        };
-
-        let mut keyword_names = Vec::new();
-        // Ignore reserved placeholder bits 56-63, used to pass information about channels
-        // (see winmeta.xml)
-        for bit in 0..56 {
-            let val = 1 << bit;
-            if (val & keywords) == 0 {
-                continue;
-            }
-            match prov_keywords.get(&(val as u64)) {
-                Some(name) => keyword_names.push(name.to_string()),
-                None => keyword_names.push(format!("0x{:X}", val)),
-            }
+        let task_name = resolve_standard_name(&names.tasks, SYSTEM_TASKS, task as u64);
+        if task_name.is_none() && task != 0 {
+            debug!("Undocumented task {} in {}/{}/{}", task, provider_name, event_id, version);
         }
 
+        let keyword_names = resolve_keyword_names(&names.keywords, keywords);
+
         // Insert everything into the final hashmap
         let versions = result.entry(event_id).or_insert(BTreeMap::new());
         if versions.contains_key(&version) {
@@ -701,6 +772,7 @@ pub fn get_evt_provider_events(provider_name: &str,
             keywords,
             keyword_names,
             fields,
+            sources: vec![],
         };
         let prev = versions.insert(version, event_def);
         if prev.is_some() {
@@ -879,6 +951,11 @@ pub fn open_evt_backup(path: &str, xml_query: &Option<String>) -> Result<EvtHand
 pub extern "system" fn evt_render_callback(action: EVT_SUBSCRIBE_NOTIFY_ACTION, render_cfg: *mut c_void, handle: EVT_HANDLE) -> u32 {
     if action != EvtSubscribeActionDeliver {
         warn!("Error delivered instead of event object: cannot render this");
+        // The handle is a Win32 error code in that case
+        let render_cfg : &RenderingConfig = unsafe { *(render_cfg as *const &RenderingConfig) };
+        start_event(false);
+        record_error(render_cfg, None, None, ErrorStage::Subscription,
+                     &format!("Error {} delivered instead of event", handle as usize));
         return 0; // keep trying to render further events
     }
     // The h_event is freed by our caller. Don't EvtClose() it automatically. We just need
@@ -888,7 +965,9 @@ pub extern "system" fn evt_render_callback(action: EVT_SUBSCRIBE_NOTIFY_ACTION,
         Ok(h) => h,
     };
     let render_cfg : Box<&RenderingConfig> = unsafe { Box::from_raw(render_cfg as *mut _) };
-    if let Err(e) = render_event(&h_event, render_cfg.deref()) {
+    // Callbacks of several subscriptions can run at the same time, each counts its own event
+    let mut counter = EventCounter::new(&render_cfg.summary);
+    if let Err(e) = render_event(&h_event, render_cfg.deref(), &mut counter) {
         warn!("Error during event rendering: {} ... resuming event dump", e);
     }
 
@@ -937,77 +1016,130 @@ pub fn subscribe_channel(h_session: &EvtHandle, channel_name: &str, render_cfg:
     Ok(h_subscription)
 }
 
-pub fn synchronous_poll_all_events(h_feed: &EvtHandle, render_cfg: &RenderingConfig) -> Result<(), String> {
+// Number of events fetched per EvtNext() call, and rendered together by a worker
+const EVTNEXT_BATCH_SIZE: usize = 64;
+
+fn next_events(h_feed: &EvtHandle) -> Result<Vec<EvtHandle>, String> {
+    let mut h_events : [EVT_HANDLE; EVTNEXT_BATCH_SIZE] = [null_mut(); EVTNEXT_BATCH_SIZE];
     let mut count_events : u32 = 0;
-    loop {
-        let mut h_event : EVT_HANDLE = null_mut();
-        let res = unsafe { EvtNext(h_feed.as_ptr(), 1, &mut h_event as *mut *mut c_void, INFINITE, 0, &mut count_events as *mut u32) };
-        if res == 0 {
-            match get_win32_errcode() {
-                // These two errors are returned by EvtNext when it's out of events to return
-                ERROR_NO_MORE_ITEMS | ERROR_INVALID_OPERATION => {
-                    break;
-                },
-                other => return Err(format!("EvtNext() failed with code {}", other)),
+    let res = unsafe { EvtNext(h_feed.as_ptr(), EVTNEXT_BATCH_SIZE as u32, h_events.as_mut_ptr(), INFINITE, 0, &mut count_events as *mut u32) };
+    if res == 0 {
+        return match get_win32_errcode() {
+            // These two errors are returned by EvtNext when it's out of events to return
+            ERROR_NO_MORE_ITEMS | ERROR_INVALID_OPERATION => Ok(vec![]),
+            other => Err(format!("EvtNext() failed with code {}", other)),
+        };
+    }
+    h_events[..count_events as usize].iter().map(|h_event| EvtHandle::from_raw(*h_event)).collect()
+}
+
+pub fn synchronous_poll_all_events(h_feed: &EvtHandle, render_cfg: &RenderingConfig) -> Result<(), String> {
+    if render_cfg.threads > 1 {
+        parallel_poll_all_events(h_feed, render_cfg)?;
+    } else {
+        let mut counter = EventCounter::new(&render_cfg.summary);
+        loop {
+            let h_events = next_events(h_feed)?;
+            if h_events.is_empty() || render_cfg.output.is_closed() || stop_requested() {
+                break;
+            }
+            for h_event in h_events {
+                if let Err(e) = render_event(&h_event, render_cfg, &mut counter) {
+                    warn!("Error during rendering: {} ... resuming event dump", e);
+                }
             }
-        }
-        let h_event = EvtHandle::from_raw(h_event)?;
-        if let Err(e) = render_event(&h_event, render_cfg) {
-            warn!("Error during rendering: {} ... resuming event dump", e);
-            continue;
         }
     }
     Ok(())
 }
 
+fn parallel_poll_all_events(h_feed: &EvtHandle, render_cfg: &RenderingConfig) -> Result<(), String> {
+    let next_batch = || {
+        let h_events = next_events(h_feed)?;
+        if h_events.is_empty() || render_cfg.output.is_closed() || stop_requested() {
+            return Ok(None);
+        }
+        Ok(Some(h_events))
+    };
+    // Each worker counts its events, added to the run summary when it stops
+    let new_worker = || {
+        let mut counter = EventCounter::new(&render_cfg.summary);
+        move |h_event: EvtHandle| match render_event_output(&h_event, render_cfg, &mut counter) {
+            Ok(output) => output.map(|output| (output, event_failed())),
+            Err(e) => {
+                warn!("Error during rendering: {} ... resuming event dump", e);
+                None
+            },
+        }
+    };
+    run_pipeline(render_cfg.threads, render_cfg.ordered, next_batch, new_worker,
+                 |(output, failed)| write_event(render_cfg, output, failed))
+}
+
 fn debug_event(h_event: &EvtHandle, error: String) {
-    if get_log_level() >= LOG_LEVEL_DEBUG {
+    if log_enabled(LOG_LEVEL_DEBUG, module_path!()) {
         debug!(" [!] Event rendering failed: {}", error);
-        let mut context = crate::RenderingConfig {
-            render_callback: crate::xml::render_event_xml,
-            output_file: Box::from(std::sync::Mutex::new(std::io::stderr())),
-            datefmt: "".to_string(),
-            metadata: BTreeMap::new(),
-            field_separator: '\0',
-            json_pretty: false,
-            columns: vec![],
-            rendering_start: std::time::Instant::now(),
-            event_counter: std::sync::atomic::AtomicU64::new(0),
-        };
-        crate::xml::render_event_xml(h_event, &CommonEventProperties{
-            timestamp: SYSTEMTIME {
-                wYear: 0,
-                wMonth: 0,
-                wDayOfWeek: 0,
-                wDay: 0,
-                wHour: 0,
-                wMinute: 0,
-                wSecond: 0,
-                wMilliseconds: 0
+        match crate::xml::render_xml_string(h_event) {
+            Ok(xml) => debug!("{}", xml),
+            Err(e) => debug!(" [!] Unable to render event as XML: {}", e),
+        }
+    }
+}
+
+pub fn render_event(h_event: &EvtHandle, render_cfg: &RenderingConfig, counter: &mut EventCounter) -> Result<(), String> {
+    match render_event_output(h_event, render_cfg, counter)? {
+        Some(output) => match render_cfg.output.write_event(output) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                record_error(render_cfg, Some(h_event), None, ErrorStage::Write, &e);
+                Err(e)
             },
-            hostname: "".to_string(),
-            recordid: 0,
-            provider: "".to_string(),
-            eventid: 0,
-            version: 0
-        }, &mut context).unwrap();
+        },
+        None => Ok(()),
     }
 }
 
-pub fn render_event(h_event: &EvtHandle, render_cfg: &RenderingConfig) -> Result<(), String> {
+// Events rendered by workers are written without their handle, which may already be closed, and
+// may already have failed while being rendered
+fn write_event(render_cfg: &RenderingConfig, output: EventOutput, failed: bool) -> Result<(), String> {
+    match render_cfg.output.write_event(output) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            start_event(failed);
+            record_error(render_cfg, None, None, ErrorStage::Write, &e);
+            Err(e)
+        },
+    }
+}
+
+// Renders an event without writing it, or returns None if it must be skipped
+fn render_event_output(h_event: &EvtHandle, render_cfg: &RenderingConfig, counter: &mut EventCounter) -> Result<Option<EventOutput>, String> {
+    start_event(false);
     let common_props = match get_event_common_properties(&h_event) {
         Err(e) => {
+            counter.count(None, None, EventOutcome::Failed);
+            record_error(render_cfg, Some(h_event), None, ErrorStage::CommonProperties, &e);
             debug_event(h_event, format!("Common property formatting failed: {}", e));
             return Err(format!("Error occured during common property formatting: {}", e));
         }
-        Ok(None) => return Ok(()),
+        Ok(None) => {
+            counter.count(None, None, EventOutcome::Filtered);
+            return Ok(None);
+        },
         Ok(Some(props)) => props,
     };
 
-    if let Err(e) = (render_cfg.render_callback)(h_event, &common_props, render_cfg) {
-        debug_event(h_event, format!("Rendering function returned: {}", e));
-        return Err(format!("Error occured during rendering: {}", e));
-    }
+    let channel = common_props.channel.as_deref();
+    let output = match (render_cfg.render_callback)(h_event, &common_props, render_cfg) {
+        Ok(output) => output,
+        Err(e) => {
+            counter.count(channel, Some(&common_props.provider), EventOutcome::Failed);
+            record_error(render_cfg, Some(h_event), Some(&common_props), ErrorStage::Rendering, &e);
+            debug_event(h_event, format!("Rendering function returned: {}", e));
+            return Err(format!("Error occured during rendering: {}", e));
+        },
+    };
+    counter.count(channel, Some(&common_props.provider), EventOutcome::Rendered);
 
     let rendered_events = render_cfg.event_counter.fetch_add(1, Relaxed);
     if rendered_events % 1000 == 0 {
@@ -1015,18 +1147,14 @@ pub fn render_event(h_event: &EvtHandle, render_cfg: &RenderingConfig) -> Result
         debug!("{} events rendered ({:.2}/s)", rendered_events, (rendered_events as f64)/elapsed.as_secs_f64());
     }
 
-    Ok(())
+    Ok(Some(output))
 }
 
-// This does not support %%N syntax (references to system-wide message IDs,
-// see https://docs.microsoft.com/en-us/windows/win32/api/winevt/nf-winevt-evtformatmessage)
-// because I didn't find a way to query them and include them in the event
-// definition JSON dump. That shouldn't be a problem, since only one event
-// in one useless provider seems to use that syntax it as of 1909.
-// This also doesn't support format string like %3!S! on purpose, since the
-// actual formatting and argument type is determined by our own formatting
-// function (see comment inside).
-pub fn format_event_message(event_def: &EventDefinition, variants: *const EVT_VARIANT, variant_count: u32) -> Result<String, String> {
+// %%N references (to parameter message strings, see
+// https://docs.microsoft.com/en-us/windows/win32/api/winevt/nf-winevt-evtformatmessage)
+// are resolved using the parameter message table exported with provider metadata,
+// both in the template and in inserted values (e.g. %%1842 -> "Yes").
+pub fn format_event_message(event_def: &EventDefinition, parameters: &BTreeMap<u32, String>, variants: *const EVT_VARIANT, variant_count: u32) -> Result<String, String> {
     // We can't use EvtFormatMessage() because that would require holding a
     // handle to the metadata of the provider which generated that event,
     // and we must be able to format messages offline.
@@ -1035,90 +1163,43 @@ pub fn format_event_message(event_def: &EventDefinition, variants: *const EVT_VA
     // to strings beforehand, and would probably conflict with the few events
     // which take care to define the format string they expect (e.g. %1!S!
     // would make FormatMessage() parse our wide-string-formatted-variant as
-    // an ANSI string).
-    // The format syntax is way more complicated than replace('%1', args[1]):
-    // it supports the entire printf format specification
-    // (e.g. %1!*.*s! %4 %5!*s!", see
-    // https://docs.microsoft.com/fr-fr/windows/win32/api/winbase/nf-winbase-formatmessage )
+    // an ANSI string). Instead, variants are converted to typed arguments and
+    // formatted by our own implementation of the FormatMessage() syntax.
 
     let template = match &event_def.message {
         Some(t) => t,
         None => return Err(format!("Cannot format event without template")),
     };
 
-    // Cache for the result of each EVT_VARIANT formatting to string
-    let mut formatted_variants: Vec<Option<String>> = vec![None; variant_count as usize];
-    let mut res = String::new(); // the final returned String
-    let mut state = EventFormatterState::LookingForEndOfUnformattedChunk { chunk_start_pos: 0 };
-    for (pos, c) in template.char_indices().chain(vec![(template.len(), '\0')].into_iter()) {
-        state = match (c, state) {
-            ('%', EventFormatterState::LookingForEndOfUnformattedChunk { chunk_start_pos }) =>
-                EventFormatterState::RightAfterPercentInUnformattedChunk { chunk_start_pos },
-            ('%', EventFormatterState::RightAfterPercentInUnformattedChunk { chunk_start_pos }) =>
-                EventFormatterState::LookingForEndOfUnformattedChunk { chunk_start_pos },
-            (c, EventFormatterState::RightAfterPercentInUnformattedChunk { chunk_start_pos }) if c.is_digit(10) => {
-                res.push_str(&template[chunk_start_pos..pos - 1]);
-                EventFormatterState::LookingForEndOfFormatNumber { number_start_pos: pos }
-            },
-            (c, EventFormatterState::LookingForEndOfFormatNumber { number_start_pos }) if !c.is_digit(10) => {
-                let fmt_num = match u32::from_str(&template[number_start_pos..pos]) {
-                    Ok(fmt_num) => fmt_num,
-                    Err(_) => return Err(format!("Unable to parse format argument number from \"{}\"",
-                        &template[number_start_pos..pos])),
-                };
-                let fmt_idx = (fmt_num as usize) - 1;
-                if fmt_num > variant_count {
-                    return Err(format!("Format argument number out-of-range ({}, only {} variants)",
-                        fmt_num, variant_count));
-                }
-                if formatted_variants[fmt_idx].is_none() {
-                    let buffer_offset = fmt_idx * std::mem::size_of::<EVT_VARIANT>();
-                    let prop : EVT_VARIANT = unsafe {
-                        std::ptr::read((variants as *const u8).add(buffer_offset) as *const _)
-                    };
-                    let type_hint = if fmt_idx < event_def.fields.len() {
-                        Some(&event_def.fields[fmt_idx].out_type[..])
-                    } else {
-                        None
-                    };
-                    let prop = unwrap_variant_contents(&prop, type_hint)?;
-                    let str_to_insert = match prop {
-                        EvtVariant::Null => "null".to_string(),
-                        EvtVariant::Handle(_) => "<handle>".to_string(),
-                        EvtVariant::String(s) => s,
-                        EvtVariant::UInt(u) => format!("{}", u),
-                        EvtVariant::Int(i) => format!("{}", i),
-                        EvtVariant::Single(f) => format!("{}", f),
-                        EvtVariant::Double(d) => format!("{}", d),
-                        EvtVariant::Boolean(b) => (if b { "true" } else { "false" }).to_string(),
-                        EvtVariant::Binary(v) => format!("{:?}", v),
-                        EvtVariant::DateTime(d) => format!("{}-{}-{} {}:{}:{}.{}",
-                                                           d.wYear, d.wMonth, d.wDay, d.wHour,
-                                                           d.wMinute, d.wSecond, d.wMilliseconds),
-                    };
-                    formatted_variants[fmt_idx] = Some(str_to_insert);
-                }
-                res.push_str(formatted_variants[fmt_idx].as_ref().unwrap());
-                if c == '!' {
-                    EventFormatterState::LookingForEndOfFormatSpec
-                } else if pos == template.len() {
-                    EventFormatterState::EndOfString
-                }
-                else {
-                    EventFormatterState::LookingForEndOfUnformattedChunk { chunk_start_pos: pos }
-                }
-            },
-            ('!', EventFormatterState::LookingForEndOfFormatSpec) =>
-                EventFormatterState::LookingForEndOfUnformattedChunk { chunk_start_pos: pos + 1 },
-            ('\0', EventFormatterState::LookingForEndOfUnformattedChunk { chunk_start_pos }) if pos == template.len() => {
-                res.push_str(&template[chunk_start_pos..pos]);
-                EventFormatterState::EndOfString
-            },
-            (_, state) => state,
+    format_message_template(template, parameters, |fmt_num| {
+        if fmt_num == 0 || fmt_num > variant_count {
+            return Err(format!("Format argument number out-of-range ({}, only {} variants)",
+                               fmt_num, variant_count));
+        }
+        let fmt_idx = (fmt_num as usize) - 1;
+        let buffer_offset = fmt_idx * std::mem::size_of::<EVT_VARIANT>();
+        let prop : EVT_VARIANT = unsafe {
+            std::ptr::read((variants as *const u8).add(buffer_offset) as *const _)
         };
-    }
-    if state != EventFormatterState::EndOfString {
-        return Err(format!("Unexpected final parser state {:?}", state));
-    }
-    Ok(res)
-}
\ No newline at end of file
+        let type_hint = if fmt_idx < event_def.fields.len() {
+            Some(&event_def.fields[fmt_idx].out_type[..])
+        } else {
+            None
+        };
+        let arg = match unwrap_variant_contents(&prop, type_hint)? {
+            EvtVariant::Null => MessageArg::String("null".to_string()),
+            EvtVariant::Handle(_) => MessageArg::String("<handle>".to_string()),
+            EvtVariant::String(s) => MessageArg::String(s),
+            EvtVariant::UInt(u) => MessageArg::UInt(u),
+            EvtVariant::Int(i) => MessageArg::Int(i),
+            EvtVariant::Single(f) => MessageArg::Double(f as f64),
+            EvtVariant::Double(d) => MessageArg::Double(d),
+            EvtVariant::Boolean(b) => MessageArg::String((if b { "true" } else { "false" }).to_string()),
+            EvtVariant::Binary(v) => MessageArg::String(format!("{:?}", v)),
+            // Same as EvtFormatMessage(), which renders xs:dateTime values in UTC
+            EvtVariant::DateTime(d) => MessageArg::String(format_filetime(d, &DateConfig::rfc3339_utc())),
+            array @ EvtVariant::Array(_) => MessageArg::String(variant_as_string(array, &DateConfig::rfc3339_utc())),
+        };
+        Ok(arg)
+    })
+}
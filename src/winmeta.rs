@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+
+/*
+ * Names of the channels, levels, tasks, opcodes and keywords standardized by Windows (see
+ * winmeta.xml in the Windows SDK), which providers can use without defining them
+ */
+
+// System-wide standard channels defined by Windows. Event-provider-specific channels
+// are queried at runtime.
+pub const SYSTEM_CHANNELS: &[(u32, &str, &str)] = &[
+    (0, "TraceClassic", "Events for Classic ETW tracing"),
+    (8, "System", "Events for all installed system services.  This channel is secured to applications running under system service accounts or user applications running under local adminstrator privileges"),
+    (9, "Application", "Events for all user-level applications. This channel is not secured and open to any applications. Applications which log extensive information should define an application-specific channel"),
+    (10, "Security", "The Windows Audit Log.  For exclusive use of the Windows Local Security Authority. User events may appear as audits if supported by the underlying application"),
+    (11, "TraceLogging", "Event contains provider traits and TraceLogging event metadata"),
+    (12, "ProviderMetadata", "Event contains provider traits"),
+];
+
+// System-wide standard levels defined by Windows. Event-provider-specific levels
+// are queried at runtime.
+pub const SYSTEM_LEVELS: &[(u32, &str, &str)] = &[
+    (0, "win:LogAlways", "Log Always"),
+    (1, "win:Critical", "Only critical errors"),
+    (2, "win:Error", "All errors, includes win:Critical"),
+    (3, "win:Warning", "All warnings, includes win:Error"),
+    (4, "win:Informational", "All informational content, including win:Warning"),
+    (5, "win:Verbose", "All tracing, including previous levels"),
+];
+
+// System-wide tasks defined by Windows. Event-provider-specific tasks
+// are queried at runtime.
+pub const SYSTEM_TASKS: &[(u32, &str, &str)] = &[
+    (0, "win:None", "undefined task"),
+];
+
+// System-wide opcodes defined by Windows. Event-provider-specific opcodes
+// are queried at runtime.
+pub const SYSTEM_OPCODES: &[(u32, &str, &str)] = &[
+    (0, "win:None", "An informational event"),
+    (1, "win:Start", "An activity start event"),
+    (2, "win:Stop", "An activity end event"),
+    (3, "win:DC_Start", "A trace collection start event"),
+    (4, "win:DC_Stop", "A trace collection end event"),
+    (5, "win:Extension", "An extensional event"),
+    (6, "win:Reply", "A reply event"),
+    (7, "win:Resume", "An event representing the activity resuming from the suspension"),
+    (8, "win:Suspend", "An event representing the activity is suspended, pending another activity's completion"),
+    (9, "win:Send", "An event representing the activity is transferred to another component, and can continue to work"),
+];
+
+// System-wide keywords defined by Windows, in bits 48-55. Event-provider-specific keywords
+// are queried at runtime.
+pub const SYSTEM_KEYWORDS: &[(u64, &str, &str)] = &[
+    (0x0001_0000_0000_0000, "win:ResponseTime", "Response Time"),
+    (0x0002_0000_0000_0000, "win:WDIContext", "WDI Context"),
+    (0x0004_0000_0000_0000, "win:WDIDiag", "WDI Diag"),
+    (0x0008_0000_0000_0000, "win:SQM", "SQM"),
+    (0x0010_0000_0000_0000, "win:AuditFailure", "Audit Failure"),
+    (0x0020_0000_0000_0000, "win:AuditSuccess", "Audit Success"),
+    (0x0040_0000_0000_0000, "win:CorrelationHint", "Correlation Hint"),
+    (0x0080_0000_0000_0000, "win:EventlogClassic", "Classic"),
+];
+
+pub fn get_system_standard_val(arr: &'static [(u32, &'static str, &'static str)], val: u32) -> Option<(&'static str, &'static str)> {
+    match arr.binary_search_by(|(k, _, _)| k.cmp(&val)) {
+        Ok(i) => Some((arr[i].1, arr[i].2)),
+        _ => None,
+    }
+}
+
+// Resolves a level, task, opcode or channel value to its name, from the provider's own
+// definitions first, then from the ones standardized by Windows
+pub fn resolve_standard_name(prov_names: &BTreeMap<u64, String>, standard: &'static [(u32, &'static str, &'static str)], val: u64) -> Option<String> {
+    match prov_names.get(&val) {
+        Some(s) => Some(s.to_string()),
+        None if val <= u32::MAX as u64 => get_system_standard_val(standard, val as u32).map(|(name, _)| name.to_string()),
+        None => None,
+    }
+}
+
+// Opcodes defined by a provider are keyed by (opcode << 16 | task), the task being 0 for the
+// ones which are not specific to a task (like EvtPublisherMetadataOpcodeValue), so the same
+// opcode can have a different name in each task
+pub fn resolve_opcode_name(prov_opcodes: &BTreeMap<u64, String>, task: u64, opcode: u64) -> Option<String> {
+    match prov_opcodes.get(&(opcode << 16 | (task & 0xFFFF))).or_else(|| prov_opcodes.get(&(opcode << 16))) {
+        Some(s) => Some(s.to_string()),
+        None if opcode <= u32::MAX as u64 => get_system_standard_val(SYSTEM_OPCODES, opcode as u32).map(|(name, _)| name.to_string()),
+        None => None,
+    }
+}
+
+pub fn resolve_keyword_names(prov_keywords: &BTreeMap<u64, String>, keywords: u64) -> Vec<String> {
+    let mut keyword_names = Vec::new();
+    // Ignore reserved placeholder bits 56-63, used to pass information about channels
+    // (see winmeta.xml)
+    for bit in 0..56 {
+        let val : u64 = 1 << bit;
+        if (val & keywords) == 0 {
+            continue;
+        }
+        match (prov_keywords.get(&val), SYSTEM_KEYWORDS.iter().find(|(k, _, _)| *k == val)) {
+            (Some(name), _) => keyword_names.push(name.to_string()),
+            (None, Some((_, name, _))) => keyword_names.push(name.to_string()),
+            (None, None) => keyword_names.push(format!("0x{:X}", val)),
+        }
+    }
+    keyword_names
+}
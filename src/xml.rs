@@ -1,13 +1,41 @@
+#[cfg(windows)]
 use winapi::um::winevt::*;
+#[cfg(windows)]
 use winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER;
+#[cfg(windows)]
 use std::ptr::null_mut;
 use std::result::Result;
+#[cfg(windows)]
+use std::collections::BTreeMap;
+#[cfg(windows)]
 use winapi::ctypes::c_void;
+#[cfg(windows)]
 use crate::windows::{EvtHandle, get_win32_errcode};
-use crate::formatting::CommonEventProperties;
-use crate::RenderingConfig;
+#[cfg(windows)]
+use crate::formatting::{variant_as_string, render_event_values, CommonEventProperties};
+#[cfg(windows)]
+use crate::metadata::{EventDefinition, classify_event};
+#[cfg(windows)]
+use crate::output_cols::{EventColumns, OutputColumn};
+#[cfg(windows)]
+use crate::{RenderingConfig, EventOutput};
+use crate::output::OutputSink;
 
-pub fn render_event_xml(h_event: &EvtHandle, _common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<(), String> {
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+// What EvtFormatMessageXml adds to events, as a <RenderingInfo> element
+struct RenderingInfo {
+    message: Option<String>,
+    level: Option<String>,
+    task: Option<String>,
+    opcode: Option<String>,
+    channel: Option<String>,
+    provider: Option<String>,
+    keywords: Vec<String>,
+}
+
+#[cfg(windows)]
+pub fn render_xml_string(h_event: &EvtHandle) -> Result<String, String> {
     let mut buffer_len_req : u32 = 0;
     let mut unused : u32 = 0;
     let res = unsafe {
@@ -36,21 +64,362 @@ pub fn render_event_xml(h_event: &EvtHandle, _common_props: &CommonEventProperti
         return Err(format!("Event rendering as XML failed with code {}", get_win32_errcode()));
     }
     let slice = unsafe { std::slice::from_raw_parts(buffer.as_ptr(), ((buffer_len_req + 1) / 2) as usize) };
-    let xml = match String::from_utf16(slice) {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(format!("Discarding event with non-unicode XML rendering ({}): {}",
-                      e, String::from_utf16_lossy(&buffer)));
-        },
+    match String::from_utf16(slice) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!("Discarding event with non-unicode XML rendering ({}): {}",
+                              e, String::from_utf16_lossy(&buffer))),
+    }
+}
+
+fn escape_xml_text(text: &str, canonical: bool) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '\r' if canonical => res.push_str("&#xD;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn escape_xml_attribute(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '"' => res.push_str("&quot;"),
+            '\t' => res.push_str("&#x9;"),
+            '\n' => res.push_str("&#xA;"),
+            '\r' => res.push_str("&#xD;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+// Qualified name (with its prefix, if any) of the element or attribute at the given position
+// of the source XML, since the parser only keeps namespace URIs
+fn source_qname(source: &str, start: usize) -> &str {
+    let qname = source[start..].trim_start_matches('<');
+    let end = qname.find(|c: char| c.is_whitespace() || c == '=' || c == '/' || c == '>').unwrap_or(qname.len());
+    &qname[..end]
+}
+
+fn qualified_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}:{}", prefix, name)
+    }
+}
+
+struct EventXmlWriter<'a> {
+    out: String,
+    source: &'a str,
+    canonical: bool,
+    // Names of <Data> elements which don't have a Name attribute in the event
+    field_names: &'a [String],
+    rendering_info: &'a RenderingInfo,
+}
+
+impl<'a> EventXmlWriter<'a> {
+    fn start_tag(&mut self, qname: &str, namespaces: &[(Option<&str>, &str)], attributes: &[(String, String)]) {
+        self.out.push('<');
+        self.out.push_str(qname);
+        for (prefix, uri) in namespaces {
+            match prefix {
+                Some(prefix) => self.out.push_str(&format!(" xmlns:{}=\"{}\"", prefix, escape_xml_attribute(uri))),
+                None => self.out.push_str(&format!(" xmlns=\"{}\"", escape_xml_attribute(uri))),
+            }
+        }
+        for (name, value) in attributes {
+            self.out.push_str(&format!(" {}=\"{}\"", name, escape_xml_attribute(value)));
+        }
+    }
+
+    fn element(&mut self, node: roxmltree::Node, data_index: Option<usize>) {
+        let name = node.tag_name().name();
+        let qname = source_qname(self.source, node.range().start);
+        let prefix = qname.rsplit_once(':').map(|(prefix, _)| prefix).unwrap_or("");
+        // Only declare namespaces which are not already in scope from the parent element
+        let parent_namespaces = node.parent().map(|p| p.namespaces()).unwrap_or(&[]);
+        let mut namespaces : Vec<(Option<&str>, &str)> = node.namespaces().iter()
+            .filter(|ns| !parent_namespaces.iter().any(|p| p.name() == ns.name() && p.uri() == ns.uri()))
+            .map(|ns| (ns.name(), ns.uri()))
+            .collect();
+        let mut attributes : Vec<(String, String)> = node.attributes().iter().map(|attr| {
+            let name = match attr.namespace() {
+                Some(XML_NAMESPACE) => format!("xml:{}", attr.name()),
+                Some(_) => source_qname(self.source, attr.range().start).to_owned(),
+                None => attr.name().to_owned(),
+            };
+            (name, attr.value().to_owned())
+        }).collect();
+        if let Some(idx) = data_index {
+            if node.attribute("Name").is_none() {
+                if let Some(field_name) = self.field_names.get(idx) {
+                    attributes.push(("Name".to_owned(), field_name.to_owned()));
+                }
+            }
+        }
+        if self.canonical {
+            namespaces.sort();
+            attributes.sort();
+        }
+        self.start_tag(qname, &namespaces, &attributes);
+
+        let is_event = node.parent().map(|p| p.is_root()).unwrap_or(false);
+        let children : Vec<roxmltree::Node> = node.children().filter(|child| {
+            // Whitespace between elements is only there for indentation
+            !(self.canonical && child.is_text() && child.text().unwrap_or("").trim().is_empty())
+        }).collect();
+        if children.is_empty() && !is_event && !self.canonical {
+            self.out.push_str("/>");
+            return;
+        }
+        self.out.push('>');
+        let mut data_count = 0;
+        for child in children {
+            if child.is_element() {
+                let data_index = if name == "EventData" && child.has_tag_name("Data") {
+                    data_count += 1;
+                    Some(data_count - 1)
+                } else {
+                    None
+                };
+                self.element(child, data_index);
+            }
+            else if child.is_text() {
+                let text = child.text().unwrap_or("");
+                if self.canonical {
+                    self.out.push_str(&escape_xml_text(&text.replace("\r\n", "\n"), true));
+                } else {
+                    self.out.push_str(&escape_xml_text(text, false));
+                }
+            }
+        }
+        if is_event {
+            self.rendering_info(prefix);
+        }
+        self.out.push_str(&format!("</{}>", qname));
+    }
+
+    fn text_element(&mut self, prefix: &str, name: &str, text: &str) {
+        let qname = qualified_name(prefix, name);
+        self.out.push_str(&format!("<{}>{}</{}>", qname, escape_xml_text(text, self.canonical), qname));
+    }
+
+    // Written in the namespace of <Event>, with the same prefix
+    fn rendering_info(&mut self, prefix: &str) {
+        let info = self.rendering_info;
+        self.start_tag(&qualified_name(prefix, "RenderingInfo"), &[], &[]);
+        self.out.push('>');
+        for (name, value) in &[("Message", &info.message), ("Level", &info.level), ("Task", &info.task),
+                               ("Opcode", &info.opcode), ("Channel", &info.channel), ("Provider", &info.provider)] {
+            if let Some(value) = value {
+                self.text_element(prefix, name, value);
+            }
+        }
+        if !info.keywords.is_empty() {
+            self.out.push_str(&format!("<{}>", qualified_name(prefix, "Keywords")));
+            for keyword in &info.keywords {
+                self.text_element(prefix, "Keyword", keyword);
+            }
+            self.out.push_str(&format!("</{}>", qualified_name(prefix, "Keywords")));
+        }
+        self.out.push_str(&format!("</{}>", qualified_name(prefix, "RenderingInfo")));
+    }
+}
+
+// Rewrites the XML of an event with a <RenderingInfo>, and names of fields which have none
+fn enrich_event_xml(xml: &str, field_names: &[String], rendering_info: &RenderingInfo, canonical: bool) -> Result<String, String> {
+    let source = xml.trim_end_matches('\0');
+    let doc = match roxmltree::Document::parse(source) {
+        Ok(doc) => doc,
+        Err(e) => return Err(format!("Unable to parse event XML: {}", e)),
+    };
+    let mut writer = EventXmlWriter {
+        out: String::with_capacity(xml.len() * 2),
+        source,
+        canonical,
+        field_names,
+        rendering_info,
     };
+    writer.element(doc.root_element(), None);
+    Ok(writer.out)
+}
+
+#[cfg(windows)]
+fn event_rendering_info(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<(RenderingInfo, Vec<String>), String> {
+    let (buffer, props_count) = render_event_values(h_event)?;
 
-    match render_cfg.output_file.lock() {
-        Ok(mut f) => {
-            match f.write((xml + "\n").as_bytes()) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(format!("Unable to write XML to file: {:?}", e)),
+    let mut event_def = &EventDefinition::empty();
+    let no_parameters = BTreeMap::new();
+    let mut parameters = &no_parameters;
+    let mut provider = None;
+    let prov_meta = render_cfg.provider_metadata(&common_props.provider, common_props.provider_guid.as_deref());
+    if let Some(prov_meta) = &prov_meta {
+        parameters = &prov_meta.parameters;
+        provider = prov_meta.message.to_owned();
+        if let Some(versions) = prov_meta.events.get(&common_props.eventid) {
+            if let Some(known_event_def) = versions.get(&common_props.version) {
+                event_def = known_event_def;
             }
-        },
-        Err(e) => return Err(format!("Failed to acquire lock to output file: {}", e)),
+        }
+    }
+
+    let classification = classify_event(common_props, event_def, prov_meta.as_deref());
+    let columns = EventColumns {
+        common_props,
+        render_cfg,
+        event_def,
+        parameters,
+        classification: &classification,
+        variants: buffer.as_ptr() as *const EVT_VARIANT,
+        variant_count: props_count,
+    };
+    let message = match event_def.message {
+        Some(_) => Some(variant_as_string(columns.column_value(&OutputColumn::FormattedMessage)?, &render_cfg.datefmt)),
+        None => None,
+    };
+    let field_names = event_def.fields.iter().map(|field_def| field_def.name.to_owned()).collect();
+    Ok((RenderingInfo {
+        message,
+        level: classification.level_name.to_owned(),
+        task: classification.task_name.to_owned(),
+        opcode: classification.opcode_name.to_owned(),
+        channel: common_props.channel.to_owned(),
+        provider,
+        keywords: classification.keyword_names.to_owned(),
+    }, field_names))
+}
+
+// Start and end of XML outputs, when rendered as a single document
+fn xml_document_header() -> &'static str {
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<Events>\n"
+}
+
+fn xml_document_footer() -> &'static str {
+    "</Events>\n"
+}
+
+// XML output rendered as a single document, which is ended when dropped so that it stays
+// well-formed even when rendering stops early because of an error
+pub struct XmlDocument {
+    output: Option<OutputSink>,
+}
+
+impl XmlDocument {
+    pub fn start(output: &OutputSink) -> Result<XmlDocument, String> {
+        output.write_all(xml_document_header().as_bytes())?;
+        Ok(XmlDocument { output: Some(output.clone()) })
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        match self.output.take() {
+            Some(output) => output.write_all(xml_document_footer().as_bytes()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for XmlDocument {
+    fn drop(&mut self) {
+        if let Some(output) = self.output.take() {
+            if let Err(e) = output.write_all(xml_document_footer().as_bytes()) {
+                warn!("Unable to end XML document: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn render_event_xml(h_event: &EvtHandle, common_props: &CommonEventProperties, render_cfg: &RenderingConfig) -> Result<EventOutput, String> {
+    let mut xml = render_xml_string(h_event)?;
+    if render_cfg.xml_document {
+        let (rendering_info, field_names) = event_rendering_info(h_event, common_props, render_cfg)?;
+        xml = enrich_event_xml(&xml, &field_names, &rendering_info, render_cfg.xml_canonical)?;
+    }
+    Ok(EventOutput { header: None, body: xml + "\n" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendering_info() -> RenderingInfo {
+        RenderingInfo {
+            message: Some("Hello & bye".to_owned()),
+            level: Some("Information".to_owned()),
+            task: None,
+            opcode: None,
+            channel: Some("Application".to_owned()),
+            provider: None,
+            keywords: vec!["Classic".to_owned()],
+        }
+    }
+
+    #[test]
+    fn keeps_default_namespaces() {
+        let xml = "<Event xmlns='http://schemas.microsoft.com/win/2004/08/events/event'><System><EventID>104</EventID></System>\
+                   <UserData><LogFileCleared xmlns='http://manifests.microsoft.com/win/2004/08/windows/eventlog'>\
+                   <SubjectUserName>admin</SubjectUserName></LogFileCleared></UserData></Event>\0";
+        assert_eq!(enrich_event_xml(xml, &[], &rendering_info(), false).unwrap(),
+                   "<Event xmlns=\"http://schemas.microsoft.com/win/2004/08/events/event\"><System><EventID>104</EventID></System>\
+                    <UserData><LogFileCleared xmlns=\"http://manifests.microsoft.com/win/2004/08/windows/eventlog\">\
+                    <SubjectUserName>admin</SubjectUserName></LogFileCleared></UserData>\
+                    <RenderingInfo><Message>Hello &amp; bye</Message><Level>Information</Level><Channel>Application</Channel>\
+                    <Keywords><Keyword>Classic</Keyword></Keywords></RenderingInfo></Event>");
+    }
+
+    #[test]
+    fn keeps_namespace_prefixes() {
+        let xml = "<e:Event xmlns:e='urn:event' xmlns:u='urn:user'><e:EventData><e:Data>1</e:Data><e:Data u:Kind='x'>2</e:Data></e:EventData>\
+                   <u:Extra xml:lang='en' u:Id='3'/><Plain xmlns=''/></e:Event>";
+        let field_names = vec!["First".to_owned(), "Second".to_owned()];
+        let info = RenderingInfo { message: None, level: None, task: None, opcode: None, channel: None,
+                                   provider: Some("Test".to_owned()), keywords: vec![] };
+        assert_eq!(enrich_event_xml(xml, &field_names, &info, false).unwrap(),
+                   "<e:Event xmlns:e=\"urn:event\" xmlns:u=\"urn:user\"><e:EventData><e:Data Name=\"First\">1</e:Data>\
+                    <e:Data u:Kind=\"x\" Name=\"Second\">2</e:Data></e:EventData>\
+                    <u:Extra xml:lang=\"en\" u:Id=\"3\"/><Plain xmlns=\"\"/>\
+                    <e:RenderingInfo><e:Provider>Test</e:Provider></e:RenderingInfo></e:Event>");
+    }
+
+    #[test]
+    fn sorts_canonical_namespaces_and_attributes() {
+        let xml = "<Event xmlns:b='urn:b' xmlns='urn:event' xmlns:a='urn:a'><Data b:Z='1' a:Y='2'>x\r\ny</Data>\n</Event>";
+        let info = RenderingInfo { message: None, level: None, task: None, opcode: None, channel: None,
+                                   provider: None, keywords: vec![] };
+        assert_eq!(enrich_event_xml(xml, &[], &info, true).unwrap(),
+                   "<Event xmlns=\"urn:event\" xmlns:a=\"urn:a\" xmlns:b=\"urn:b\"><Data a:Y=\"2\" b:Z=\"1\">x\ny</Data>\
+                    <RenderingInfo></RenderingInfo></Event>");
+    }
+
+    #[test]
+    fn ends_document_when_dropped() {
+        let path = std::env::temp_dir().join(format!("evtq-test-{}-document.xml", std::process::id()));
+        let output = OutputSink::open(&path.display().to_string(), false).unwrap();
+        let document = XmlDocument::start(&output).unwrap();
+        output.write_all(b"<Event/>\n").unwrap();
+        // e.g. returning early on an error
+        std::mem::drop(document);
+        std::mem::drop(output);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}<Event/>\n{}", xml_document_header(), xml_document_footer()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ends_document_once() {
+        let path = std::env::temp_dir().join(format!("evtq-test-{}-finished.xml", std::process::id()));
+        let output = OutputSink::open(&path.display().to_string(), false).unwrap();
+        XmlDocument::start(&output).unwrap().finish().unwrap();
+        std::mem::drop(output);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}{}", xml_document_header(), xml_document_footer()));
+        std::fs::remove_file(&path).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,219 @@
+#!/usr/bin/env python3
+"""
+Generates the fixtures used by the WEVT_TEMPLATE, PE and offline extraction tests:
+
+wevt_template.bin  a WEVT_TEMPLATE resource, as compiled by mc.exe, defining one provider with
+                   a channel, levels, tasks, global and task-specific opcodes, keywords, and
+                   events with and without templates
+provider.dll       a minimal PE32+ file with only a resource section, holding this template and
+                   the message table of the provider
+
+Run it from this directory: python3 make_provider_dll.py
+"""
+import struct
+import uuid
+
+PROVIDER_GUID = uuid.UUID("12345678-1234-5678-9abc-def012345678")
+TASK_GUID = uuid.UUID("00000000-0000-0000-0000-000000000000")
+
+MESSAGES = {
+    0x90000001: "Evtq Test Provider (display name)",
+    0xB0000064: "Connected to %1 on port %2",
+    0xB0000065: "Disconnected from %1",
+}
+
+
+def name(text):
+    raw = (text + "\0").encode("utf-16-le")
+    size = 4 + len(raw)
+    raw += b"\0" * (-size % 4)
+    return struct.pack("<I", size + (-size % 4)) + raw
+
+
+class Blob:
+    def __init__(self):
+        self.data = bytearray()
+
+    def offset(self):
+        return len(self.data)
+
+    def add(self, raw):
+        offset = len(self.data)
+        self.data += raw
+        return offset
+
+    def patch_u32(self, offset, value):
+        self.data[offset:offset + 4] = struct.pack("<I", value)
+
+
+def wevt_template():
+    blob = Blob()
+    blob.add(b"CRIM" + struct.pack("<IHHI", 0, 3, 1, 1))
+    provider_entry = blob.add(PROVIDER_GUID.bytes_le + struct.pack("<I", 0))
+
+    provider = blob.add(b"WEVT" + struct.pack("<IIII", 0, 0x90000001, 6, 0))
+    blob.patch_u32(provider_entry + 16, provider)
+    descriptors = blob.add(b"\0" * 6 * 8)
+    elements = []
+
+    def table(signature, entries, entry_size):
+        start = blob.add(signature + struct.pack("<II", 0, len(entries)))
+        rows = blob.add(b"\0" * entry_size * len(entries))
+        elements.append(start)
+        return start, rows
+
+    # CHAN { u32 value, u32 name_offset, u32 unknown, u32 message_id }
+    channels = [(16, "Evtq-Test/Operational")]
+    start, rows = table(b"CHAN", channels, 16)
+    for i, (value, text) in enumerate(channels):
+        blob.data[rows + i * 16:rows + (i + 1) * 16] = struct.pack("<IIII", value, blob.add(name(text)), 0, 0xFFFFFFFF)
+    blob.patch_u32(start + 4, blob.offset() - start)
+
+    # LEVL and OPCO { u32 value, u32 message_id, u32 name_offset }
+    levels = [(16, "evtq:Trace")]
+    # Opcodes are (opcode << 16 | task), task-specific ones share the same opcode value
+    opcodes = [(10 << 16, "Retry"), (11 << 16 | 1, "Handshake"), (11 << 16 | 2, "Teardown")]
+    for signature, entries in ((b"LEVL", levels), (b"OPCO", opcodes)):
+        start, rows = table(signature, entries, 12)
+        for i, (value, text) in enumerate(entries):
+            blob.data[rows + i * 12:rows + (i + 1) * 12] = struct.pack("<III", value, 0xFFFFFFFF, blob.add(name(text)))
+        blob.patch_u32(start + 4, blob.offset() - start)
+
+    # TASK { u32 value, u32 message_id, GUID, u32 name_offset }
+    tasks = [(1, "Connect"), (2, "Disconnect")]
+    start, rows = table(b"TASK", tasks, 28)
+    for i, (value, text) in enumerate(tasks):
+        blob.data[rows + i * 28:rows + (i + 1) * 28] = struct.pack("<II", value, 0xFFFFFFFF) + TASK_GUID.bytes_le + struct.pack("<I", blob.add(name(text)))
+    blob.patch_u32(start + 4, blob.offset() - start)
+
+    # KEYW { u64 mask, u32 message_id, u32 name_offset }
+    keywords = [(0x1, "Network"), (0x2, "Authentication")]
+    start, rows = table(b"KEYW", keywords, 16)
+    for i, (value, text) in enumerate(keywords):
+        blob.data[rows + i * 16:rows + (i + 1) * 16] = struct.pack("<QII", value, 0xFFFFFFFF, blob.add(name(text)))
+    blob.patch_u32(start + 4, blob.offset() - start)
+
+    # TTBL, with TEMP { ..., u32 item_count, u32 name_count, u32 items_offset, u32 unknown, GUID, BinXML }
+    ttbl = blob.add(b"TTBL" + struct.pack("<II", 0, 2))
+
+    def template(items):
+        start = blob.add(b"TEMP" + struct.pack("<IIIII", 0, len(items), len(items), 0, 0) + b"\0" * 16)
+        blob.add(b"\x0f\x01\x01\x00\x00")  # BinXML fragment header, contents are not parsed
+        items_offset = blob.add(b"\0" * 20 * len(items))
+        blob.patch_u32(start + 16, items_offset)
+        for i, (text, in_type, out_type) in enumerate(items):
+            name_offset = blob.add(name(text)) if text else 0
+            blob.data[items_offset + i * 20:items_offset + (i + 1) * 20] = struct.pack(
+                "<IBBHIHHI", 0, in_type, out_type, 0, 0, 1, 0, name_offset)
+        blob.patch_u32(start + 4, blob.offset() - start)
+        return start
+
+    # Host as win:UnicodeString with the default outType, Port as win:UInt16 with outType win:Port
+    connect_template = template([("Host", 1, 0), ("Port", 6, 22)])
+    # An unnamed win:UInt32
+    disconnect_template = template([(None, 8, 0)])
+    blob.patch_u32(ttbl + 4, blob.offset() - ttbl)
+
+    # EVNT { u16 id, u8 version, u8 channel, u8 level, u8 opcode, u16 task, u64 keywords,
+    #        u32 message_id, u32 template_offset, u32 opcode_offset, u32 level_offset,
+    #        u32 task_offset, u32 unknown_count, u32 unknown_offset, u32 flags }
+    events = [
+        (100, 0, 16, 4, 11, 1, 0x8000000000000001, 0xB0000064, connect_template),
+        (101, 1, 16, 16, 11, 2, 0x8000000000000003, 0xB0000065, disconnect_template),
+        (102, 0, 16, 2, 10, 0, 0, 0xFFFFFFFF, 0),
+    ]
+    start = blob.add(b"EVNT" + struct.pack("<III", 0, len(events), 0))
+    elements.append(start)
+    for (event_id, version, channel, level, opcode, task, keywords, message_id, template_offset) in events:
+        blob.add(struct.pack("<HBBBBHQIIIIIIII", event_id, version, channel, level, opcode, task, keywords,
+                             message_id, template_offset, 0, 0, 0, 0, 0, 0))
+    blob.patch_u32(start + 4, blob.offset() - start)
+
+    for i, element in enumerate(elements):
+        blob.patch_u32(descriptors + i * 8, element)
+    blob.patch_u32(provider + 4, blob.offset() - provider)
+    blob.patch_u32(4, blob.offset())
+    return bytes(blob.data)
+
+
+def message_table():
+    ids = sorted(MESSAGES)
+    blocks = [[ids[0]]]
+    for message_id in ids[1:]:
+        if message_id == blocks[-1][-1] + 1:
+            blocks[-1].append(message_id)
+        else:
+            blocks.append([message_id])
+    header = struct.pack("<I", len(blocks))
+    entries = b""
+    offset = 4 + 12 * len(blocks)
+    for block in blocks:
+        header += struct.pack("<III", block[0], block[-1], offset + len(entries))
+        for message_id in block:
+            text = (MESSAGES[message_id] + "\r\n\0").encode("utf-16-le")
+            text += b"\0" * (-len(text) % 4)
+            entries += struct.pack("<HH", 4 + len(text), 1) + text
+    return header + entries
+
+
+def resource_section(rva, wevt, messages):
+    # Root { "WEVT_TEMPLATE", RT_MESSAGETABLE } -> name ID 1 -> language 0x409 -> data entry
+    def directory(named, ids):
+        return struct.pack("<IIHHHH", 0, 0, 0, 0, named, ids)
+
+    layout = bytearray()
+    root = directory(1, 1)
+    entries_size = 16 + 2 * 8
+    type_dirs = [entries_size + i * 24 for i in range(2)]
+    name_dirs = [entries_size + 48 + i * 24 for i in range(2)]
+    data_entries = [entries_size + 96 + i * 16 for i in range(2)]
+    name_string = entries_size + 128
+    wevt_name = "WEVT_TEMPLATE".encode("utf-16-le")
+    data_start = name_string + 2 + len(wevt_name)
+    data_start += -data_start % 8
+    wevt_offset = data_start
+    messages_offset = wevt_offset + len(wevt) + (-len(wevt) % 8)
+
+    layout += root
+    layout += struct.pack("<II", 0x80000000 | name_string, 0x80000000 | type_dirs[0])
+    layout += struct.pack("<II", 11, 0x80000000 | type_dirs[1])
+    for i in range(2):
+        layout += directory(0, 1) + struct.pack("<II", 1, 0x80000000 | name_dirs[i])
+    for i in range(2):
+        layout += directory(0, 1) + struct.pack("<II", 0x409, data_entries[i])
+    layout += struct.pack("<IIII", rva + wevt_offset, len(wevt), 0, 0)
+    layout += struct.pack("<IIII", rva + messages_offset, len(messages), 0, 0)
+    layout += struct.pack("<H", len(wevt_name) // 2) + wevt_name
+    layout += b"\0" * (wevt_offset - len(layout))
+    layout += wevt
+    layout += b"\0" * (messages_offset - len(layout))
+    layout += messages
+    return bytes(layout)
+
+
+def pe_file(rsrc):
+    rsrc_rva, file_alignment = 0x1000, 0x200
+    raw_size = len(rsrc) + (-len(rsrc) % file_alignment)
+    dos = b"MZ" + b"\0" * 58 + struct.pack("<I", 0x40)
+    file_header = struct.pack("<HHIIIHH", 0x8664, 1, 0, 0, 0, 240, 0x2022)
+    optional = bytearray(240)
+    struct.pack_into("<H", optional, 0, 0x20B)
+    struct.pack_into("<I", optional, 32, 0x1000)  # SectionAlignment
+    struct.pack_into("<I", optional, 36, file_alignment)
+    struct.pack_into("<I", optional, 56, rsrc_rva + raw_size)  # SizeOfImage
+    struct.pack_into("<I", optional, 60, file_alignment)  # SizeOfHeaders
+    struct.pack_into("<I", optional, 108, 16)  # NumberOfRvaAndSizes
+    struct.pack_into("<II", optional, 112 + 2 * 8, rsrc_rva, len(rsrc))
+    section = b".rsrc\0\0\0" + struct.pack("<IIIIIIHHI", len(rsrc), rsrc_rva, raw_size, file_alignment,
+                                           0, 0, 0, 0, 0x40000040)
+    headers = dos + b"PE\0\0" + file_header + bytes(optional) + section
+    headers += b"\0" * (file_alignment - len(headers))
+    return headers + rsrc + b"\0" * (raw_size - len(rsrc))
+
+
+if __name__ == "__main__":
+    wevt = wevt_template()
+    with open("wevt_template.bin", "wb") as f:
+        f.write(wevt)
+    with open("provider.dll", "wb") as f:
+        f.write(pe_file(resource_section(0x1000, wevt, message_table())))